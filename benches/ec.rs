@@ -0,0 +1,180 @@
+//! Benchmarks of EC command throughput and the fade loop.
+//!
+//! Run with `cargo bench`, optionally followed by `--` and a substring of the benchmark names to
+//! run. The round trips go through the EC emulator (`keylightd emulate-ec`), so they measure the
+//! overhead of keylightd and the socket, not of a real EC.
+//!
+//! This is not a statistical benchmark harness like criterion: it only measures rough wall-clock
+//! time, with no outlier detection, confidence intervals or comparison against earlier runs. Only
+//! differences well above the run-to-run noise (easily 10% or more) mean anything.
+
+use std::{
+    cell::LazyCell,
+    env,
+    hint::black_box,
+    io,
+    path::PathBuf,
+    process::{self, Child, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use keylightd::{
+    command::{
+        GetKeyboardBacklight, GetKeyboardBacklightResponse, GetVersionResponse, LedControl, LedId,
+        SetKeyboardBacklight,
+    },
+    ec::EmbeddedController,
+    fade::{fade_to, Backlight},
+    wire::{Encode, Reader},
+};
+
+const KEYLIGHTD: &str = env!("CARGO_BIN_EXE_keylightd");
+
+/// How long each benchmark runs, after warming up for a tenth of that.
+const MEASUREMENT_TIME: Duration = Duration::from_secs(2);
+
+/// Runs `f` repeatedly and prints the mean and the fastest time per iteration.
+///
+/// Iterations are timed in batches, which grow until a batch takes long enough to be timed
+/// accurately.
+fn bench(filter: Option<&str>, name: &str, mut f: impl FnMut()) {
+    if filter.is_some_and(|filter| !name.contains(filter)) {
+        return;
+    }
+    let mut batch = 1u32;
+    let warm_up = Instant::now();
+    while warm_up.elapsed() < MEASUREMENT_TIME / 10 {
+        let start = Instant::now();
+        for _ in 0..batch {
+            f();
+        }
+        if start.elapsed() < Duration::from_millis(1) {
+            batch *= 2;
+        }
+    }
+
+    let (mut total, mut iterations, mut fastest) = (Duration::ZERO, 0, Duration::MAX);
+    while total < MEASUREMENT_TIME {
+        let start = Instant::now();
+        for _ in 0..batch {
+            f();
+        }
+        let elapsed = start.elapsed();
+        total += elapsed;
+        iterations += batch;
+        fastest = fastest.min(elapsed / batch);
+    }
+    println!(
+        "{name:<32} {:>12?}/iter (fastest {fastest:?}, {iterations} iterations)",
+        total / iterations
+    );
+}
+
+/// A backlight that only stores its level.
+struct MockBacklight(u8);
+
+impl Backlight for MockBacklight {
+    fn get(&mut self) -> io::Result<u8> {
+        Ok(self.0)
+    }
+
+    fn set(&mut self, _: u8, to: u8) -> io::Result<()> {
+        self.0 = black_box(to);
+        Ok(())
+    }
+}
+
+/// The emulated EC's keyboard backlight.
+struct EcBacklight<'a>(&'a EmbeddedController);
+
+impl Backlight for EcBacklight<'_> {
+    fn get(&mut self) -> io::Result<u8> {
+        Ok(self.0.command(GetKeyboardBacklight)?.percent)
+    }
+
+    fn set(&mut self, _: u8, to: u8) -> io::Result<()> {
+        self.0.command(SetKeyboardBacklight { percent: to })?;
+        Ok(())
+    }
+}
+
+/// An emulator process and a connection to it. The process is killed when dropped.
+struct Emulator {
+    child: Child,
+    socket: PathBuf,
+    ec: EmbeddedController,
+}
+
+impl Emulator {
+    fn start() -> Self {
+        let socket = env::temp_dir().join(format!("keylightd-bench-{}.sock", process::id()));
+        let child = process::Command::new(KEYLIGHTD)
+            .arg("emulate-ec")
+            .arg(&socket)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let ec = EmbeddedController::open_path(&socket).unwrap();
+        Self { child, socket, ec }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`; anything else selects benchmarks by name.
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let filter = filter.as_deref();
+
+    let mut buf = Vec::new();
+    bench(filter, "encode/set_keyboard_backlight", || {
+        buf.clear();
+        black_box(SetKeyboardBacklight { percent: 50 }).encode(&mut buf);
+    });
+    bench(filter, "encode/led_control", || {
+        buf.clear();
+        black_box(LedControl::auto(LedId::POWER)).encode(&mut buf);
+    });
+    let response = [0x5a; 256];
+    bench(filter, "decode/get_keyboard_backlight", || {
+        black_box(Reader::new(black_box(&response)).read::<GetKeyboardBacklightResponse>());
+    });
+    bench(filter, "decode/get_version", || {
+        black_box(Reader::new(black_box(&response)).read::<GetVersionResponse>());
+    });
+
+    let mut backlight = MockBacklight(0);
+    bench(filter, "fade/mock/0-100-0", || {
+        for target in [100, 0] {
+            fade_to(&mut backlight, false, || target, || {}).unwrap();
+        }
+    });
+
+    // Only started if one of its benchmarks runs.
+    let emulator = LazyCell::new(Emulator::start);
+    bench(filter, "emulator/get_keyboard_backlight", || {
+        black_box(emulator.ec.command(GetKeyboardBacklight).unwrap());
+    });
+    bench(filter, "emulator/set_keyboard_backlight", || {
+        let cmd = SetKeyboardBacklight { percent: 50 };
+        emulator.ec.command(cmd).unwrap();
+    });
+    bench(filter, "emulator/fade/0-100-0", || {
+        for target in [100, 0] {
+            fade_to(&mut EcBacklight(&emulator.ec), false, || target, || {}).unwrap();
+        }
+    });
+}
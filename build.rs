@@ -0,0 +1,129 @@
+//! Generates the `keylightd(1)` man page from the command-line definitions in `src/cli.rs`.
+//!
+//! The man page is written to `$OUT_DIR/keylightd.1`. Packagers can set `KEYLIGHTD_MAN_DIR` to
+//! have a copy placed in a more convenient location.
+
+use std::{env, fmt::Write, fs, path::PathBuf};
+
+use argh::{ArgsInfo, CommandInfoWithArgs, FlagInfoKind, Optionality};
+
+#[allow(dead_code)]
+#[path = "src/cli.rs"]
+mod cli;
+
+const BIN: &str = env!("CARGO_PKG_NAME");
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/cli.rs");
+    println!("cargo:rerun-if-env-changed=KEYLIGHTD_MAN_DIR");
+
+    let page = man_page(&cli::Args::get_args_info());
+    let name = format!("{BIN}.1");
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join(&name), &page).unwrap();
+
+    if let Some(dir) = env::var_os("KEYLIGHTD_MAN_DIR") {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(&name), &page).unwrap();
+    }
+}
+
+fn man_page(info: &CommandInfoWithArgs) -> String {
+    let (_, summary) = info
+        .description
+        .split_once(" - ")
+        .unwrap_or(("", info.description));
+
+    let mut s = String::new();
+    writeln!(
+        s,
+        ".TH {} 1 \"\" \"{BIN} {}\"",
+        BIN.to_uppercase(),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(s, ".SH NAME").unwrap();
+    writeln!(s, "{BIN} \\- {}", escape(summary)).unwrap();
+    writeln!(s, ".SH SYNOPSIS").unwrap();
+    writeln!(s, "{}", synopsis(BIN, info)).unwrap();
+    writeln!(s, ".SH DESCRIPTION").unwrap();
+    writeln!(s, "{}", escape(env!("CARGO_PKG_DESCRIPTION"))).unwrap();
+    if !info.flags.is_empty() {
+        writeln!(s, ".SH OPTIONS").unwrap();
+        flags(&mut s, info);
+    }
+    if !info.commands.is_empty() {
+        writeln!(s, ".SH COMMANDS").unwrap();
+        commands(&mut s, BIN, info);
+    }
+    s
+}
+
+fn commands(s: &mut String, path: &str, info: &CommandInfoWithArgs) {
+    for sub in &info.commands {
+        let path = format!("{path} {}", sub.name);
+        writeln!(s, ".SS {}", escape(&path)).unwrap();
+        writeln!(s, "{}", synopsis(&path, &sub.command)).unwrap();
+        writeln!(s, ".PP").unwrap();
+        writeln!(s, "{}", escape(sub.command.description)).unwrap();
+        for pos in sub.command.positionals.iter().filter(|p| !p.hidden) {
+            writeln!(s, ".TP").unwrap();
+            writeln!(s, "\\fI{}\\fR", escape(pos.name)).unwrap();
+            writeln!(s, "{}", escape(pos.description)).unwrap();
+        }
+        flags(s, &sub.command);
+        commands(s, &path, &sub.command);
+    }
+}
+
+fn flags(s: &mut String, info: &CommandInfoWithArgs) {
+    for flag in info.flags.iter().filter(|f| !f.hidden) {
+        writeln!(s, ".TP").unwrap();
+        match flag.kind {
+            FlagInfoKind::Switch => writeln!(s, "\\fB{}\\fR", escape(flag.long)),
+            FlagInfoKind::Option { arg_name } => writeln!(
+                s,
+                "\\fB{}\\fR \\fI{}\\fR",
+                escape(flag.long),
+                escape(arg_name)
+            ),
+        }
+        .unwrap();
+        writeln!(s, "{}", escape(flag.description)).unwrap();
+    }
+}
+
+fn synopsis(path: &str, info: &CommandInfoWithArgs) -> String {
+    let mut s = format!("\\fB{}\\fR", escape(path));
+    for flag in info
+        .flags
+        .iter()
+        .filter(|f| !f.hidden && f.long != "--help")
+    {
+        let flag = match flag.kind {
+            FlagInfoKind::Switch => format!("\\fB{}\\fR", escape(flag.long)),
+            FlagInfoKind::Option { arg_name } => {
+                format!("\\fB{}\\fR \\fI{}\\fR", escape(flag.long), escape(arg_name))
+            }
+        };
+        s.push_str(&format!(" [{flag}]"));
+    }
+    for pos in info.positionals.iter().filter(|p| !p.hidden) {
+        let name = format!("\\fI{}\\fR", escape(pos.name));
+        match pos.optionality {
+            Optionality::Required => s.push_str(&format!(" {name}")),
+            Optionality::Optional => s.push_str(&format!(" [{name}]")),
+            Optionality::Repeating | Optionality::Greedy => s.push_str(&format!(" [{name}...]")),
+        }
+    }
+    if !info.commands.is_empty() {
+        s.push_str(" [\\fICOMMAND\\fR]");
+    }
+    s
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\e").replace('-', "\\-")
+}
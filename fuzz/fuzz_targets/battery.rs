@@ -0,0 +1,13 @@
+//! Decodes arbitrary bytes as the battery section of the EC memory map.
+
+#![no_main]
+
+use keylightd::{battery::Battery, wire::Reader};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let battery: Battery = Reader::new(data).read();
+    let _ = battery.percent();
+    let _ = (battery.is_present(), battery.is_charging(), battery.is_discharging());
+    let _ = format!("{battery:?} {} {}", battery.manufacturer, battery.model);
+});
@@ -0,0 +1,60 @@
+//! Decodes arbitrary bytes as the response to each EC command.
+//!
+//! The first byte selects the command, the rest is the response payload. Responses are decoded the
+//! same way `EmbeddedController::command` does, and then used like the CLI and the daemon use them.
+
+#![no_main]
+
+use keylightd::{
+    command::{
+        FpLedLevelControlResponse, GetKeyboardBacklightResponse, GetProtocolInfoResponse,
+        GetVersionResponse, HelloResponse, LedColor, LedControlResponse,
+    },
+    wire::{Decode, Reader},
+};
+use libfuzzer_sys::fuzz_target;
+
+/// Decodes `data` like `EmbeddedController::command`, which always passes a buffer of exactly
+/// `T::SIZE` bytes.
+fn decode<T: Decode>(data: &[u8]) -> T {
+    let mut buf = vec![0; T::SIZE];
+    let len = data.len().min(T::SIZE);
+    buf[..len].copy_from_slice(&data[..len]);
+    Reader::new(&buf).read()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, data)) = data.split_first() else {
+        return;
+    };
+    // Also decode the raw data directly, which can be shorter or longer than expected.
+    match selector % 6 {
+        0 => {
+            let _: HelloResponse = decode(data);
+            let _: HelloResponse = Reader::new(data).read();
+        }
+        1 => {
+            for version in [decode(data), Reader::new(data).read::<GetVersionResponse>()] {
+                let _ = format!("{} {}", version.version_string_ro, version.version_string_rw);
+                let _ = version.current_image_name();
+            }
+        }
+        2 => {
+            let info: GetProtocolInfoResponse = decode(data);
+            let _ = 31 - info.protocol_versions.leading_zeros().min(31);
+        }
+        3 => {
+            let _: GetKeyboardBacklightResponse = decode(data);
+        }
+        4 => {
+            let resp: LedControlResponse = decode(data);
+            for color in LedColor::ALL {
+                let _ = (color.name(), resp.brightness.get(color));
+            }
+        }
+        _ => {
+            let resp: FpLedLevelControlResponse = decode(data);
+            let _ = format!("{:?}", resp.level);
+        }
+    }
+});
@@ -0,0 +1,64 @@
+//! systemd socket activation.
+//!
+//! With `keylightd.socket` enabled, systemd creates the control socket at boot and starts the
+//! daemon when the first client connects, passing the listening socket on (see `sd_listen_fds(3)`).
+//! Clients can connect right away, and their requests are answered once the daemon is up.
+//!
+//! Passed sockets are identified by their `FileDescriptorName=`.
+
+use std::{
+    env,
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    process,
+    sync::Mutex,
+};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+/// The first file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets passed by systemd that haven't been taken yet, with their names.
+static SOCKETS: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+
+/// Takes ownership of the sockets passed by systemd, if any.
+///
+/// This has to be called at startup, before anything else could close the inherited descriptors.
+pub fn init() {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    if !for_us {
+        return;
+    }
+    let Some(count) = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let mut sockets = SOCKETS.lock().unwrap();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        // Don't leak the socket to hooks.
+        if let Err(e) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            log::warn!("ignoring socket {fd} (`{name}`) passed by systemd: {e}");
+            continue;
+        }
+        log::debug!("received socket {fd} (`{name}`) from systemd");
+        // SAFETY: systemd passes the descriptors from `LISTEN_FDS_START` on to the process whose
+        // PID is in `LISTEN_PID`, which was checked above, and nothing else owns them.
+        sockets.push((name, unsafe { OwnedFd::from_raw_fd(fd) }));
+    }
+}
+
+/// Takes the socket named `name` that was passed by systemd, if there is one.
+pub fn take(name: &str) -> Option<OwnedFd> {
+    let mut sockets = SOCKETS.lock().unwrap();
+    let index = sockets.iter().position(|(n, _)| n == name)?;
+    Some(sockets.remove(index).1)
+}
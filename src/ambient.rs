@@ -0,0 +1,192 @@
+//! Turning the keyboard backlight off in bright surroundings, using the ambient light sensor.
+//!
+//! The sensor is read through the kernel's iio subsystem, or from the EC's memory map where the
+//! EC keeps the latest reading, which also works when no iio driver is bound to the sensor.
+//! Readings are smoothed exponentially, and the backlight is only switched off above `off-above` lux and back on below `on-below` lux, so
+//! that passing shadows or a phone's flashlight don't make the keyboard brightness oscillate.
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{AlsSource, AmbientLight},
+    daemon::Shared,
+    ec::EmbeddedController,
+    history,
+    iio::{self, read_number},
+    worker::Priority,
+};
+
+/// How often the sensor is read.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Offset of the ALS readings in the EC memory map (`EC_MEMMAP_ALS`).
+const MEMMAP_ALS: u8 = 0x80;
+
+/// Reads the ambient light in lux from the EC memory map.
+///
+/// The memory map holds two 16-bit readings, of which the first one is the lid sensor.
+fn read_ec(ec: &EmbeddedController) -> io::Result<f64> {
+    let mut buf = [0; 2];
+    ec.read_memmap(MEMMAP_ALS, &mut buf)?;
+    Ok(f64::from(u16::from_le_bytes(buf)))
+}
+
+enum Sensor {
+    Iio(IioSensor),
+    Ec,
+}
+
+impl Sensor {
+    fn find(source: AlsSource) -> io::Result<Option<Self>> {
+        Ok(match source {
+            AlsSource::Iio => IioSensor::find()?.map(Self::Iio),
+            AlsSource::Ec => Some(Self::Ec),
+            AlsSource::Auto => Some(IioSensor::find()?.map_or(Self::Ec, Self::Iio)),
+        })
+    }
+
+    fn read(&self, shared: &Shared) -> io::Result<f64> {
+        match self {
+            Self::Iio(sensor) => sensor.read(),
+            Self::Ec => shared.ec.submit(Priority::Normal, None, read_ec),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Iio(sensor) => sensor.path.display().to_string(),
+            Self::Ec => "the EC memory map".to_string(),
+        }
+    }
+}
+
+/// An ambient light sensor exposed through iio.
+struct IioSensor {
+    /// Attribute containing the illuminance.
+    path: PathBuf,
+    /// Factor to convert the attribute's value to lux.
+    scale: f64,
+}
+
+impl IioSensor {
+    /// Finds the first iio device that measures illuminance.
+    fn find() -> io::Result<Option<Self>> {
+        for dir in iio::devices()? {
+            // `input` is already in lux, `raw` has to be scaled.
+            let input = dir.join("in_illuminance_input");
+            if input.exists() {
+                return Ok(Some(Self {
+                    path: input,
+                    scale: 1.0,
+                }));
+            }
+            let raw = dir.join("in_illuminance_raw");
+            if raw.exists() {
+                let scale = match read_number(&dir.join("in_illuminance_scale")) {
+                    Ok(scale) => scale,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 1.0,
+                    Err(e) => return Err(e),
+                };
+                return Ok(Some(Self { path: raw, scale }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the current illuminance in lux.
+    fn read(&self) -> io::Result<f64> {
+        Ok(read_number(&self.path)? * self.scale)
+    }
+}
+
+/// Exponential smoothing of the readings, with hysteresis between two thresholds.
+struct Filter {
+    smoothed: Option<f64>,
+    last_update: Instant,
+    bright: bool,
+}
+
+impl Filter {
+    fn new() -> Self {
+        Self {
+            smoothed: None,
+            last_update: Instant::now(),
+            bright: false,
+        }
+    }
+
+    /// Adds a new reading and returns whether the surroundings are considered bright.
+    fn update(&mut self, lux: f64, settings: &AmbientLight) -> bool {
+        let now = Instant::now();
+        let smoothed = match self.smoothed {
+            None => lux,
+            Some(prev) => {
+                // The weight of the new reading depends on how much time has passed, so that the
+                // time constant doesn't depend on the polling interval.
+                let dt = (now - self.last_update).as_secs_f64();
+                let weight = if settings.smoothing > 0 {
+                    1.0 - (-dt / f64::from(settings.smoothing)).exp()
+                } else {
+                    1.0
+                };
+                prev + (lux - prev) * weight
+            }
+        };
+        self.smoothed = Some(smoothed);
+        self.last_update = now;
+
+        if self.bright && smoothed < f64::from(settings.on_below) {
+            self.bright = false;
+        } else if !self.bright && smoothed > f64::from(settings.off_above) {
+            self.bright = true;
+        }
+        self.bright
+    }
+}
+
+/// Finds the ambient light sensor and spawns a thread that follows the ambient light.
+pub fn spawn(shared: Arc<Shared>, settings: AmbientLight) -> io::Result<()> {
+    let Some(sensor) = Sensor::find(settings.source)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no ambient light sensor found",
+        ));
+    };
+    log::info!("reading ambient light from {}", sensor.describe());
+    thread::spawn(move || {
+        if let Err(e) = run(&shared, &settings, &sensor) {
+            log::error!("cannot follow the ambient light: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn run(shared: &Shared, settings: &AmbientLight, sensor: &Sensor) -> io::Result<()> {
+    let mut filter = Filter::new();
+    loop {
+        let lux = sensor.read(shared)?;
+        let bright = filter.update(lux, settings);
+        log::trace!("ambient light: {lux} lux, smoothed {:?}", filter.smoothed);
+        // Only wake up the main loop when something changed.
+        if shared.state.lock().unwrap().bright_ambient != bright {
+            shared.update(|state| state.bright_ambient = bright);
+            log::info!(
+                "surroundings are now {} ({:.0} lux)",
+                if bright { "bright" } else { "dark" },
+                filter.smoothed.unwrap_or(lux)
+            );
+            history::record(format!(
+                "surroundings {} ({:.0} lux)",
+                if bright { "bright" } else { "dark" },
+                filter.smoothed.unwrap_or(lux)
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,71 @@
+//! Running the daemon in the background (`keylightd run --background`).
+//!
+//! systemd, runit and s6 expect services to stay in the foreground, which is what `keylightd run`
+//! does by default. Init systems like OpenRC instead expect the service to detach on its own. With
+//! `--background`, keylightd forks before doing anything else, and the original process only exits
+//! once the daemon has started up, with the daemon's exit code if it failed to. That way, a broken
+//! configuration or a missing EC still makes starting the service fail.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::fd::{FromRawFd, OwnedFd},
+    process,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use nix::{
+    fcntl::OFlag,
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{self, ForkResult},
+};
+
+/// The pipe to the waiting parent, until the daemon is ready.
+static READY: Mutex<Option<File>> = Mutex::new(None);
+
+/// Forks into the background. Only returns in the child.
+///
+/// This has to be called while the process still has a single thread.
+pub fn detach() -> anyhow::Result<()> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC).context("failed to create a pipe")?;
+    // SAFETY: `pipe2` returns two new file descriptors that nothing else owns.
+    let (mut read, write) = unsafe {
+        (
+            File::from(OwnedFd::from_raw_fd(read)),
+            File::from(OwnedFd::from_raw_fd(write)),
+        )
+    };
+    // SAFETY: no other threads have been started yet, so the child can't inherit a lock that is
+    // held by a thread that doesn't exist in it.
+    match unsafe { unistd::fork() }.context("failed to fork")? {
+        ForkResult::Parent { child } => {
+            drop(write);
+            let mut byte = [0];
+            if read.read(&mut byte).unwrap_or(0) == 1 {
+                process::exit(0);
+            }
+            // The daemon exited before it was ready; report how.
+            let code = match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                _ => 1,
+            };
+            process::exit(code);
+        }
+        ForkResult::Child => {
+            drop(read);
+            // Don't receive the signals of the terminal or session that started the daemon.
+            unistd::setsid().context("failed to create a session")?;
+            *READY.lock().unwrap() = Some(write);
+            Ok(())
+        }
+    }
+}
+
+/// Lets the original process exit, if the daemon was started with `--background`.
+pub fn ready() {
+    if let Some(mut pipe) = READY.lock().unwrap().take() {
+        // If the parent is gone, there is nobody left to tell.
+        let _ = pipe.write_all(&[1]);
+    }
+}
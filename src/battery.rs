@@ -0,0 +1,114 @@
+//! Battery state, read from the EC memory map.
+//!
+//! Reference: `EC_MEMMAP_BATT_*` in `include/ec_commands.h`. Reading the memory map works on all
+//! Framework EC versions, unlike `EC_CMD_BATTERY_GET_DYNAMIC`, and doesn't depend on sysfs.
+
+#![allow(dead_code)]
+
+use std::io;
+
+use bitflags::bitflags;
+
+use crate::{
+    ec::EmbeddedController,
+    wire::{Decode, EcString, Reader},
+};
+
+/// Offset of the battery data in the memory map (`EC_MEMMAP_BATT_VOLT`).
+const MEMMAP_OFFSET: u8 = 0x40;
+
+bitflags! {
+    /// Battery flags (`EC_BATT_FLAG_*`).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BatteryFlags: u8 {
+        const AC_PRESENT = 1 << 0;
+        const BATT_PRESENT = 1 << 1;
+        const DISCHARGING = 1 << 2;
+        const CHARGING = 1 << 3;
+        const LEVEL_CRITICAL = 1 << 4;
+    }
+}
+
+/// The battery section of the EC memory map.
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    /// Voltage in mV.
+    pub voltage: u32,
+    /// Charge or discharge rate in mA.
+    pub rate: u32,
+    /// Remaining capacity in mAh.
+    pub remaining_capacity: u32,
+    pub flags: BatteryFlags,
+    /// Design capacity in mAh.
+    pub design_capacity: u32,
+    /// Design voltage in mV.
+    pub design_voltage: u32,
+    /// Capacity after the last full charge in mAh.
+    pub last_full_capacity: u32,
+    pub cycle_count: u32,
+    pub manufacturer: EcString<8>,
+    pub model: EcString<8>,
+    pub serial: EcString<8>,
+    pub kind: EcString<8>,
+}
+
+impl Battery {
+    /// Reads the current battery state from the EC.
+    pub fn read(ec: &EmbeddedController) -> io::Result<Self> {
+        let mut buf = [0; Self::SIZE];
+        ec.read_memmap(MEMMAP_OFFSET, &mut buf)?;
+        Ok(Reader::new(&buf).read())
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.flags.contains(BatteryFlags::BATT_PRESENT)
+    }
+
+    pub fn is_ac_present(&self) -> bool {
+        self.flags.contains(BatteryFlags::AC_PRESENT)
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.flags.contains(BatteryFlags::CHARGING)
+    }
+
+    pub fn is_discharging(&self) -> bool {
+        self.flags.contains(BatteryFlags::DISCHARGING)
+    }
+
+    /// Returns the state of charge in percent.
+    pub fn percent(&self) -> u8 {
+        if self.last_full_capacity == 0 {
+            return 0;
+        }
+        let percent = u64::from(self.remaining_capacity) * 100 / u64::from(self.last_full_capacity);
+        percent.min(100) as u8
+    }
+}
+
+impl Decode for Battery {
+    const SIZE: usize = 0x40;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        let voltage = reader.read();
+        let rate = reader.read();
+        let remaining_capacity = reader.read();
+        let flags = BatteryFlags::from_bits_retain(reader.read());
+        // 3 bytes of padding, then `EC_MEMMAP_BATT_DCAP` at 0x50.
+        let _: [u8; 3] = reader.read();
+        Self {
+            voltage,
+            rate,
+            remaining_capacity,
+            flags,
+            design_capacity: reader.read(),
+            design_voltage: reader.read(),
+            last_full_capacity: reader.read(),
+            cycle_count: reader.read(),
+            manufacturer: reader.read(),
+            model: reader.read(),
+            serial: reader.read(),
+            kind: reader.read(),
+        }
+    }
+}
@@ -0,0 +1,599 @@
+//! Command-line interface definition.
+//!
+//! This module is also included by `build.rs` to generate the man page, so it must not refer to
+//! anything else in this crate.
+
+use argh::{ArgsInfo, FromArgValue, FromArgs};
+use serde::{Deserialize, Serialize};
+
+/// Largest response payload any EC can send: the maximum packet size is a 16-bit field, and the
+/// packet includes an 8-byte header.
+const MAX_RESPONSE_SIZE: usize = u16::MAX as usize - 8;
+
+/// keylightd - automatic keyboard backlight daemon for Framework laptops and Chromebooks
+#[derive(Debug, FromArgs, ArgsInfo)]
+pub struct Args {
+    /// path of the EC device node to use instead of detecting it among /dev/cros_*
+    #[argh(option)]
+    pub ec_device: Option<String>,
+
+    #[argh(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+pub enum Command {
+    Run(RunArgs),
+    Status(StatusArgs),
+    Health(HealthArgs),
+    Set(SetArgs),
+    Preview(PreviewArgs),
+    Off(OffArgs),
+    On(OnArgs),
+    Backend(BackendArgs),
+    LogLevel(LogLevelArgs),
+    DumpHistory(DumpHistoryArgs),
+    Ec(EcArgs),
+    Led(LedArgs),
+    CheckConfig(CheckConfigArgs),
+    Doctor(DoctorArgs),
+    ListDevices(ListDevicesArgs),
+    Setup(SetupArgs),
+    Install(InstallArgs),
+    Uninstall(UninstallArgs),
+    Restore(RestoreArgs),
+    EmulateEc(EmulateEcArgs),
+    Completions(CompletionsArgs),
+}
+
+/// run the backlight daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "run")]
+pub struct RunArgs {
+    /// path to the configuration file [default=/etc/keylightd/config.toml]
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// brightness level when active (0-100), overrides the configuration file
+    #[argh(option, from_str_fn(parse_brightness))]
+    pub brightness: Option<u8>,
+
+    /// brightness level when active and the desktop uses a dark color scheme (0-100)
+    #[argh(option, from_str_fn(parse_brightness))]
+    pub dark_brightness: Option<u8>,
+
+    /// activity timeout in seconds, overrides the configuration file
+    #[argh(option)]
+    pub timeout: Option<u32>,
+
+    /// also control the power LED in the fingerprint module
+    #[argh(switch)]
+    pub power: bool,
+
+    /// dim the power LED to this level when idle instead of switching it off (high, medium,
+    /// low; requires a recent BIOS)
+    #[argh(option)]
+    pub power_brightness: Option<PowerLedLevel>,
+
+    /// pulse the side LEDs red while discharging and below this battery percentage
+    #[argh(option, from_str_fn(parse_percentage))]
+    pub low_battery_led: Option<u8>,
+
+    /// hardware profile to use instead of detecting it (eg. framework-13, chromebook, generic)
+    #[argh(option)]
+    pub hardware_profile: Option<String>,
+
+    /// exit if a configured device (input devices, sensors, LEDs) is missing
+    #[argh(switch)]
+    pub fail_fast: bool,
+
+    /// run without configured devices that are missing (the default), overrides `fail-fast` in
+    /// the configuration file
+    #[argh(switch)]
+    pub best_effort: bool,
+
+    /// detach from the terminal once started, for init systems that expect services to fork
+    /// (eg. OpenRC)
+    #[argh(switch)]
+    pub background: bool,
+
+    /// the PID file, which is locked to keep a second instance from starting
+    /// [default=/run/keylightd.pid]
+    #[argh(option)]
+    pub pidfile: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromArgValue, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerLedLevel {
+    High,
+    Medium,
+    Low,
+}
+
+/// show the state of the running daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "status")]
+pub struct StatusArgs {
+    /// print the status as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// check that the running daemon can reach the EC and receives input events
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "health")]
+pub struct HealthArgs {
+    /// print the health report as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// change settings of the running daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "set")]
+pub struct SetArgs {
+    /// brightness level when active (0-100), or a relative change (eg. +10 or -10)
+    #[argh(option, from_str_fn(parse_brightness_change))]
+    pub brightness: Option<BrightnessChange>,
+
+    /// activity timeout in seconds
+    #[argh(option)]
+    pub timeout: Option<u32>,
+
+    /// hardware profile to use from the next start of the daemon (requires --save)
+    #[argh(option)]
+    pub hardware_profile: Option<String>,
+
+    /// also write the changed settings to the daemon's configuration file
+    #[argh(switch)]
+    pub save: bool,
+}
+
+/// fade the keyboard backlight to a brightness and back once, to try out settings
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "preview")]
+pub struct PreviewArgs {
+    /// brightness level to fade to (0-100)
+    #[argh(option, from_str_fn(parse_brightness))]
+    pub brightness: u8,
+
+    /// duration of the fade in each direction, in milliseconds (at most 1500) [default=300]
+    #[argh(option, default = "300")]
+    pub fade_ms: u32,
+}
+
+/// keep the keyboard backlight off regardless of activity
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "off")]
+pub struct OffArgs {
+    /// whether the next input activity turns the backlight back on (otherwise, it stays off
+    /// until `keylightd on`) [default=true]
+    #[argh(option, default = "true")]
+    pub until_activity: bool,
+}
+
+/// let the keyboard backlight follow activity again after `keylightd off`
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "on")]
+pub struct OnArgs {}
+
+/// enable or disable an activity backend of the running daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "backend")]
+pub struct BackendArgs {
+    #[argh(subcommand)]
+    pub command: BackendCommand,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+pub enum BackendCommand {
+    Enable(BackendEnableArgs),
+    Disable(BackendDisableArgs),
+}
+
+/// count activity reported by a backend again
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "enable")]
+pub struct BackendEnableArgs {
+    /// the backend (evdev, command, logind, or wayland)
+    #[argh(positional)]
+    pub backend: Backend,
+}
+
+/// ignore activity reported by a backend until it is enabled again
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "disable")]
+pub struct BackendDisableArgs {
+    /// the backend (evdev, command, logind, or wayland)
+    #[argh(positional)]
+    pub backend: Backend,
+}
+
+/// A source of activity.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, FromArgValue, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The builtin input devices (and gamepads), read through evdev.
+    Evdev,
+    /// The `activity-command`.
+    Command,
+    /// The idle hint that the desktop maintains in logind.
+    Logind,
+    /// The idle notifications of the Wayland compositor.
+    Wayland,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Evdev => "evdev",
+            Self::Command => "command",
+            Self::Logind => "logind",
+            Self::Wayland => "wayland",
+        }
+    }
+}
+
+/// show or change the log level of the running daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "log-level")]
+pub struct LogLevelArgs {
+    /// the new log level (off, error, warn, info, debug, or trace)
+    #[argh(positional)]
+    pub level: Option<String>,
+}
+
+/// print the recent state transitions and EC commands of the running daemon
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "dump-history")]
+pub struct DumpHistoryArgs {}
+
+/// An absolute or relative brightness adjustment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BrightnessChange {
+    Set(u8),
+    Increase(u8),
+    Decrease(u8),
+}
+
+impl BrightnessChange {
+    /// Applies the change to `brightness`, clamping the result to the valid range.
+    pub fn apply(self, brightness: u8) -> u8 {
+        match self {
+            Self::Set(value) => value,
+            Self::Increase(step) => brightness.saturating_add(step).min(100),
+            Self::Decrease(step) => brightness.saturating_sub(step),
+        }
+    }
+}
+
+/// talk to the embedded controller directly
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "ec")]
+pub struct EcArgs {
+    #[argh(subcommand)]
+    pub command: EcCommand,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+pub enum EcCommand {
+    Version(EcVersionArgs),
+    Console(EcConsoleArgs),
+    Raw(EcRawArgs),
+    Lightbar(EcLightbarArgs),
+}
+
+/// print the firmware versions reported by the embedded controller
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "version")]
+pub struct EcVersionArgs {}
+
+/// print the recent console output of the embedded controller
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "console")]
+pub struct EcConsoleArgs {}
+
+/// send an arbitrary command to the embedded controller and print the response
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "raw")]
+pub struct EcRawArgs {
+    /// command ID (decimal, or hexadecimal with a `0x` prefix)
+    #[argh(positional, from_str_fn(parse_u16))]
+    pub command: u16,
+
+    /// request payload as a hex string (eg. `0401`)
+    #[argh(positional)]
+    pub payload: Option<HexBytes>,
+
+    /// command version [default=0]
+    #[argh(option, default = "0")]
+    pub version: u32,
+
+    /// maximum number of response bytes to read [default=256]
+    #[argh(option, default = "256", from_str_fn(parse_response_size))]
+    pub response_size: usize,
+}
+
+/// control the lightbar of Chromebooks that have one
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "lightbar")]
+pub struct EcLightbarArgs {
+    #[argh(subcommand)]
+    pub command: LightbarCommand,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+pub enum LightbarCommand {
+    Version(LightbarVersionArgs),
+    Show(LightbarShowArgs),
+    Brightness(LightbarBrightnessArgs),
+}
+
+/// print the version of the lightbar interface
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "version")]
+pub struct LightbarVersionArgs {}
+
+/// switch the lightbar off, or to a light pattern
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "show")]
+pub struct LightbarShowArgs {
+    /// the pattern to show (off, s0, s3, s5, tap, konami, auto)
+    #[argh(positional)]
+    pub pattern: LightbarPattern,
+}
+
+/// set the overall brightness of the lightbar
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "brightness")]
+pub struct LightbarBrightnessArgs {
+    /// brightness level (0-255)
+    #[argh(positional)]
+    pub brightness: u8,
+}
+
+/// A lightbar state keylightd can switch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromArgValue, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightbarPattern {
+    /// Switched off.
+    Off,
+    /// The pattern of a running system (the battery level).
+    S0,
+    /// The pattern of a suspended system (a slow pulse).
+    S3,
+    /// The pattern of a system that's off.
+    S5,
+    /// A short flash of the battery level.
+    Tap,
+    Konami,
+    /// Whatever the EC shows on its own for the system's power state.
+    Auto,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl FromArgValue for HexBytes {
+    fn from_arg_value(s: &str) -> Result<Self, String> {
+        let s = s.strip_prefix("0x").unwrap_or(s);
+        if !s.len().is_multiple_of(2) {
+            return Err(format!("hex string '{s}' has an odd number of digits"));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| format!("invalid hex string '{s}'"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// control the system LEDs directly
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "led")]
+pub struct LedArgs {
+    #[argh(subcommand)]
+    pub command: LedCommand,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+pub enum LedCommand {
+    Set(LedSetArgs),
+    Query(LedQueryArgs),
+}
+
+/// switch an LED off, or hand control back to the embedded controller
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "set")]
+pub struct LedSetArgs {
+    /// the LED to control (battery, power, adapter, left, right)
+    #[argh(positional)]
+    pub led: Led,
+
+    /// the mode to put the LED in (auto, off)
+    #[argh(positional)]
+    pub mode: LedMode,
+}
+
+/// print the brightness ranges supported by an LED
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "query")]
+pub struct LedQueryArgs {
+    /// the LED to query (battery, power, adapter, left, right) [default: all of them]
+    #[argh(positional)]
+    pub led: Option<Led>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromArgValue)]
+pub enum Led {
+    Battery,
+    Power,
+    Adapter,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromArgValue)]
+pub enum LedMode {
+    Auto,
+    Off,
+}
+
+/// validate the configuration file and print the resulting settings
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "check-config")]
+pub struct CheckConfigArgs {
+    /// path to the configuration file [default=/etc/keylightd/config.toml]
+    #[argh(positional)]
+    pub path: Option<String>,
+}
+
+/// check for common setup problems and run a hardware self-test
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "doctor")]
+pub struct DoctorArgs {}
+
+/// list the input devices, and which of them the daemon would use
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "list-devices")]
+pub struct ListDevicesArgs {
+    /// path to the configuration file [default=/etc/keylightd/config.toml]
+    #[argh(option)]
+    pub config: Option<String>,
+}
+
+/// interactively set up keylightd: pick the hardware profile and settings, try a fade, and
+/// install the service
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "setup")]
+pub struct SetupArgs {
+    /// path of the configuration file to write [default=/etc/keylightd/config.toml]
+    #[argh(option)]
+    pub config: Option<String>,
+}
+
+/// install keylightd as a systemd or OpenRC service, and enable and start it
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "install")]
+pub struct InstallArgs {
+    /// install a system service (the default when running as root)
+    #[argh(switch)]
+    pub system: bool,
+
+    /// install a service for the current user's session (the default otherwise)
+    #[argh(switch)]
+    pub user: bool,
+
+    /// install an OpenRC init script instead of a systemd unit
+    #[argh(switch)]
+    pub openrc: bool,
+}
+
+/// stop and remove the service installed with `keylightd install`
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "uninstall")]
+pub struct UninstallArgs {
+    /// remove the system service (the default when running as root)
+    #[argh(switch)]
+    pub system: bool,
+
+    /// remove the current user's service (the default otherwise)
+    #[argh(switch)]
+    pub user: bool,
+
+    /// remove the OpenRC init script
+    #[argh(switch)]
+    pub openrc: bool,
+}
+
+/// hand the LEDs back to the embedded controller and turn the keyboard backlight back on
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "restore")]
+pub struct RestoreArgs {
+    /// path to the configuration file [default=/etc/keylightd/config.toml]
+    #[argh(option)]
+    pub config: Option<String>,
+}
+
+/// emulate an embedded controller on a Unix socket, for testing with --ec-device
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "emulate-ec")]
+pub struct EmulateEcArgs {
+    /// path of the socket to listen on
+    #[argh(positional)]
+    pub socket: String,
+}
+
+/// print a shell completion script to stdout
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "completions")]
+pub struct CompletionsArgs {
+    /// shell to generate completions for (bash, zsh, fish)
+    #[argh(positional)]
+    pub shell: Shell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromArgValue)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+pub fn parse_brightness(s: &str) -> Result<u8, String> {
+    parse_percent(s, "brightness value")
+}
+
+fn parse_percentage(s: &str) -> Result<u8, String> {
+    parse_percent(s, "percentage")
+}
+
+/// Parses a number from 0 to 100, naming it `what` in the error message.
+fn parse_percent(s: &str, what: &str) -> Result<u8, String> {
+    let percent = s.parse::<u8>().map_err(|e| e.to_string())?;
+    if percent > 100 {
+        return Err(format!("invalid {what} {percent} (valid range: 0-100)"));
+    }
+    Ok(percent)
+}
+
+fn parse_response_size(s: &str) -> Result<usize, String> {
+    let size = s.parse::<usize>().map_err(|e| e.to_string())?;
+    if size > MAX_RESPONSE_SIZE {
+        return Err(format!(
+            "invalid response size {size} (an EC response has at most {MAX_RESPONSE_SIZE} bytes)"
+        ));
+    }
+    Ok(size)
+}
+
+fn parse_brightness_change(s: &str) -> Result<BrightnessChange, String> {
+    if let Some(step) = s.strip_prefix('+') {
+        parse_brightness(step).map(BrightnessChange::Increase)
+    } else if let Some(step) = s.strip_prefix('-') {
+        parse_brightness(step).map(BrightnessChange::Decrease)
+    } else {
+        parse_brightness(s).map(BrightnessChange::Set)
+    }
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid number '{s}': {e}"))
+}
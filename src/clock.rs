@@ -0,0 +1,152 @@
+//! The source of time for the state machine, the fades and the schedules.
+//!
+//! Everything that decides when the backlight changes asks this module for the time instead of
+//! using [`Instant::now`] and [`thread::sleep`] directly, so that the daemon can run on a
+//! simulated clock. With `KEYLIGHTD_TIME_SCALE`, time passes that many times faster, which makes
+//! it possible to watch the timeout and the fades against the emulator without waiting for them.
+//! `KEYLIGHTD_START_TIME` (in seconds since the Unix epoch) sets the wall-clock time the daemon
+//! starts at, eg. shortly before the `night-off` hours begin or before midnight.
+
+use std::{
+    env,
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// Returns the current monotonic time.
+    fn now(&self) -> Instant;
+    /// Returns the current wall-clock time in seconds since the Unix epoch.
+    fn unix_time(&self) -> i64;
+    /// Returns how long it really takes for `duration` to pass on this clock.
+    fn real_duration(&self, duration: Duration) -> Duration;
+}
+
+/// The system's own clocks.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> i64 {
+        // Before 1970, the time would be the least of the problems.
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64)
+    }
+
+    fn real_duration(&self, duration: Duration) -> Duration {
+        duration
+    }
+}
+
+/// A clock that runs `speed` times faster than the real one, starting at a given wall-clock time.
+pub struct ScaledClock {
+    start: Instant,
+    start_unix: i64,
+    speed: u32,
+}
+
+impl ScaledClock {
+    pub fn new(start_unix: i64, speed: u32) -> Self {
+        Self {
+            start: Instant::now(),
+            start_unix,
+            speed: speed.max(1),
+        }
+    }
+
+    /// Returns the wall-clock time the clock started at.
+    pub fn start_unix(&self) -> i64 {
+        self.start_unix
+    }
+
+    /// Returns how many times faster than real time the clock runs.
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed() * self.speed
+    }
+}
+
+impl Clock for ScaledClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_time(&self) -> i64 {
+        self.start_unix + self.elapsed().as_secs() as i64
+    }
+
+    fn real_duration(&self, duration: Duration) -> Duration {
+        duration / self.speed
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// Installs the clock to use from now on, instead of the [`SystemClock`].
+///
+/// This has to happen before the time is first asked for; later calls have no effect.
+pub fn set(clock: Box<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+fn clock() -> &'static dyn Clock {
+    &**CLOCK.get_or_init(|| Box::new(SystemClock))
+}
+
+/// Returns the simulated clock requested through the environment, if any.
+pub fn from_env() -> anyhow::Result<Option<ScaledClock>> {
+    let speed = env::var("KEYLIGHTD_TIME_SCALE").ok();
+    let start = env::var("KEYLIGHTD_START_TIME").ok();
+    if speed.is_none() && start.is_none() {
+        return Ok(None);
+    }
+    let speed = match speed {
+        Some(speed) => speed
+            .parse()
+            .with_context(|| format!("invalid KEYLIGHTD_TIME_SCALE '{speed}'"))?,
+        None => 1,
+    };
+    let start = match start {
+        Some(start) => start
+            .parse()
+            .with_context(|| format!("invalid KEYLIGHTD_START_TIME '{start}'"))?,
+        None => SystemClock.unix_time(),
+    };
+    Ok(Some(ScaledClock::new(start, speed)))
+}
+
+/// Returns the current monotonic time.
+pub fn now() -> Instant {
+    clock().now()
+}
+
+/// Returns the current wall-clock time in seconds since the Unix epoch.
+pub fn unix_time() -> i64 {
+    clock().unix_time()
+}
+
+/// Returns how much time has passed since `earlier`.
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+/// Returns how long to really wait until `deadline` has come.
+pub fn until(deadline: Instant) -> Duration {
+    clock().real_duration(deadline.saturating_duration_since(now()))
+}
+
+/// Blocks the current thread until `duration` has passed.
+pub fn sleep(duration: Duration) {
+    thread::sleep(clock().real_duration(duration));
+}
@@ -6,140 +6,576 @@
 
 #![allow(dead_code)]
 
-use bytemuck::{NoUninit, Pod, Zeroable};
+use std::io;
+
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ec::EmbeddedController,
+    wire::{Decode, EcString, Encode, Reader},
+};
 
 /// Trait implemented by Embedded Controller commands.
-pub trait Command: NoUninit {
+///
+/// The command itself is encoded as the request payload, see the [`wire`](crate::wire) module.
+pub trait Command: Encode {
     /// The command ID.
     const CMD: Cmd;
 
     /// Command version.
     ///
-    /// Some commands come in multiple versions.
+    /// Some commands come in multiple versions. This is the one that is sent if the EC can't tell
+    /// which versions it supports.
     const VERSION: u32 = 0;
 
+    /// All versions of the command that the request and response encoding works with, as a bit
+    /// mask (bit N = version N).
+    ///
+    /// The highest of these that the EC supports is sent (see
+    /// [`EmbeddedController::command_versions`]).
+    const VERSIONS: u32 = 1 << Self::VERSION;
+
     /// The associated response type.
-    type Response: Pod;
+    ///
+    /// Commands whose response has no fixed size have an empty response type, and are sent with
+    /// [`EmbeddedController::command_into`].
+    type Response: Decode;
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Cmd {
-    #[allow(unused)] // no longer used by cros-ec
-    ProtoVersion = 0x0000,
-    Hello = 0x0001,
-    GetVersion = 0x0002,
+/// Declares a command and its response, and implements [`Command`], [`Encode`] and [`Decode`]
+/// for them.
+///
+/// The fields are encoded and decoded in the order they are declared, so they have to be listed
+/// in the order of `ec_commands.h`, and every field type has to implement [`Encode`] (for the
+/// request) or [`Decode`] (for the response). Structs without fields are declared as unit
+/// structs, eg. `pub struct GetVersion;`.
+///
+/// ```ignore
+/// define_ec_command! {
+///     Cmd::Hello;
+///     pub struct Hello {
+///         pub in_data: u32,
+///     }
+///     pub struct HelloResponse {
+///         pub out_data: u32,
+///     }
+/// }
+/// ```
+///
+/// The command version defaults to 0 and can be given after the command ID, eg.
+/// `Cmd::LedControl, version 1;`.
+macro_rules! define_ec_command {
+    (
+        Cmd::$cmd:ident $(, version $version:literal)?;
+        $(#[$req_attr:meta])* $req_vis:vis struct $req:ident $req_body:tt
+        $(#[$resp_attr:meta])* $resp_vis:vis struct $resp:ident $resp_body:tt
+    ) => {
+        define_ec_command!(@struct [$(#[$req_attr])*] $req_vis $req $req_body);
+        define_ec_command!(@struct [$(#[$resp_attr])*] $resp_vis $resp $resp_body);
+        define_ec_command!(@encode $req $req_body);
+        define_ec_command!(@decode $resp $resp_body);
+
+        impl $req {
+            /// Size of the encoded request in bytes.
+            pub const SIZE: usize = define_ec_command!(@size $req_body);
+        }
+
+        impl Command for $req {
+            const CMD: Cmd = Cmd::$cmd;
+            $(const VERSION: u32 = $version;)?
+            type Response = $resp;
+        }
+
+        // `EmbeddedController::command` receives the response into a fixed-size buffer.
+        const _: () = assert!(<$resp as Decode>::SIZE <= crate::ec::RESPONSE_BUF_SIZE);
+    };
+    (@struct [$($attr:tt)*] $vis:vis $name:ident ;) => {
+        $($attr)*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name;
+    };
+    (@struct [$($attr:tt)*] $vis:vis $name:ident {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+    }) => {
+        $($attr)*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $ty,)*
+        }
+    };
+    (@size ;) => {
+        0
+    };
+    (@size {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+    }) => {
+        0 $(+ <$ty as Decode>::SIZE)*
+    };
+    (@encode $name:ident ;) => {
+        impl Encode for $name {
+            fn encode(&self, _: &mut Vec<u8>) {}
+        }
+    };
+    (@encode $name:ident {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+    }) => {
+        impl Encode for $name {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                $(self.$field.encode(buf);)*
+            }
+        }
+    };
+    (@decode $name:ident ;) => {
+        impl Decode for $name {
+            const SIZE: usize = 0;
+
+            fn decode(_: &mut Reader<'_>) -> Self {
+                Self
+            }
+        }
+    };
+    (@decode $name:ident {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+    }) => {
+        impl Decode for $name {
+            const SIZE: usize = 0 $(+ <$ty as Decode>::SIZE)*;
+
+            fn decode(reader: &mut Reader<'_>) -> Self {
+                Self {
+                    $($field: reader.read(),)*
+                }
+            }
+        }
+    };
+}
+
+/// Declares the [`Cmd`] enum, along with the lookup of commands by ID and their names.
+macro_rules! commands {
+    ($($(#[$attr:meta])* $variant:ident = $id:literal => $name:literal,)*) => {
+        /// The ID of a host command.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u16)]
+        pub enum Cmd {
+            $($(#[$attr])* $variant = $id,)*
+        }
+
+        impl Cmd {
+            /// Returns the command with the ID `id`, if it is known.
+            pub fn from_raw(id: u16) -> Option<Self> {
+                match id {
+                    $($id => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Returns the name of the command in `ec_commands.h`, without the `EC_CMD_` prefix.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+commands! {
+    ProtoVersion = 0x0000 => "PROTO_VERSION", // no longer used by cros-ec
+    Hello = 0x0001 => "HELLO",
+    GetVersion = 0x0002 => "GET_VERSION",
+    // ...
+    ReadMemmap = 0x0007 => "READ_MEMMAP",
+    GetCmdVersions = 0x0008 => "GET_CMD_VERSIONS",
+    // ...
+    GetProtocolInfo = 0x000B => "GET_PROTOCOL_INFO",
     // ...
-    GetKeyboardBacklight = 0x0022,
-    SetKeyboardBacklight = 0x0023,
-    LedControl = 0x0029,
+    GetKeyboardBacklight = 0x0022 => "PWM_GET_KEYBOARD_BACKLIGHT",
+    SetKeyboardBacklight = 0x0023 => "PWM_SET_KEYBOARD_BACKLIGHT",
+    // ...
+    LightbarCmd = 0x0028 => "LIGHTBAR_CMD",
+    LedControl = 0x0029 => "LED_CONTROL",
+    // ...
+    TempSensorGetInfo = 0x0070 => "TEMP_SENSOR_GET_INFO",
+    // ...
+    ConsoleSnapshot = 0x0097 => "CONSOLE_SNAPSHOT",
+    ConsoleRead = 0x0098 => "CONSOLE_READ",
+    // ...
+    // Framework-specific commands (see `board/hx20/host_command_customization.h`).
+    FpLedLevelControl = 0x3E0E => "FP_LED_LEVEL_CONTROL",
+}
+
+/// Returns the name of an EC result code (`EC_RES_*`), if it is known.
+pub fn result_name(code: u32) -> Option<&'static str> {
+    Some(match code {
+        0 => "SUCCESS",
+        1 => "INVALID_COMMAND",
+        2 => "ERROR",
+        3 => "INVALID_PARAM",
+        4 => "ACCESS_DENIED",
+        5 => "INVALID_RESPONSE",
+        6 => "INVALID_VERSION",
+        7 => "INVALID_CHECKSUM",
+        8 => "IN_PROGRESS",
+        9 => "UNAVAILABLE",
+        10 => "TIMEOUT",
+        11 => "OVERFLOW",
+        12 => "INVALID_HEADER",
+        13 => "REQUEST_TRUNCATED",
+        14 => "RESPONSE_TOO_BIG",
+        15 => "BUS_ERROR",
+        16 => "BUSY",
+        _ => return None,
+    })
 }
 
 //////////////////////////////////
 // Hello
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
-#[repr(C)]
-pub struct Hello {
-    pub in_data: u32,
+define_ec_command! {
+    Cmd::Hello;
+    pub struct Hello {
+        pub in_data: u32,
+    }
+    pub struct HelloResponse {
+        pub out_data: u32,
+    }
 }
 
-#[derive(Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct HelloResponse {
-    pub out_data: u32,
+//////////////////////////////////
+// GetVersion
+//////////////////////////////////
+
+define_ec_command! {
+    Cmd::GetVersion;
+    pub struct GetVersion;
+    pub struct GetVersionResponse {
+        pub version_string_ro: EcString<32>,
+        pub version_string_rw: EcString<32>,
+        reserved: [u8; 32],
+        pub current_image: u32,
+    }
 }
 
-impl Command for Hello {
-    const CMD: Cmd = Cmd::Hello;
-    type Response = HelloResponse;
+impl GetVersionResponse {
+    /// Returns the name of the firmware image the EC is currently running.
+    pub fn current_image_name(&self) -> &'static str {
+        match self.current_image {
+            1 => "RO",
+            2 => "RW",
+            _ => "unknown",
+        }
+    }
 }
 
 //////////////////////////////////
-// GetVersion
+// GetCmdVersions
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
-#[repr(C)]
-pub struct GetVersion;
-
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct GetVersionResponse {
-    version_string_ro: [u8; 32],
-    version_string_rw: [u8; 32],
-    reserved: [u8; 32],
-    current_image: u32,
+define_ec_command! {
+    Cmd::GetCmdVersions, version 1;
+    /// Queries which versions of a command the EC supports.
+    ///
+    /// Version 0 of this command takes an 8-bit command ID instead.
+    pub struct GetCmdVersions {
+        pub cmd: u16,
+    }
+    pub struct GetCmdVersionsResponse {
+        /// Bit N is set if version N of the command is supported.
+        pub version_mask: u32,
+    }
 }
 
-impl Command for GetVersion {
-    const CMD: Cmd = Cmd::GetVersion;
-    type Response = GetVersionResponse;
+//////////////////////////////////
+// GetProtocolInfo
+//////////////////////////////////
+
+define_ec_command! {
+    Cmd::GetProtocolInfo;
+    /// Queries the host command protocol versions and packet sizes supported by the EC.
+    ///
+    /// Only ECs implementing protocol version 3 support this command.
+    pub struct GetProtocolInfo;
+    pub struct GetProtocolInfoResponse {
+        /// Bitmask of supported protocol versions (bit N = version N).
+        pub protocol_versions: u32,
+        /// Maximum request packet size, including the 8-byte request header.
+        pub max_request_packet_size: u16,
+        /// Maximum response packet size, including the 8-byte response header.
+        pub max_response_packet_size: u16,
+        pub flags: u32,
+    }
 }
 
 //////////////////////////////////
 // GetKeyboardBacklight
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
-#[repr(C)]
-pub struct GetKeyboardBacklight;
+define_ec_command! {
+    Cmd::GetKeyboardBacklight;
+    pub struct GetKeyboardBacklight;
+    pub struct GetKeyboardBacklightResponse {
+        pub percent: u8,
+        pub enabled: u8,
+    }
+}
 
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct GetKeyboardBacklightResponse {
-    pub percent: u8,
-    pub enabled: u8,
+impl GetKeyboardBacklight {
+    /// Checks whether the EC controls the keyboard backlight.
+    ///
+    /// Some Chromebooks control it from the SoC instead, and the EC rejects the command as invalid.
+    /// Other errors are returned.
+    pub fn probe(ec: &EmbeddedController) -> io::Result<bool> {
+        match ec.command(Self) {
+            Ok(_) => Ok(true),
+            Err(e) if is_rejected(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 }
 
-impl Command for GetKeyboardBacklight {
-    const CMD: Cmd = Cmd::GetKeyboardBacklight;
-    type Response = GetKeyboardBacklightResponse;
+/// Returns whether `e` means that the EC doesn't implement a command, or rejected its parameters
+/// (`EC_RES_INVALID_COMMAND`, `EC_RES_INVALID_VERSION` or `EC_RES_INVALID_PARAM`).
+fn is_rejected(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Unsupported | io::ErrorKind::InvalidInput
+    )
 }
 
 //////////////////////////////////
 // SetKeyboardBacklight
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
-#[repr(C)]
-pub struct SetKeyboardBacklight {
-    pub percent: u8,
+define_ec_command! {
+    Cmd::SetKeyboardBacklight;
+    pub struct SetKeyboardBacklight {
+        pub percent: u8,
+    }
+    pub struct SetKeyboardBacklightResponse;
 }
 
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct SetKeyboardBacklightResponse;
+//////////////////////////////////
+// LightbarCmd
+//////////////////////////////////
 
-impl Command for SetKeyboardBacklight {
-    const CMD: Cmd = Cmd::SetKeyboardBacklight;
-    type Response = SetKeyboardBacklightResponse;
+/// Controls the lightbar found on some Chromebooks (like the Chromebook Pixel).
+///
+/// All lightbar operations share one command ID and are told apart by a subcommand byte. Only the
+/// operations that don't return any data are represented here; see [`LightbarVersion`] for
+/// checking whether the EC has a lightbar at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lightbar {
+    /// Switches the lightbar off.
+    Off,
+    /// Switches the lightbar back on.
+    On,
+    /// Sets the overall brightness (0-255).
+    SetBrightness(u8),
+    /// Switches to a light sequence.
+    Seq(LightbarSeq),
+    /// Sets the color of a single LED of the lightbar.
+    SetRgb {
+        led: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+    },
+}
+
+impl Lightbar {
+    /// Returns the subcommand ID (`LIGHTBAR_CMD_*`).
+    fn subcommand(&self) -> u8 {
+        match self {
+            Self::Off => 1,
+            Self::On => 2,
+            Self::SetBrightness(_) => 4,
+            Self::Seq(_) => 5,
+            Self::SetRgb { .. } => 7,
+        }
+    }
+}
+
+/// A light sequence run by the EC's lightbar task (`enum lightbar_sequence`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LightbarSeq(u8);
+
+impl LightbarSeq {
+    /// Shown while the system is off.
+    pub const S5: Self = Self(2);
+    /// Shown while the system is suspended (a slow pulse).
+    pub const S3: Self = Self(3);
+    /// Shown while the system is running (the battery level).
+    pub const S0: Self = Self(4);
+    /// Stops the current sequence.
+    pub const STOP: Self = Self(9);
+    /// Resumes the sequence matching the system's power state.
+    pub const RUN: Self = Self(10);
+    pub const KONAMI: Self = Self(11);
+    /// Shows the battery level briefly, like after tapping the lid.
+    pub const TAP: Self = Self(12);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LightbarResponse;
+
+impl Command for Lightbar {
+    const CMD: Cmd = Cmd::LightbarCmd;
+    type Response = LightbarResponse;
+}
+
+impl Encode for Lightbar {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.subcommand().encode(buf);
+        match *self {
+            Self::Off | Self::On => {}
+            Self::SetBrightness(brightness) => brightness.encode(buf),
+            Self::Seq(seq) => seq.0.encode(buf),
+            Self::SetRgb {
+                led,
+                red,
+                green,
+                blue,
+            } => [led, red, green, blue].encode(buf),
+        }
+    }
+}
+
+impl Decode for LightbarResponse {
+    const SIZE: usize = 0;
+
+    fn decode(_: &mut Reader<'_>) -> Self {
+        Self
+    }
+}
+
+/// Queries the version of the lightbar interface (`LIGHTBAR_CMD_VERSION`).
+#[derive(Debug, Clone, Copy)]
+pub struct LightbarVersion;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LightbarVersionResponse {
+    pub num: u32,
+    pub flags: u32,
+}
+
+impl LightbarVersion {
+    /// Checks whether the EC has a lightbar, and returns the version of its interface if it does.
+    ///
+    /// ECs without a lightbar reject the command as invalid. Other errors are returned.
+    pub fn probe(ec: &EmbeddedController) -> io::Result<Option<LightbarVersionResponse>> {
+        match ec.command(Self) {
+            Ok(version) => Ok(Some(version)),
+            Err(e) if is_rejected(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Command for LightbarVersion {
+    const CMD: Cmd = Cmd::LightbarCmd;
+    type Response = LightbarVersionResponse;
+}
+
+impl Encode for LightbarVersion {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // `LIGHTBAR_CMD_VERSION`
+        12u8.encode(buf);
+    }
+}
+
+impl Decode for LightbarVersionResponse {
+    const SIZE: usize = 4 + 4;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self {
+            num: reader.read(),
+            flags: reader.read(),
+        }
+    }
 }
 
 //////////////////////////////////
 // LedControl
 //////////////////////////////////
 
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct LedControl {
-    pub led_id: LedId,
-    pub flags: LedFlags,
-    pub brightness: LedBrightnesses,
+define_ec_command! {
+    // ectool always uses version 1 for this command, version 0 does not work and returns
+    // unexpected data.
+    Cmd::LedControl, version 1;
+    pub struct LedControl {
+        pub led_id: LedId,
+        pub flags: LedFlags,
+        pub brightness: LedBrightnesses,
+    }
+    #[derive(Default)]
+    pub struct LedControlResponse {
+        /// When [`LedFlags::QUERY`] was set, this contains the maximum brightness of every color
+        /// the LED supports (0 for unsupported colors).
+        pub brightness: LedBrightnesses,
+    }
 }
 
-impl Command for LedControl {
-    const CMD: Cmd = Cmd::LedControl;
-    // ectool always uses version 1 for this command, version 0 does not work and returns unexpected
-    // data.
-    const VERSION: u32 = 1;
-    type Response = LedControlResponse;
+impl LedControl {
+    /// Hands control of the LED back to the EC.
+    pub fn auto(led_id: LedId) -> Self {
+        Self {
+            led_id,
+            flags: LedFlags::AUTO,
+            brightness: LedBrightnesses::default(),
+        }
+    }
+
+    /// Switches the LED off.
+    pub fn off(led_id: LedId) -> Self {
+        Self::manual(led_id, LedBrightnesses::default())
+    }
+
+    /// Takes control of the LED and sets the brightness of each of its colors.
+    pub fn manual(led_id: LedId, brightness: LedBrightnesses) -> Self {
+        Self {
+            led_id,
+            flags: LedFlags::NONE,
+            brightness,
+        }
+    }
+
+    /// Queries the maximum brightness of each color supported by the LED (see
+    /// [`LedControlResponse`]).
+    pub fn query(led_id: LedId) -> Self {
+        Self {
+            led_id,
+            flags: LedFlags::QUERY,
+            brightness: LedBrightnesses::default(),
+        }
+    }
+
+    /// Takes control of the LED and lights it in a single color.
+    ///
+    /// `brightness` should not exceed the maximum brightness of `color` reported by
+    /// [`LedControl::query`].
+    pub fn solid(self, color: LedColor, brightness: u8) -> Self {
+        Self::manual(self.led_id, LedBrightnesses::single(color, brightness))
+    }
 }
 
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LedId(u8);
 
+impl Encode for LedId {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decode for LedId {
+    const SIZE: usize = 1;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self(reader.read())
+    }
+}
+
 impl LedId {
     pub const BATTERY: Self = Self(0);
     pub const POWER: Self = Self(1);
@@ -148,18 +584,83 @@ impl LedId {
     pub const RIGHT: Self = Self(4);
     pub const RECOVERY_HW_REINIT: Self = Self(5);
     pub const SYSRQ_DEBUG: Self = Self(6);
+
+    /// The physical LEDs (`RECOVERY_HW_REINIT` and `SYSRQ_DEBUG` are only used to signal events).
+    pub const ALL: [Self; 5] = [
+        Self::BATTERY,
+        Self::POWER,
+        Self::ADAPTER,
+        Self::LEFT,
+        Self::RIGHT,
+    ];
+
+    /// Returns an iterator over all physical LEDs.
+    ///
+    /// Not every machine has all of them; use [`LedId::capabilities`] to find out which ones can
+    /// be controlled.
+    pub fn all() -> impl Iterator<Item = Self> {
+        Self::ALL.into_iter()
+    }
+
+    pub fn name(self) -> &'static str {
+        [
+            "battery",
+            "power",
+            "adapter",
+            "left",
+            "right",
+            "recovery-hw-reinit",
+            "sysrq-debug",
+        ][usize::from(self.0)]
+    }
+
+    /// Queries the maximum brightness of every color the LED supports (0 for unsupported colors).
+    ///
+    /// LEDs the machine doesn't have support no colors.
+    pub fn capabilities(self, ec: &EmbeddedController) -> io::Result<LedBrightnesses> {
+        match ec.command(LedControl::query(self)) {
+            Ok(resp) => Ok(resp.brightness),
+            // The EC rejects queries for LEDs that don't exist.
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(LedBrightnesses::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns whether the LED can show `color`.
+    pub fn supports(self, ec: &EmbeddedController, color: LedColor) -> io::Result<bool> {
+        Ok(self.capabilities(ec)?.get(color) != 0)
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
-#[repr(transparent)]
-pub struct LedFlags(u8);
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct LedFlags: u8 {
+        /// Query the LED's capabilities instead of changing it.
+        const QUERY = 1 << 0;
+        /// Let the EC control the LED automatically.
+        const AUTO = 1 << 1;
+    }
+}
 
 impl LedFlags {
-    pub const NONE: Self = Self(0);
-    pub const QUERY: Self = Self(1 << 0);
-    pub const AUTO: Self = Self(1 << 1);
+    pub const NONE: Self = Self::empty();
+}
+
+impl Encode for LedFlags {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.bits().encode(buf);
+    }
+}
+
+impl Decode for LedFlags {
+    const SIZE: usize = 1;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self::from_bits_retain(reader.read())
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LedColor(u8);
 
 impl LedColor {
@@ -170,10 +671,22 @@ impl LedColor {
     pub const WHITE: Self = Self(4);
     pub const AMBER: Self = Self(5);
     pub const COUNT: usize = 6;
+
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::RED,
+        Self::GREEN,
+        Self::BLUE,
+        Self::YELLOW,
+        Self::WHITE,
+        Self::AMBER,
+    ];
+
+    pub fn name(self) -> &'static str {
+        ["red", "green", "blue", "yellow", "white", "amber"][usize::from(self.0)]
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
-#[repr(transparent)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct LedBrightnesses {
     raw: [u8; LedColor::COUNT],
 }
@@ -187,10 +700,199 @@ impl LedBrightnesses {
         self.raw[usize::from(color.0)] = brightness;
         self
     }
+
+    pub fn get(&self, color: LedColor) -> u8 {
+        self.raw[usize::from(color.0)]
+    }
+
+    /// Blends `self` into `other`, each color by `percent` percent (0 returns `self`, 100 returns
+    /// `other`).
+    pub fn mix(self, other: Self, percent: u8) -> Self {
+        let percent = u16::from(percent.min(100));
+        let mut raw = self.raw;
+        for (a, b) in raw.iter_mut().zip(other.raw) {
+            *a = ((u16::from(*a) * (100 - percent) + u16::from(b) * percent) / 100) as u8;
+        }
+        Self { raw }
+    }
+}
+
+impl Encode for LedBrightnesses {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.raw.encode(buf);
+    }
+}
+
+impl Decode for LedBrightnesses {
+    const SIZE: usize = LedColor::COUNT;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self { raw: reader.read() }
+    }
+}
+
+//////////////////////////////////
+// TempSensorGetInfo
+//////////////////////////////////
+
+define_ec_command! {
+    Cmd::TempSensorGetInfo;
+    /// Queries the name and type of a temperature sensor.
+    pub struct TempSensorGetInfo {
+        /// Index of the sensor in the memory map (see [`thermal`](crate::thermal)).
+        pub id: u8,
+    }
+    pub struct TempSensorGetInfoResponse {
+        pub sensor_name: EcString<32>,
+        pub sensor_type: u8,
+    }
+}
+
+//////////////////////////////////
+// ConsoleSnapshot / ConsoleRead
+//////////////////////////////////
+
+define_ec_command! {
+    Cmd::ConsoleSnapshot;
+    /// Takes a snapshot of the EC console output, to be read with [`ConsoleRead`].
+    pub struct ConsoleSnapshot;
+    pub struct ConsoleSnapshotResponse;
+}
+
+define_ec_command! {
+    Cmd::ConsoleRead;
+    /// Reads the next chunk of the console snapshot, as a NUL-terminated string.
+    ///
+    /// The response is as long as the chunk, so this is sent with
+    /// [`EmbeddedController::command_into`]. An empty string marks the end of the snapshot.
+    pub struct ConsoleRead;
+    pub struct ConsoleReadResponse;
+}
+
+impl ConsoleRead {
+    /// Snapshots the EC console and returns its contents.
+    ///
+    /// The EC keeps its most recent console output in a ring buffer of a few kilobytes, so this
+    /// includes the output from before keylightd was started.
+    pub fn snapshot(ec: &EmbeddedController) -> io::Result<String> {
+        ec.command(ConsoleSnapshot)?;
+        let mut output = Vec::new();
+        let mut buf = vec![0; ec.protocol().max_response];
+        loop {
+            let len = ec.command_into(ConsoleRead, &mut buf)?;
+            let chunk = buf[..len].split(|&b| b == 0).next().unwrap_or_default();
+            if chunk.is_empty() {
+                break;
+            }
+            output.extend_from_slice(chunk);
+        }
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+}
+
+//////////////////////////////////
+// FpLedLevelControl
+//////////////////////////////////
+
+define_ec_command! {
+    Cmd::FpLedLevelControl;
+    /// Gets or sets the brightness level of the power LED in the fingerprint module.
+    ///
+    /// This is a Framework-specific command that is only supported by newer BIOS versions.
+    pub struct FpLedLevelControl {
+        pub set_level: FpLedLevel,
+        /// If non-zero, `set_level` is ignored and the current level is returned instead.
+        pub get_level: u8,
+    }
+    pub struct FpLedLevelControlResponse {
+        pub level: FpLedLevel,
+    }
+}
+
+impl FpLedLevelControl {
+    pub fn get() -> Self {
+        Self {
+            set_level: FpLedLevel::HIGH,
+            get_level: 1,
+        }
+    }
+
+    pub fn set(level: FpLedLevel) -> Self {
+        Self {
+            set_level: level,
+            get_level: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FpLedLevel(u8);
+
+impl FpLedLevel {
+    pub const HIGH: Self = Self(0);
+    pub const MEDIUM: Self = Self(1);
+    pub const LOW: Self = Self(2);
+}
+
+impl Encode for FpLedLevel {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decode for FpLedLevel {
+    const SIZE: usize = 1;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self(reader.read())
+    }
+}
+
+//////////////////////////////////
+// Layout checks
+//////////////////////////////////
+
+/// Checks the size of every request and response against the size of its struct in
+/// `ec_commands.h`.
+///
+/// The wire format of each command is spelled out field by field, so a missing, extra or
+/// mistyped field would otherwise only show up as an EC rejecting the command (or, worse,
+/// misinterpreting it), possibly only on some firmware versions.
+macro_rules! assert_sizes {
+    ($($ty:ty => $c_type:literal = $size:literal,)*) => {
+        $(
+            const _: () = assert!(
+                <$ty>::SIZE == $size,
+                concat!(
+                    "the size of `", stringify!($ty), "` doesn't match ", $c_type,
+                    " in ec_commands.h",
+                ),
+            );
+        )*
+    };
 }
 
-#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
-#[repr(transparent)]
-pub struct LedControlResponse {
-    brightness: LedBrightnesses,
+assert_sizes! {
+    Hello => "struct ec_params_hello" = 4,
+    HelloResponse => "struct ec_response_hello" = 4,
+    GetVersion => "the empty parameters" = 0,
+    GetVersionResponse => "struct ec_response_get_version" = 100,
+    GetCmdVersions => "struct ec_params_get_cmd_versions_v1" = 2,
+    GetCmdVersionsResponse => "struct ec_response_get_cmd_versions" = 4,
+    GetProtocolInfo => "the empty parameters" = 0,
+    GetProtocolInfoResponse => "struct ec_response_get_protocol_info" = 12,
+    GetKeyboardBacklight => "the empty parameters" = 0,
+    GetKeyboardBacklightResponse => "struct ec_response_pwm_get_keyboard_backlight" = 2,
+    SetKeyboardBacklight => "struct ec_params_pwm_set_keyboard_backlight" = 1,
+    SetKeyboardBacklightResponse => "the empty response" = 0,
+    LightbarVersionResponse => "struct ec_response_lightbar (version)" = 8,
+    LedControl => "struct ec_params_led_control" = 8,
+    LedControlResponse => "struct ec_response_led_control" = 6,
+    TempSensorGetInfo => "struct ec_params_temp_sensor_get_info" = 1,
+    TempSensorGetInfoResponse => "struct ec_response_temp_sensor_get_info" = 33,
+    ConsoleSnapshot => "the empty parameters" = 0,
+    ConsoleRead => "the empty parameters" = 0,
+    FpLedLevelControl => "struct ec_params_fp_led_control" = 2,
+    FpLedLevelControlResponse => "struct ec_response_fp_led_level" = 1,
 }
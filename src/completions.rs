@@ -0,0 +1,221 @@
+//! Shell completion script generation.
+//!
+//! The scripts are derived from the `argh` definitions in [`crate::cli`], so they never go out of
+//! sync with the actual command-line interface.
+
+use std::fmt::Write;
+
+use argh::{ArgsInfo, CommandInfoWithArgs, FlagInfoKind};
+
+use crate::cli::{Args, Shell};
+
+const BIN: &str = env!("CARGO_PKG_NAME");
+
+/// Returns the completion script for `shell`.
+pub fn generate(shell: Shell) -> String {
+    let info = Args::get_args_info();
+    let mut commands = Vec::new();
+    collect(&mut commands, BIN.to_string(), &info);
+
+    match shell {
+        Shell::Bash => bash(&commands),
+        Shell::Zsh => zsh(&commands),
+        Shell::Fish => fish(&commands),
+    }
+}
+
+/// A (sub)command, flattened out of the tree of [`CommandInfoWithArgs`].
+struct Cmd<'a> {
+    /// Space-separated command path, starting with the binary name.
+    path: String,
+    info: &'a CommandInfoWithArgs,
+}
+
+impl Cmd<'_> {
+    fn depth(&self) -> usize {
+        self.path.split(' ').count() - 1
+    }
+
+    fn subcommands(&self) -> impl Iterator<Item = &str> {
+        self.info.commands.iter().map(|c| c.name)
+    }
+
+    fn flags(&self) -> impl Iterator<Item = &argh::FlagInfo<'_>> {
+        self.info.flags.iter().filter(|f| !f.hidden)
+    }
+}
+
+fn collect<'a>(out: &mut Vec<Cmd<'a>>, path: String, info: &'a CommandInfoWithArgs) {
+    for sub in &info.commands {
+        collect(out, format!("{path} {}", sub.name), &sub.command);
+    }
+    out.push(Cmd { path, info });
+}
+
+fn bash(commands: &[Cmd<'_>]) -> String {
+    let mut s = String::new();
+    writeln!(s, "_{BIN}() {{").unwrap();
+    writeln!(s, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(s, "    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+    writeln!(s, "    local path=\"{BIN}\" i").unwrap();
+    writeln!(s, "    for ((i = 1; i < COMP_CWORD; i++)); do").unwrap();
+    writeln!(s, "        case \"$path ${{COMP_WORDS[i]}}\" in").unwrap();
+    for cmd in commands.iter().filter(|c| c.depth() > 0) {
+        writeln!(s, "            \"{0}\") path=\"{0}\" ;;", cmd.path).unwrap();
+    }
+    writeln!(s, "        esac").unwrap();
+    writeln!(s, "    done").unwrap();
+    writeln!(s, "    case \"$path\" in").unwrap();
+    for cmd in commands {
+        writeln!(s, "        \"{}\")", cmd.path).unwrap();
+        let valued = cmd
+            .flags()
+            .filter(|f| matches!(f.kind, FlagInfoKind::Option { .. }))
+            .map(|f| f.long)
+            .collect::<Vec<_>>();
+        if !valued.is_empty() {
+            writeln!(
+                s,
+                "            case \"$prev\" in {}) return ;; esac",
+                valued.join("|")
+            )
+            .unwrap();
+        }
+        let words = cmd
+            .flags()
+            .map(|f| f.long)
+            .chain(cmd.subcommands())
+            .collect::<Vec<_>>();
+        writeln!(
+            s,
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            words.join(" ")
+        )
+        .unwrap();
+        writeln!(s, "            ;;").unwrap();
+    }
+    writeln!(s, "    esac").unwrap();
+    writeln!(s, "}}").unwrap();
+    writeln!(s, "complete -F _{BIN} {BIN}").unwrap();
+    s
+}
+
+fn zsh(commands: &[Cmd<'_>]) -> String {
+    let escape = |d: &str| {
+        d.replace('\'', "'\\''")
+            .replace('[', "\\[")
+            .replace(']', "\\]")
+            .replace(':', "\\:")
+    };
+
+    let mut s = String::new();
+    writeln!(s, "#compdef {BIN}").unwrap();
+    writeln!(s).unwrap();
+    for cmd in commands {
+        let func = format!("_{}", cmd.path.replace(' ', "_"));
+        writeln!(s, "{func}() {{").unwrap();
+        let has_subcommands = !cmd.info.commands.is_empty();
+        if has_subcommands {
+            writeln!(s, "    local curcontext=\"$curcontext\" state line").unwrap();
+        }
+        write!(s, "    _arguments -C").unwrap();
+        for flag in cmd.flags() {
+            let desc = escape(flag.description);
+            match flag.kind {
+                FlagInfoKind::Switch => write!(s, " \\\n        '{}[{desc}]'", flag.long),
+                FlagInfoKind::Option { arg_name } => {
+                    write!(s, " \\\n        '{}[{desc}]:{arg_name}: '", flag.long)
+                }
+            }
+            .unwrap();
+        }
+        for (i, pos) in cmd.info.positionals.iter().enumerate() {
+            write!(s, " \\\n        '{}:{}: '", i + 1, escape(pos.description)).unwrap();
+        }
+        if has_subcommands {
+            write!(s, " \\\n        '1: :->command' \\\n        '*:: :->args'").unwrap();
+        }
+        writeln!(s).unwrap();
+        if has_subcommands {
+            writeln!(s, "    case $state in").unwrap();
+            writeln!(s, "        command)").unwrap();
+            writeln!(s, "            local -a commands").unwrap();
+            writeln!(s, "            commands=(").unwrap();
+            for sub in &cmd.info.commands {
+                writeln!(
+                    s,
+                    "                '{}:{}'",
+                    sub.name,
+                    escape(sub.command.description)
+                )
+                .unwrap();
+            }
+            writeln!(s, "            )").unwrap();
+            writeln!(s, "            _describe 'command' commands").unwrap();
+            writeln!(s, "            ;;").unwrap();
+            writeln!(s, "        args)").unwrap();
+            writeln!(s, "            case $line[1] in").unwrap();
+            for sub in &cmd.info.commands {
+                writeln!(s, "                {}) {func}_{} ;;", sub.name, sub.name).unwrap();
+            }
+            writeln!(s, "            esac").unwrap();
+            writeln!(s, "            ;;").unwrap();
+            writeln!(s, "    esac").unwrap();
+        }
+        writeln!(s, "}}").unwrap();
+        writeln!(s).unwrap();
+    }
+    writeln!(s, "_{BIN} \"$@\"").unwrap();
+    s
+}
+
+fn fish(commands: &[Cmd<'_>]) -> String {
+    let escape = |d: &str| d.replace('\\', "\\\\").replace('\'', "\\'");
+
+    let mut s = String::new();
+    writeln!(s, "complete -c {BIN} -f").unwrap();
+    for cmd in commands {
+        // Condition under which completions for this command apply: all path components have
+        // been typed, and none of its subcommands have been.
+        let mut conds = Vec::new();
+        let path = cmd.path.split(' ').skip(1).collect::<Vec<_>>();
+        if path.is_empty() {
+            conds.push("__fish_use_subcommand".to_string());
+        }
+        for component in &path {
+            conds.push(format!("__fish_seen_subcommand_from {component}"));
+        }
+        let subs = cmd.subcommands().collect::<Vec<_>>();
+        if !subs.is_empty() && !path.is_empty() {
+            conds.push(format!(
+                "not __fish_seen_subcommand_from {}",
+                subs.join(" ")
+            ));
+        }
+        let cond = conds.join("; and ");
+
+        for sub in &cmd.info.commands {
+            writeln!(
+                s,
+                "complete -c {BIN} -n '{cond}' -a {} -d '{}'",
+                sub.name,
+                escape(sub.command.description)
+            )
+            .unwrap();
+        }
+        for flag in cmd.flags() {
+            let long = flag.long.trim_start_matches("--");
+            let value = match flag.kind {
+                FlagInfoKind::Switch => "",
+                FlagInfoKind::Option { .. } => " -r",
+            };
+            writeln!(
+                s,
+                "complete -c {BIN} -n '{cond}' -l {long}{value} -d '{}'",
+                escape(flag.description)
+            )
+            .unwrap();
+        }
+    }
+    s
+}
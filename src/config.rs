@@ -0,0 +1,862 @@
+//! Configuration file handling.
+//!
+//! The configuration is a TOML file, by default located at [`DEFAULT_PATH`]. When running as a
+//! regular user, a per-user configuration file can override individual settings. Every setting is
+//! optional, and command-line options take precedence over all configuration files.
+
+use std::{
+    env, fmt,
+    fs::{self, File},
+    io::{self, Write},
+    net::SocketAddr,
+    os::unix::{self, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{Backend, LightbarPattern, PowerLedLevel, RunArgs},
+    learning::LearnTimeout,
+    quirks::{self, Quirks},
+    rules::{Condition, Fact},
+    schedule::{Schedule, TimeOfDay},
+};
+
+pub const DEFAULT_PATH: &str = "/etc/keylightd/config.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Keyboard backlight brightness when active (0-100).
+    pub brightness: u8,
+    /// Keyboard backlight brightness when active and the desktop uses a dark color scheme.
+    pub dark_brightness: Option<u8>,
+    /// Keyboard backlight brightness when only the touchpad was used recently.
+    pub pointer_brightness: Option<u8>,
+    /// Ignore touchpad events that move the finger less than this many device units.
+    pub touchpad_min_movement: Option<u32>,
+    /// Ignore touchpad events unless a touch has reached this pressure.
+    pub touchpad_min_pressure: Option<u32>,
+    /// Ignore touchpad events unless a touch has reached this size (its major axis).
+    pub touchpad_min_size: Option<u32>,
+    /// Activity timeout in seconds.
+    pub timeout: u32,
+    /// How to tell when the user is idle.
+    pub idle_detection: IdleDetection,
+    /// Time of day during which the backlight stays off, regardless of activity.
+    pub night_off: Option<Schedule>,
+    /// Seconds after fading out during which activity restores the backlight instantly.
+    pub grace_period: u32,
+    /// Whether to learn a timeout from the user's typical pauses.
+    pub learn_timeout: LearnTimeout,
+    /// Whether to also control the power LED in the fingerprint module.
+    pub power: bool,
+    /// If set, the power LED is dimmed to this level when idle instead of being switched off.
+    pub power_brightness: Option<PowerLedLevel>,
+    /// Pulse the side LEDs red while discharging and below this battery percentage.
+    pub low_battery_led: Option<u8>,
+    /// Where to get activity from. Activity reported by any of them counts.
+    pub activity_backends: Vec<Backend>,
+    /// Whether gamepads and joysticks count as activity.
+    pub gamepads: bool,
+    /// Whether to listen on all keyboards if none of the builtin input devices is found.
+    pub keyboard_fallback: bool,
+    /// Command (program and arguments) whose output lines count as activity.
+    pub activity_command: Option<Vec<String>>,
+    /// Command to run when the user becomes active after being idle.
+    pub on_active: Option<Vec<String>>,
+    /// Command to run when the user becomes idle.
+    pub on_idle: Option<Vec<String>>,
+    /// Show the charging state on the side LEDs with custom colors.
+    pub charge_leds: Option<ChargeLeds>,
+    /// Drive the lightbar of Chromebooks that have one along with the keyboard backlight.
+    pub lightbar: Option<Lightbar>,
+    /// Blend amber into white side LEDs during certain hours.
+    pub warm_white: Option<WarmWhite>,
+    /// Turn the backlight off in bright surroundings.
+    pub ambient_light: Option<AmbientLight>,
+    /// Slowly fade in the backlight at a fixed time every day, as a wake-up alarm.
+    pub sunrise: Option<Sunrise>,
+    /// Keep the backlight on while the proximity sensor detects someone.
+    pub proximity: Option<Proximity>,
+    /// Use a different brightness while an external display is connected.
+    pub docked: Option<Docked>,
+    /// Brightness levels for specific conditions. The first rule that applies wins.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// Where the machine is, for rules that depend on sunset.
+    pub location: Option<Location>,
+    /// Keep the backlight on while certain applications are running.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub wake_locks: Vec<WakeLockRule>,
+    /// Detect remote sessions, and optionally keep the backlight off while they are the only
+    /// activity.
+    pub remote_sessions: Option<RemoteSessions>,
+    /// Publish the state to an MQTT broker and accept commands from it.
+    pub mqtt: Option<MqttConfig>,
+    /// Serve the HTTP API on this (loopback) address.
+    pub http: Option<SocketAddr>,
+    /// Whether to provide the `io.keylightd` varlink interface.
+    pub varlink: bool,
+    /// Whether to provide a UPower-compatible `KbdBacklight` object on D-Bus.
+    pub kbd_backlight_dbus: bool,
+    /// Whether to change the keyboard backlight through UPower instead of the EC.
+    pub upower: bool,
+    /// Whether to write the brightness level into `systemd-backlight`'s saved state.
+    pub systemd_backlight: bool,
+    /// Name of the hardware profile to use. Detected from the firmware if unset.
+    pub hardware: Option<String>,
+    /// Whether to exit at startup if a configured device is missing, instead of running without
+    /// it.
+    pub fail_fast: bool,
+}
+
+/// How to tell when the user is idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleDetection {
+    /// After `timeout` seconds without input.
+    #[default]
+    Input,
+    /// When the primary display is switched off.
+    Dpms,
+}
+
+/// Connection settings for the MQTT broker.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Prefix of the topics used by keylightd.
+    pub topic: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// If set, the backlight is announced to Home Assistant under this discovery prefix.
+    pub discovery_prefix: Option<String>,
+}
+
+// The configuration is logged, so keep the password out of it.
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("topic", &self.topic)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("discovery_prefix", &self.discovery_prefix)
+            .finish()
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 1883,
+            topic: "keylightd".into(),
+            client_id: "keylightd".into(),
+            username: None,
+            password: None,
+            discovery_prefix: None,
+        }
+    }
+}
+
+/// Thresholds for turning the backlight off in bright surroundings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AmbientLight {
+    /// Turn the backlight off when the ambient light rises above this many lux.
+    pub off_above: u32,
+    /// Turn the backlight back on when the ambient light falls below this many lux.
+    pub on_below: u32,
+    /// Time constant of the exponential smoothing of the readings, in seconds.
+    pub smoothing: u32,
+    /// Where to read the ambient light from.
+    pub source: AlsSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlsSource {
+    /// The iio sensor if there is one, the EC otherwise.
+    #[default]
+    Auto,
+    /// The kernel's iio driver.
+    Iio,
+    /// The EC memory map.
+    Ec,
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        Self {
+            off_above: 200,
+            on_below: 100,
+            smoothing: 10,
+            source: AlsSource::Auto,
+        }
+    }
+}
+
+/// Settings for the daily wake-up fade.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Sunrise {
+    /// Local time at which the fade starts.
+    pub time: TimeOfDay,
+    /// Minutes it takes to fade up to full brightness.
+    #[serde(default = "default_sunrise_duration")]
+    pub duration: u32,
+    /// Minutes the backlight stays at full brightness after the fade, unless there's activity.
+    #[serde(default = "default_sunrise_hold")]
+    pub hold: u32,
+    /// Brightness to fade up to. Defaults to the regular brightness.
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    /// Whether to also fade in the side LEDs, in amber.
+    #[serde(default)]
+    pub side_leds: bool,
+}
+
+fn default_sunrise_duration() -> u32 {
+    15
+}
+
+fn default_sunrise_hold() -> u32 {
+    10
+}
+
+/// Settings for the proximity sensor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Proximity {
+    /// Raw readings at or above this value mean that someone is present. Defaults to the sensor's
+    /// `nearlevel`.
+    pub near_level: Option<u32>,
+}
+
+/// Settings for while the laptop is docked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Docked {
+    /// Keyboard backlight brightness when active and docked. Off by default.
+    pub brightness: u8,
+}
+
+/// A brightness level that applies under a condition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rule {
+    /// When the rule applies, like `on_battery && after_sunset`.
+    pub when: Condition,
+    /// Keyboard backlight brightness when active and the rule applies.
+    pub brightness: u8,
+}
+
+/// A place on Earth, in degrees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Location {
+    /// Degrees north of the equator (negative for south).
+    pub latitude: f64,
+    /// Degrees east of Greenwich (negative for west).
+    pub longitude: f64,
+}
+
+// The coordinates are validated to be finite, so they can't be NaN.
+impl Eq for Location {}
+
+/// `[docked]` is a shorthand for a rule that applies while docked, after all others.
+static DOCKED: Condition = Condition::Fact(Fact::Docked);
+
+/// An application that keeps the backlight on while it is running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WakeLockRule {
+    /// Name of the process, as shown by `ps`.
+    pub process: String,
+    /// Brightness level to use while the application is running, instead of the usual one.
+    #[serde(default)]
+    pub brightness: Option<u8>,
+}
+
+/// Settings for detecting remote sessions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RemoteSessions {
+    /// Local TCP ports whose incoming connections count as remote sessions.
+    pub ports: Vec<u16>,
+    /// Keep the backlight off while remote sessions are open and the builtin keyboard and
+    /// touchpad aren't used.
+    pub keep_off: bool,
+}
+
+impl Default for RemoteSessions {
+    fn default() -> Self {
+        Self {
+            // SSH, RDP, and the first VNC display.
+            ports: vec![22, 3389, 5900],
+            keep_off: false,
+        }
+    }
+}
+
+/// Colors for the side LEDs, depending on the charging state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ChargeLeds {
+    pub charging: SideLedColor,
+    /// Color while on AC power, but not charging.
+    pub full: SideLedColor,
+    pub discharging: SideLedColor,
+    /// If set, the colors are only used during these hours, and the EC controls the LEDs
+    /// otherwise.
+    pub hours: Option<Schedule>,
+}
+
+impl Default for ChargeLeds {
+    fn default() -> Self {
+        Self {
+            charging: SideLedColor::Amber,
+            full: SideLedColor::Green,
+            discharging: SideLedColor::Off,
+            hours: None,
+        }
+    }
+}
+
+/// Settings for the lightbar.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Lightbar {
+    /// Pattern shown while the keyboard backlight is on.
+    pub active: LightbarPattern,
+    /// Pattern shown while the keyboard backlight is off.
+    pub idle: LightbarPattern,
+    /// Overall brightness of the lightbar (0-255). Left alone if unset.
+    pub brightness: Option<u8>,
+}
+
+impl Default for Lightbar {
+    fn default() -> Self {
+        Self {
+            active: LightbarPattern::S0,
+            idle: LightbarPattern::Off,
+            brightness: None,
+        }
+    }
+}
+
+/// Settings for shifting white side LEDs towards amber.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WarmWhite {
+    /// Hours during which white is shown as amber.
+    pub hours: Schedule,
+    /// Minutes over which the color shifts before the start and end of `hours`.
+    #[serde(default = "default_warm_white_transition")]
+    pub transition: u32,
+}
+
+fn default_warm_white_transition() -> u32 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SideLedColor {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    White,
+    Amber,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            brightness: 30,
+            dark_brightness: None,
+            pointer_brightness: None,
+            touchpad_min_movement: None,
+            touchpad_min_pressure: None,
+            touchpad_min_size: None,
+            timeout: 10,
+            idle_detection: IdleDetection::Input,
+            grace_period: 0,
+            night_off: None,
+            learn_timeout: LearnTimeout::Off,
+            power: false,
+            power_brightness: None,
+            low_battery_led: None,
+            activity_backends: vec![Backend::Evdev],
+            gamepads: false,
+            keyboard_fallback: false,
+            activity_command: None,
+            on_active: None,
+            on_idle: None,
+            charge_leds: None,
+            warm_white: None,
+            lightbar: None,
+            ambient_light: None,
+            sunrise: None,
+            proximity: None,
+            docked: None,
+            rules: Vec::new(),
+            location: None,
+            wake_locks: Vec::new(),
+            remote_sessions: None,
+            mqtt: None,
+            http: None,
+            varlink: false,
+            kbd_backlight_dbus: false,
+            upower: false,
+            systemd_backlight: false,
+            hardware: None,
+            fail_fast: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration.
+    ///
+    /// If `path` is given, only that file is loaded. Otherwise, the system-wide configuration at
+    /// [`DEFAULT_PATH`] is loaded, and the per-user configuration (see [`user_path`]) is layered
+    /// on top of it, with settings in the user configuration taking precedence. Missing files are
+    /// not an error in that case.
+    pub fn load(path: Option<&str>) -> anyhow::Result<Self> {
+        let sources = Self::sources(path)?;
+        let mut merged = toml::Table::new();
+        for path in &sources {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let table: toml::Table = toml::from_str(&text)
+                .with_context(|| format!("invalid configuration file {}", path.display()))?;
+            log::debug!("loaded configuration from {}", path.display());
+            merge(&mut merged, table);
+        }
+
+        // A file may only set some of the keys of a section, so only the merged configuration
+        // has to be complete.
+        Self::parse(merged).with_context(|| match &*sources {
+            [] => "invalid configuration".to_string(),
+            [path] => format!("invalid configuration file {}", path.display()),
+            _ => {
+                let paths = sources.iter().map(|path| path.display().to_string());
+                format!(
+                    "invalid configuration (merged from {})",
+                    paths.collect::<Vec<_>>().join(", ")
+                )
+            }
+        })
+    }
+
+    /// Returns the configuration files that [`Config::load`] would read, in order of increasing
+    /// precedence.
+    pub fn sources(path: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+        if let Some(path) = path {
+            return Ok(vec![path.into()]);
+        }
+        let mut sources = Vec::new();
+        for path in [Some(PathBuf::from(DEFAULT_PATH)), user_path()]
+            .into_iter()
+            .flatten()
+        {
+            match fs::metadata(&path) {
+                Ok(_) => sources.push(path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context(format!("failed to access {}", path.display())),
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Returns the file that runtime changes should be saved to.
+    pub fn save_path(path: Option<&str>) -> PathBuf {
+        path.map(PathBuf::from)
+            .or_else(user_path)
+            .unwrap_or_else(|| DEFAULT_PATH.into())
+    }
+
+    /// Deserializes and validates a (merged) configuration.
+    fn parse(table: toml::Table) -> anyhow::Result<Self> {
+        let config = Self::deserialize(table)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the configured hardware profile, or the detected one if none is configured.
+    pub fn quirks(&self) -> anyhow::Result<&'static Quirks> {
+        let Some(id) = &self.hardware else {
+            return Ok(quirks::detect());
+        };
+        quirks::by_id(id).with_context(|| {
+            let ids = quirks::ids().collect::<Vec<_>>().join(", ");
+            format!("unknown hardware profile `{id}` (known profiles: {ids})")
+        })
+    }
+
+    /// Returns the activity backends to use. The activity command is always one of them if it is
+    /// set.
+    pub fn backends(&self) -> Vec<Backend> {
+        let mut backends = self.activity_backends.clone();
+        if self.activity_command.is_some() {
+            backends.push(Backend::Command);
+        }
+        backends.sort();
+        backends.dedup();
+        backends
+    }
+
+    /// Returns the conditions and brightness levels of all rules, in order of precedence.
+    pub fn rules(&self) -> impl Iterator<Item = (&Condition, u8)> {
+        self.rules
+            .iter()
+            .map(|rule| (&rule.when, rule.brightness))
+            .chain(
+                self.docked
+                    .as_ref()
+                    .map(|docked| (&DOCKED, docked.brightness)),
+            )
+    }
+
+    /// Returns whether any rule depends on `fact`.
+    pub fn uses(&self, fact: Fact) -> bool {
+        self.rules().any(|(when, _)| when.uses(fact))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for brightness in [
+            Some(self.brightness),
+            self.dark_brightness,
+            self.pointer_brightness,
+        ]
+        .into_iter()
+        .chain(self.wake_locks.iter().map(|rule| rule.brightness))
+        .chain(self.sunrise.as_ref().map(|sunrise| sunrise.brightness))
+        .chain(self.rules().map(|(_, brightness)| Some(brightness)))
+        .flatten()
+        {
+            if brightness > 100 {
+                bail!("invalid brightness value {brightness} (valid range: 0-100)");
+            }
+        }
+        if let Some(percent) = self.low_battery_led {
+            if percent > 100 {
+                bail!("invalid battery percentage {percent} (valid range: 0-100)");
+            }
+        }
+        for (name, command) in [
+            ("activity-command", &self.activity_command),
+            ("on-active", &self.on_active),
+            ("on-idle", &self.on_idle),
+        ] {
+            if command.as_ref().is_some_and(|c| c.is_empty()) {
+                bail!("`{name}` must contain at least the program to run");
+            }
+        }
+        if self.activity_backends.contains(&Backend::Command) && self.activity_command.is_none() {
+            bail!("the `command` activity backend needs an `activity-command`");
+        }
+        if self.wake_locks.iter().any(|rule| rule.process.is_empty()) {
+            bail!("`wake-locks.process` must not be empty");
+        }
+        if self
+            .sunrise
+            .as_ref()
+            .is_some_and(|sunrise| sunrise.duration == 0)
+        {
+            bail!("`sunrise.duration` must be at least 1 minute");
+        }
+        match &self.location {
+            Some(location) => {
+                if !(-90.0..=90.0).contains(&location.latitude) {
+                    bail!(
+                        "invalid latitude {} (valid range: -90-90)",
+                        location.latitude
+                    );
+                }
+                if !(-180.0..=180.0).contains(&location.longitude) {
+                    bail!(
+                        "invalid longitude {} (valid range: -180-180)",
+                        location.longitude
+                    );
+                }
+            }
+            None if self.uses(Fact::AfterSunset) => {
+                bail!("rules with `after_sunset` need a `[location]`");
+            }
+            None => {}
+        }
+        if self.uses(Fact::Remote) && self.remote_sessions.is_none() {
+            bail!("rules with `remote` need a `[remote-sessions]` section");
+        }
+        if let Some(ambient) = &self.ambient_light {
+            if ambient.on_below > ambient.off_above {
+                bail!("`ambient-light.on-below` must not be larger than `off-above`");
+            }
+        }
+        self.quirks()?;
+        if let Some(mqtt) = &self.mqtt {
+            // MQTT 3.1.1 doesn't allow a password without a user name.
+            if mqtt.password.is_some() && mqtt.username.is_none() {
+                bail!("`mqtt.password` needs a `mqtt.username`");
+            }
+        }
+        if let Some(addr) = self.http {
+            // The API is unauthenticated, so it must not be reachable from other machines.
+            if !addr.ip().is_loopback() {
+                bail!("`http` must be a loopback address like 127.0.0.1, not {addr}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the overrides given on the command line.
+    pub fn apply_args(&mut self, args: &RunArgs) {
+        if let Some(brightness) = args.brightness {
+            self.brightness = brightness;
+        }
+        if let Some(brightness) = args.dark_brightness {
+            self.dark_brightness = Some(brightness);
+        }
+        if let Some(timeout) = args.timeout {
+            self.timeout = timeout;
+        }
+        if args.power {
+            self.power = true;
+        }
+        if let Some(level) = args.power_brightness {
+            self.power_brightness = Some(level);
+        }
+        if let Some(percent) = args.low_battery_led {
+            self.low_battery_led = Some(percent);
+        }
+        if let Some(profile) = &args.hardware_profile {
+            self.hardware = Some(profile.clone());
+        }
+        if args.fail_fast {
+            self.fail_fast = true;
+        }
+        if args.best_effort {
+            self.fail_fast = false;
+        }
+    }
+
+    /// Writes settings that were changed at runtime to the configuration file at `path`.
+    ///
+    /// Only `settings` are written, so that values that merely came from the command line (like
+    /// `run --timeout`) don't end up in the file. Everything else in the file, including comments
+    /// and formatting, is preserved. The file is replaced atomically, so a crash can never leave a
+    /// half-written configuration behind.
+    pub fn save(path: &Path, settings: &[Setting]) -> anyhow::Result<()> {
+        Self::edit(path, |doc| {
+            for setting in settings {
+                let (key, value) = match setting {
+                    Setting::Brightness(level) => ("brightness", i64::from(*level).into()),
+                    Setting::DarkBrightness(level) => ("dark-brightness", i64::from(*level).into()),
+                    Setting::Timeout(secs) => ("timeout", i64::from(*secs).into()),
+                    Setting::Hardware(id) => ("hardware", id.as_str().into()),
+                };
+                doc[key] = toml_edit::value::<toml_edit::Value>(value);
+            }
+        })
+    }
+
+    /// Changes the configuration file at `path` (which doesn't have to exist yet) with `f`,
+    /// preserving everything `f` doesn't touch, and replaces the file atomically.
+    ///
+    /// The new file gets the permissions and owner of the old one, since it may contain secrets
+    /// like `mqtt.password`.
+    pub fn edit(path: &Path, f: impl FnOnce(&mut toml_edit::DocumentMut)) -> anyhow::Result<()> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+        };
+        let mut doc = text
+            .parse::<toml_edit::DocumentMut>()
+            .with_context(|| format!("invalid configuration file {}", path.display()))?;
+        f(&mut doc);
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        let original = match fs::metadata(path) {
+            Ok(meta) => Some(meta),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(format!("failed to access {}", path.display())),
+        };
+        let tmp = path.with_extension("toml.tmp");
+        let mut file = File::create(&tmp)?;
+        // Before writing anything, so that the contents are never more accessible than before.
+        if let Some(meta) = &original {
+            file.set_permissions(meta.permissions())?;
+            unix::fs::fchown(&file, Some(meta.uid()), Some(meta.gid()))
+                .with_context(|| format!("failed to keep the owner of {}", path.display()))?;
+        }
+        file.write_all(doc.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // Makes the rename itself survive a crash.
+        File::open(dir)?.sync_all()?;
+        log::info!("saved configuration to {}", path.display());
+        Ok(())
+    }
+}
+
+/// A setting that `keylightd set --save` writes to the configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Setting {
+    Brightness(u8),
+    DarkBrightness(u8),
+    Timeout(u32),
+    /// The hardware profile, which only takes effect when the daemon is restarted.
+    Hardware(String),
+}
+
+/// Returns the path of the per-user configuration file.
+///
+/// The per-user configuration is only used when keylightd runs as a regular user (eg. as a
+/// systemd user service), never when running as root.
+pub fn user_path() -> Option<PathBuf> {
+    if Uid::effective().is_root() {
+        return None;
+    }
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config")))?;
+    Some(config_home.join("keylightd").join("config.toml"))
+}
+
+/// Layers `overlay` on top of `base`.
+///
+/// Sections are merged key by key, so that a user configuration can change a single setting of
+/// eg. `[mqtt]` and keep the others. All other values, including arrays like `[[rules]]`, replace
+/// those in `base`.
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(files: &[&str]) -> anyhow::Result<Config> {
+        let mut merged = toml::Table::new();
+        for file in files {
+            merge(&mut merged, toml::from_str(file)?);
+        }
+        Config::parse(merged)
+    }
+
+    #[test]
+    fn merge_nested_table() {
+        let system = r#"
+            brightness = 30
+
+            [mqtt]
+            host = "broker.lan"
+            topic = "office/keylightd"
+        "#;
+        let user = r#"
+            [mqtt]
+            port = 1884
+        "#;
+
+        let config = load(&[system, user]).unwrap();
+        assert_eq!(config.brightness, 30);
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!(mqtt.host, "broker.lan");
+        assert_eq!(mqtt.topic, "office/keylightd");
+        assert_eq!(mqtt.port, 1884);
+    }
+
+    #[test]
+    fn save_only_changed_settings() {
+        let path = env::temp_dir().join(format!("keylightd-save-{}.toml", std::process::id()));
+        fs::write(&path, "# my settings\nbrightness = 30\ntimeout = 10\n").unwrap();
+
+        let settings = [
+            Setting::Timeout(60),
+            Setting::Hardware("framework-13".into()),
+        ];
+        Config::save(&path, &settings).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            text,
+            "# my settings\nbrightness = 30\ntimeout = 60\nhardware = \"framework-13\"\n"
+        );
+    }
+
+    #[test]
+    fn mqtt_password_needs_username() {
+        let config = r#"
+            [mqtt]
+            host = "broker.lan"
+            password = "secret"
+        "#;
+        let e = load(&[config]).unwrap_err();
+        assert_eq!(e.to_string(), "`mqtt.password` needs a `mqtt.username`");
+
+        let config = r#"
+            [mqtt]
+            host = "broker.lan"
+            username = "keylightd"
+            password = "secret"
+        "#;
+        load(&[config]).unwrap();
+    }
+
+    #[test]
+    fn save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("keylightd-mode-{}.toml", std::process::id()));
+        fs::write(&path, "[mqtt]\nusername = \"kl\"\npassword = \"secret\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        Config::save(&path, &[Setting::Brightness(40)]).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn overlay_replaces_values() {
+        let system = r#"
+            brightness = 30
+            activity-backends = ["evdev", "logind"]
+        "#;
+        let user = r#"
+            brightness = 70
+            activity-backends = ["wayland"]
+        "#;
+
+        let config = load(&[system, user]).unwrap();
+        assert_eq!(config.brightness, 70);
+        assert_eq!(config.activity_backends, [Backend::Wayland]);
+    }
+}
@@ -0,0 +1,738 @@
+//! The control socket used by `keylightd status`/`keylightd set` to talk to the running daemon.
+//!
+//! A daemon running as root listens on [`SYSTEM_SOCKET_PATH`], while one running as a regular
+//! user listens in `$XDG_RUNTIME_DIR`.
+//!
+//! The protocol is line-based: the client sends a single JSON-encoded [`Request`] terminated by a
+//! newline, and the daemon answers with a single JSON-encoded [`Response`] line.
+//!
+//! Everyone may query the daemon through the system socket, but only root and members of the
+//! group that owns the socket may change its state (see [`may_change`]). When the daemon binds
+//! the socket itself, that is the [`GROUP`] group if it exists.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{MetadataExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials, UnixCredentials},
+    unistd::{self, Gid, Group, Uid, User},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activation,
+    cli::{Backend, BrightnessChange},
+    clock,
+    command::{GetKeyboardBacklight, Hello},
+    config::{Config, Setting},
+    daemon::{self, Shared},
+    dirs,
+    ec::EmbeddedController,
+    history,
+    learning::LearnTimeout,
+    logging, quirks, thermal,
+    worker::Priority,
+};
+
+/// Path of the control socket when the daemon runs as root.
+pub const SYSTEM_SOCKET_PATH: &str = "/run/keylightd.sock";
+
+/// Group that the system socket is given if it exists, whose members may change settings.
+pub const GROUP: &str = "keylightd";
+
+/// Maximum size of a request line.
+const MAX_REQUEST: u64 = 4096;
+
+/// How long a client may take to send its request or receive the response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of connections that are served at the same time.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Returns the path of the control socket of a daemon running as the current (non-root) user, or
+/// in another [runtime directory](dirs::runtime_dir).
+fn user_socket_path() -> Option<PathBuf> {
+    Some(dirs::runtime_dir()?.join("keylightd.sock"))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Health,
+    Set {
+        brightness: Option<BrightnessChange>,
+        timeout: Option<u32>,
+        /// Hardware profile to save, which the daemon uses from its next start. Requires `save`.
+        #[serde(default)]
+        hardware_profile: Option<String>,
+        /// Whether to persist the new settings in the configuration file.
+        #[serde(default)]
+        save: bool,
+    },
+    /// Fades to `brightness` and back, without changing any settings.
+    Preview {
+        brightness: u8,
+        fade_ms: u32,
+    },
+    /// Keeps the backlight off regardless of activity.
+    Off {
+        until_activity: bool,
+    },
+    /// Releases the latch set by [`Request::Off`].
+    On,
+    /// Starts or stops ignoring the activity reported by `backend`.
+    Backend {
+        backend: Backend,
+        enabled: bool,
+    },
+    /// Changes the log level to `level` (one of `off`, `error`, `warn`, `info`, `debug`, or
+    /// `trace`), or just returns the current one.
+    LogLevel {
+        level: Option<String>,
+    },
+    /// Returns the recent state transitions and EC commands.
+    History,
+}
+
+impl Request {
+    /// Returns whether the request only queries the daemon, without changing anything.
+    pub fn is_query(&self) -> bool {
+        match self {
+            Self::Status | Self::Health | Self::History => true,
+            Self::LogLevel { level } => level.is_none(),
+            Self::Set { .. }
+            | Self::Preview { .. }
+            | Self::Off { .. }
+            | Self::On
+            | Self::Backend { .. } => false,
+        }
+    }
+}
+
+/// Why the backlight is kept off regardless of activity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OffLatch {
+    /// Until the next input activity.
+    UntilActivity,
+    /// Until `keylightd on`.
+    UntilEnabled,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum Response {
+    Status(Box<Status>),
+    Health(Health),
+    LogLevel { level: String },
+    History { entries: Vec<HistoryEntry> },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    /// Whether there was recent input activity (`None` before the first state change).
+    pub active: Option<bool>,
+    /// Brightness level used when active (taking the desktop color scheme into account).
+    pub brightness: u8,
+    /// Activity timeout in seconds.
+    pub timeout: u32,
+    /// Whether the power LED is controlled.
+    pub power: bool,
+    /// The current keyboard backlight brightness reported by the EC, if it could be queried.
+    #[serde(default)]
+    pub backlight: Option<u8>,
+    /// The timeout learned from the user's pauses, if learning is enabled and has enough data.
+    #[serde(default)]
+    pub suggested_timeout: Option<u32>,
+    /// Whether the backlight is kept off by `keylightd off`.
+    #[serde(default)]
+    pub off: Option<OffLatch>,
+    /// Whether the backlight is kept off because it's within the `night-off` hours.
+    #[serde(default)]
+    pub night_off: bool,
+    /// Statistics about the input devices and other activity sources.
+    #[serde(default)]
+    pub sources: Vec<SourceStatus>,
+    /// Readings of the EC's temperature sensors.
+    #[serde(default)]
+    pub temperatures: Vec<TemperatureStatus>,
+    /// Speeds of the fans controlled by the EC.
+    #[serde(default)]
+    pub fans: Vec<FanStatus>,
+    /// What last woke up the backlight.
+    #[serde(default)]
+    pub wake: Option<WakeStatus>,
+    /// Whether someone is in front of the machine, according to the proximity sensor or the
+    /// activity command's presence reports (`None` if neither is used).
+    #[serde(default)]
+    pub present: Option<bool>,
+    /// Number of open remote sessions (`None` if they aren't tracked).
+    #[serde(default)]
+    pub remote_sessions: Option<usize>,
+    /// Whether an external display is connected (`None` if it isn't tracked).
+    #[serde(default)]
+    pub docked: Option<bool>,
+    /// Whether the primary display is on (`None` if it isn't tracked).
+    #[serde(default)]
+    pub display_on: Option<bool>,
+    /// Whether the lid is closed (`None` without a lid switch).
+    #[serde(default)]
+    pub lid_closed: Option<bool>,
+    /// The condition of the rule that sets the brightness, if any applies.
+    #[serde(default)]
+    pub rule: Option<String>,
+    /// The activity backends that are running.
+    #[serde(default)]
+    pub backends: Vec<BackendStatus>,
+    /// The application whose wake lock keeps the backlight on, if any.
+    #[serde(default)]
+    pub wake_lock: Option<String>,
+    /// Progress of the running wake-up fade in percent, if any.
+    #[serde(default)]
+    pub sunrise: Option<u8>,
+    /// Version of the daemon.
+    #[serde(default)]
+    pub version: String,
+    /// Version of the EC firmware, if it could be queried.
+    #[serde(default)]
+    pub ec_version: Option<String>,
+    /// Known problem of the EC firmware that affects keylightd, if any.
+    #[serde(default)]
+    pub firmware_bug: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub name: String,
+    /// Device node of an input device.
+    pub path: Option<PathBuf>,
+    /// Number of input events received.
+    pub events: u64,
+    /// Number of events that were ignored by the touchpad filter.
+    #[serde(default)]
+    pub ignored: u64,
+    /// Number of errors while reading from the source.
+    pub errors: u64,
+    /// Seconds since the last event, if there was one.
+    pub last_event_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackendStatus {
+    pub backend: Backend,
+    /// Whether activity reported by the backend counts.
+    pub enabled: bool,
+    /// Whether the backend considers the user idle, if it tracks that on its own and knows.
+    pub idle: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WakeStatus {
+    /// Name of the activity source.
+    pub source: String,
+    /// The input event, if the source is an input device (keyboard keys are not named).
+    pub event: Option<String>,
+    /// Seconds since the backlight was woken up.
+    pub secs_ago: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TemperatureStatus {
+    pub name: String,
+    /// Temperature in degrees Celsius, or `None` if the sensor could not be read.
+    pub celsius: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FanStatus {
+    /// Fan speed in RPM (0 if the fan is stopped or stalled).
+    pub rpm: u16,
+    /// Whether the fan is stalled, meaning it should spin but doesn't.
+    pub stalled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the event happened, as a Unix timestamp.
+    pub time: f64,
+    pub event: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Health {
+    /// Whether the EC answers and at least one input listener works.
+    pub healthy: bool,
+    /// Why the EC did not answer a hello command, if it didn't.
+    pub ec_error: Option<String>,
+    /// Number of input listeners that are currently receiving events.
+    pub listeners: usize,
+}
+
+/// Sends `req` to the running daemon and returns its response.
+pub fn request(req: &Request) -> anyhow::Result<Response> {
+    // Prefer a daemon running in the user session, if there is one.
+    let path = user_socket_path()
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| SYSTEM_SOCKET_PATH.into());
+    let stream = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "failed to connect to {} (is the keylightd daemon running?)",
+            path.display()
+        )
+    })?;
+    let mut line = serde_json::to_string(req)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        Response::Error { message } => bail!("{message}"),
+        resp => Ok(resp),
+    }
+}
+
+/// Binds the control socket, or takes the one passed by systemd, and spawns a thread serving
+/// requests on it.
+pub fn spawn_server(shared: Arc<Shared>) -> io::Result<()> {
+    let listener = match activation::take("control") {
+        Some(fd) => {
+            log::info!("listening on the control socket passed by systemd");
+            UnixListener::from(fd)
+        }
+        None => bind()?,
+    };
+
+    let group = socket_group(&listener)?;
+
+    thread::spawn(move || {
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("failed to accept control connection: {e}");
+                    continue;
+                }
+            };
+            // Serve every client on its own thread, so that a slow one doesn't hold up the others.
+            if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::Relaxed);
+                log::warn!("too many control connections, rejecting one");
+                continue;
+            }
+            let shared = shared.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(&shared, group, stream) {
+                    log::warn!("error while serving control connection: {e}");
+                }
+                connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Returns the group owning the socket that `listener` is bound to.
+pub fn socket_group(listener: &UnixListener) -> io::Result<Gid> {
+    let addr = listener.local_addr()?;
+    let path = addr
+        .as_pathname()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket has no path"))?;
+    Ok(Gid::from_raw(fs::metadata(path)?.gid()))
+}
+
+/// Gives the socket at `path` to the [`GROUP`] group, if it exists.
+pub fn set_group(path: &Path) -> io::Result<()> {
+    if let Ok(Some(group)) = Group::from_name(GROUP) {
+        unistd::chown(path, None, Some(group.gid))?;
+    }
+    Ok(())
+}
+
+/// Returns the credentials of the process connected to `stream`.
+pub fn peer(stream: &UnixStream) -> io::Result<UnixCredentials> {
+    Ok(getsockopt(stream.as_raw_fd(), PeerCredentials)?)
+}
+
+/// Returns whether the client `peer` may make requests that change the daemon's state.
+///
+/// That is root, the user the daemon runs as, and the members of `group` (the group owning the
+/// socket).
+pub fn may_change(peer: &UnixCredentials, group: Gid) -> bool {
+    let uid = Uid::from_raw(peer.uid());
+    if uid.is_root() || uid == Uid::effective() || Gid::from_raw(peer.gid()) == group {
+        return true;
+    }
+    // The credentials only include the primary group of the client.
+    match (User::from_uid(uid), Group::from_gid(group)) {
+        (Ok(Some(user)), Ok(Some(group))) => group.mem.contains(&user.name),
+        _ => false,
+    }
+}
+
+/// The error message for a client that isn't allowed to change the daemon's state.
+pub fn permission_denied(group: Gid) -> String {
+    let group = match Group::from_gid(group) {
+        Ok(Some(group)) => group.name,
+        _ => group.to_string(),
+    };
+    format!("permission denied (only root and members of the `{group}` group may change settings)")
+}
+
+fn bind() -> io::Result<UnixListener> {
+    let user_path = user_socket_path();
+    let path = user_path
+        .clone()
+        .unwrap_or_else(|| SYSTEM_SOCKET_PATH.into());
+
+    // A previous instance might have left its socket behind.
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(&path)?;
+    if user_path.is_none() {
+        // Let everyone query the daemon through the system socket (like UPower does). Changes are
+        // checked against the group of the socket.
+        set_group(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+    }
+    log::info!("listening on {}", path.display());
+    Ok(listener)
+}
+
+fn serve(shared: &Shared, group: Gid, stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let peer = peer(&stream)?;
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST)).read_line(&mut line)?;
+    let resp = if !line.ends_with('\n') {
+        Response::Error {
+            message: format!("invalid request: not a line of at most {MAX_REQUEST} bytes"),
+        }
+    } else {
+        match serde_json::from_str::<Request>(&line) {
+            Ok(req) if !req.is_query() && !may_change(&peer, group) => {
+                log::debug!("denied control request from UID {}: {req:?}", peer.uid());
+                Response::Error {
+                    message: permission_denied(group),
+                }
+            }
+            Ok(req) => {
+                log::debug!("control request: {req:?}");
+                handle(shared, req)
+            }
+            Err(e) => Response::Error {
+                message: format!("invalid request: {e}"),
+            },
+        }
+    };
+    let mut line = serde_json::to_string(&resp)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())
+}
+
+pub fn handle(shared: &Shared, req: Request) -> Response {
+    match req {
+        Request::Status => status(shared),
+        Request::Off { until_activity } => {
+            let latch = if until_activity {
+                OffLatch::UntilActivity
+            } else {
+                OffLatch::UntilEnabled
+            };
+            log::info!("keeping the backlight off ({latch:?})");
+            history::record(format!("kept off ({latch:?})"));
+            shared.update(|state| state.off = Some((latch, clock::now())));
+            status(shared)
+        }
+        Request::On => {
+            history::record("released by `keylightd on`");
+            shared.update(|state| state.off = None);
+            status(shared)
+        }
+        Request::Backend { backend, enabled } => {
+            if !shared.backends.contains(&backend) {
+                return Response::Error {
+                    message: format!("the `{}` activity backend isn't running", backend.name()),
+                };
+            }
+            let verb = if enabled { "enabled" } else { "disabled" };
+            log::info!("{verb} the {} activity backend", backend.name());
+            history::record(format!("{verb} the {} backend", backend.name()));
+            shared.update(|state| {
+                state.disabled_backends.retain(|&b| b != backend);
+                if !enabled {
+                    state.disabled_backends.push(backend);
+                }
+            });
+            status(shared)
+        }
+        Request::Health => health(shared),
+        Request::History => Response::History {
+            entries: history::entries(),
+        },
+        Request::LogLevel { level } => {
+            if let Some(level) = level {
+                match level.parse() {
+                    Ok(level) => logging::set_level(level),
+                    Err(_) => {
+                        return Response::Error {
+                            message: format!(
+                                "invalid log level `{level}` (valid levels: off, error, warn, \
+                                 info, debug, trace)"
+                            ),
+                        }
+                    }
+                }
+            }
+            Response::LogLevel {
+                level: log::max_level().as_str().to_lowercase(),
+            }
+        }
+        Request::Preview {
+            brightness,
+            fade_ms,
+        } => {
+            if brightness > 100 {
+                return Response::Error {
+                    message: format!("invalid brightness value {brightness} (valid range: 0-100)"),
+                };
+            }
+            if fade_ms > daemon::MAX_PREVIEW_FADE_MS {
+                return Response::Error {
+                    message: format!(
+                        "fade duration of {fade_ms}ms is too long (at most {}ms)",
+                        daemon::MAX_PREVIEW_FADE_MS
+                    ),
+                };
+            }
+            let fade = Duration::from_millis(fade_ms.into());
+            let timeout = daemon::preview_timeout(fade);
+            let result = shared
+                .ec
+                .submit_with_timeout(Priority::High, None, timeout, move |ec| {
+                    daemon::preview(ec, brightness, fade)
+                });
+            match result {
+                Ok(()) => status(shared),
+                Err(e) => Response::Error {
+                    message: format!("preview failed: {e}"),
+                },
+            }
+        }
+        Request::Set {
+            brightness,
+            timeout,
+            hardware_profile,
+            save,
+        } => {
+            let mut changed = Vec::new();
+            if let Some(id) = hardware_profile {
+                if !save {
+                    return Response::Error {
+                        message: "the hardware profile is only used when the daemon starts, so \
+                                  it can only be changed with `--save`"
+                            .into(),
+                    };
+                }
+                if quirks::by_id(&id).is_none() {
+                    let ids = quirks::ids().collect::<Vec<_>>().join(", ");
+                    return Response::Error {
+                        message: format!("unknown hardware profile `{id}` (known profiles: {ids})"),
+                    };
+                }
+                changed.push(Setting::Hardware(id));
+            }
+            let result = shared.reconfigure(|state, config| {
+                if let Some(change) = brightness {
+                    // Adjust whichever brightness setting is currently in effect.
+                    match &mut config.dark_brightness {
+                        Some(dark) if state.dark_theme => {
+                            *dark = change.apply(*dark);
+                            changed.push(Setting::DarkBrightness(*dark));
+                        }
+                        _ => {
+                            config.brightness = change.apply(config.brightness);
+                            changed.push(Setting::Brightness(config.brightness));
+                        }
+                    }
+                }
+                if let Some(timeout) = timeout {
+                    config.timeout = timeout;
+                    changed.push(Setting::Timeout(timeout));
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                return Response::Error {
+                    message: e.to_string(),
+                };
+            }
+            // The file is written without holding the state lock.
+            if save {
+                if let Err(e) = Config::save(&shared.config_path, &changed) {
+                    return Response::Error {
+                        message: format!(
+                            "the new settings are in effect, but saving them failed: {e:#}"
+                        ),
+                    };
+                }
+            }
+            status(shared)
+        }
+    }
+}
+
+fn health(shared: &Shared) -> Response {
+    let in_data = 0xa0b0c0d0;
+    let ec_error = match shared.ec.submit(Priority::High, None, move |ec| {
+        ec.command(Hello { in_data })
+    }) {
+        Ok(resp) if resp.out_data == in_data.wrapping_add(0x01020304) => None,
+        Ok(resp) => Some(format!(
+            "invalid response to hello command: {:#010x}",
+            resp.out_data
+        )),
+        Err(e) => Some(e.to_string()),
+    };
+    let listeners = shared.listeners.load(Ordering::Relaxed);
+    Response::Health(Health {
+        healthy: ec_error.is_none() && listeners > 0,
+        ec_error,
+        listeners,
+    })
+}
+
+fn status(shared: &Shared) -> Response {
+    let backlight = shared
+        .ec
+        .submit(Priority::High, None, |ec| ec.command(GetKeyboardBacklight))
+        .map(|resp| if resp.enabled != 0 { resp.percent } else { 0 })
+        .map_err(|e| log::warn!("failed to query the keyboard backlight: {e}"))
+        .ok();
+    let temperatures = shared
+        .ec
+        .submit(Priority::High, None, temperatures)
+        .unwrap_or_else(|e| {
+            log::warn!("failed to read the temperature sensors: {e}");
+            Vec::new()
+        });
+    let fans = shared
+        .ec
+        .submit(Priority::High, None, thermal::read_fans)
+        .unwrap_or_else(|e| {
+            log::warn!("failed to read the fan speeds: {e}");
+            Vec::new()
+        })
+        .into_iter()
+        .map(|speed| match speed {
+            thermal::FanSpeed::Rpm(rpm) => FanStatus {
+                rpm,
+                stalled: false,
+            },
+            _ => FanStatus {
+                rpm: 0,
+                stalled: true,
+            },
+        })
+        .collect();
+    let state = shared.state.lock().unwrap();
+    let sources = shared.sources.lock().unwrap();
+    Response::Status(Box::new(Status {
+        active: state.active,
+        brightness: state.active_brightness(),
+        timeout: state.config.timeout,
+        power: state.config.power,
+        backlight,
+        suggested_timeout: match state.config.learn_timeout {
+            LearnTimeout::Off => None,
+            _ => state.pauses.suggestion(),
+        },
+        off: state.off.map(|(latch, _)| latch),
+        night_off: state.night_off(),
+        present: state.presence(),
+        remote_sessions: state.remote_sessions,
+        docked: state.docked,
+        display_on: state.display_on,
+        lid_closed: state.lid_closed,
+        rule: state.rule().map(|(when, _)| when.to_string()),
+        backends: shared
+            .backends
+            .iter()
+            .map(|&backend| BackendStatus {
+                backend,
+                enabled: !state.disabled_backends.contains(&backend),
+                idle: state.idle_hints.get(&backend).copied(),
+            })
+            .collect(),
+        wake_lock: state.wake_lock.as_ref().map(|lock| lock.process.clone()),
+        sunrise: state.sunrise,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ec_version: shared.ec_version.clone(),
+        firmware_bug: shared
+            .ec_version
+            .as_deref()
+            .and_then(quirks::firmware_bug)
+            .map(String::from),
+        wake: state.last_wake.map(|(cause, at)| {
+            let (source, event) = cause.describe(&sources);
+            WakeStatus {
+                source,
+                event,
+                secs_ago: clock::since(at).as_secs(),
+            }
+        }),
+        sources: sources
+            .iter()
+            .map(|source| SourceStatus {
+                name: source.name.clone(),
+                path: source.path.clone(),
+                events: source.events,
+                ignored: source.ignored,
+                errors: source.errors,
+                last_event_secs: source.last_event.map(|at| clock::since(at).as_secs()),
+            })
+            .collect(),
+        temperatures,
+        fans,
+    }))
+}
+
+fn temperatures(ec: &EmbeddedController) -> io::Result<Vec<TemperatureStatus>> {
+    let sensors = thermal::Sensor::read_all(ec)?;
+    Ok(sensors
+        .iter()
+        .map(|sensor| TemperatureStatus {
+            // Not all ECs implement the info command, so fall back to the sensor index.
+            name: sensor
+                .name(ec)
+                .unwrap_or_else(|_| format!("sensor {}", sensor.id)),
+            celsius: sensor.reading.celsius(),
+        })
+        .collect())
+}
@@ -0,0 +1,1361 @@
+//! The backlight daemon itself (`keylightd run`).
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::AtomicUsize,
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+#[cfg(any(feature = "dbus", feature = "socket"))]
+use anyhow::Context;
+use evdev::InputEventKind;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::{
+    background,
+    cli::{Backend, LightbarPattern, PowerLedLevel},
+    clock,
+    command::{
+        FpLedLevel, FpLedLevelControl, GetKeyboardBacklight, GetVersion, LedControl, LedId,
+        LightbarVersion, SetKeyboardBacklight,
+    },
+    config::{Config, IdleDetection},
+    control::{self, OffLatch},
+    diagnose,
+    ec::{self, EmbeddedController},
+    error::KeylightdError,
+    fade::{self, Backlight},
+    history, hooks, input,
+    learning::{LearnTimeout, Pauses},
+    leds, logging,
+    persist::{self, PersistedState, Settings},
+    pidfile,
+    quirks::{self, Quirks},
+    recovery::{self, SavedState},
+    rules::{Condition, Fact},
+    sun, systemd_backlight, upower,
+    wakelock::WakeLock,
+    worker::{self, EcWorker, Priority},
+};
+
+/// How long activity is ignored after `keylightd off --until-activity true`.
+const OFF_LATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Where input activity came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityKind {
+    /// A keyboard.
+    Keyboard,
+    /// A touchpad or mouse.
+    Pointer,
+    /// The activity command or a desktop backend, which can't tell what kind of input it was and
+    /// might report activity on behalf of a remote user.
+    External,
+}
+
+/// What reported input activity, for telling what woke up the backlight.
+#[derive(Debug, Clone, Copy)]
+pub struct ActivityCause {
+    /// Index into [`Shared::sources`].
+    pub source: usize,
+    /// The first input event of the report, if the source is an input device.
+    pub event: Option<InputEventKind>,
+}
+
+impl ActivityCause {
+    /// Returns the name of the source and a description of the event.
+    pub fn describe(&self, sources: &[input::SourceStats]) -> (String, Option<String>) {
+        let name = sources[self.source].name.clone();
+        (name, self.event.map(input::describe_event))
+    }
+}
+
+/// Something that happened, published by the input listeners and integrations and consumed by the
+/// main loop.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// Input activity of the given kind, reported at `at`.
+    Activity {
+        kind: ActivityKind,
+        cause: ActivityCause,
+        at: Instant,
+    },
+    /// An external presence detector saw someone in front of the machine at `at`.
+    ///
+    /// Unlike activity, this doesn't turn the backlight back on, and doesn't restart the timeout
+    /// from scratch: it only makes sure that the user doesn't become idle within the next
+    /// `timeout` seconds, so a slow detector can't cut a longer remaining timeout short.
+    Presence { at: Instant },
+    /// The lid was closed.
+    LidClosed,
+    /// The lid was opened.
+    LidOpened,
+    /// The machine is about to suspend.
+    // Only logind announces suspend and resume.
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    Suspend,
+    /// The machine resumed from suspend.
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    Resume,
+    /// The runtime configuration was replaced.
+    ConfigReloaded,
+    /// Something else in [`State`] was changed directly.
+    StateChanged,
+}
+
+/// State shared between the input listeners, the control socket, and the main loop.
+pub struct Shared {
+    pub state: Mutex<State>,
+    /// All EC access has to go through the worker.
+    pub ec: EcWorker,
+    /// Events for the main loop.
+    events: Sender<Event>,
+    receiver: Mutex<Receiver<Event>>,
+    /// Path of the configuration file, for persisting runtime changes.
+    pub config_path: PathBuf,
+    /// Number of input listeners that are currently receiving events.
+    pub listeners: AtomicUsize,
+    /// Statistics about every activity source.
+    pub sources: Mutex<Vec<input::SourceStats>>,
+    /// If set, the keyboard backlight is changed through UPower instead of the EC.
+    pub upower: Option<upower::KbdBacklight>,
+    /// How the machine differs from others.
+    pub quirks: &'static Quirks,
+    /// Version of the firmware image the EC is running, if it could be queried.
+    pub ec_version: Option<String>,
+    /// The activity backends that were started.
+    pub backends: Vec<Backend>,
+}
+
+pub struct State {
+    pub last_activity: Instant,
+    /// Like `last_activity`, but only for keyboard activity (or external activity, which can't
+    /// tell).
+    pub last_keyboard_activity: Instant,
+    /// Like `last_activity`, but only for the builtin input devices.
+    pub last_local_activity: Instant,
+    /// The current runtime configuration.
+    pub config: Config,
+    /// Whether the daemon currently considers the user active (`None` before the first fade).
+    pub active: Option<bool>,
+    /// Whether the desktop prefers a dark color scheme.
+    pub dark_theme: bool,
+    /// Whether the ambient light is bright enough to not need the backlight.
+    pub bright_ambient: bool,
+    /// Whether the backlight is kept on regardless of activity (eg. on request via MQTT).
+    pub inhibit: bool,
+    /// Whether the proximity sensor detects someone in front of the machine (`None` without one).
+    pub present: Option<bool>,
+    /// Until when the last `presence` line of the activity command keeps the user from becoming
+    /// idle.
+    present_until: Option<Instant>,
+    /// Number of open remote sessions (`None` if they aren't tracked).
+    pub remote_sessions: Option<usize>,
+    /// Whether an external display is connected (`None` if it isn't tracked).
+    pub docked: Option<bool>,
+    /// Whether the primary display is on (`None` if it isn't tracked).
+    pub display_on: Option<bool>,
+    /// Whether the lid is closed (`None` without a lid switch).
+    pub lid_closed: Option<bool>,
+    /// Whether the machine runs on battery (`None` if it isn't tracked).
+    pub on_battery: Option<bool>,
+    /// Whether the machine is about to suspend.
+    pub suspended: bool,
+    /// Whether the daemon is restoring the LEDs to exit, after which nothing may change them.
+    pub shutting_down: bool,
+    /// Activity backends whose activity is currently ignored.
+    pub disabled_backends: Vec<Backend>,
+    /// Whether the backends that track idleness on their own (logind and Wayland) consider the
+    /// user idle. Backends are missing until they know.
+    pub idle_hints: BTreeMap<Backend, bool>,
+    /// The wake lock held by a running application, if any.
+    pub wake_lock: Option<WakeLock>,
+    /// Progress of the running wake-up fade in percent, if any.
+    pub sunrise: Option<u8>,
+    /// The pauses between input events, for learning a timeout.
+    pub pauses: Pauses,
+    /// Set by `keylightd off` to keep the backlight off, along with when that happened.
+    pub off: Option<(OffLatch, Instant)>,
+    /// The first activity since the user became idle, which will wake up the backlight.
+    pending_wake: Option<ActivityCause>,
+    /// What last woke up the backlight, and when.
+    pub last_wake: Option<(ActivityCause, Instant)>,
+}
+
+impl State {
+    /// Returns the keyboard backlight brightness to use while the user is active.
+    pub fn active_brightness(&self) -> u8 {
+        match self.config.dark_brightness {
+            Some(brightness) if self.dark_theme => brightness,
+            _ => self.config.brightness,
+        }
+    }
+
+    /// Returns whether the user is kept from becoming idle, either on request, by an application,
+    /// or because someone is still in front of the machine.
+    fn kept_active(&self) -> bool {
+        self.inhibit
+            || self.wake_lock.is_some()
+            || (self.present == Some(true) && self.active == Some(true))
+    }
+
+    /// Takes or releases the wake lock of an application.
+    pub fn set_wake_lock(&mut self, lock: Option<WakeLock>) {
+        // Like with presence, the timeout starts when the application exits.
+        if self.wake_lock.is_some() && lock.is_none() && self.active == Some(true) {
+            self.last_activity = clock::now();
+        }
+        self.wake_lock = lock;
+    }
+
+    /// Updates whether someone is in front of the machine.
+    pub fn set_present(&mut self, present: bool) {
+        // The timeout starts when they leave, not when they last touched the keyboard.
+        if self.present == Some(true) && !present && self.active == Some(true) {
+            self.last_activity = clock::now();
+        }
+        self.present = Some(present);
+    }
+
+    /// Returns whether someone is known to be in front of the machine, either from the proximity
+    /// sensor or from a recent presence report (`None` if neither is used).
+    pub fn presence(&self) -> Option<bool> {
+        match self.present_until {
+            Some(until) => Some(self.present == Some(true) || clock::now() < until),
+            None => self.present,
+        }
+    }
+
+    /// Returns the point in time at which the user will be considered idle.
+    fn idle_at(&self) -> Instant {
+        let idle_at = self.last_activity + Duration::from_secs(self.config.timeout.into());
+        // Presence reports only delay becoming idle, they don't make an idle user active again.
+        match self.present_until {
+            Some(until) if self.active == Some(true) => idle_at.max(until),
+            _ => idle_at,
+        }
+    }
+
+    /// Returns whether any enabled backend that tracks idleness on its own considers the user
+    /// active.
+    fn hinted_active(&self) -> bool {
+        self.idle_hints
+            .iter()
+            .any(|(backend, &idle)| !idle && !self.disabled_backends.contains(backend))
+    }
+
+    /// Returns whether the user counts as active at `now`.
+    fn is_active(&self, now: Instant) -> bool {
+        self.kept_active()
+            || match self.config.idle_detection {
+                IdleDetection::Input => now < self.idle_at() || self.hinted_active(),
+                // Until the display state is known, assume that the screen is on.
+                IdleDetection::Dpms => self.display_on != Some(false),
+            }
+    }
+
+    /// Returns the point in time at which only pointer activity is left.
+    fn keyboard_idle_at(&self) -> Instant {
+        self.last_keyboard_activity + Duration::from_secs(self.config.timeout.into())
+    }
+
+    /// Returns the point in time at which only remote activity is left.
+    fn local_idle_at(&self) -> Instant {
+        self.last_local_activity + Duration::from_secs(self.config.timeout.into())
+    }
+
+    /// Returns whether the backlight is kept off because the machine is only used remotely.
+    fn remote_only(&self, now: Instant) -> bool {
+        self.config
+            .remote_sessions
+            .as_ref()
+            .is_some_and(|s| s.keep_off)
+            && self.remote_sessions.is_some_and(|count| count > 0)
+            && now >= self.local_idle_at()
+    }
+
+    /// Returns whether `fact` currently holds.
+    fn fact(&self, fact: Fact) -> bool {
+        match fact {
+            Fact::OnBattery => self.on_battery == Some(true),
+            Fact::AfterSunset => self.config.location.as_ref().is_some_and(sun::is_dark),
+            Fact::Docked => self.docked == Some(true),
+            Fact::DarkTheme => self.dark_theme,
+            Fact::Remote => self.remote_sessions.is_some_and(|count| count > 0),
+            Fact::Present => self.presence() == Some(true),
+        }
+    }
+
+    /// Returns the condition and brightness of the first rule that applies, if any.
+    pub fn rule(&self) -> Option<(&Condition, u8)> {
+        self.config
+            .rules()
+            .find(|(when, _)| when.eval(&|fact| self.fact(fact)))
+    }
+
+    /// Returns the brightness to use while the user is active at `now`, taking into account
+    /// whether they only used a pointing device recently, the ambient light, remote sessions,
+    /// rules, and wake locks.
+    fn current_brightness(&self, now: Instant) -> u8 {
+        if self.bright_ambient || self.remote_only(now) {
+            return 0;
+        }
+        if let Some((_, brightness)) = self.rule() {
+            return brightness;
+        }
+        if let Some(brightness) = self.wake_lock.as_ref().and_then(|lock| lock.brightness) {
+            return brightness;
+        }
+        match self.config.pointer_brightness {
+            Some(brightness) if now >= self.keyboard_idle_at() => brightness,
+            _ => self.active_brightness(),
+        }
+    }
+
+    /// Returns whether the backlight is kept off because it's within the `night-off` hours.
+    pub fn night_off(&self) -> bool {
+        self.config
+            .night_off
+            .as_ref()
+            .is_some_and(|hours| hours.contains_now())
+    }
+
+    /// Returns the brightness the wake-up fade has reached (0 if it isn't running).
+    fn sunrise_brightness(&self) -> u8 {
+        let (Some(progress), Some(sunrise)) = (self.sunrise, &self.config.sunrise) else {
+            return 0;
+        };
+        let full = sunrise
+            .brightness
+            .unwrap_or_else(|| self.active_brightness());
+        (u16::from(full) * u16::from(progress) / 100) as u8
+    }
+
+    /// Returns the brightness the keyboard backlight should have at `now`, given whether the user
+    /// is `active`.
+    fn target(&self, now: Instant, active: bool) -> u8 {
+        // Nobody can see the keyboard with the lid closed, or while the machine goes to sleep.
+        if self.off.is_some() || self.lid_closed == Some(true) || self.suspended {
+            0
+        } else if active && !self.night_off() {
+            self.current_brightness(now).max(self.sunrise_brightness())
+        } else {
+            self.sunrise_brightness()
+        }
+    }
+
+    /// Returns the brightness the keyboard backlight should have right now.
+    pub fn target_brightness(&self) -> u8 {
+        let now = clock::now();
+        self.target(now, self.is_active(now))
+    }
+
+    /// Applies an event to the state.
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Activity { kind, cause, at } => self.activity(kind, cause, at),
+            Event::Presence { at } => {
+                let until = at + Duration::from_secs(self.config.timeout.into());
+                self.present_until = Some(until);
+            }
+            Event::LidClosed | Event::LidOpened => {
+                let closed = matches!(event, Event::LidClosed);
+                if self.lid_closed != Some(closed) {
+                    log::info!("lid {}", if closed { "closed" } else { "opened" });
+                    history::record(if closed { "lid closed" } else { "lid opened" });
+                }
+                self.lid_closed = Some(closed);
+            }
+            Event::Suspend => {
+                log::info!("suspending");
+                history::record("suspending");
+                self.suspended = true;
+            }
+            Event::Resume => {
+                log::info!("resumed");
+                history::record("resumed");
+                self.suspended = false;
+            }
+            Event::ConfigReloaded => {
+                let config = &self.config;
+                log::info!("new configuration: {config:?}");
+                history::record(format!(
+                    "settings changed: brightness {}%, dark brightness {:?}, timeout {}s",
+                    config.brightness, config.dark_brightness, config.timeout
+                ));
+            }
+            Event::StateChanged => {}
+        }
+    }
+
+    /// Records input activity of the given kind that happened at `at`.
+    fn activity(&mut self, kind: ActivityKind, cause: ActivityCause, at: Instant) {
+        // Once someone is up, the regular brightness takes over.
+        self.sunrise = None;
+        if self.active == Some(false) && self.pending_wake.is_none() {
+            self.pending_wake = Some(cause);
+        }
+        if kind != ActivityKind::Pointer {
+            self.last_keyboard_activity = self.last_keyboard_activity.max(at);
+        }
+        if kind != ActivityKind::External {
+            self.last_local_activity = self.last_local_activity.max(at);
+        }
+        if self.config.learn_timeout != LearnTimeout::Off {
+            self.pauses
+                .record(at.saturating_duration_since(self.last_activity));
+            if self.config.learn_timeout == LearnTimeout::Apply {
+                if let Some(timeout) = self.pauses.suggestion() {
+                    if timeout != self.config.timeout {
+                        log::info!("using the learned timeout of {timeout} seconds");
+                        history::record(format!("learned timeout: {timeout}s"));
+                        self.config.timeout = timeout;
+                    }
+                }
+            }
+        }
+        // Events are applied in batches, so the last activity might already be more recent.
+        self.last_activity = self.last_activity.max(at);
+        // Activity right after latching is most likely from running `keylightd off` itself.
+        if let Some((OffLatch::UntilActivity, since)) = self.off {
+            if at.saturating_duration_since(since) > OFF_LATCH_DELAY {
+                log::info!("activity, no longer keeping the backlight off");
+                history::record("released by activity");
+                self.off = None;
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Sends `event` to the main loop.
+    pub fn publish(&self, event: Event) {
+        // The receiver lives as long as `self`, so this can't fail.
+        self.events.send(event).unwrap();
+    }
+
+    /// Modifies the shared state and wakes up the main loop to apply the change.
+    pub fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let result = f(&mut self.state.lock().unwrap());
+        self.publish(Event::StateChanged);
+        result
+    }
+
+    /// Applies the events that were published since the last call.
+    fn handle_pending(&self, state: &mut State) {
+        let receiver = self.receiver.lock().unwrap();
+        while let Ok(event) = receiver.try_recv() {
+            state.handle(event);
+        }
+    }
+
+    /// Waits for the next event until `deadline` (or forever), and applies it along with any
+    /// others that are pending.
+    fn wait(&self, deadline: Option<Instant>) -> MutexGuard<'_, State> {
+        let event = {
+            let receiver = self.receiver.lock().unwrap();
+            match deadline {
+                Some(deadline) => receiver.recv_timeout(clock::until(deadline)).ok(),
+                // The sender lives as long as `self`, so this can't fail.
+                None => Some(receiver.recv().unwrap()),
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        if let Some(event) = event {
+            state.handle(event);
+        }
+        self.handle_pending(&mut state);
+        state
+    }
+
+    /// Returns whether activity reported by `backend` counts.
+    pub fn backend_enabled(&self, backend: Backend) -> bool {
+        !self
+            .state
+            .lock()
+            .unwrap()
+            .disabled_backends
+            .contains(&backend)
+    }
+
+    /// Records input activity of the given kind.
+    pub fn activity(&self, kind: ActivityKind, cause: ActivityCause) {
+        let backend = self.sources.lock().unwrap()[cause.source].backend;
+        if self.backend_enabled(backend) {
+            self.publish(Event::Activity {
+                kind,
+                cause,
+                at: clock::now(),
+            });
+        }
+    }
+
+    /// Records whether `backend`, which tracks idleness on its own, considers the user idle.
+    ///
+    /// Coming back from idle also counts as activity of the backend's `source`, so that it shows up
+    /// as what woke up the backlight.
+    pub fn idle_hint(&self, backend: Backend, source: usize, idle: bool) {
+        let previous = self.state.lock().unwrap().idle_hints.get(&backend).copied();
+        if previous == Some(idle) {
+            return;
+        }
+        log::debug!(
+            "{} reports the user as {}",
+            backend.name(),
+            if idle { "idle" } else { "active" }
+        );
+        input::record_events(self, source, 1, 0);
+        if previous == Some(true) {
+            let cause = ActivityCause {
+                source,
+                event: None,
+            };
+            self.activity(ActivityKind::External, cause);
+        }
+        self.update(|state| state.idle_hints.insert(backend, idle));
+    }
+
+    /// Records that an external presence detector saw someone in front of the machine.
+    pub fn presence(&self) {
+        self.publish(Event::Presence { at: clock::now() });
+    }
+
+    /// Changes the runtime configuration with `f`, which also gets the state to base the change
+    /// on.
+    ///
+    /// `f` runs under the state lock, so that concurrent changes (like two quick `keylightd set
+    /// --brightness +10`) can't overwrite each other. The configuration is only changed if `f`
+    /// succeeds and the result is valid. It takes effect right away, so that anything reading it
+    /// afterwards (like the response to `keylightd set`) sees the change.
+    pub fn reconfigure<R>(
+        &self,
+        f: impl FnOnce(&State, &mut Config) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        let mut state = self.state.lock().unwrap();
+        let mut config = state.config.clone();
+        let result = f(&state, &mut config)?;
+        config.validate()?;
+        state.config = config;
+        drop(state);
+        self.publish(Event::ConfigReloaded);
+        Ok(result)
+    }
+}
+
+pub fn run(ec: EmbeddedController, mut config: Config, config_path: PathBuf) -> anyhow::Result<()> {
+    // Block the termination signals before spawning any threads, so that all threads inherit the
+    // mask and only the signal handler thread receives them.
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGUSR1);
+    signals.thread_block()?;
+
+    ec::set_command_hook(history::record_ec);
+    history::record("daemon started");
+    let ec = EcWorker::new(ec);
+    let upower = connect_upower(&config)?;
+
+    let state_path = recovery::path();
+    if let Some(saved) = recovery::load(&state_path)? {
+        log::warn!("the previous instance did not exit cleanly, restoring {saved:?}");
+        let upower = upower.clone();
+        ec.submit(Priority::Normal, None, move |ec| {
+            restore(ec, upower.as_ref(), saved.backlight, saved.power_level)
+        })
+        .map_err(KeylightdError::ec)?;
+    }
+
+    let quirks = config.quirks().map_err(KeylightdError::config)?;
+    log::info!("hardware profile: {} ({})", quirks.id, quirks.name);
+    if config.power && !quirks.power_led {
+        missing_device(
+            &config,
+            "`power = true` is set, but the power LED can't be controlled on this hardware",
+        )?;
+        config.power = false;
+    }
+    if config.power_brightness.is_some() && !quirks.power_led_level {
+        missing_device(
+            &config,
+            "`power-brightness` is set, but the power LED brightness can't be changed on this \
+             hardware",
+        )?;
+        config.power_brightness = None;
+    }
+    let ec_version = match ec.command(GetVersion) {
+        Ok(version) => {
+            let version = match version.current_image_name() {
+                "RO" => version.version_string_ro,
+                _ => version.version_string_rw,
+            };
+            Some(version.as_str().to_string())
+        }
+        Err(e) => {
+            log::warn!("failed to query the EC firmware version: {e}");
+            None
+        }
+    };
+    if let Some(version) = &ec_version {
+        log::info!("EC firmware: {version}");
+        if let Some(problem) = quirks::firmware_bug(version) {
+            log::warn!(
+                "this EC firmware is known to {problem}; a BIOS update should fix this, see your \
+                 vendor's support site"
+            );
+        }
+    }
+    if !config.upower
+        && !ec
+            .submit(Priority::Normal, None, GetKeyboardBacklight::probe)
+            .map_err(KeylightdError::ec)?
+    {
+        return Err(KeylightdError::device(anyhow!(
+            "the EC doesn't control the keyboard backlight on this machine; if the kernel \
+             provides a keyboard backlight, set `upower = true` to change it through UPower \
+             instead"
+        ))
+        .into());
+    }
+
+    // When dimming the power LED, restore whatever level the user configured in the BIOS on
+    // activity.
+    let power_level = if config.power_brightness.is_some() {
+        match ec.command(FpLedLevelControl::get()) {
+            Ok(resp) => {
+                log::info!("power LED brightness level: {:?}", resp.level);
+                Some(resp.level)
+            }
+            // Only recent BIOS versions implement the command.
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                missing_device(
+                    &config,
+                    &format!("`power-brightness` is set, but {e} (a BIOS update might help)"),
+                )?;
+                config.power_brightness = None;
+                None
+            }
+            Err(e) => return Err(KeylightdError::ec(e).into()),
+        }
+    } else {
+        None
+    };
+    let backlight =
+        match &upower {
+            Some(upower) => upower.get(),
+            None => ec.command(GetKeyboardBacklight).map(|resp| {
+                if resp.enabled != 0 {
+                    resp.percent
+                } else {
+                    0
+                }
+            }),
+        };
+    let saved = SavedState {
+        backlight: backlight.map_err(KeylightdError::ec)?,
+        power_level,
+    };
+    // The daemon works without the file, it just can't undo its changes after a crash.
+    if let Err(e) = recovery::save(&state_path, &saved) {
+        log::warn!("{e:#}; the LEDs can't be restored if the daemon crashes");
+    }
+    let power_level = power_level.unwrap_or(FpLedLevel::HIGH);
+
+    // Reapply the runtime adjustments made while the previous instance was running.
+    let persist_path = persist::path();
+    let base = Settings::of(&config);
+    let mut inhibit = false;
+    let mut off = None;
+    match persist::load(&persist_path) {
+        Ok(Some(persisted)) => {
+            let mut restored = config.clone();
+            persisted.settings.apply(&mut restored);
+            if persisted.base != base {
+                log::info!("the configuration has changed, discarding runtime adjustments");
+            } else if let Err(e) = restored.validate() {
+                log::warn!("ignoring invalid runtime adjustments: {e}");
+            } else {
+                log::debug!("restored runtime settings: {:?}", persisted.settings);
+                config = restored;
+            }
+            inhibit = persisted.inhibit;
+            off = persisted
+                .off
+                .then(|| (OffLatch::UntilEnabled, clock::now()));
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("failed to restore the runtime state: {e:#}"),
+    }
+
+    let backends = config.backends();
+    log::info!(
+        "activity backends: {}",
+        backends
+            .iter()
+            .map(|backend| backend.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let (events, receiver) = mpsc::channel();
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            last_activity: clock::now(),
+            last_keyboard_activity: clock::now(),
+            last_local_activity: clock::now(),
+            config,
+            active: None,
+            dark_theme: false,
+            bright_ambient: false,
+            inhibit,
+            present: None,
+            present_until: None,
+            remote_sessions: None,
+            docked: None,
+            display_on: None,
+            lid_closed: None,
+            on_battery: None,
+            suspended: false,
+            shutting_down: false,
+            disabled_backends: Vec::new(),
+            idle_hints: BTreeMap::new(),
+            wake_lock: None,
+            sunrise: None,
+            pauses: Pauses::new(),
+            off,
+            pending_wake: None,
+            last_wake: None,
+        }),
+        ec,
+        events,
+        receiver: Mutex::new(receiver),
+        config_path,
+        listeners: AtomicUsize::new(0),
+        sources: Mutex::new(Vec::new()),
+        upower,
+        quirks,
+        ec_version,
+        backends,
+    });
+
+    let config = shared.state.lock().unwrap().config.clone();
+    if shared.backends.contains(&Backend::Evdev) && input::spawn_listeners(&shared) == 0 {
+        let devices = input::available_devices();
+        let problem = match diagnose::input_hint() {
+            Some(hint) => format!("no input devices found: {hint}"),
+            None if devices.is_empty() => "no input devices found".to_string(),
+            None => {
+                let fix = if config.keyboard_fallback {
+                    "set `hardware` to the right profile"
+                } else {
+                    "set `hardware` to the right profile, or `keyboard-fallback = true` to use \
+                     all keyboards"
+                };
+                format!(
+                    "none of the input devices belongs to the '{}' hardware profile, so no \
+                     keyboard or touchpad activity will be detected; {fix}. Available devices:\n  \
+                     {}",
+                    shared.quirks.id,
+                    devices.join("\n  ")
+                )
+            }
+        };
+        missing_device(&config, &problem)?;
+    }
+    if shared.backends.contains(&Backend::Evdev) {
+        crate::lid::spawn(shared.clone());
+    }
+    let command = shared.state.lock().unwrap().config.activity_command.clone();
+    if let Some(command) = command {
+        input::spawn_command(&shared, command);
+    }
+    if shared.backends.contains(&Backend::Logind) {
+        #[cfg(feature = "dbus")]
+        crate::logind::spawn(shared.clone());
+        #[cfg(not(feature = "dbus"))]
+        log::warn!(
+            "ignoring the `logind` activity backend, keylightd was built without D-Bus support"
+        );
+    }
+    if shared.backends.contains(&Backend::Wayland) {
+        crate::wayland::spawn(shared.clone());
+    }
+
+    spawn_signal_handler(shared.clone(), signals, power_level);
+    control::spawn_server(shared.clone())?;
+    #[cfg(feature = "socket")]
+    {
+        if let Some(addr) = config.http {
+            crate::http::spawn_server(shared.clone(), addr)
+                .with_context(|| format!("failed to listen on {addr}"))?;
+        }
+        if config.varlink {
+            crate::varlink::spawn_server(shared.clone())
+                .context("failed to create the varlink socket")?;
+        }
+    }
+    #[cfg(not(feature = "socket"))]
+    if config.http.is_some() || config.varlink {
+        log::warn!(
+            "ignoring the `http` and `varlink` settings, keylightd was built without socket \
+             API support"
+        );
+    }
+    #[cfg(feature = "dbus")]
+    {
+        crate::logind::spawn_sleep_watcher(shared.clone(), power_level);
+        if config.dark_brightness.is_some() || config.uses(Fact::DarkTheme) {
+            crate::theme::spawn_watcher(shared.clone());
+        }
+        if shared.upower.is_some() {
+            upower::spawn_watcher(shared.clone());
+        }
+        if config.kbd_backlight_dbus {
+            crate::kbd_backlight::spawn(shared.clone());
+        }
+    }
+    #[cfg(not(feature = "dbus"))]
+    if config.dark_brightness.is_some() || config.uses(Fact::DarkTheme) || config.kbd_backlight_dbus
+    {
+        log::warn!(
+            "ignoring the `dark-brightness` and `kbd-backlight-dbus` settings and `dark_theme` \
+             in rules, keylightd was built without D-Bus support"
+        );
+    }
+    #[cfg(feature = "als")]
+    if let Some(ambient) = config.ambient_light.clone() {
+        if let Err(e) = crate::ambient::spawn(shared.clone(), ambient) {
+            missing_device(&config, &format!("cannot follow the ambient light: {e}"))?;
+        }
+    }
+    #[cfg(not(feature = "als"))]
+    if config.ambient_light.is_some() {
+        log::warn!(
+            "ignoring the `[ambient-light]` settings, keylightd was built without ambient light \
+             sensor support"
+        );
+    }
+    if !config.wake_locks.is_empty() {
+        crate::wakelock::spawn(shared.clone(), config.wake_locks.clone());
+    }
+    if let Some(remote) = config.remote_sessions.clone() {
+        crate::remote::spawn(shared.clone(), remote);
+    }
+    if let Some(lightbar) = config.lightbar.clone() {
+        if let Err(e) = crate::lightbar::spawn(shared.clone(), lightbar) {
+            missing_device(
+                &config,
+                &format!("cannot use the `[lightbar]` settings: {e}"),
+            )?;
+        }
+    }
+    if let Some(sunrise) = config.sunrise.clone() {
+        crate::sunrise::spawn(shared.clone(), sunrise);
+    }
+    if let Some(proximity) = config.proximity.clone() {
+        if let Err(e) = crate::proximity::spawn(shared.clone(), proximity) {
+            missing_device(&config, &format!("cannot use the proximity sensor: {e}"))?;
+        }
+    }
+    if config.uses(Fact::Docked) {
+        crate::dock::spawn(shared.clone());
+    }
+    if config.uses(Fact::OnBattery) {
+        crate::power::spawn(shared.clone());
+    }
+    if config.idle_detection == IdleDetection::Dpms {
+        crate::dpms::spawn(shared.clone());
+    }
+    if leds::enabled(&config) {
+        if quirks.side_leds {
+            leds::spawn(shared.clone());
+        } else {
+            missing_device(
+                &config,
+                "side LEDs are configured, but this hardware has none",
+            )?;
+        }
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = config.mqtt.clone() {
+        crate::mqtt::spawn(shared.clone(), mqtt);
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.is_some() {
+        log::warn!("ignoring the `[mqtt]` settings, keylightd was built without MQTT support");
+    }
+
+    let mut guard = shared.state.lock().unwrap();
+    shared.handle_pending(&mut guard);
+    log::info!("idle timeout: {} seconds", guard.config.timeout);
+    log::info!("brightness level: {}%", guard.config.brightness);
+    background::ready();
+
+    let mut brightness = None;
+    let mut saved_level = None;
+    let mut persisted = None;
+    // When the backlight was last faded out because the user became idle.
+    let mut dimmed_at: Option<Instant> = None;
+    loop {
+        let now = clock::now();
+        let idle_at = guard.idle_at();
+        let active = guard.is_active(now);
+        let target = guard.target(now, active);
+        // This loop runs on every reported input event, so it must not clone the configuration
+        // or do other expensive work unless something actually changes. (It isn't entirely free
+        // of allocations: the unbounded event channel allocates a block of slots every few dozen
+        // events. A bounded channel would avoid that, but could block a `publish` from the
+        // thread that has to drain it.)
+        let level = guard.active_brightness();
+        if guard.config.systemd_backlight && saved_level != Some(level) {
+            match systemd_backlight::save(level) {
+                Ok(0) => log::debug!("no systemd-backlight state for the keyboard backlight yet"),
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to update the systemd-backlight state: {e}"),
+            }
+            saved_level = Some(level);
+        }
+
+        let current = PersistedState {
+            base,
+            settings: Settings::of(&guard.config),
+            inhibit: guard.inhibit,
+            off: matches!(guard.off, Some((OffLatch::UntilEnabled, _))),
+        };
+        if persisted != Some(current) {
+            if let Err(e) = persist::save(&persist_path, &current) {
+                log::warn!("failed to save the runtime state: {e:#}");
+            }
+            persisted = Some(current);
+        }
+
+        if guard.active != Some(active) {
+            log::info!("activity state changed: {:?} -> {active}", guard.active);
+            history::record(if active { "active" } else { "idle" });
+            match guard.pending_wake.take() {
+                Some(cause) if active => {
+                    let wake = match cause.describe(&shared.sources.lock().unwrap()) {
+                        (source, Some(event)) => format!("{source} ({event})"),
+                        (source, None) => source,
+                    };
+                    log::info!("woken up by {wake}");
+                    history::record(format!("woken up by {wake}"));
+                    guard.last_wake = Some((cause, now));
+                }
+                _ => {}
+            }
+            // The hooks only run on actual transitions, not when the daemon starts.
+            if guard.active.is_some() {
+                match (active, &guard.config.on_active, &guard.config.on_idle) {
+                    (true, Some(command), _) => hooks::run("active", command),
+                    (false, _, Some(command)) => hooks::run("idle", command),
+                    _ => {}
+                }
+            }
+            guard.active = Some(active);
+        }
+
+        if guard.shutting_down {
+            // `shut_down` restores the LEDs and exits the process; a fade now would undo that.
+            drop(guard);
+            loop {
+                thread::park();
+            }
+        }
+
+        if brightness != Some(target) {
+            let config = guard.config.clone();
+            drop(guard);
+            // Activity shortly after fading out most likely means that the user noticed the dimming
+            // and wants the light back, so don't make them wait for a fade.
+            let grace = Duration::from_secs(config.grace_period.into());
+            let instant = target != 0 && dimmed_at.is_some_and(|at| clock::since(at) < grace);
+            // The whole fade is a single job, so that no other EC commands are interleaved with it.
+            // A pending fade is replaced by a newer one.
+            let fade_shared = shared.clone();
+            let result = shared.ec.submit(Priority::Normal, Some("fade"), move |ec| {
+                if fade_shared.state.lock().unwrap().shutting_down {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "the daemon is shutting down",
+                    ));
+                }
+                fade(
+                    ec,
+                    fade_shared.upower.as_ref(),
+                    fade_shared.quirks.fade_step,
+                    &config,
+                    power_level,
+                    instant,
+                    || {
+                        let mut state = fade_shared.state.lock().unwrap();
+                        fade_shared.handle_pending(&mut state);
+                        state.target_brightness()
+                    },
+                )
+            });
+            match result {
+                Ok(reached) => {
+                    if brightness != Some(reached) {
+                        history::record(format!("backlight at {reached}%"));
+                    }
+                    if reached == 0 && brightness != Some(0) {
+                        dimmed_at = Some(clock::now());
+                    } else if reached != 0 {
+                        dimmed_at = None;
+                    }
+                    brightness = Some(reached);
+                }
+                // A hung EC shouldn't bring down the daemon. The worker has already waited and
+                // reopened the device, so just try again.
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    log::error!("failed to fade the keyboard backlight: {e}");
+                    history::record(format!("fade failed: {e}"));
+                }
+                // The fade didn't start, because it was superseded or the daemon is shutting down.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    log::debug!("fade skipped: {e}");
+                }
+                Err(e) => return Err(KeylightdError::ec(e).into()),
+            }
+            // Settings or activity might have changed while fading; re-evaluate before waiting.
+            guard = shared.state.lock().unwrap();
+            shared.handle_pending(&mut guard);
+            continue;
+        }
+
+        // While inhibited or present, or following the display, the user can't become idle just by
+        // waiting. Neither can they while a backend considers them active after the timeout.
+        let mut deadline = (now < idle_at
+            && !guard.kept_active()
+            && guard.config.idle_detection == IdleDetection::Input)
+            .then_some(idle_at);
+        let keyboard_idle_at = guard.keyboard_idle_at();
+        if active && guard.config.pointer_brightness.is_some() && now < keyboard_idle_at {
+            deadline = Some(deadline.map_or(keyboard_idle_at, |d| d.min(keyboard_idle_at)));
+        }
+        if let Some(hours) = &guard.config.night_off {
+            let change = now + hours.until_change();
+            deadline = Some(deadline.map_or(change, |d| d.min(change)));
+        }
+        if let Some(location) = &guard.config.location {
+            if guard.config.uses(Fact::AfterSunset) {
+                let change = now + sun::until_change(location);
+                deadline = Some(deadline.map_or(change, |d| d.min(change)));
+            }
+        }
+        let local_idle_at = guard.local_idle_at();
+        if active && guard.remote_sessions.is_some_and(|count| count > 0) && now < local_idle_at {
+            deadline = Some(deadline.map_or(local_idle_at, |d| d.min(local_idle_at)));
+        }
+        drop(guard);
+        guard = shared.wait(deadline);
+    }
+}
+
+/// Reports that a configured device is missing or unusable.
+///
+/// With `fail-fast`, this is a [`KeylightdError::Device`] error that stops the daemon. Otherwise,
+/// the daemon runs without the device.
+fn missing_device(config: &Config, problem: &str) -> anyhow::Result<()> {
+    if config.fail_fast {
+        return Err(KeylightdError::device(anyhow!("{problem}")).into());
+    }
+    log::warn!("{problem}");
+    Ok(())
+}
+
+/// Waits for a termination signal in `signals`, then restores the LEDs and the keyboard backlight
+/// and exits. `SIGUSR1` toggles verbose logging instead.
+///
+/// Without this, stopping the daemon while the user is idle would leave the backlight off and the
+/// power LED disabled or dimmed until it is started again, even across reboots.
+fn spawn_signal_handler(shared: Arc<Shared>, signals: SigSet, power_level: FpLedLevel) {
+    thread::spawn(move || {
+        let signal = loop {
+            match signals.wait() {
+                // `SIGUSR1` toggles verbose logging.
+                Ok(Signal::SIGUSR1) => logging::toggle(),
+                Ok(signal) => break signal,
+                Err(e) => {
+                    log::error!("failed to wait for signals: {e}");
+                    return;
+                }
+            }
+        };
+        shut_down(&shared, power_level, &format!("received {signal}"));
+    });
+}
+
+/// Restores the LEDs and the keyboard backlight, and exits.
+///
+/// `reason` is logged and recorded in the history.
+pub fn shut_down(shared: &Arc<Shared>, power_level: FpLedLevel, reason: &str) -> ! {
+    log::info!("{reason}, restoring LEDs and keyboard backlight");
+    history::record(reason);
+
+    let (brightness, power_level) = {
+        let mut state = shared.state.lock().unwrap();
+        state.shutting_down = true;
+        let power_level = state.config.power_brightness.map(|_| power_level);
+        (state.active_brightness(), power_level)
+    };
+    // Runs before anything else that is queued. It has no key, so that a fade the main loop queues
+    // in the meantime can't replace it; that fade sees `shutting_down` and doesn't start.
+    let restore_shared = shared.clone();
+    let result = shared.ec.submit(Priority::High, None, move |ec| {
+        restore(ec, restore_shared.upower.as_ref(), brightness, power_level)
+    });
+    match result {
+        Ok(()) => {
+            if let Err(e) = recovery::clear(&recovery::path()) {
+                log::warn!("failed to remove the state file: {e}");
+            }
+            pidfile::remove();
+            process::exit(0)
+        }
+        Err(e) => {
+            log::error!("failed to restore LEDs: {e}");
+            pidfile::remove();
+            process::exit(1);
+        }
+    }
+}
+
+/// Fades the keyboard backlight out before the machine goes to sleep, and hands the LEDs back to
+/// the EC.
+///
+/// logind doesn't tell suspend and hibernate apart, and a hibernated machine might not resume
+/// (eg. when booting another system), so the LEDs are handed back for every sleep. The EC then
+/// shows its own sleep indication. The backlight, side LEDs and lightbar are taken over again
+/// after resuming. Returns once all of that is done, so that the caller can let the machine
+/// sleep.
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub fn prepare_for_sleep(shared: &Arc<Shared>, power_level: FpLedLevel) -> io::Result<()> {
+    // Keeps the main loop (and the side LEDs and lightbar) from turning anything back on.
+    shared.publish(Event::Suspend);
+    let config = shared.state.lock().unwrap().config.clone();
+    let restore_level = config.power_brightness.map(|_| power_level);
+    let fade_shared = shared.clone();
+    // Runs before the fade of the main loop, which then has nothing left to do.
+    shared.ec.submit(Priority::High, None, move |ec| {
+        fade(
+            ec,
+            fade_shared.upower.as_ref(),
+            fade_shared.quirks.fade_step,
+            &config,
+            power_level,
+            false,
+            || 0,
+        )?;
+        restore(ec, fade_shared.upower.as_ref(), 0, restore_level)
+    })
+}
+
+/// Hands all LEDs (and the lightbar, if there is one) back to the EC and sets the keyboard
+/// backlight to `brightness`, through `upower` if it is given.
+///
+/// If `power_level` is given, the brightness level of the power LED is also reset to it.
+pub fn restore(
+    ec: &EmbeddedController,
+    upower: Option<&upower::KbdBacklight>,
+    brightness: u8,
+    power_level: Option<FpLedLevel>,
+) -> io::Result<()> {
+    if let Some(level) = power_level {
+        ec.command(FpLedLevelControl::set(level))?;
+    }
+    for led in LedId::all() {
+        match ec.command(LedControl::auto(led)) {
+            // There are no LEDs to hand back on ECs without LED control.
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => break,
+            // The machine doesn't have this LED.
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
+            result => result?,
+        };
+    }
+    if LightbarVersion::probe(ec)?.is_some() {
+        crate::lightbar::show(ec, LightbarPattern::Auto)?;
+    }
+    match upower {
+        Some(upower) => upower.set(brightness),
+        // The EC may not control the backlight on machines that use UPower.
+        None => ec
+            .command(SetKeyboardBacklight {
+                percent: brightness,
+            })
+            .map(drop),
+    }
+}
+
+/// Connects to UPower's keyboard backlight if `upower = true` is set.
+pub fn connect_upower(config: &Config) -> anyhow::Result<Option<upower::KbdBacklight>> {
+    #[cfg(feature = "dbus")]
+    if config.upower {
+        let upower = upower::KbdBacklight::connect()
+            .context("failed to access UPower's keyboard backlight")
+            .map_err(KeylightdError::device)?;
+        log::info!("changing the keyboard backlight through UPower");
+        Ok(Some(upower))
+    } else {
+        if upower::detect() {
+            log::warn!(
+                "UPower also manages the keyboard backlight, set `upower = true` to avoid \
+                 conflicts with it"
+            );
+        }
+        Ok(None)
+    }
+    #[cfg(not(feature = "dbus"))]
+    {
+        if config.upower {
+            log::warn!("ignoring `upower = true`, keylightd was built without D-Bus support");
+        }
+        Ok(None)
+    }
+}
+
+/// Longest fade accepted by [`preview`].
+pub const MAX_PREVIEW_FADE_MS: u32 = 1500;
+
+/// How long [`preview`] holds the previewed brightness.
+const PREVIEW_HOLD: Duration = Duration::from_secs(1);
+
+/// Returns how long the EC worker should give a [`preview`] with fades of `fade` to finish.
+///
+/// The whole preview runs as a single job, so on top of the usual [`worker::TIMEOUT`] for the EC
+/// commands, it gets the time the preview spends waiting on purpose.
+pub fn preview_timeout(fade: Duration) -> Duration {
+    worker::TIMEOUT + 2 * fade + PREVIEW_HOLD
+}
+
+/// Fades the keyboard backlight to `brightness` and back to where it was, taking `fade` for each
+/// direction.
+pub fn preview(ec: &EmbeddedController, brightness: u8, fade: Duration) -> io::Result<()> {
+    let resp = ec.command(GetKeyboardBacklight)?;
+    let original = if resp.enabled != 0 { resp.percent } else { 0 };
+    ramp(ec, original, brightness, fade)?;
+    clock::sleep(PREVIEW_HOLD);
+    ramp(ec, brightness, original, fade)
+}
+
+/// Fades the keyboard backlight from `from` to `to` in 1% steps, spread evenly over `duration`.
+fn ramp(ec: &EmbeddedController, from: u8, to: u8, duration: Duration) -> io::Result<()> {
+    let steps = from.abs_diff(to);
+    let delay = duration / u32::from(steps.max(1));
+    let mut cur = from;
+    while cur != to {
+        if cur > to {
+            cur -= 1;
+        } else {
+            cur += 1;
+        }
+        ec.command(SetKeyboardBacklight { percent: cur })?;
+        clock::sleep(delay);
+    }
+    Ok(())
+}
+
+/// Fades the keyboard backlight to the brightness returned by `target`, and returns the brightness
+/// that was reached.
+///
+/// `target` is re-evaluated on every step, so that a fade can change direction immediately when
+/// the user becomes active or idle, instead of first running to completion. If `upower` is given,
+/// the backlight is changed through it, and only the LEDs are controlled via the EC. If `instant` is
+/// set, the backlight jumps to the target brightness without fading, and otherwise, it moves in 1%
+/// steps that are `step` apart.
+fn fade(
+    ec: &EmbeddedController,
+    upower: Option<&upower::KbdBacklight>,
+    step: Duration,
+    config: &Config,
+    power_level: FpLedLevel,
+    instant: bool,
+    target: impl Fn() -> u8,
+) -> io::Result<u8> {
+    let mut backlight = KeyboardBacklight {
+        ec,
+        upower,
+        config,
+        power_level,
+    };
+    fade::fade_to(&mut backlight, instant, target, || clock::sleep(step))
+}
+
+/// The keyboard backlight, along with the power LED that follows it.
+struct KeyboardBacklight<'a> {
+    ec: &'a EmbeddedController,
+    upower: Option<&'a upower::KbdBacklight>,
+    config: &'a Config,
+    power_level: FpLedLevel,
+}
+
+impl Backlight for KeyboardBacklight<'_> {
+    fn get(&mut self) -> io::Result<u8> {
+        match self.upower {
+            Some(upower) => upower.get(),
+            None => {
+                let resp = self.ec.command(GetKeyboardBacklight)?;
+                Ok(if resp.enabled != 0 { resp.percent } else { 0 })
+            }
+        }
+    }
+
+    fn set(&mut self, from: u8, to: u8) -> io::Result<()> {
+        // The power LED only has to change when the backlight switches between off and on.
+        if (from == 0) != (to == 0) {
+            set_power_led(self.ec, to != 0, self.config, self.power_level)?;
+        }
+
+        match self.upower {
+            Some(upower) => upower.set(to),
+            None => {
+                self.ec.command(SetKeyboardBacklight { percent: to })?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn set_power_led(
+    ec: &EmbeddedController,
+    on: bool,
+    config: &Config,
+    power_level: FpLedLevel,
+) -> io::Result<()> {
+    if let Some(idle_level) = config.power_brightness {
+        // Newer BIOS versions allow selecting one of 3 brightness levels for the power LED.
+        // We use the configured one while idle, and restore the original one otherwise.
+        let level = if on {
+            power_level
+        } else {
+            fp_led_level(idle_level)
+        };
+        ec.command(FpLedLevelControl::set(level))?;
+    } else if config.power {
+        // The power LED cannot be faded from software, so instead, we treat 0 as off and set it
+        // back to auto for any non-zero value.
+        ec.command(if on {
+            LedControl::auto(LedId::POWER)
+        } else {
+            LedControl::off(LedId::POWER)
+        })?;
+    }
+    Ok(())
+}
+
+fn fp_led_level(level: PowerLedLevel) -> FpLedLevel {
+    match level {
+        PowerLedLevel::High => FpLedLevel::HIGH,
+        PowerLedLevel::Medium => FpLedLevel::MEDIUM,
+        PowerLedLevel::Low => FpLedLevel::LOW,
+    }
+}
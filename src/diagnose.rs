@@ -0,0 +1,352 @@
+//! Diagnostics for common setup problems.
+//!
+//! Most of these are about the kernel drivers and device permissions: the raw `ENOENT` or
+//! `EACCES` from opening `/dev/cros_ec` or an input device doesn't tell the user what to do about
+//! it. The checks are run by `keylightd doctor`, and are also used to explain errors whenever
+//! opening a device fails.
+//!
+//! `keylightd doctor` additionally runs a hardware self-test that talks to the EC and the input
+//! devices, so that bug reports come with the relevant information attached.
+
+use std::{
+    fs, io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use nix::unistd::{access, AccessFlags, Gid, Group, Uid, User};
+
+use crate::{
+    command::{GetKeyboardBacklight, GetVersion, Hello, LedColor, LedId, SetKeyboardBacklight},
+    ec::{self, EmbeddedController},
+    quirks::Quirks,
+};
+
+/// Group that the udev rules in `etc/70-keylightd.rules` grant EC access to.
+const EC_GROUP: &str = "keylightd";
+
+/// Locations the udev rules may be installed to.
+const UDEV_RULES: &[&str] = &[
+    "/etc/udev/rules.d/70-keylightd.rules",
+    "/usr/lib/udev/rules.d/70-keylightd.rules",
+    "/lib/udev/rules.d/70-keylightd.rules",
+];
+
+/// The result of a single diagnostic check.
+pub struct Check {
+    /// What was checked.
+    pub name: &'static str,
+    /// A description of the problem and how to fix it, or `None` if the check passed.
+    pub problem: Option<String>,
+    /// Additional information gathered by the check.
+    pub details: Vec<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, problem: Option<String>) -> Self {
+        Self {
+            name,
+            problem,
+            details: Vec::new(),
+        }
+    }
+}
+
+/// Runs all diagnostic checks, including the hardware self-test.
+///
+/// If `ec_device` is given, the EC at that path is tested instead of the detected one.
+pub fn checks(ec_device: Option<&Path>, quirks: &Quirks) -> Vec<Check> {
+    let mut checks = Vec::new();
+    if ec_device.is_none() {
+        checks.push(Check::new("embedded controller device", ec_hint()));
+    }
+    checks.push(Check::new("input device access", input_hint()));
+    checks.push(input_devices(quirks));
+
+    let ec = match ec_device {
+        Some(path) => EmbeddedController::open_path(path),
+        None => EmbeddedController::open(),
+    };
+    match ec {
+        Ok(ec) => {
+            checks.push(hello(&ec, quirks));
+            checks.push(firmware_version(&ec));
+            checks.push(leds(&ec));
+            checks.push(backlight_blink(&ec));
+        }
+        Err(e) => checks.push(Check::new(
+            "embedded controller communication",
+            Some(format!("failed to open the EC: {e}")),
+        )),
+    }
+    checks
+}
+
+fn hello(ec: &EmbeddedController, quirks: &Quirks) -> Check {
+    let in_data = 0xa0b0c0d0;
+    let expected = in_data + 0x01020304;
+    let problem = match ec.command(Hello { in_data }) {
+        Ok(resp) if resp.out_data == expected => None,
+        Ok(resp) => Some(format!(
+            "invalid response to hello command (received {:#010x}, expected {expected:#010x})",
+            resp.out_data
+        )),
+        Err(e) => Some(format!("hello command failed: {e}")),
+    };
+    Check {
+        details: vec![
+            format!("device: {}", ec.path().display()),
+            format!("hardware profile: {} ({})", quirks.id, quirks.name),
+        ],
+        ..Check::new("embedded controller handshake", problem)
+    }
+}
+
+fn firmware_version(ec: &EmbeddedController) -> Check {
+    match ec.command(GetVersion) {
+        Ok(version) => Check {
+            details: vec![
+                format!("RO version: {}", version.version_string_ro),
+                format!("RW version: {}", version.version_string_rw),
+                format!("current image: {}", version.current_image_name()),
+                format!("protocol: v{}", ec.protocol().version),
+            ],
+            ..Check::new("firmware version", None)
+        },
+        Err(e) => Check::new(
+            "firmware version",
+            Some(format!("failed to query the firmware version: {e}")),
+        ),
+    }
+}
+
+/// Lists the colors supported by every LED.
+fn leds(ec: &EmbeddedController) -> Check {
+    let mut details = Vec::new();
+    for led in LedId::all() {
+        let capabilities = match led.capabilities(ec) {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                return Check::new(
+                    "LEDs",
+                    Some(format!("failed to query the {} LED: {e}", led.name())),
+                )
+            }
+        };
+        let colors = LedColor::ALL
+            .into_iter()
+            .filter(|&color| capabilities.get(color) != 0)
+            .map(LedColor::name)
+            .collect::<Vec<_>>();
+        details.push(if colors.is_empty() {
+            format!("{}: not controllable", led.name())
+        } else {
+            format!("{}: {}", led.name(), colors.join(", "))
+        });
+    }
+    Check {
+        details,
+        ..Check::new("LEDs", None)
+    }
+}
+
+/// Lists all input devices, marking the ones the daemon listens to.
+fn input_devices(quirks: &Quirks) -> Check {
+    let mut details = Vec::new();
+    let mut found = 0;
+    let mut devices = evdev::enumerate().collect::<Vec<_>>();
+    devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, device) in devices {
+        let name = device.name().unwrap_or("<unknown>");
+        if quirks.is_input_device(name) {
+            found += 1;
+            details.push(format!("{}: {name} (used)", path.display()));
+        } else {
+            details.push(format!("{}: {name}", path.display()));
+        }
+    }
+
+    let problem = (found == 0).then(|| {
+        let mut expected = quirks.input_devices.join(", ");
+        if quirks.any_touchpad {
+            expected.push_str(", or any touchpad");
+        }
+        format!("none of the builtin input devices were found (expected: {expected})")
+    });
+    Check {
+        details,
+        ..Check::new("input devices", problem)
+    }
+}
+
+/// Briefly toggles the keyboard backlight and checks that the EC applied the change.
+fn backlight_blink(ec: &EmbeddedController) -> Check {
+    let name = "keyboard backlight";
+    let result = (|| -> io::Result<Option<String>> {
+        if !GetKeyboardBacklight::probe(ec)? {
+            return Ok(Some(
+                "the EC doesn't control the keyboard backlight on this machine (if the kernel \
+                 provides one, try `upower = true`)"
+                    .into(),
+            ));
+        }
+        let resp = ec.command(GetKeyboardBacklight)?;
+        let original = if resp.enabled != 0 { resp.percent } else { 0 };
+        let blink = if original == 0 { 100 } else { 0 };
+
+        ec.command(SetKeyboardBacklight { percent: blink })?;
+        thread::sleep(Duration::from_millis(500));
+        let resp = ec.command(GetKeyboardBacklight)?;
+        ec.command(SetKeyboardBacklight { percent: original })?;
+
+        let readback = if resp.enabled != 0 { resp.percent } else { 0 };
+        Ok((readback != blink)
+            .then(|| format!("set the brightness to {blink}%, but the EC reports {readback}%")))
+    })();
+    match result {
+        Ok(problem) => Check::new(name, problem),
+        Err(e) => Check::new(name, Some(format!("EC command failed: {e}"))),
+    }
+}
+
+/// Returns a hint explaining why `/dev/cros_ec` can't be opened, or `None` if it can.
+pub fn ec_hint() -> Option<String> {
+    let path = Path::new(ec::DEVICE_PATH);
+    match check_access(path, AccessFlags::R_OK | AccessFlags::W_OK) {
+        Access::Ok => None,
+        Access::Missing => Some(if !Path::new("/sys/class/chromeos").exists() {
+            "the kernel has not detected a ChromeOS Embedded Controller; on Framework laptops, \
+             this requires Linux 6.1 or newer with the `cros_ec_lpcs` driver (try \
+             `sudo modprobe cros_ec_lpcs`)"
+                .into()
+        } else if !Path::new("/sys/class/misc/cros_ec").exists() {
+            "the `cros_ec_chardev` kernel module is not loaded (try `sudo modprobe cros_ec_chardev`)"
+                .into()
+        } else {
+            format!(
+                "the kernel driver is loaded, but udev did not create {}",
+                path.display()
+            )
+        }),
+        Access::RootOnly if !Uid::effective().is_root() => {
+            Some(if Group::from_name(EC_GROUP).ok().flatten().is_none() {
+                format!(
+                    "{} is only accessible to root; create the `{EC_GROUP}` group with \
+                 `sudo groupadd --system {EC_GROUP}` and install `etc/70-keylightd.rules` to \
+                 /etc/udev/rules.d, or run keylightd as root",
+                    path.display()
+                )
+            } else if !UDEV_RULES.iter().any(|rule| Path::new(rule).exists()) {
+                format!(
+                    "{} is only accessible to root; install `etc/70-keylightd.rules` to \
+                 /etc/udev/rules.d, or run keylightd as root",
+                    path.display()
+                )
+            } else {
+                format!(
+                "{} is only accessible to root, even though the keylightd udev rules are \
+                 installed; apply them with `sudo udevadm control --reload && sudo udevadm trigger`",
+                path.display()
+            )
+            })
+        }
+        access => access.hint(path),
+    }
+}
+
+/// Returns a hint about input devices that can not be opened, if there are any.
+///
+/// `evdev::enumerate` silently skips devices it can't open, so without this, running as a user
+/// without access to input devices would just result in keylightd never detecting any activity.
+pub fn input_hint() -> Option<String> {
+    let entries = match fs::read_dir("/dev/input") {
+        Ok(entries) => entries,
+        Err(e) => return Some(format!("cannot list /dev/input: {e}")),
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .find_map(|entry| {
+            let path = entry.path();
+            check_access(&path, AccessFlags::R_OK).hint(&path)
+        })
+}
+
+enum Access {
+    Ok,
+    Missing,
+    /// The device is accessible to `group`. `listed` indicates whether the user is listed as a
+    /// member in the group database.
+    NeedsGroup {
+        group: String,
+        listed: bool,
+    },
+    RootOnly,
+    Other(io::Error),
+}
+
+impl Access {
+    fn hint(self, path: &Path) -> Option<String> {
+        let path = path.display();
+        match self {
+            Access::Ok => None,
+            Access::Missing => Some(format!("{path} does not exist")),
+            // In the group according to the group database, but not in this process.
+            Access::NeedsGroup {
+                group,
+                listed: true,
+            } => Some(format!(
+                "{path} is accessible to the `{group}` group, but this process is not a member \
+                 of it yet (log out and back in to apply group changes)"
+            )),
+            Access::NeedsGroup {
+                group,
+                listed: false,
+            } => Some(format!(
+                "{path} is only accessible to the `{group}` group; add your user to it with \
+                 `sudo usermod -aG {group} $USER` and log in again"
+            )),
+            Access::RootOnly if Uid::effective().is_root() => Some(format!(
+                "{path} is not accessible even though keylightd is running as root"
+            )),
+            Access::RootOnly => Some(format!(
+                "{path} is only accessible to root; run keylightd as root"
+            )),
+            Access::Other(e) => Some(format!("cannot access {path}: {e}")),
+        }
+    }
+}
+
+fn check_access(path: &Path, mode: AccessFlags) -> Access {
+    if access(path, mode).is_ok() {
+        return Access::Ok;
+    }
+
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Access::Missing,
+        Err(e) => return Access::Other(e),
+    };
+
+    let perms = meta.permissions().mode();
+    let group_bits = if mode.contains(AccessFlags::W_OK) {
+        0o060
+    } else {
+        0o040
+    };
+    let gid = Gid::from_raw(meta.gid());
+    if perms & group_bits != group_bits || gid == Gid::from_raw(0) {
+        return Access::RootOnly;
+    }
+
+    let group = Group::from_gid(gid).ok().flatten();
+    let user = User::from_uid(Uid::effective()).ok().flatten();
+    let listed =
+        matches!((&group, &user), (Some(group), Some(user)) if group.mem.contains(&user.name));
+    Access::NeedsGroup {
+        group: group.map_or_else(|| gid.to_string(), |g| g.name),
+        listed,
+    }
+}
@@ -0,0 +1,38 @@
+//! Where the daemon keeps its sockets, PID file and state.
+//!
+//! A daemon running as root uses `/run` and `/var/lib/keylightd`, while one running as a regular
+//! user uses `$XDG_RUNTIME_DIR` and `$XDG_STATE_HOME/keylightd`. `KEYLIGHTD_RUNTIME_DIR` and
+//! `KEYLIGHTD_STATE_DIR` override both, which lets tests run a daemon (and talk to it) without
+//! touching the files of the one that is installed.
+
+use std::{env, path::PathBuf};
+
+use nix::unistd::Uid;
+
+/// Returns the directory for the sockets, the PID file and the recovery state, or `None` if the
+/// system-wide paths in `/run` are used.
+pub fn runtime_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("KEYLIGHTD_RUNTIME_DIR") {
+        return Some(dir.into());
+    }
+    if Uid::effective().is_root() {
+        return None;
+    }
+    env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+}
+
+/// Returns the directory for state that survives a reboot, or `None` if the system-wide
+/// `/var/lib/keylightd` is used.
+pub fn state_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("KEYLIGHTD_STATE_DIR") {
+        return Some(dir.into());
+    }
+    if Uid::effective().is_root() {
+        return None;
+    }
+    let state_home = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".local/state")))?;
+    Some(state_home.join("keylightd"))
+}
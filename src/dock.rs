@@ -0,0 +1,50 @@
+//! Detection of external displays, to tell when the laptop is docked.
+//!
+//! Many people park the laptop to the side (or close it) while working at a desk with an external
+//! monitor, and don't want the keyboard backlight at all then. The laptop counts as docked while
+//! any display connector other than the builtin panel reports a connected display in
+//! `/sys/class/drm`, which works the same for HDMI, DisplayPort and USB-C docks.
+
+use std::{io, sync::Arc, thread, time::Duration};
+
+use crate::{daemon::Shared, drm, history};
+
+/// How often the connectors are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns the names of the external connectors that have a display connected, like
+/// `card1-HDMI-A-1`.
+fn connected_displays() -> io::Result<Vec<String>> {
+    let mut connected = Vec::new();
+    for connector in drm::connectors()? {
+        if !connector.internal && connector.connected()? {
+            connected.push(connector.name);
+        }
+    }
+    Ok(connected)
+}
+
+/// Spawns a thread that keeps track of whether an external display is connected.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || loop {
+        match connected_displays() {
+            Ok(displays) => {
+                let docked = !displays.is_empty();
+                if shared.state.lock().unwrap().docked != Some(docked) {
+                    shared.update(|state| state.docked = Some(docked));
+                    if docked {
+                        log::info!("docked (external displays: {})", displays.join(", "));
+                    } else {
+                        log::info!("undocked");
+                    }
+                    history::record(if docked { "docked" } else { "undocked" });
+                }
+            }
+            Err(e) => {
+                log::error!("cannot detect external displays: {e}");
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
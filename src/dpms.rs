@@ -0,0 +1,58 @@
+//! Following the power state of the display instead of detecting idleness from input.
+//!
+//! With `idle-detection = "dpms"`, the backlight is on exactly while the primary display is: the
+//! desktop already decides when to blank the screen (and knows about video players, presentations
+//! and the like), so the keyboard simply follows it. The primary display is the builtin panel if
+//! it is connected, and the first connected display otherwise.
+
+use std::{io, sync::Arc, thread, time::Duration};
+
+use crate::{daemon::Shared, drm, history};
+
+/// How often the display state is checked. Short, since the backlight should come on right along
+/// with the screen.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Returns the name and power state of the primary display, if any display is connected.
+fn primary_display() -> io::Result<Option<(String, bool)>> {
+    let mut connected = Vec::new();
+    for connector in drm::connectors()? {
+        if connector.connected()? {
+            connected.push(connector);
+        }
+    }
+    let primary = match connected.iter().position(|connector| connector.internal) {
+        Some(index) => connected.swap_remove(index),
+        None if !connected.is_empty() => connected.remove(0),
+        None => return Ok(None),
+    };
+    let on = primary.on()?;
+    Ok(Some((primary.name, on)))
+}
+
+/// Spawns a thread that keeps track of whether the primary display is on.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || loop {
+        match primary_display() {
+            Ok(display) => {
+                // Without any display, there's nothing to see the keyboard by.
+                let on = display.as_ref().is_some_and(|&(_, on)| on);
+                if shared.state.lock().unwrap().display_on != Some(on) {
+                    shared.update(|state| state.display_on = Some(on));
+                    match &display {
+                        Some((name, _)) => {
+                            log::info!("display {name} is {}", if on { "on" } else { "off" })
+                        }
+                        None => log::info!("no display connected"),
+                    }
+                    history::record(if on { "display on" } else { "display off" });
+                }
+            }
+            Err(e) => {
+                log::error!("cannot read the display state: {e}");
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
@@ -0,0 +1,66 @@
+//! Access to the display connectors through the kernel's DRM subsystem.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the kernel lists the DRM devices and their connectors.
+const DRM_PATH: &str = "/sys/class/drm";
+
+/// Types of the connectors builtin panels are attached to.
+const INTERNAL_CONNECTORS: &[&str] = &["eDP", "LVDS", "DSI"];
+
+/// A connector that a display can be attached to.
+pub struct Connector {
+    /// Name of the connector, like `card1-HDMI-A-1`.
+    pub name: String,
+    /// Whether the builtin panel is attached to the connector.
+    pub internal: bool,
+    path: PathBuf,
+}
+
+impl Connector {
+    /// Returns whether a display is connected.
+    pub fn connected(&self) -> io::Result<bool> {
+        Ok(read(&self.path.join("status"))? == "connected")
+    }
+
+    /// Returns whether the connected display is on, according to its DPMS state.
+    pub fn on(&self) -> io::Result<bool> {
+        // The other states (`Standby`, `Suspend`, `Off`) all mean that the screen is dark.
+        Ok(read(&self.path.join("dpms"))? == "On")
+    }
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// Returns all display connectors, in a stable order.
+pub fn connectors() -> io::Result<Vec<Connector>> {
+    let mut connectors = Vec::new();
+    for entry in fs::read_dir(DRM_PATH)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Connectors are named after their card, like `card0-eDP-1`. The other entries are the
+        // cards themselves, render nodes, and `version`.
+        let Some((card, connector)) = name.split_once('-') else {
+            continue;
+        };
+        // Writeback connectors don't have a status, and never have a display attached.
+        if !card.starts_with("card") || !entry.path().join("status").exists() {
+            continue;
+        }
+        let internal = INTERNAL_CONNECTORS
+            .iter()
+            .any(|internal| connector.starts_with(&format!("{internal}-")));
+        connectors.push(Connector {
+            name,
+            internal,
+            path: entry.path(),
+        });
+    }
+    connectors.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(connectors)
+}
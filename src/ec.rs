@@ -1,48 +1,226 @@
 use std::{
-    fs::File,
-    io,
-    mem::{size_of, size_of_val, MaybeUninit},
-    os::fd::AsRawFd,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{fs::FileTypeExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
 };
 
-use nix::{errno::Errno, libc::ioctl, request_code_readwrite};
+use bytemuck::{Pod, Zeroable};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
 
-use crate::command::{self, Hello};
+use crate::{
+    command::{
+        result_name, Cmd, Command, GetCmdVersions, GetCmdVersionsResponse, GetKeyboardBacklight,
+        GetProtocolInfoResponse, Hello, HelloResponse,
+    },
+    wire::{Decode, Encode, Reader},
+};
 
+/// How commands are sent to the EC.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum IoctlVersion {
-    V1,
-    V2,
+enum Interface {
+    /// Version 1 of the `cros_ec_chardev` ioctl interface.
+    IoctlV1,
+    /// Version 2 of the `cros_ec_chardev` ioctl interface.
+    IoctlV2,
+    /// The framing spoken by the EC emulator (`keylightd emulate-ec`).
+    Socket,
+}
+
+/// Path of the character device exposed by the `cros_ec_chardev` kernel driver.
+pub const DEVICE_PATH: &str = "/dev/cros_ec";
+
+/// Prefix of the device nodes of all ChromeOS ECs, like `/dev/cros_fp` for the fingerprint MCU.
+const DEVICE_PREFIX: &str = "cros_";
+
+/// Lock file that serializes EC access between keylightd and other tools.
+///
+/// Every command is sent while holding an exclusive `flock(2)` on this file, so wrapping manual
+/// `ectool` invocations in `flock(1)` keeps them from interleaving with keylightd's ioctls.
+pub const LOCK_PATH: &str = "/run/lock/cros_ec_lock";
+
+/// How long to wait for another process to release [`LOCK_PATH`].
+///
+/// This has to be well below the worker timeout, so that a busy EC is reported as such instead of
+/// being treated as a hang.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to check whether [`LOCK_PATH`] was released.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A function that is called with every command sent to an EC and its outcome.
+pub type CommandHook = fn(cmd: u16, version: u32, result: &io::Result<(u32, usize)>);
+
+static COMMAND_HOOK: OnceLock<CommandHook> = OnceLock::new();
+
+/// Installs a [`CommandHook`] for all ECs, eg. to keep a history of EC traffic.
+///
+/// Only the first hook that is installed takes effect.
+pub fn set_command_hook(hook: CommandHook) {
+    let _ = COMMAND_HOOK.set(hook);
+}
+
+/// Size of the stack buffer that typed command responses are received into.
+///
+/// All responses of the commands in [`crate::command`] are much smaller than this.
+pub(crate) const RESPONSE_BUF_SIZE: usize = 256;
+
+/// Maximum request and response payload size of protocol version 2 (`EC_PROTO2_MAX_PARAM_SIZE`).
+const PROTO2_MAX_PARAM_SIZE: usize = 252;
+
+/// Size of the protocol version 3 packet headers, which count against the maximum packet sizes.
+const PROTO3_HEADER_SIZE: usize = 8;
+
+/// The host command protocol spoken by the EC.
+#[derive(Debug, Clone, Copy)]
+pub struct ProtocolInfo {
+    /// Highest supported protocol version.
+    pub version: u32,
+    /// Maximum size of a request payload, in bytes.
+    pub max_request: usize,
+    /// Maximum size of a response payload, in bytes.
+    pub max_response: usize,
+}
+
+impl ProtocolInfo {
+    const V2: Self = Self {
+        version: 2,
+        max_request: PROTO2_MAX_PARAM_SIZE,
+        max_response: PROTO2_MAX_PARAM_SIZE,
+    };
 }
 
 /// A handle to the system's ChromiumOS Embedded Controller.
 ///
-/// This uses the ioctl interface of `/dev/cros_ec` to issue commands.
+/// This uses the ioctl interface of `/dev/cros_ec` (or another `/dev/cros_*` device) to issue
+/// commands. For testing, `path` can also refer to the Unix socket of an emulated EC.
 pub struct EmbeddedController {
+    /// The device node, or the connection to the emulator.
     fd: File,
-    version: IoctlVersion,
+    path: PathBuf,
+    /// The opened [`LOCK_PATH`], if it could be opened.
+    lock: Option<File>,
+    /// Buffers that are reused for every command, so that talking to the EC doesn't allocate
+    /// once they have grown to the largest command size.
+    request_buf: Cell<Vec<u8>>,
+    packet_buf: Cell<Vec<u8>>,
+    interface: Interface,
+    protocol: ProtocolInfo,
+    /// The results of [`EmbeddedController::command_versions`], by command ID.
+    versions: RefCell<HashMap<u16, Option<u32>>>,
 }
 
 impl EmbeddedController {
+    /// Opens the EC that controls the keyboard backlight.
+    ///
+    /// Some machines expose several ECs (eg. `/dev/cros_fp` for the fingerprint MCU, or a
+    /// separate PD controller), so this tries all `/dev/cros_*` devices, starting with
+    /// [`DEVICE_PATH`], and picks the first one that answers keyboard backlight commands. If none
+    /// does, the first one that responds at all is used.
     pub fn open() -> io::Result<Self> {
-        let mut this = Self {
-            fd: File::options()
-                .read(true)
-                .write(true)
-                .open("/dev/cros_ec")?,
-            version: IoctlVersion::V1,
-        };
+        let mut paths = vec![PathBuf::from(DEVICE_PATH)];
+        if let Ok(entries) = fs::read_dir("/dev") {
+            let mut others = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(DEVICE_PREFIX)
+                })
+                .map(|entry| entry.path())
+                .filter(|path| *path != Path::new(DEVICE_PATH))
+                .collect::<Vec<_>>();
+            others.sort();
+            paths.extend(others);
+        }
+
+        let mut fallback = None;
+        let mut first_error = None;
+        for path in paths {
+            match Self::open_path(&path) {
+                Ok(ec) if matches!(GetKeyboardBacklight::probe(&ec), Ok(true)) => return Ok(ec),
+                Ok(ec) => {
+                    log::debug!("{} does not control the keyboard backlight", path.display());
+                    fallback.get_or_insert(ec);
+                }
+                Err(e) => {
+                    log::debug!("failed to open {}: {e}", path.display());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
 
-        // The framework EC uses ioctl interface version 2, but this mirrors the logic in ectool
-        // just to make sure it doesn't do something nonsensical on non-Framework machines.
-        this.version = match this.cmd_v1(Hello {
-            in_data: 0xa0b0c0d0,
-        }) {
-            Err(Errno::ENOTTY) => IoctlVersion::V2,
-            _ => IoctlVersion::V1,
+        match fallback {
+            Some(ec) => {
+                log::warn!(
+                    "no EC answers keyboard backlight commands, using {}",
+                    ec.path.display()
+                );
+                Ok(ec)
+            }
+            None => Err(first_error.unwrap()),
+        }
+    }
+
+    /// Opens the EC device (or emulator socket) at `path`.
+    pub fn open_path(path: &Path) -> io::Result<Self> {
+        let mut this = if fs::metadata(path)?.file_type().is_socket() {
+            Self {
+                fd: OwnedFd::from(UnixStream::connect(path)?).into(),
+                path: path.to_owned(),
+                // The emulator serializes requests itself.
+                lock: None,
+                request_buf: Cell::default(),
+                packet_buf: Cell::default(),
+                interface: Interface::Socket,
+                protocol: ProtocolInfo::V2,
+                versions: RefCell::default(),
+            }
+        } else {
+            let mut this = Self {
+                fd: File::options().read(true).write(true).open(path)?,
+                path: path.to_owned(),
+                lock: open_lock(),
+                request_buf: Cell::default(),
+                packet_buf: Cell::default(),
+                interface: Interface::IoctlV1,
+                protocol: ProtocolInfo::V2,
+                versions: RefCell::default(),
+            };
+
+            // The framework EC uses ioctl interface version 2, but this mirrors the logic in
+            // ectool just to make sure it doesn't do something nonsensical on non-Framework
+            // machines.
+            let mut hello = Vec::new();
+            Hello {
+                in_data: 0xa0b0c0d0,
+            }
+            .encode(&mut hello);
+            let mut resp = [0; HelloResponse::SIZE];
+            let probe = this.locked(|| Ok(this.raw_v1(Cmd::Hello as u16, 0, &hello, &mut resp)));
+            this.interface = match probe {
+                Ok(Err(Errno::ENOTTY)) => Interface::IoctlV2,
+                _ => Interface::IoctlV1,
+            };
+            this
         };
 
-        log::debug!("ioctl version {:?}", this.version);
+        log::debug!("interface: {:?}", this.interface);
 
         // Test communication by issuing a `Hello` command and reading back the result.
         let magic = 0xaa55dead;
@@ -58,65 +236,503 @@ impl EmbeddedController {
             ));
         }
 
-        log::info!("connected to embedded controller");
+        this.protocol = this.probe_protocol();
+        log::debug!("EC protocol: {:?}", this.protocol);
+
+        log::info!("connected to embedded controller at {}", path.display());
 
         Ok(this)
     }
 
-    pub fn command<C: command::Command>(&self, cmd: C) -> io::Result<C::Response> {
-        match self.version {
-            IoctlVersion::V1 => self.cmd_v1(cmd),
-            IoctlVersion::V2 => self.cmd_v2(cmd),
+    /// Returns the path of the device node.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a handle to the lock on [`LOCK_PATH`] that is held while a command runs.
+    ///
+    /// Returns `None` if EC access isn't locked (see [`open_lock`]).
+    pub fn lock_handle(&self) -> Option<LockHandle> {
+        let lock = self.lock.as_ref()?.try_clone().ok()?;
+        Some(LockHandle(lock))
+    }
+
+    /// Returns the protocol version and payload size limits of the EC.
+    pub fn protocol(&self) -> ProtocolInfo {
+        self.protocol
+    }
+
+    /// Determines the protocol version and payload size limits via `EC_CMD_GET_PROTOCOL_INFO`.
+    ///
+    /// ECs that don't support the command speak protocol version 2.
+    fn probe_protocol(&self) -> ProtocolInfo {
+        let mut buf = [0; GetProtocolInfoResponse::SIZE];
+        let result = self.raw_command(Cmd::GetProtocolInfo as u16, 0, &[], &mut buf);
+        let resp: GetProtocolInfoResponse = Reader::new(&buf).read();
+        match result {
+            Ok((0, len)) if len == GetProtocolInfoResponse::SIZE => ProtocolInfo {
+                version: 31 - resp.protocol_versions.leading_zeros().min(31),
+                max_request: usize::from(resp.max_request_packet_size)
+                    .saturating_sub(PROTO3_HEADER_SIZE),
+                max_response: usize::from(resp.max_response_packet_size)
+                    .saturating_sub(PROTO3_HEADER_SIZE),
+            },
+            Ok((result, _)) => {
+                log::debug!("GET_PROTOCOL_INFO returned {result}, assuming protocol v2");
+                ProtocolInfo::V2
+            }
+            Err(e) => {
+                log::debug!("GET_PROTOCOL_INFO failed ({e}), assuming protocol v2");
+                ProtocolInfo::V2
+            }
         }
-        .map_err(Into::into)
     }
 
-    fn cmd_v1<C: command::Command>(&self, cmd: C) -> nix::Result<C::Response> {
-        let mut resp = MaybeUninit::<C::Response>::uninit();
-        let mut cmd = CommandV1 {
-            version: C::VERSION,
-            command: C::CMD as u32,
-            outdata: bytemuck::bytes_of(&cmd).as_ptr() as *mut _,
-            outsize: size_of_val(&cmd).try_into().unwrap(),
-            indata: resp.as_mut_ptr().cast(),
-            insize: size_of_val(&resp).try_into().unwrap(),
-            result: 0xff,
+    /// Returns an error if a command with the given payload sizes can't be sent to the EC.
+    ///
+    /// The kernel silently truncates responses that exceed the EC's limit, so this has to be
+    /// checked up front.
+    fn check_size(&self, cmd: u16, req: usize, resp: usize) -> io::Result<()> {
+        let max = self.protocol;
+        if req > max.max_request || resp > max.max_response {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "command {cmd:#06x} exceeds the EC's payload size limits (request: {req}/{} \
+                     bytes, response: {resp}/{} bytes)",
+                    max.max_request, max.max_response,
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the versions of the command `cmd` that the EC supports, as a bit mask (bit N =
+    /// version N), via `EC_CMD_GET_CMD_VERSIONS`.
+    ///
+    /// A mask of 0 means that the EC doesn't implement the command at all. Returns `None` if the
+    /// EC can't tell. The result is cached, since it can't change while the EC is running.
+    pub fn command_versions(&self, cmd: u16) -> io::Result<Option<u32>> {
+        if let Some(mask) = self.versions.borrow().get(&cmd) {
+            return Ok(*mask);
+        }
+        let mask = self.query_command_versions(cmd)?;
+        match mask {
+            Some(mask) => log::debug!("versions of command {cmd:#06x}: {mask:#x}"),
+            None => log::debug!("versions of command {cmd:#06x}: unknown"),
+        }
+        self.versions.borrow_mut().insert(cmd, mask);
+        Ok(mask)
+    }
+
+    fn query_command_versions(&self, cmd: u16) -> io::Result<Option<u32>> {
+        let mut req = Vec::new();
+        GetCmdVersions { cmd }.encode(&mut req);
+        let mut requests = vec![(1, req)];
+        // Version 0 only takes 8-bit command IDs, but is all that older ECs implement.
+        if let Ok(cmd) = u8::try_from(cmd) {
+            requests.push((0, vec![cmd]));
+        }
+        for (version, req) in requests {
+            let mut buf = [0; GetCmdVersionsResponse::SIZE];
+            let (result, len) =
+                self.raw_command(Cmd::GetCmdVersions as u16, version, &req, &mut buf)?;
+            match result {
+                0 if len == buf.len() => {
+                    let resp: GetCmdVersionsResponse = Reader::new(&buf).read();
+                    return Ok(Some(resp.version_mask));
+                }
+                // `EC_RES_INVALID_PARAM`: the EC doesn't know the command.
+                3 => return Ok(Some(0)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Picks the highest version of `C` that both keylightd and the EC support.
+    fn negotiate<C: Command>(&self) -> io::Result<u32> {
+        let Some(supported) = self.command_versions(C::CMD as u16)? else {
+            return Ok(C::VERSION);
         };
-        unsafe {
-            let ret = ioctl(
-                self.fd.as_raw_fd(),
-                request_code_readwrite!(':', 0, size_of::<CommandV1>()),
-                &mut cmd,
+        let common = supported & C::VERSIONS;
+        if common == 0 {
+            let message = if supported == 0 {
+                format!("the EC doesn't implement {}", C::CMD.name())
+            } else {
+                format!(
+                    "the EC doesn't support any version of {} that keylightd implements (EC: \
+                     {supported:#x}, keylightd: {:#x})",
+                    C::CMD.name(),
+                    C::VERSIONS,
+                )
+            };
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        Ok(31 - common.leading_zeros())
+    }
+
+    pub fn command<C: Command>(&self, cmd: C) -> io::Result<C::Response> {
+        let mut buf = [0; RESPONSE_BUF_SIZE];
+        let resp = &mut buf[..C::Response::SIZE];
+        let len = self.send(&cmd, resp)?;
+        if len < resp.len() {
+            // Some commands only fill in the fields that apply (eg. `FP_LED_LEVEL_CONTROL` only
+            // returns the level when asked for it), so this is not an error. Don't rely on the
+            // driver to leave the rest of the buffer alone, though.
+            log::trace!(
+                "{} returned {len} of {} bytes",
+                C::CMD.name(),
+                C::Response::SIZE
             );
-            Errno::result(ret)?;
-            Ok(resp.assume_init())
+            resp[len..].fill(0);
         }
+        Ok(Reader::new(resp).read())
     }
 
-    fn cmd_v2<C: command::Command>(&self, cmd: C) -> nix::Result<C::Response> {
-        let mut cmd = CommandV2 {
-            header: CommandV2Header {
-                version: C::VERSION,
-                command: C::CMD as u32,
-                outsize: size_of::<C>().try_into().unwrap(),
-                insize: size_of::<C::Response>().try_into().unwrap(),
-                result: 0xff,
-            },
-            data: CommandV2Union { req: cmd },
+    /// Sends a command whose response has no fixed size, and writes the response into `buf`.
+    ///
+    /// This is for commands like `CONSOLE_READ` that return as much data as they have (or as fits
+    /// into a response). Their [`Command::Response`] is empty. Returns the number of bytes the EC
+    /// responded with. At most [`ProtocolInfo::max_response`] bytes are requested, regardless of
+    /// the size of `buf`.
+    pub fn command_into<C: Command>(&self, cmd: C, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf.len().min(self.protocol.max_response);
+        self.send(&cmd, &mut buf[..max])
+    }
+
+    /// Sends `cmd` in the highest common version, receiving at most `resp.len()` bytes into `resp`.
+    ///
+    /// Returns the number of bytes received, or a [`CommandError`] if the EC reports a failure.
+    fn send<C: Command>(&self, cmd: &C, resp: &mut [u8]) -> io::Result<usize> {
+        let version = self.negotiate::<C>()?;
+        let mut req = self.request_buf.take();
+        req.clear();
+        cmd.encode(&mut req);
+        let result = self.xfer(C::CMD as u16, version, &req, resp);
+        self.request_buf.set(req);
+        let (result, len) = result?;
+        if result != 0 {
+            return Err(CommandError {
+                cmd: C::CMD as u16,
+                result,
+            }
+            .into());
+        }
+        Ok(len)
+    }
+
+    /// Sends a command with a raw request payload and writes the response into `resp`.
+    ///
+    /// Returns the EC result code and the number of response bytes received. At most
+    /// [`ProtocolInfo::max_response`] bytes are requested, regardless of the size of `resp`. This
+    /// is meant for debugging; everything else should use the typed
+    /// [`EmbeddedController::command`] instead.
+    pub fn raw_command(
+        &self,
+        cmd: u16,
+        version: u32,
+        req: &[u8],
+        resp: &mut [u8],
+    ) -> io::Result<(u32, usize)> {
+        let max = resp.len().min(self.protocol.max_response);
+        self.xfer(cmd, version, req, &mut resp[..max])
+    }
+
+    fn xfer(
+        &self,
+        cmd: u16,
+        version: u32,
+        req: &[u8],
+        resp: &mut [u8],
+    ) -> io::Result<(u32, usize)> {
+        self.check_size(cmd, req.len(), resp.len())?;
+        let result = self
+            .locked(|| match self.interface {
+                Interface::IoctlV1 => self.raw_v1(cmd, version, req, resp).map_err(Into::into),
+                Interface::IoctlV2 => self.raw_v2(cmd, version, req, resp).map_err(Into::into),
+                Interface::Socket => self.raw_socket(cmd, version, req, resp),
+            })
+            .and_then(|(result, len)| {
+                if len > resp.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "the driver reported a {len} byte response to command {cmd:#06x}, \
+                             but only {} bytes were requested",
+                            resp.len()
+                        ),
+                    ));
+                }
+                Ok((result, len))
+            });
+        if let Some(hook) = COMMAND_HOOK.get() {
+            hook(cmd, version, &result);
+        }
+        result
+    }
+
+    /// Runs `f` while holding the lock on [`LOCK_PATH`].
+    ///
+    /// If another process holds the lock, this retries until [`LOCK_TIMEOUT`] expires and then
+    /// fails with [`io::ErrorKind::WouldBlock`].
+    fn locked<R>(&self, f: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        let Some(lock) = &self.lock else {
+            return f();
         };
+        let start = Instant::now();
+        loop {
+            match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                Ok(()) => break,
+                Err(Errno::EWOULDBLOCK) if start.elapsed() < LOCK_TIMEOUT => {
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                Err(Errno::EWOULDBLOCK) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("the EC is busy ({LOCK_PATH} is locked by another process)"),
+                    ));
+                }
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let result = f();
+        if let Err(e) = flock(lock.as_raw_fd(), FlockArg::Unlock) {
+            log::warn!("failed to unlock {LOCK_PATH}: {e}");
+        }
+        result
+    }
 
-        unsafe {
-            let ret = ioctl(
-                self.fd.as_raw_fd(),
-                request_code_readwrite!(0xEC, 0, size_of::<CommandV2Header>()),
-                &mut cmd,
-            );
-            Errno::result(ret)?;
-            Ok(cmd.data.resp)
+    /// Reads `buf.len()` bytes from the EC's memory-mapped region, starting at `offset`.
+    ///
+    /// The memory map contains frequently polled data like battery and sensor values, which can
+    /// be read without sending a command. Returns the number of bytes read.
+    pub fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let mut mem = ReadMem {
+            offset: offset.into(),
+            bytes: buf.len().try_into().unwrap(),
+            buffer: [0; MEMMAP_SIZE],
+        };
+        if buf.len() > MEMMAP_SIZE - usize::from(offset) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read exceeds the EC memory map",
+            ));
+        }
+        let readmem = match self.interface {
+            Interface::IoctlV1 => ioctl::readmem_v1,
+            Interface::IoctlV2 => ioctl::readmem_v2,
+            Interface::Socket => {
+                // The emulator has no memory map, so use the host command that reads from it on
+                // ECs without memory-mapped I/O.
+                let req = [offset, buf.len() as u8];
+                let (result, len) = self.xfer(Cmd::ReadMemmap as u16, 0, &req, buf)?;
+                if result != 0 {
+                    return Err(io::Error::other(format!("READ_MEMMAP returned {result}")));
+                }
+                return Ok(len);
+            }
+        };
+        // SAFETY: `mem` is a valid `cros_ec_readmem_ioctl`, and the kernel writes at most
+        // `MEMMAP_SIZE` bytes into its buffer.
+        let len =
+            self.locked(|| unsafe { readmem(self.fd.as_raw_fd(), &mut mem).map_err(Into::into) })?;
+        let len = (len as usize).min(buf.len());
+        buf[..len].copy_from_slice(&mem.buffer[..len]);
+        Ok(len)
+    }
+
+    fn raw_v1(
+        &self,
+        cmd: u16,
+        version: u32,
+        req: &[u8],
+        resp: &mut [u8],
+    ) -> nix::Result<(u32, usize)> {
+        let mut cmd = CommandV1 {
+            version,
+            command: cmd.into(),
+            outdata: req.as_ptr() as *mut _,
+            outsize: req.len().try_into().unwrap(),
+            indata: resp.as_mut_ptr(),
+            insize: resp.len().try_into().unwrap(),
+            result: 0xff,
+        };
+        // SAFETY: `outdata` and `indata` point to buffers of `outsize` and `insize` bytes, which
+        // outlive the call.
+        let len = unsafe { ioctl::command_v1(self.fd.as_raw_fd(), &mut cmd)? };
+        Ok((cmd.result, len as usize))
+    }
+
+    fn raw_v2(
+        &self,
+        cmd: u16,
+        version: u32,
+        req: &[u8],
+        resp: &mut [u8],
+    ) -> nix::Result<(u32, usize)> {
+        // The kernel expects the header to be followed by a buffer that is large enough for both
+        // the request and the response.
+        let header = CommandV2Header {
+            version,
+            command: cmd.into(),
+            outsize: req.len().try_into().unwrap(),
+            insize: resp.len().try_into().unwrap(),
+            result: 0xff,
+        };
+        let hdr_len = size_of::<CommandV2Header>();
+        let mut buf = self.packet_buf.take();
+        buf.clear();
+        buf.resize(hdr_len + req.len().max(resp.len()), 0);
+        buf[..hdr_len].copy_from_slice(bytemuck::bytes_of(&header));
+        buf[hdr_len..][..req.len()].copy_from_slice(req);
+
+        // SAFETY: `buf` starts with the header, followed by `max(outsize, insize)` bytes. The
+        // kernel copies the header out of it, so the pointer doesn't have to be aligned.
+        let ret = unsafe { ioctl::command_v2(self.fd.as_raw_fd(), buf.as_mut_ptr().cast()) };
+        let result = ret.map(|len| {
+            let header: CommandV2Header = bytemuck::pod_read_unaligned(&buf[..hdr_len]);
+            // An oversized length is reported by `xfer`.
+            let len = len as usize;
+            let copied = len.min(resp.len());
+            resp[..copied].copy_from_slice(&buf[hdr_len..][..copied]);
+            (header.result, len)
+        });
+        self.packet_buf.set(buf);
+        result
+    }
+
+    fn raw_socket(
+        &self,
+        cmd: u16,
+        version: u32,
+        req: &[u8],
+        resp: &mut [u8],
+    ) -> io::Result<(u32, usize)> {
+        let mut frame = Vec::new();
+        version.encode(&mut frame);
+        u32::from(cmd).encode(&mut frame);
+        (req.len() as u32).encode(&mut frame);
+        (resp.len() as u32).encode(&mut frame);
+        frame.extend_from_slice(req);
+        (&self.fd).write_all(&frame)?;
+
+        let mut header = [0; 8];
+        (&self.fd).read_exact(&mut header)?;
+        let mut reader = Reader::new(&header);
+        let result: u32 = reader.read();
+        let len = reader.read::<u32>() as usize;
+        if len > resp.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "emulator sent {len} bytes, but at most {} were requested",
+                    resp.len()
+                ),
+            ));
+        }
+        (&self.fd).read_exact(&mut resp[..len])?;
+        Ok((result, len))
+    }
+}
+
+/// A command that the EC received, but failed to execute.
+///
+/// [`EmbeddedController::command`] returns this (wrapped in an [`io::Error`]) when the EC
+/// responds with a result code other than `EC_RES_SUCCESS`. Commands the EC doesn't implement and
+/// versions it doesn't support fail with [`io::ErrorKind::Unsupported`], and invalid parameters
+/// (like an LED the machine doesn't have) with [`io::ErrorKind::InvalidInput`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandError {
+    /// The command ID.
+    pub cmd: u16,
+    /// The EC result code (`EC_RES_*`).
+    pub result: u32,
+}
+
+impl CommandError {
+    fn kind(&self) -> io::ErrorKind {
+        match self.result {
+            // `EC_RES_INVALID_COMMAND`, `EC_RES_INVALID_VERSION`
+            1 | 6 => io::ErrorKind::Unsupported,
+            // `EC_RES_INVALID_PARAM`
+            3 => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Cmd::from_raw(self.cmd) {
+            Some(cmd) => write!(f, "EC command {} failed", cmd.name())?,
+            None => write!(f, "EC command {:#06x} failed", self.cmd)?,
+        }
+        match result_name(self.result) {
+            Some(name) => write!(f, " with {name}"),
+            None => write!(f, " with result {}", self.result),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+impl From<CommandError> for io::Error {
+    fn from(e: CommandError) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// A handle to the lock on [`LOCK_PATH`] of an [`EmbeddedController`].
+///
+/// The handle shares the open file description that is locked, so it can release the lock held
+/// by a command that is stuck in the kernel, from another thread.
+pub struct LockHandle(File);
+
+impl LockHandle {
+    /// Releases the lock, if it is held.
+    pub fn release(&self) {
+        if let Err(e) = flock(self.0.as_raw_fd(), FlockArg::Unlock) {
+            log::warn!("failed to unlock {LOCK_PATH}: {e}");
         }
     }
 }
 
+/// Opens [`LOCK_PATH`], creating it if necessary.
+///
+/// The lock is only advisory, so EC access continues without it if the file can't be opened (eg.
+/// because `/run/lock` doesn't exist).
+fn open_lock() -> Option<File> {
+    // `flock` works on read-only descriptors too, which is all a regular user may get if root
+    // created the file.
+    let result = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_PATH)
+        .or_else(|_| File::open(LOCK_PATH));
+    match result {
+        Ok(file) => Some(file),
+        Err(e) => {
+            log::debug!("failed to open {LOCK_PATH}, not locking EC access: {e}");
+            None
+        }
+    }
+}
+
+/// Size of the EC memory map (`EC_MEMMAP_SIZE`).
+const MEMMAP_SIZE: usize = 255;
+
+/// `struct cros_ec_readmem_ioctl`, which has the same layout in both ioctl versions.
+#[repr(C)]
+struct ReadMem {
+    offset: u32,
+    bytes: u32,
+    buffer: [u8; MEMMAP_SIZE],
+}
+
 #[repr(C)]
 struct CommandV1 {
     version: u32,
@@ -128,14 +744,7 @@ struct CommandV1 {
     result: u32,
 }
 
-#[repr(C)]
-struct CommandV2<C: command::Command> {
-    header: CommandV2Header,
-    // Request and response are stored in a `union` rather than using an empty trailing array like
-    // the C code does. I believe this is ABI-equivalent, so it shouldn't cause problems.
-    data: CommandV2Union<C>,
-}
-
+#[derive(Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 struct CommandV2Header {
     version: u32,
@@ -145,8 +754,18 @@ struct CommandV2Header {
     result: u32,
 }
 
-#[repr(C)]
-union CommandV2Union<C: command::Command> {
-    req: C,
-    resp: C::Response,
+/// The ioctls of the `cros_ec_chardev` driver.
+///
+/// The request codes are computed at compile time from the argument types, so an argument struct
+/// that is changed can't be sent with a stale size. Version 1 of the interface uses the type `':'`
+/// and version 2 uses `0xEC`.
+mod ioctl {
+    use nix::ioctl_readwrite;
+
+    use super::{CommandV1, CommandV2Header, ReadMem};
+
+    ioctl_readwrite!(command_v1, b':', 0, CommandV1);
+    ioctl_readwrite!(readmem_v1, b':', 1, ReadMem);
+    ioctl_readwrite!(command_v2, 0xEC, 0, CommandV2Header);
+    ioctl_readwrite!(readmem_v2, 0xEC, 1, ReadMem);
 }
@@ -0,0 +1,388 @@
+//! A userspace emulation of a Framework EC, for testing without hardware (`keylightd emulate-ec`).
+//!
+//! The emulator listens on a Unix socket that can be passed to `--ec-device` instead of a device
+//! node. It implements the subset of host commands used by keylightd, and keeps the keyboard
+//! backlight, LED and battery state in memory, logging every change (to its own log and to the
+//! emulated EC console).
+//!
+//! The framing mirrors `struct cros_ec_command` of the kernel's ioctl interface. Every request is
+//! a header of four little-endian `u32`s (command version, command, request size, maximum response
+//! size) followed by the request payload. The emulator answers with two `u32`s (result code and
+//! response size) followed by the response payload.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    command::{Cmd, LedColor, LedId},
+    wire::{Encode, Reader},
+};
+
+/// `EC_RES_SUCCESS`
+const SUCCESS: u32 = 0;
+/// `EC_RES_INVALID_COMMAND`
+const INVALID_COMMAND: u32 = 1;
+/// `EC_RES_INVALID_PARAM`
+const INVALID_PARAM: u32 = 3;
+/// `EC_RES_INVALID_VERSION`
+const INVALID_VERSION: u32 = 6;
+
+/// Maximum request and response payload size advertised by the emulator.
+const MAX_PAYLOAD: u16 = 256;
+
+/// Offset of the temperature readings in the memory map (`EC_MEMMAP_TEMP_SENSOR`).
+const TEMP_OFFSET: usize = 0x00;
+
+/// Offset of the fan speeds in the memory map (`EC_MEMMAP_FAN`).
+const FAN_OFFSET: usize = 0x10;
+
+/// Names of the emulated temperature sensors.
+const TEMP_SENSORS: &[&str] = &["Battery", "Charger"];
+
+/// Offset of the battery data in the memory map (`EC_MEMMAP_BATT_VOLT`).
+const BATTERY_OFFSET: usize = 0x40;
+
+/// Offset of the ALS readings in the memory map (`EC_MEMMAP_ALS`).
+const ALS_OFFSET: usize = 0x80;
+
+/// Size of the memory map (`EC_MEMMAP_SIZE`).
+const MEMMAP_SIZE: usize = 255;
+
+/// Size of the emulated console output buffer.
+const CONSOLE_SIZE: usize = 4096;
+
+/// State of an emulated LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Led {
+    Auto,
+    Manual([u8; LedColor::COUNT]),
+}
+
+struct Emulator {
+    backlight: u8,
+    leds: [Led; LedId::ALL.len()],
+    power_level: u8,
+    memmap: [u8; MEMMAP_SIZE],
+    /// The most recent console output, at most [`CONSOLE_SIZE`] bytes.
+    console: String,
+    /// The part of the last console snapshot that hasn't been read yet.
+    snapshot: Vec<u8>,
+    started: Instant,
+}
+
+/// Listens on `path` and serves EC commands until the process is terminated.
+pub fn run(path: &Path) -> io::Result<()> {
+    // A previous instance might have left its socket behind.
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    log::info!("emulating an EC on {}", path.display());
+
+    let emulator = Arc::new(Mutex::new(Emulator::new()));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let emulator = emulator.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&emulator, stream) {
+                log::warn!("error while serving emulator connection: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve(emulator: &Mutex<Emulator>, mut stream: UnixStream) -> io::Result<()> {
+    loop {
+        let mut header = [0; 16];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            // The client closed the connection.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut reader = Reader::new(&header);
+        let version: u32 = reader.read();
+        let command: u32 = reader.read();
+        let outsize = reader.read::<u32>() as usize;
+        let insize = reader.read::<u32>() as usize;
+        if outsize > usize::from(MAX_PAYLOAD) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request payload too large ({outsize} bytes)"),
+            ));
+        }
+        let mut req = vec![0; outsize];
+        stream.read_exact(&mut req)?;
+
+        let (result, mut resp) = emulator.lock().unwrap().handle(command, version, &req);
+        log::debug!("command {command:#06x} v{version}: {req:02x?} -> {result} {resp:02x?}");
+        resp.truncate(insize);
+
+        let mut frame = Vec::new();
+        result.encode(&mut frame);
+        (resp.len() as u32).encode(&mut frame);
+        frame.extend_from_slice(&resp);
+        stream.write_all(&frame)?;
+    }
+}
+
+impl Emulator {
+    fn new() -> Self {
+        let mut emulator = Self {
+            backlight: 50,
+            leds: [Led::Auto; LedId::ALL.len()],
+            power_level: 0,
+            memmap: memmap(),
+            console: String::new(),
+            snapshot: Vec::new(),
+            started: Instant::now(),
+        };
+        emulator.print("keylightd-emulator ready");
+        emulator
+    }
+
+    /// Writes a line to the console, with a timestamp like the EC's.
+    fn print(&mut self, line: &str) {
+        let time = self.started.elapsed().as_secs_f64();
+        self.console.push_str(&format!("[{time:.6} {line}]\n"));
+        if self.console.len() > CONSOLE_SIZE {
+            // Drop the oldest lines, like the EC's ring buffer would.
+            let excess = self.console.len() - CONSOLE_SIZE;
+            let start = self.console[excess..]
+                .find('\n')
+                .map_or(self.console.len(), |i| excess + i + 1);
+            self.console.drain(..start);
+        }
+    }
+
+    /// Executes a host command and returns the result code and response payload.
+    fn handle(&mut self, command: u32, version: u32, req: &[u8]) -> (u32, Vec<u8>) {
+        let mut reader = Reader::new(req);
+        let mut resp = Vec::new();
+        let Some(command) = u16::try_from(command).ok().and_then(Cmd::from_raw) else {
+            return (INVALID_COMMAND, resp);
+        };
+        let result = match command {
+            Cmd::Hello => {
+                let in_data: u32 = reader.read();
+                in_data.wrapping_add(0x01020304).encode(&mut resp);
+                SUCCESS
+            }
+            Cmd::GetVersion => {
+                let mut version = [0; 32];
+                version[..18].copy_from_slice(b"keylightd-emulator");
+                version.encode(&mut resp);
+                version.encode(&mut resp);
+                [0u8; 32].encode(&mut resp);
+                // Running the RW image.
+                2u32.encode(&mut resp);
+                SUCCESS
+            }
+            Cmd::GetProtocolInfo => {
+                // Protocol version 3, with 8-byte packet headers.
+                (1u32 << 3).encode(&mut resp);
+                (MAX_PAYLOAD + 8).encode(&mut resp);
+                (MAX_PAYLOAD + 8).encode(&mut resp);
+                0u32.encode(&mut resp);
+                SUCCESS
+            }
+            Cmd::GetCmdVersions => {
+                let cmd = match version {
+                    0 => Some(u16::from(reader.read::<u8>())),
+                    1 => Some(reader.read::<u16>()),
+                    _ => None,
+                };
+                let Some(cmd) = cmd else {
+                    return (INVALID_VERSION, resp);
+                };
+                match Cmd::from_raw(cmd).and_then(versions) {
+                    Some(mask) => {
+                        mask.encode(&mut resp);
+                        SUCCESS
+                    }
+                    None => INVALID_PARAM,
+                }
+            }
+            Cmd::ReadMemmap => {
+                let offset = usize::from(reader.read::<u8>());
+                let size = usize::from(reader.read::<u8>());
+                match self.memmap.get(offset..offset + size) {
+                    Some(data) => {
+                        resp.extend_from_slice(data);
+                        SUCCESS
+                    }
+                    None => INVALID_PARAM,
+                }
+            }
+            Cmd::GetKeyboardBacklight => {
+                self.backlight.encode(&mut resp);
+                1u8.encode(&mut resp);
+                SUCCESS
+            }
+            Cmd::SetKeyboardBacklight => {
+                let percent: u8 = reader.read();
+                if percent > 100 {
+                    INVALID_PARAM
+                } else {
+                    log::debug!("keyboard backlight: {}% -> {percent}%", self.backlight);
+                    self.print(&format!("kblight: {percent}%"));
+                    self.backlight = percent;
+                    SUCCESS
+                }
+            }
+            Cmd::LedControl => {
+                if version != 1 {
+                    return (INVALID_VERSION, resp);
+                }
+                self.led_control(&mut reader, &mut resp)
+            }
+            Cmd::TempSensorGetInfo => {
+                let id: u8 = reader.read();
+                let Some(name) = TEMP_SENSORS.get(usize::from(id)) else {
+                    return (INVALID_PARAM, resp);
+                };
+                let mut sensor_name = [0u8; 32];
+                sensor_name[..name.len()].copy_from_slice(name.as_bytes());
+                sensor_name.encode(&mut resp);
+                // `TEMP_SENSOR_TYPE_BOARD`
+                1u8.encode(&mut resp);
+                SUCCESS
+            }
+            Cmd::FpLedLevelControl => {
+                let set_level: u8 = reader.read();
+                let get_level: u8 = reader.read();
+                if get_level != 0 {
+                    self.power_level.encode(&mut resp);
+                    SUCCESS
+                } else if set_level > 2 {
+                    INVALID_PARAM
+                } else {
+                    log::info!("power LED level: {} -> {set_level}", self.power_level);
+                    self.print(&format!("fp led level: {set_level}"));
+                    self.power_level = set_level;
+                    SUCCESS
+                }
+            }
+            Cmd::ConsoleSnapshot => {
+                self.snapshot = self.console.clone().into_bytes();
+                SUCCESS
+            }
+            Cmd::ConsoleRead => {
+                // Leave room for the terminating NUL.
+                let len = self.snapshot.len().min(usize::from(MAX_PAYLOAD) - 1);
+                resp.extend(self.snapshot.drain(..len));
+                resp.push(0);
+                SUCCESS
+            }
+            _ => INVALID_COMMAND,
+        };
+        (result, resp)
+    }
+
+    fn led_control(&mut self, reader: &mut Reader<'_>, resp: &mut Vec<u8>) -> u32 {
+        let id = usize::from(reader.read::<u8>());
+        let flags: u8 = reader.read();
+        let brightness: [u8; LedColor::COUNT] = reader.read();
+        let Some(&led_id) = LedId::ALL.get(id) else {
+            return INVALID_PARAM;
+        };
+        let max = led_capabilities(led_id);
+
+        // `EC_LED_FLAGS_QUERY` and `EC_LED_FLAGS_AUTO`
+        let new = if flags & 1 != 0 {
+            max.encode(resp);
+            return SUCCESS;
+        } else if flags & 2 != 0 {
+            Led::Auto
+        } else if brightness.iter().zip(max).any(|(&b, max)| b > max) {
+            return INVALID_PARAM;
+        } else {
+            Led::Manual(brightness)
+        };
+
+        if self.leds[id] != new {
+            log::info!("{} LED: {:?} -> {new:?}", led_id.name(), self.leds[id]);
+            self.print(&format!("led {}: {new:?}", led_id.name()));
+            self.leds[id] = new;
+        }
+        [0u8; LedColor::COUNT].encode(resp);
+        SUCCESS
+    }
+}
+
+/// Returns the versions of `cmd` that the emulator implements, as a bit mask, or `None` if it
+/// doesn't implement the command.
+fn versions(cmd: Cmd) -> Option<u32> {
+    match cmd {
+        Cmd::GetCmdVersions => Some(0b11),
+        Cmd::LedControl => Some(0b10),
+        Cmd::Hello
+        | Cmd::GetVersion
+        | Cmd::ReadMemmap
+        | Cmd::GetProtocolInfo
+        | Cmd::GetKeyboardBacklight
+        | Cmd::SetKeyboardBacklight
+        | Cmd::TempSensorGetInfo
+        | Cmd::ConsoleSnapshot
+        | Cmd::ConsoleRead
+        | Cmd::FpLedLevelControl => Some(0b1),
+        Cmd::ProtoVersion | Cmd::LightbarCmd => None,
+    }
+}
+
+/// Returns the maximum brightness of each color of an emulated LED.
+///
+/// Like on Framework laptops, only the power LED and the side LEDs exist.
+fn led_capabilities(led: LedId) -> [u8; LedColor::COUNT] {
+    match led {
+        // Only white.
+        LedId::POWER => [0, 0, 0, 0, 100, 0],
+        LedId::LEFT | LedId::RIGHT => [100; LedColor::COUNT],
+        _ => [0; LedColor::COUNT],
+    }
+}
+
+/// Returns a memory map describing a charging battery in a dimly lit room.
+fn memmap() -> [u8; MEMMAP_SIZE] {
+    let mut batt = Vec::new();
+    // Voltage (mV), rate (mA), remaining capacity (mAh).
+    11_900u32.encode(&mut batt);
+    1_500u32.encode(&mut batt);
+    2_800u32.encode(&mut batt);
+    // AC present, battery present, charging.
+    (1u8 | 1 << 1 | 1 << 3).encode(&mut batt);
+    [0u8; 3].encode(&mut batt);
+    // Design capacity (mAh), design voltage (mV), last full capacity (mAh), cycle count.
+    3_572u32.encode(&mut batt);
+    11_550u32.encode(&mut batt);
+    3_400u32.encode(&mut batt);
+    42u32.encode(&mut batt);
+    for s in [&b"EMU"[..], b"EC-BATT", b"0001", b"LION"] {
+        let mut field = [0u8; 8];
+        field[..s.len()].copy_from_slice(s);
+        field.encode(&mut batt);
+    }
+
+    let mut memmap = [0; MEMMAP_SIZE];
+    // Temperatures in Kelvin minus 200, 0xff for absent sensors. Thermal version 0 means there is
+    // no second bank of sensors.
+    memmap[TEMP_OFFSET..][..16].fill(0xff);
+    memmap[TEMP_OFFSET..][..TEMP_SENSORS.len()].copy_from_slice(&[120, 112]);
+    // One fan, the other entries are marked as not present.
+    memmap[FAN_OFFSET..][..8].fill(0xff);
+    memmap[FAN_OFFSET..][..2].copy_from_slice(&2_400u16.to_le_bytes());
+    memmap[BATTERY_OFFSET..][..batt.len()].copy_from_slice(&batt);
+    // Lid sensor (lux), second sensor.
+    memmap[ALS_OFFSET..][..4].copy_from_slice(&[80, 0, 0, 0]);
+    memmap
+}
@@ -0,0 +1,122 @@
+//! Failure classes and the exit codes they map to.
+//!
+//! Errors are passed around as [`anyhow::Error`]s. Where the class of a failure is known, it is
+//! wrapped in a [`KeylightdError`] before being passed on, and `main` picks the exit code of the
+//! first class it finds in the chain. This lets a service manager or a wrapper script tell a
+//! broken configuration, which restarting won't fix, from a device that isn't there yet.
+
+use std::{error::Error, fmt, io, process::ExitCode};
+
+/// An error, classified by what went wrong.
+#[derive(Debug)]
+pub enum KeylightdError {
+    /// The configuration (or the environment) is invalid.
+    Config(anyhow::Error),
+    /// Access to a device or file was denied.
+    Permission(anyhow::Error),
+    /// The EC or another device is missing, can't be opened, or doesn't support what's needed.
+    Device(anyhow::Error),
+    /// The EC failed to execute a command.
+    Ec(anyhow::Error),
+    /// Any other failure.
+    Other(anyhow::Error),
+}
+
+impl KeylightdError {
+    pub fn config(e: impl Into<anyhow::Error>) -> Self {
+        Self::Config(e.into())
+    }
+
+    pub fn device(e: impl Into<anyhow::Error>) -> Self {
+        Self::Device(e.into())
+    }
+
+    pub fn ec(e: impl Into<anyhow::Error>) -> Self {
+        Self::Ec(e.into())
+    }
+
+    /// Classifies a failure to open a device, which is a [`Permission`](Self::Permission) error
+    /// if access was denied, and a [`Device`](Self::Device) error otherwise.
+    pub fn open_device(e: impl Into<anyhow::Error>) -> Self {
+        let e = e.into();
+        if e.chain().any(denied) {
+            Self::Permission(e)
+        } else {
+            Self::Device(e)
+        }
+    }
+
+    /// Classifies `e` by the first [`KeylightdError`] in its chain.
+    ///
+    /// Errors that weren't classified where they occurred still count as permission errors if
+    /// they were caused by one, since those are the ones that can occur almost anywhere.
+    pub fn classify(e: anyhow::Error) -> Self {
+        let class = e.chain().find_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<KeylightdError>() {
+                Some(error.class())
+            } else if denied(cause) {
+                Some(Self::Permission as fn(_) -> _)
+            } else {
+                None
+            }
+        });
+        class.unwrap_or(Self::Other)(e)
+    }
+
+    fn class(&self) -> fn(anyhow::Error) -> Self {
+        match self {
+            Self::Config(_) => Self::Config,
+            Self::Permission(_) => Self::Permission,
+            Self::Device(_) => Self::Device,
+            Self::Ec(_) => Self::Ec,
+            Self::Other(_) => Self::Other,
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            Self::Config(e)
+            | Self::Permission(e)
+            | Self::Device(e)
+            | Self::Ec(e)
+            | Self::Other(e) => e,
+        }
+    }
+
+    /// Returns the code keylightd exits with after this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Other(_) => 1,
+            Self::Config(_) => 2,
+            Self::Device(_) => 3,
+            Self::Permission(_) => 4,
+            Self::Ec(_) => 5,
+        }
+    }
+
+    /// Prints the error and its causes, and returns the exit code.
+    pub fn report(&self) -> ExitCode {
+        eprintln!("Error: {:?}", self.inner());
+        ExitCode::from(self.exit_code())
+    }
+}
+
+fn denied(cause: &(dyn Error + 'static)) -> bool {
+    cause
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+impl fmt::Display for KeylightdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl Error for KeylightdError {
+    // The message of the wrapped error is already displayed as this error's message, so the
+    // chain continues with its causes.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner().source()
+    }
+}
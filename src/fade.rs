@@ -0,0 +1,48 @@
+//! The stepping logic of keyboard backlight fades.
+//!
+//! The daemon fades the backlight through the EC or UPower, and also switches the power LED along
+//! with it. Only the stepping itself lives here, behind the [`Backlight`] trait, so that it can be
+//! tested against a mock backlight.
+
+use std::io;
+
+/// Something with a brightness in percent that can be faded.
+pub trait Backlight {
+    /// Returns the current brightness (0 if the backlight is off).
+    fn get(&mut self) -> io::Result<u8>;
+
+    /// Changes the brightness from `from` to `to`.
+    fn set(&mut self, from: u8, to: u8) -> io::Result<()>;
+}
+
+/// Fades `backlight` to the brightness returned by `target`, and returns the brightness that was
+/// reached.
+///
+/// `target` is re-evaluated on every step, so that a fade can change direction immediately, and
+/// values above 100 are treated as 100. If `instant` is set, the backlight jumps to the target
+/// without fading, and otherwise, it moves in 1% steps. `pause` is called after every change.
+pub fn fade_to(
+    backlight: &mut impl Backlight,
+    instant: bool,
+    mut target: impl FnMut() -> u8,
+    mut pause: impl FnMut(),
+) -> io::Result<u8> {
+    let mut cur = backlight.get()?;
+    loop {
+        let target = target().min(100);
+        if cur == target {
+            return Ok(cur);
+        }
+
+        let prev = cur;
+        if instant {
+            cur = target;
+        } else if cur > target {
+            cur -= 1;
+        } else {
+            cur += 1;
+        }
+        backlight.set(prev, cur)?;
+        pause();
+    }
+}
@@ -0,0 +1,147 @@
+//! A small in-memory history of recent state transitions and EC commands.
+//!
+//! `keylightd dump-history` prints it, so that reports like "the backlight randomly turned on at
+//! 3am" can come with the events that led up to it. State transitions and EC commands are kept
+//! in separate ring buffers, so that a burst of EC traffic (eg. a status bar polling `keylightd
+//! status`) can't push the transitions out. Identical consecutive EC commands, like the steps of a
+//! fade, are combined into one entry.
+
+use std::{
+    collections::VecDeque,
+    io,
+    mem::MaybeUninit,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::libc;
+
+use crate::{command::Cmd, control::HistoryEntry};
+
+/// Number of entries kept in each ring buffer.
+const CAPACITY: usize = 100;
+
+struct Transition {
+    at: SystemTime,
+    what: String,
+}
+
+struct EcCommand {
+    at: SystemTime,
+    cmd: u16,
+    version: u32,
+    /// The EC result code, or the OS error of the failed ioctl.
+    result: Result<u32, io::ErrorKind>,
+    /// How many times the command was sent in a row.
+    count: u32,
+}
+
+static TRANSITIONS: Mutex<VecDeque<Transition>> = Mutex::new(VecDeque::new());
+static EC_COMMANDS: Mutex<VecDeque<EcCommand>> = Mutex::new(VecDeque::new());
+
+fn push<T>(ring: &mut VecDeque<T>, entry: T) {
+    if ring.len() == CAPACITY {
+        ring.pop_front();
+    } else if ring.capacity() == 0 {
+        ring.reserve_exact(CAPACITY);
+    }
+    ring.push_back(entry);
+}
+
+/// Records a state transition.
+pub fn record(what: impl Into<String>) {
+    let entry = Transition {
+        at: SystemTime::now(),
+        what: what.into(),
+    };
+    push(&mut TRANSITIONS.lock().unwrap(), entry);
+}
+
+/// Records an EC command. Installed as the EC command hook by the daemon.
+pub fn record_ec(cmd: u16, version: u32, result: &io::Result<(u32, usize)>) {
+    let result = match result {
+        Ok((result, _)) => Ok(*result),
+        Err(e) => Err(e.kind()),
+    };
+    let mut ring = EC_COMMANDS.lock().unwrap();
+    if let Some(last) = ring.back_mut() {
+        if (last.cmd, last.version, last.result) == (cmd, version, result) {
+            last.count += 1;
+            return;
+        }
+    }
+    let entry = EcCommand {
+        at: SystemTime::now(),
+        cmd,
+        version,
+        result,
+        count: 1,
+    };
+    push(&mut ring, entry);
+}
+
+/// Returns all recorded entries, oldest first.
+pub fn entries() -> Vec<HistoryEntry> {
+    let secs = |at: SystemTime| {
+        at.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    };
+    let mut entries = TRANSITIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|t| HistoryEntry {
+            time: secs(t.at),
+            event: t.what.clone(),
+        })
+        .collect::<Vec<_>>();
+    entries.extend(EC_COMMANDS.lock().unwrap().iter().map(|c| {
+        let result = match c.result {
+            Ok(result) => format!("result {result}"),
+            Err(kind) => format!("failed: {kind}"),
+        };
+        let repeat = if c.count > 1 {
+            format!(" ({} times)", c.count)
+        } else {
+            String::new()
+        };
+        HistoryEntry {
+            time: secs(c.at),
+            event: match Cmd::from_raw(c.cmd) {
+                Some(cmd) => format!(
+                    "EC command {:#06x} ({}) v{}: {result}{repeat}",
+                    c.cmd,
+                    cmd.name(),
+                    c.version
+                ),
+                None => format!("EC command {:#06x} v{}: {result}{repeat}", c.cmd, c.version),
+            },
+        }
+    }));
+    entries.sort_by(|a, b| a.time.total_cmp(&b.time));
+    entries
+}
+
+/// Formats a Unix timestamp as local date and time, with milliseconds.
+pub fn format_time(time: f64) -> String {
+    let secs = time.floor() as libc::time_t;
+    let millis = ((time - time.floor()) * 1000.0) as u32;
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: `localtime_r` initializes `tm` if it returns non-null.
+    let tm = unsafe {
+        if libc::localtime_r(&secs, tm.as_mut_ptr()).is_null() {
+            return format!("{time:.3}");
+        }
+        tm.assume_init()
+    };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}.{millis:03}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+    )
+}
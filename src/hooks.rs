@@ -0,0 +1,32 @@
+//! User commands that are run when the daemon's state changes (`on-active` and `on-idle`).
+
+use std::{process, thread};
+
+/// Runs `command` (program and arguments) in the background.
+///
+/// `event` is passed to the command in the `KEYLIGHTD_EVENT` environment variable, so that one
+/// script can handle several hooks. Failures are only logged.
+pub fn run(event: &'static str, command: &[String]) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    log::debug!("running {event} hook {command:?}");
+    let child = process::Command::new(program)
+        .args(args)
+        .env("KEYLIGHTD_EVENT", event)
+        .stdin(process::Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            log::warn!("failed to run {event} hook {command:?}: {e}");
+            return;
+        }
+    };
+    // Reap the process without blocking the caller.
+    thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => log::warn!("{event} hook exited with {status}"),
+        Ok(_) => {}
+        Err(e) => log::warn!("failed to wait for {event} hook: {e}"),
+    });
+}
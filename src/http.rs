@@ -0,0 +1,152 @@
+//! A minimal HTTP API mirroring the control socket, for scripts and browsers.
+//!
+//! It is disabled by default and only ever listens on a loopback address, since there is no
+//! authentication. The following endpoints exist:
+//!
+//! - `GET /state` returns the state shown by `keylightd status` as a JSON object.
+//! - `PUT /brightness` changes the active brightness, like `keylightd set --brightness`. The body
+//!   is either a number (`50`) or a relative change (`{"increase":10}`, `{"decrease":10}`), and
+//!   the response is the new state.
+//!
+//! Every response closes the connection, and errors are returned as `{"error":"..."}`.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    cli::BrightnessChange,
+    control::{self, Request, Response},
+    daemon::Shared,
+};
+
+/// Maximum size of a request body.
+const MAX_BODY: usize = 1024;
+
+/// How long a client may take to send its request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BrightnessBody {
+    Absolute(u8),
+    Change(BrightnessChange),
+}
+
+/// Binds to `addr` and spawns a thread serving HTTP requests.
+pub fn spawn_server(shared: Arc<Shared>, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("serving the HTTP API on http://{addr}");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| serve(&shared, stream));
+            if let Err(e) = result {
+                log::warn!("error while serving HTTP connection: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return respond(&stream, 400, &error("malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return respond(&stream, 413, &error("request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    log::debug!("HTTP request: {method} {path}");
+    let (status, json) = match (method.as_str(), path.as_str()) {
+        ("GET", "/state") => response(control::handle(shared, Request::Status)),
+        ("PUT", "/brightness") => match serde_json::from_slice::<BrightnessBody>(&body) {
+            Ok(brightness) => {
+                let change = match brightness {
+                    BrightnessBody::Absolute(value) => BrightnessChange::Set(value),
+                    BrightnessBody::Change(change) => change,
+                };
+                let req = Request::Set {
+                    brightness: Some(change),
+                    timeout: None,
+                    hardware_profile: None,
+                    save: false,
+                };
+                response(control::handle(shared, req))
+            }
+            Err(e) => (400, error(&format!("invalid brightness: {e}"))),
+        },
+        (_, "/state" | "/brightness") => (405, error("method not allowed")),
+        _ => (404, error("not found")),
+    };
+    respond(&stream, status, &json)
+}
+
+fn response(resp: Response) -> (u16, serde_json::Value) {
+    match resp {
+        Response::Status(status) => (200, serde_json::to_value(status).unwrap()),
+        Response::Health(health) => (200, serde_json::to_value(health).unwrap()),
+        Response::LogLevel { level } => (200, serde_json::json!({ "level": level })),
+        Response::History { entries } => (200, serde_json::to_value(entries).unwrap()),
+        Response::Error { message } => (400, error(&message)),
+    }
+}
+
+fn error(message: &str) -> serde_json::Value {
+    serde_json::json!({ "error": message })
+}
+
+fn respond(mut stream: &TcpStream, status: u16, json: &serde_json::Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "",
+    };
+    let body = json.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    )
+}
@@ -0,0 +1,38 @@
+//! Access to sensors through the kernel's iio subsystem.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Where the kernel exposes iio devices.
+const IIO_DEVICES: &str = "/sys/bus/iio/devices";
+
+/// Returns the sysfs directories of all iio devices, in a stable order.
+pub fn devices() -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(IIO_DEVICES) {
+        Ok(entries) => entries,
+        // No iio drivers are loaded.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut devices = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    devices.sort();
+    Ok(devices)
+}
+
+/// Reads a numeric sysfs attribute.
+pub fn read_number(path: &Path) -> io::Result<f64> {
+    // sysfs attributes are tiny, so read them into a stack buffer to keep polling allocation-free.
+    let mut buf = [0; 32];
+    let len = File::open(path)?.read(&mut buf)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid number");
+    std::str::from_utf8(&buf[..len])
+        .map_err(|_| invalid())?
+        .trim()
+        .parse()
+        .map_err(|_| invalid())
+}
@@ -0,0 +1,866 @@
+//! Input device listeners, which report user activity to the daemon.
+//!
+//! Every device is read by its own thread, which waits for input with edge-triggered epoll and then
+//! drains all pending events without blocking, so that a burst of events is consumed at once
+//! instead of piling up in the kernel's buffer. When reading fails (eg. because the device was
+//! reset), the listener reopens it with exponential backoff. A supervisor thread watches the listeners and
+//! restarts any that panicked, so that a bug triggered by one event doesn't permanently stop that
+//! device from resetting the idle timer.
+//!
+//! On request, touchpad events that barely move the pointer or that come from a very light touch
+//! are ignored (see [`TouchFilter`]), since the touchpad can emit a steady trickle of events for a
+//! resting palm, a brushing sleeve, or even on its own.
+//!
+//! Optionally, gamepads and joysticks are used as well. Unlike the builtin devices, they come and
+//! go, so the supervisor looks for new ones periodically, and their listeners stop when they are
+//! disconnected.
+//!
+//! Additionally, a user-provided `activity-command` can act as an activity source: every line it
+//! prints counts as activity, except for [`PRESENCE_LINE`], which only reports that someone is in
+//! front of the machine (eg. from a webcam-based presence detector).
+//!
+//! These are the `evdev` and `command` activity backends. The desktop's idea of idleness is
+//! available through the `logind` and `wayland` backends, which live in their own modules.
+
+use std::{
+    any::Any,
+    io,
+    io::{BufRead, BufReader},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use evdev::{
+    AbsoluteAxisType, Device, InputEvent, InputEventKind, Key, RelativeAxisType, Synchronization,
+};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::epoll::{
+        epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+    },
+};
+
+use crate::{
+    cli::Backend,
+    clock,
+    config::Config,
+    daemon::{ActivityCause, ActivityKind, Shared},
+    lid,
+    quirks::Quirks,
+};
+
+/// A line printed by the activity command that reports presence instead of activity.
+pub const PRESENCE_LINE: &str = "presence";
+
+/// Minimum interval between activity reports from one device, so that a burst of events doesn't
+/// wake up the main loop for every single one.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the supervisor checks on the listener threads.
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the supervisor looks for newly connected gamepads.
+const GAMEPAD_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before the first attempt to reopen a device after an error.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum delay between attempts to reopen a device.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Listener {
+    path: PathBuf,
+    name: String,
+    /// Index into [`Shared::sources`].
+    id: usize,
+    /// Gamepads are removable, so their listener stops when reading fails.
+    gamepad: bool,
+    thread: JoinHandle<()>,
+}
+
+/// Statistics about an activity source, to help figuring out which one resets the idle timer.
+pub struct SourceStats {
+    pub name: String,
+    /// The backend the source belongs to.
+    pub backend: Backend,
+    /// Device node of an input device (`None` for the activity command).
+    pub path: Option<PathBuf>,
+    /// Number of input events (or lines printed by the activity command).
+    pub events: u64,
+    /// Number of those events that didn't count as activity because of the [`TouchFilter`].
+    pub ignored: u64,
+    /// Number of read errors, panics and command failures.
+    pub errors: u64,
+    pub last_event: Option<Instant>,
+}
+
+/// Adds an activity source to [`Shared::sources`] and returns its index.
+///
+/// A device that was seen before (like a reconnected gamepad) keeps its statistics.
+pub fn register(shared: &Shared, name: &str, path: Option<&Path>, backend: Backend) -> usize {
+    let mut sources = shared.sources.lock().unwrap();
+    if let Some(id) = path.and_then(|path| {
+        sources
+            .iter()
+            .position(|source| source.name == name && source.path.as_deref() == Some(path))
+    }) {
+        return id;
+    }
+    sources.push(SourceStats {
+        name: name.to_string(),
+        backend,
+        path: path.map(Path::to_path_buf),
+        events: 0,
+        ignored: 0,
+        errors: 0,
+        last_event: None,
+    });
+    sources.len() - 1
+}
+
+pub fn record_events(shared: &Shared, id: usize, count: u64, ignored: u64) {
+    let source = &mut shared.sources.lock().unwrap()[id];
+    source.events += count;
+    source.ignored += ignored;
+    source.last_event = Some(clock::now());
+}
+
+pub fn record_error(shared: &Shared, id: usize) {
+    shared.sources.lock().unwrap()[id].errors += 1;
+}
+
+/// What an input device is, judging by the events it supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Keyboard,
+    Touchpad,
+    Touchscreen,
+    Mouse,
+    Gamepad,
+    LidSwitch,
+    Other,
+}
+
+impl DeviceClass {
+    pub fn of(device: &Device) -> Self {
+        let keys = device.supported_keys();
+        let has_key = |key| keys.is_some_and(|keys| keys.contains(key));
+        if is_gamepad(device) {
+            Self::Gamepad
+        } else if is_keyboard(device) {
+            Self::Keyboard
+        } else if has_key(Key::BTN_TOOL_FINGER) {
+            Self::Touchpad
+        } else if device
+            .supported_absolute_axes()
+            .is_some_and(|axes| axes.contains(AbsoluteAxisType::ABS_MT_POSITION_X))
+        {
+            Self::Touchscreen
+        } else if device
+            .supported_relative_axes()
+            .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+        {
+            Self::Mouse
+        } else if lid::is_lid_switch(device) {
+            Self::LidSwitch
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Keyboard => "keyboard",
+            Self::Touchpad => "touchpad",
+            Self::Touchscreen => "touchscreen",
+            Self::Mouse => "mouse",
+            Self::Gamepad => "gamepad",
+            Self::LidSwitch => "lid switch",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Why the `evdev` backend uses an input device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Use {
+    /// It's one of the builtin keyboards and touchpads of the hardware profile.
+    Builtin,
+    /// It's a keyboard, none of the builtin devices is present, and `keyboard-fallback` is set.
+    Fallback,
+    /// It's a gamepad, and `gamepads` is set.
+    Gamepad,
+    /// It's the lid switch.
+    Lid,
+}
+
+impl Use {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Builtin => "builtin",
+            Self::Fallback => "fallback",
+            Self::Gamepad => "gamepad",
+            Self::Lid => "lid",
+        }
+    }
+}
+
+/// Decides which of `devices` the `evdev` backend uses with `config` on the hardware described by
+/// `quirks`.
+pub fn select(config: &Config, quirks: &Quirks, devices: &[(PathBuf, Device)]) -> Vec<Option<Use>> {
+    let builtin = |device: &Device| {
+        device
+            .name()
+            .is_some_and(|name| quirks.is_input_device(name))
+    };
+    let fallback = config.keyboard_fallback && !devices.iter().any(|(_, device)| builtin(device));
+    let mut lid = false;
+    devices
+        .iter()
+        .map(|(_, device)| {
+            if builtin(device) {
+                Some(Use::Builtin)
+            } else if is_gamepad(device) {
+                config.gamepads.then_some(Use::Gamepad)
+            } else if fallback && is_keyboard(device) {
+                Some(Use::Fallback)
+            } else if !lid && lid::is_lid_switch(device) {
+                // Only the first one is followed.
+                lid = true;
+                Some(Use::Lid)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns whether `device` is a keyboard. Keyboards have letter keys, touchpads (and mice) don't.
+fn is_keyboard(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::KEY_A))
+}
+
+/// Returns whether `device` is a gamepad or joystick.
+fn is_gamepad(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::BTN_SOUTH) || keys.contains(Key::BTN_TRIGGER))
+}
+
+/// Starts listening on all of the builtin input devices that are present (and on gamepads, if
+/// enabled), and returns the number of devices found.
+///
+/// Which devices are builtin depends on the [`Quirks`](crate::quirks::Quirks). Since we don't
+/// support hotplug, listening on USB devices wouldn't work reliably. If none of the builtin
+/// devices is present and `keyboard-fallback` is enabled, all keyboards are used instead.
+pub fn spawn_listeners(shared: &Arc<Shared>) -> usize {
+    let config = shared.state.lock().unwrap().config.clone();
+    let gamepads = config.gamepads;
+    let devices = evdev::enumerate().collect::<Vec<_>>();
+    let uses = select(&config, shared.quirks, &devices);
+    if uses.contains(&Some(Use::Fallback)) {
+        log::warn!(
+            "none of the builtin input devices of the '{}' hardware profile was found, falling \
+             back to all keyboards",
+            shared.quirks.id
+        );
+    }
+    let mut listeners = Vec::new();
+    for ((path, device), used) in devices.into_iter().zip(uses) {
+        // Gamepads and the lid switch are taken care of separately.
+        if !matches!(used, Some(Use::Builtin | Use::Fallback)) {
+            continue;
+        }
+        let name = device.name().unwrap_or("unnamed keyboard").to_string();
+        log::info!("starting listener on {}: {name}", path.display());
+        listeners.push(Listener::start(shared, device, path, name, false));
+    }
+    if gamepads {
+        scan_gamepads(shared, &mut listeners);
+    }
+
+    let count = listeners.len();
+    if count != 0 || gamepads {
+        let shared = shared.clone();
+        thread::spawn(move || supervise(&shared, listeners, gamepads));
+    }
+    count
+}
+
+/// Returns the path and name of every input device that can be opened, sorted by path.
+pub fn available_devices() -> Vec<String> {
+    let mut devices = evdev::enumerate()
+        .map(|(path, device)| {
+            let name = device.name().unwrap_or("(unnamed)");
+            format!("{}: {name}", path.display())
+        })
+        .collect::<Vec<_>>();
+    devices.sort();
+    devices
+}
+
+/// Starts listening on gamepads that don't have a listener yet.
+fn scan_gamepads(shared: &Arc<Shared>, listeners: &mut Vec<Listener>) {
+    for (path, device) in evdev::enumerate() {
+        if !is_gamepad(&device) || listeners.iter().any(|listener| listener.path == path) {
+            continue;
+        }
+        let name = device.name().unwrap_or("unnamed gamepad").to_string();
+        log::info!("starting listener on gamepad {}: {name}", path.display());
+        listeners.push(Listener::start(shared, device, path, name, true));
+    }
+}
+
+impl Listener {
+    fn start(
+        shared: &Arc<Shared>,
+        device: Device,
+        path: PathBuf,
+        name: String,
+        gamepad: bool,
+    ) -> Self {
+        let id = register(shared, &name, Some(&path), Backend::Evdev);
+        Self {
+            thread: spawn(
+                shared.clone(),
+                device,
+                path.clone(),
+                name.clone(),
+                id,
+                gamepad,
+            ),
+            path,
+            name,
+            id,
+            gamepad,
+        }
+    }
+}
+
+fn spawn(
+    shared: Arc<Shared>,
+    mut device: Device,
+    path: PathBuf,
+    name: String,
+    id: usize,
+    gamepad: bool,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        let alive = Alive::new(&shared.listeners);
+        // Only returns on error.
+        let e = listen(&shared, &mut device, id).unwrap_err();
+        if gamepad {
+            // Most likely, the gamepad was disconnected. If it comes back, the supervisor will find
+            // it again.
+            log::info!("stopped listening on gamepad '{name}': {e}");
+            return;
+        }
+        log::warn!("error while fetching events for device '{name}': {e}; reopening it");
+        record_error(&shared, id);
+        drop(alive);
+        device = reopen(&path, &name);
+    })
+}
+
+/// Reports activity whenever `device` delivers events, until reading from it fails.
+fn listen(shared: &Shared, device: &mut Device, id: usize) -> io::Result<()> {
+    let kind = if is_keyboard(device) {
+        ActivityKind::Keyboard
+    } else {
+        ActivityKind::Pointer
+    };
+    let mut filter = if is_gamepad(device) {
+        GamepadFilter::new(device).map(Filter::Gamepad)
+    } else if kind == ActivityKind::Pointer {
+        TouchFilter::new(&shared.state.lock().unwrap().config, device).map(Filter::Touch)
+    } else {
+        None
+    };
+    let fd = device.as_raw_fd();
+    fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    // SAFETY: `epoll_create1` returns a new file descriptor that nothing else owns.
+    let epoll = unsafe { OwnedFd::from_raw_fd(epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?) };
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, 0);
+    epoll_ctl(epoll.as_raw_fd(), EpollOp::EpollCtlAdd, fd, &mut event)?;
+
+    let mut last_report: Option<Instant> = None;
+    // The first event since the last report, which is what woke up the backlight if the user was
+    // idle.
+    let mut first_event = None;
+    loop {
+        // Edge-triggered polling only wakes us up for new events, so the device has to be drained
+        // completely every time.
+        let mut received = 0;
+        let mut accepted = 0;
+        loop {
+            match device.fetch_events() {
+                Ok(events) => {
+                    for event in events {
+                        received += 1;
+                        let kind = match &mut filter {
+                            Some(filter) => match filter.accept(&event) {
+                                Some(kind) => kind,
+                                None => continue,
+                            },
+                            None => event.kind(),
+                        };
+                        accepted += 1;
+                        if first_event.is_none() && is_user_input(kind) {
+                            first_event = Some(kind);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if received != 0 {
+            record_events(shared, id, received, received - accepted);
+        }
+        if accepted != 0 && last_report.is_none_or(|at| at.elapsed() >= REPORT_INTERVAL) {
+            let cause = ActivityCause {
+                source: id,
+                event: first_event.take(),
+            };
+            shared.activity(kind, cause);
+            last_report = Some(Instant::now());
+        }
+
+        match epoll_wait(epoll.as_raw_fd(), &mut [EpollEvent::empty()], -1) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Decides which input events count as activity.
+enum Filter {
+    Touch(TouchFilter),
+    Gamepad(GamepadFilter),
+}
+
+impl Filter {
+    /// Returns the kind of event to attribute the activity to if `event` counts as activity.
+    fn accept(&mut self, event: &InputEvent) -> Option<InputEventKind> {
+        match self {
+            Self::Touch(filter) => filter.accept(event),
+            Self::Gamepad(filter) => filter.accept(event),
+        }
+    }
+}
+
+/// Ignores touchpad events that are unlikely to come from a finger using the touchpad.
+///
+/// With a minimum movement, the position of the first finger (`ABS_X`/`ABS_Y`) is compared to where
+/// it was when movement was last accepted, or to where the finger touched down, once per
+/// `SYN_REPORT` frame. So slow but steady movement still counts once it adds up, while a resting
+/// palm or a finger that jitters in place doesn't.
+///
+/// With a minimum pressure or touch size, events only count while at least one touch has reached
+/// them. Multitouch slots are tracked individually, and a touch keeps counting until it is lifted,
+/// even if it gets lighter towards the end. That way a sleeve brushing across the touchpad is
+/// ignored, but a finger isn't.
+///
+/// Presses of the touchpad's physical buttons always count.
+struct TouchFilter {
+    /// Minimum distance in device units (at least 1, so events without movement are ignored).
+    min_movement: Option<u32>,
+    min_pressure: Option<u32>,
+    min_size: Option<u32>,
+    /// The last known position of the first finger.
+    position: [Option<i32>; 2],
+    /// Where the finger was when movement was last accepted, or `None` right after a touch began.
+    anchor: Option<[i32; 2]>,
+    /// The multitouch slot that `ABS_MT_*` events currently refer to.
+    slot: usize,
+    touches: Vec<Touch>,
+    /// The first input event of the current frame.
+    frame: Option<InputEventKind>,
+}
+
+/// The state of a multitouch slot.
+#[derive(Clone, Copy, Default)]
+struct Touch {
+    pressure: u32,
+    size: u32,
+    /// Whether the touch has reached the minimum pressure and size.
+    firm: bool,
+}
+
+impl TouchFilter {
+    /// Creates a filter for `device` from the configuration, or returns `None` if no filtering is
+    /// configured.
+    fn new(config: &Config, device: &Device) -> Option<Self> {
+        let axes = device.supported_absolute_axes();
+        let supports = |axis, setting| {
+            let supported = axes.is_some_and(|axes| axes.contains(axis));
+            if !supported {
+                log::warn!(
+                    "ignoring `{setting}`, '{}' doesn't report {axis:?}",
+                    device.name().unwrap_or_default(),
+                );
+            }
+            supported
+        };
+        let min_pressure = config
+            .touchpad_min_pressure
+            .filter(|_| supports(AbsoluteAxisType::ABS_MT_PRESSURE, "touchpad-min-pressure"));
+        let min_size = config
+            .touchpad_min_size
+            .filter(|_| supports(AbsoluteAxisType::ABS_MT_TOUCH_MAJOR, "touchpad-min-size"));
+        let min_movement = config.touchpad_min_movement.map(|min| min.max(1));
+        if min_movement.is_none() && min_pressure.is_none() && min_size.is_none() {
+            return None;
+        }
+        Some(Self {
+            min_movement,
+            min_pressure,
+            min_size,
+            position: [None, None],
+            anchor: None,
+            slot: 0,
+            touches: Vec::new(),
+            frame: None,
+        })
+    }
+
+    /// Returns the kind of event to attribute the activity to if `event` counts as activity.
+    fn accept(&mut self, event: &InputEvent) -> Option<InputEventKind> {
+        let kind = event.kind();
+        let value = event.value();
+        match kind {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X) => self.position[0] = Some(value),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Y) => self.position[1] = Some(value),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_SLOT) => {
+                self.slot = usize::try_from(value).unwrap_or(0);
+            }
+            // A new tracking ID starts a new touch in the slot, and -1 ends it.
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TRACKING_ID) => {
+                *self.touch() = Touch::default();
+            }
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_PRESSURE) => {
+                self.touch().pressure = value.max(0).unsigned_abs();
+                self.update_firmness();
+            }
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TOUCH_MAJOR) => {
+                self.touch().size = value.max(0).unsigned_abs();
+                self.update_firmness();
+            }
+            // Lifting the finger ends the touch. The next one may start anywhere.
+            InputEventKind::Key(Key::BTN_TOUCH) if value == 0 => self.anchor = None,
+            InputEventKind::Key(Key::BTN_LEFT | Key::BTN_RIGHT | Key::BTN_MIDDLE) if value == 1 => {
+                return Some(kind);
+            }
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                return self.end_frame();
+            }
+            _ => {}
+        }
+        if self.frame.is_none() && is_user_input(kind) {
+            self.frame = Some(kind);
+        }
+        None
+    }
+
+    fn touch(&mut self) -> &mut Touch {
+        if self.touches.len() <= self.slot {
+            self.touches.resize(self.slot + 1, Touch::default());
+        }
+        &mut self.touches[self.slot]
+    }
+
+    fn update_firmness(&mut self) {
+        let (min_pressure, min_size) = (self.min_pressure, self.min_size);
+        let touch = self.touch();
+        touch.firm |= min_pressure.is_none_or(|min| touch.pressure >= min)
+            && min_size.is_none_or(|min| touch.size >= min);
+    }
+
+    /// Decides whether the frame that a `SYN_REPORT` just ended counts as activity.
+    fn end_frame(&mut self) -> Option<InputEventKind> {
+        let frame = self.frame.take();
+        let needs_firm_touch = self.min_pressure.is_some() || self.min_size.is_some();
+        if needs_firm_touch && !self.touches.iter().any(|touch| touch.firm) {
+            return None;
+        }
+        let Some(min_movement) = self.min_movement else {
+            return frame;
+        };
+        let [Some(x), Some(y)] = self.position else {
+            return None;
+        };
+        let Some([anchor_x, anchor_y]) = self.anchor else {
+            self.anchor = Some([x, y]);
+            return None;
+        };
+        let (dx, dy) = (x.abs_diff(anchor_x), y.abs_diff(anchor_y));
+        if dx.max(dy) < min_movement {
+            return None;
+        }
+        self.anchor = Some([x, y]);
+        let axis = if dx >= dy {
+            AbsoluteAxisType::ABS_X
+        } else {
+            AbsoluteAxisType::ABS_Y
+        };
+        Some(InputEventKind::AbsAxis(axis))
+    }
+}
+
+/// Ignores the jitter of a gamepad's analog sticks and triggers.
+///
+/// Worn sticks keep reporting tiny movements around their rest position, which would keep the
+/// backlight on for as long as the gamepad is connected. So an axis only counts once it moved by
+/// at least 1/16 of its range since it last counted. Buttons and the d-pad always count.
+struct GamepadFilter {
+    /// Minimum change of each axis, indexed by axis code.
+    thresholds: Vec<u32>,
+    /// The value of each axis when it last counted.
+    last: Vec<Option<i32>>,
+}
+
+impl GamepadFilter {
+    fn new(device: &Device) -> Option<Self> {
+        let info = match device.get_abs_state() {
+            Ok(info) => info,
+            Err(e) => {
+                log::warn!("failed to query the axes of a gamepad: {e}");
+                return None;
+            }
+        };
+        let thresholds = info
+            .iter()
+            .map(|axis| (axis.maximum.abs_diff(axis.minimum) / 16).max(axis.flat.unsigned_abs()))
+            .collect::<Vec<_>>();
+        Some(Self {
+            last: vec![None; thresholds.len()],
+            thresholds,
+        })
+    }
+
+    /// Returns the kind of event to attribute the activity to if `event` counts as activity.
+    fn accept(&mut self, event: &InputEvent) -> Option<InputEventKind> {
+        let kind = event.kind();
+        match kind {
+            InputEventKind::AbsAxis(axis) => {
+                let code = usize::from(axis.0);
+                let (Some(&threshold), Some(last)) =
+                    (self.thresholds.get(code), self.last.get_mut(code))
+                else {
+                    return Some(kind);
+                };
+                let value = event.value();
+                // The first event of an axis is compared to the rest position in the middle.
+                if last.is_some_and(|last| value.abs_diff(last) < threshold) {
+                    return None;
+                }
+                *last = Some(value);
+                Some(kind)
+            }
+            InputEventKind::Key(_) => Some(kind),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether an event of this kind describes the input itself, as opposed to metadata like
+/// `EV_SYN` or the scan codes and timestamps sent as `EV_MSC`.
+fn is_user_input(kind: InputEventKind) -> bool {
+    !matches!(
+        kind,
+        InputEventKind::Synchronization(_) | InputEventKind::Misc(_)
+    )
+}
+
+/// Describes an input event that woke up the backlight.
+///
+/// Buttons (like `BTN_TOUCH` or `BTN_LEFT`) and axes are named, but keyboard keys are not, since
+/// that would end up in the log and reveal what was typed.
+pub fn describe_event(kind: InputEventKind) -> String {
+    match kind {
+        InputEventKind::Key(key) if key.code() < Key::BTN_0.code() => "key".to_string(),
+        InputEventKind::Key(key) => format!("{key:?}"),
+        InputEventKind::RelAxis(axis) => format!("{axis:?}"),
+        InputEventKind::AbsAxis(axis) => format!("{axis:?}"),
+        InputEventKind::Switch(switch) => format!("{switch:?}"),
+        kind => format!("{kind:?}"),
+    }
+}
+
+/// Counts a working listener in [`Shared::listeners`] while it exists, even if the listener
+/// panics.
+pub struct Alive<'a>(&'a AtomicUsize);
+
+impl<'a> Alive<'a> {
+    pub fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for Alive<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Reopens the device at `path`, retrying with exponential backoff until it is back.
+///
+/// The device node might be reused for a different device in the meantime, so this also checks
+/// the name.
+fn reopen(path: &Path, name: &str) -> Device {
+    let mut delay = MIN_BACKOFF;
+    loop {
+        thread::sleep(delay);
+        match Device::open(path) {
+            Ok(device) if device.name() == Some(name) => {
+                log::info!("reopened {}: {name}", path.display());
+                return device;
+            }
+            Ok(_) => log::debug!("{} is now a different device", path.display()),
+            Err(e) => log::debug!("failed to reopen {}: {e}", path.display()),
+        }
+        delay = (delay * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Runs `command` and treats every line it prints as activity.
+///
+/// If the command exits, it is restarted with exponential backoff.
+pub fn spawn_command(shared: &Arc<Shared>, command: Vec<String>) {
+    let shared = shared.clone();
+    let id = register(&shared, "activity command", None, Backend::Command);
+    thread::spawn(move || {
+        let mut delay = MIN_BACKOFF;
+        loop {
+            log::info!("starting activity command {command:?}");
+            let start = Instant::now();
+            match run_command(&shared, &command, id) {
+                Ok(status) => log::warn!("activity command exited with {status}"),
+                Err(e) => log::warn!("failed to run activity command: {e}"),
+            }
+            record_error(&shared, id);
+            // Only back off if the command keeps failing quickly.
+            if start.elapsed() > MAX_BACKOFF {
+                delay = MIN_BACKOFF;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+fn run_command(shared: &Shared, command: &[String], id: usize) -> io::Result<process::ExitStatus> {
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let _alive = Alive::new(&shared.listeners);
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    // Reuse the line buffer instead of allocating a new one for every line.
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if stdout.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        log::debug!("activity command: {text}");
+        record_events(shared, id, 1, 0);
+        if text == PRESENCE_LINE {
+            if shared.backend_enabled(Backend::Command) {
+                shared.presence();
+            }
+            continue;
+        }
+        let cause = ActivityCause {
+            source: id,
+            event: None,
+        };
+        shared.activity(ActivityKind::External, cause);
+    }
+    child.wait()
+}
+
+/// Restarts listeners whose thread panicked, or exits the process if that fails.
+fn supervise(shared: &Arc<Shared>, mut listeners: Vec<Listener>, gamepads: bool) {
+    let mut last_scan = Instant::now();
+    loop {
+        thread::sleep(SUPERVISOR_INTERVAL);
+        if gamepads && last_scan.elapsed() >= GAMEPAD_SCAN_INTERVAL {
+            scan_gamepads(shared, &mut listeners);
+            last_scan = Instant::now();
+        }
+        listeners = listeners
+            .into_iter()
+            .filter_map(|listener| {
+                if !listener.thread.is_finished() {
+                    return Some(listener);
+                }
+                let Listener {
+                    path,
+                    name,
+                    id,
+                    gamepad,
+                    thread,
+                } = listener;
+                match thread.join() {
+                    // Listeners only return by panicking, or when a gamepad is disconnected.
+                    Ok(()) => None,
+                    Err(panic) => {
+                        record_error(shared, id);
+                        log::error!(
+                            "listener for '{name}' panicked: {}; restarting it",
+                            panic_message(&*panic)
+                        );
+                        match Device::open(&path) {
+                            Ok(device) => Some(Listener {
+                                thread: spawn(
+                                    shared.clone(),
+                                    device,
+                                    path.clone(),
+                                    name.clone(),
+                                    id,
+                                    gamepad,
+                                ),
+                                path,
+                                name,
+                                id,
+                                gamepad,
+                            }),
+                            // The next scan picks the gamepad up again if it's still there.
+                            Err(_) if gamepad => None,
+                            Err(e) => {
+                                // Let the service manager restart the whole daemon instead.
+                                log::error!("failed to reopen {}: {e}; exiting", path.display());
+                                process::exit(1);
+                            }
+                        }
+                    }
+                }
+            })
+            .collect();
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "<unknown>"
+    }
+}
@@ -0,0 +1,194 @@
+//! Installing and uninstalling the service (`keylightd install` and `keylightd uninstall`).
+//!
+//! The unit files and the OpenRC init script are the ones in `etc/`, built into the binary, with
+//! the path of the binary replaced by the one that is running. That way, the installed services
+//! stay in sync with the ones that are shipped, including their sandboxing options and ordering
+//! dependencies.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context};
+use nix::unistd::Uid;
+
+const SYSTEM_UNIT: &str = include_str!("../etc/keylightd.service");
+const USER_UNIT: &str = include_str!("../etc/keylightd-user.service");
+const SYSTEM_SOCKET: &str = include_str!("../etc/keylightd.socket");
+const USER_SOCKET: &str = include_str!("../etc/keylightd-user.socket");
+const OPENRC_SCRIPT: &str = include_str!("../etc/keylightd.openrc");
+
+/// Where the provided unit files expect the binary.
+const UNIT_EXEC_PATH: &str = "/usr/local/bin/keylightd";
+
+const UNIT_NAME: &str = "keylightd.service";
+const SOCKET_NAME: &str = "keylightd.socket";
+const OPENRC_NAME: &str = "keylightd";
+
+/// Which service manager instance the service is installed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// The system instance of systemd, running keylightd as root.
+    System,
+    /// The user's instance of systemd, running keylightd in the user session.
+    User,
+    /// OpenRC, running keylightd as root.
+    OpenRc,
+}
+
+impl Target {
+    /// Picks the target from the `--system`, `--user` and `--openrc` switches. Without any,
+    /// keylightd is installed as a system service when running as root, and as a user service
+    /// otherwise.
+    pub fn from_args(system: bool, user: bool, openrc: bool) -> anyhow::Result<Self> {
+        match (system, user, openrc) {
+            (true, false, false) => Ok(Self::System),
+            (false, true, false) => Ok(Self::User),
+            (false, false, true) => Ok(Self::OpenRc),
+            (false, false, false) if Uid::effective().is_root() => Ok(Self::System),
+            (false, false, false) => Ok(Self::User),
+            _ => bail!("only one of `--system`, `--user` and `--openrc` can be used"),
+        }
+    }
+
+    /// Returns the unit file or init script for this target, running the binary at `exe`.
+    pub fn unit(self, exe: &Path) -> String {
+        let template = match self {
+            Self::System => SYSTEM_UNIT,
+            Self::User => USER_UNIT,
+            Self::OpenRc => OPENRC_SCRIPT,
+        };
+        template.replace(UNIT_EXEC_PATH, &exe.to_string_lossy())
+    }
+
+    /// Returns the socket unit for this target, if it has one.
+    fn socket(self) -> Option<&'static str> {
+        match self {
+            Self::System => Some(SYSTEM_SOCKET),
+            Self::User => Some(USER_SOCKET),
+            Self::OpenRc => None,
+        }
+    }
+
+    /// Returns the path the unit file or init script is installed at.
+    pub fn path(self) -> anyhow::Result<PathBuf> {
+        let dir = match self {
+            Self::OpenRc => return Ok(Path::new("/etc/init.d").join(OPENRC_NAME)),
+            Self::System => PathBuf::from("/etc/systemd/system"),
+            Self::User => {
+                let config_home = env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .filter(|path| path.is_absolute());
+                match config_home {
+                    Some(dir) => dir,
+                    None => PathBuf::from(env::var_os("HOME").context("$HOME is not set")?)
+                        .join(".config"),
+                }
+                .join("systemd/user")
+            }
+        };
+        Ok(dir.join(UNIT_NAME))
+    }
+
+    fn systemctl(self, args: &[&str]) -> anyhow::Result<()> {
+        let mut command = process::Command::new("systemctl");
+        if self == Self::User {
+            command.arg("--user");
+        }
+        command.args(args);
+        run(command)
+    }
+}
+
+/// Runs `command`, failing if it does.
+fn run(mut command: process::Command) -> anyhow::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run {program}"))?;
+    if !status.success() {
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>();
+        bail!("`{program} {}` failed with {status}", args.join(" "));
+    }
+    Ok(())
+}
+
+fn openrc(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let mut command = process::Command::new(program);
+    command.arg(OPENRC_NAME).args(args);
+    run(command)
+}
+
+/// Writes the unit file or init script for `target`, and enables and starts the service.
+pub fn install(target: Target) -> anyhow::Result<()> {
+    match target {
+        Target::System | Target::User if !Path::new("/run/systemd/system").exists() => {
+            bail!("systemd isn't running on this machine (use `--openrc` for OpenRC)");
+        }
+        Target::OpenRc if !Path::new("/run/openrc").exists() => {
+            bail!("OpenRC isn't running on this machine");
+        }
+        _ => {}
+    }
+    let exe = env::current_exe().context("failed to find the keylightd binary")?;
+    let path = target.path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, target.unit(&exe))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    println!("wrote {}", path.display());
+    if let Some(socket) = target.socket() {
+        let path = path.with_file_name(SOCKET_NAME);
+        fs::write(&path, socket).with_context(|| format!("failed to write {}", path.display()))?;
+        println!("wrote {}", path.display());
+    }
+
+    match target {
+        Target::System | Target::User => {
+            target.systemctl(&["daemon-reload"])?;
+            target.systemctl(&["enable", "--now", SOCKET_NAME, UNIT_NAME])?;
+        }
+        Target::OpenRc => {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            openrc("rc-update", &["add", "default"])?;
+            openrc("rc-service", &["start"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Stops and disables the service of `target`, and removes its unit file or init script.
+pub fn uninstall(target: Target) -> anyhow::Result<()> {
+    let path = target.path()?;
+    if !path.exists() {
+        bail!("{} is not installed", path.display());
+    }
+    match target {
+        Target::System | Target::User => {
+            target.systemctl(&["disable", "--now", SOCKET_NAME, UNIT_NAME])?;
+        }
+        Target::OpenRc => {
+            openrc("rc-service", &["stop"])?;
+            openrc("rc-update", &["del", "default"])?;
+        }
+    }
+    fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+    println!("removed {}", path.display());
+    let socket = path.with_file_name(SOCKET_NAME);
+    if target.socket().is_some() && socket.exists() {
+        fs::remove_file(&socket)
+            .with_context(|| format!("failed to remove {}", socket.display()))?;
+        println!("removed {}", socket.display());
+    }
+    if target != Target::OpenRc {
+        target.systemctl(&["daemon-reload"])?;
+    }
+    Ok(())
+}
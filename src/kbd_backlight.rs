@@ -0,0 +1,117 @@
+//! A UPower-compatible `KbdBacklight` D-Bus object, so that desktop brightness sliders and OSD
+//! popups work with keylightd.
+//!
+//! The object implements `org.freedesktop.UPower.KbdBacklight` at
+//! `/org/freedesktop/UPower/KbdBacklight` under the bus name `io.keylightd` (on the system bus
+//! when running as root, on the session bus otherwise). Its brightness is the brightness used
+//! while active: setting it through D-Bus works like `keylightd set --brightness`, and the idle
+//! timeout keeps working as usual. Fading out when idle is not reported as a brightness change,
+//! since desktops would show an OSD popup for every one of them.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use nix::unistd::Uid;
+use zbus::{blocking::connection, fdo, interface, object_server::SignalEmitter};
+
+use crate::{
+    cli::BrightnessChange,
+    control::{self, Request, Response},
+    daemon::Shared,
+};
+
+const BUS_NAME: &str = "io.keylightd";
+const PATH: &str = "/org/freedesktop/UPower/KbdBacklight";
+const INTERFACE: &str = "org.freedesktop.UPower.KbdBacklight";
+
+/// How often the brightness is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct KbdBacklight {
+    shared: Arc<Shared>,
+}
+
+#[interface(name = "org.freedesktop.UPower.KbdBacklight")]
+impl KbdBacklight {
+    #[zbus(out_args("value"))]
+    fn get_max_brightness(&self) -> i32 {
+        100
+    }
+
+    #[zbus(out_args("value"))]
+    fn get_brightness(&self) -> i32 {
+        self.shared.state.lock().unwrap().active_brightness().into()
+    }
+
+    fn set_brightness(&self, value: i32) -> fdo::Result<()> {
+        let value = u8::try_from(value)
+            .map_err(|_| fdo::Error::InvalidArgs(format!("invalid brightness value {value}")))?;
+        let req = Request::Set {
+            brightness: Some(BrightnessChange::Set(value)),
+            timeout: None,
+            hardware_profile: None,
+            save: false,
+        };
+        match control::handle(&self.shared, req) {
+            Response::Error { message } => Err(fdo::Error::InvalidArgs(message)),
+            _ => Ok(()),
+        }
+    }
+
+    #[zbus(signal)]
+    async fn brightness_changed(emitter: &SignalEmitter<'_>, value: i32) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn brightness_changed_with_source(
+        emitter: &SignalEmitter<'_>,
+        value: i32,
+        source: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Spawns a thread that serves the `KbdBacklight` object and signals brightness changes.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        if let Err(e) = serve(shared) {
+            log::warn!("cannot provide the KbdBacklight D-Bus interface: {e}");
+        }
+    });
+}
+
+fn serve(shared: Arc<Shared>) -> zbus::Result<()> {
+    let builder = if Uid::effective().is_root() {
+        connection::Builder::system()?
+    } else {
+        connection::Builder::session()?
+    };
+    let conn = builder
+        .name(BUS_NAME)?
+        .serve_at(
+            PATH,
+            KbdBacklight {
+                shared: shared.clone(),
+            },
+        )?
+        .build()?;
+    log::info!("providing the KbdBacklight D-Bus interface as {BUS_NAME}");
+
+    let mut brightness = shared.state.lock().unwrap().active_brightness();
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let new = shared.state.lock().unwrap().active_brightness();
+        if new == brightness {
+            continue;
+        }
+        brightness = new;
+        let value = i32::from(brightness);
+        conn.emit_signal(None::<()>, PATH, INTERFACE, "BrightnessChanged", &value)?;
+        // "external" means that the change didn't come from a hardware key, so desktops won't
+        // show an OSD popup for it.
+        conn.emit_signal(
+            None::<()>,
+            PATH,
+            INTERFACE,
+            "BrightnessChangedWithSource",
+            &(value, "external"),
+        )?;
+    }
+}
@@ -0,0 +1,76 @@
+//! Learning a timeout from the user's typical pauses.
+//!
+//! Every gap between two input events is recorded in a histogram with 1-second buckets. Gaps
+//! longer than [`MAX_PAUSE`] are assumed to mean that the user left, and are ignored. Once enough
+//! pauses have been seen, the suggested timeout is the one that would have kept the backlight on
+//! through [`PERCENTILE`] percent of them.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest gap between input events that still counts as a pause instead of leaving.
+const MAX_PAUSE: Duration = Duration::from_secs(600);
+
+/// Shortest gap that counts as a pause. Continuous input is reported several times per second.
+const MIN_PAUSE: Duration = Duration::from_secs(1);
+
+/// Number of pauses needed before a timeout is suggested.
+const MIN_SAMPLES: u32 = 50;
+
+/// Percentage of pauses the suggested timeout should cover.
+const PERCENTILE: u32 = 95;
+
+/// What to do with the learned timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LearnTimeout {
+    /// Don't learn a timeout.
+    #[default]
+    Off,
+    /// Show the learned timeout in `keylightd status`.
+    Suggest,
+    /// Also use the learned timeout instead of the configured one.
+    Apply,
+}
+
+/// Histogram of the pauses between input events.
+pub struct Pauses {
+    /// Number of pauses per length in seconds.
+    counts: Vec<u32>,
+    total: u32,
+}
+
+impl Pauses {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; MAX_PAUSE.as_secs() as usize + 1],
+            total: 0,
+        }
+    }
+
+    /// Records the gap between two input events.
+    pub fn record(&mut self, gap: Duration) {
+        if (MIN_PAUSE..=MAX_PAUSE).contains(&gap) {
+            self.counts[gap.as_secs() as usize] += 1;
+            self.total += 1;
+        }
+    }
+
+    /// Returns the suggested timeout in seconds, if enough pauses were recorded.
+    pub fn suggestion(&self) -> Option<u32> {
+        if self.total < MIN_SAMPLES {
+            return None;
+        }
+        let needed = (self.total * PERCENTILE).div_ceil(100);
+        let mut seen = 0;
+        for (secs, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= needed {
+                // Pauses in this bucket last up to a second longer.
+                return Some(secs as u32 + 1);
+            }
+        }
+        None
+    }
+}
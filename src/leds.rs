@@ -0,0 +1,235 @@
+//! Policies for the side LEDs.
+//!
+//! The EC normally uses the LEDs on both sides of the laptop to show the charging state. While a
+//! policy applies, keylightd takes over control of them, and hands it back to the EC afterwards.
+//!
+//! In order of precedence, the policies are:
+//!
+//! - pulsing red while the battery is low (`low-battery-led`),
+//! - fading in during the wake-up alarm (`sunrise.side-leds`),
+//! - showing the charging state with custom colors (`charge-leds`).
+//!
+//! With `warm-white`, white is gradually shifted to amber in the evening, if the LEDs have both.
+
+use std::{io, sync::Arc, thread, time::Duration};
+
+use crate::{
+    battery::Battery,
+    command::{LedBrightnesses, LedColor, LedControl, LedId},
+    config::{Config, SideLedColor, WarmWhite},
+    daemon::Shared,
+    worker::Priority,
+};
+
+/// How often the battery is checked, and how fast the LEDs pulse.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns whether any side LED policy is enabled in `config`.
+pub fn enabled(config: &Config) -> bool {
+    config.low_battery_led.is_some()
+        || config.charge_leds.is_some()
+        || config
+            .sunrise
+            .as_ref()
+            .is_some_and(|sunrise| sunrise.side_leds)
+}
+
+/// Spawns a thread that applies the side LED policies.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        if let Err(e) = run(&shared) {
+            log::error!("side LED control disabled: {e}");
+        }
+    });
+}
+
+/// What the side LEDs should show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Controlled by the EC.
+    Auto,
+    LowBattery,
+    /// Fading in, with the progress in percent.
+    Sunrise(u8),
+    Solid(SideLedColor),
+    /// White blended with amber, with the share of amber in percent.
+    WarmWhite(u8),
+}
+
+fn run(shared: &Shared) -> io::Result<()> {
+    // The maximum brightness of each color supported by the side LEDs.
+    let max = shared
+        .ec
+        .submit(Priority::Normal, None, |ec| LedId::LEFT.capabilities(ec))?;
+
+    let battery = shared.ec.submit(Priority::Normal, None, Battery::read)?;
+    log::info!(
+        "battery: {} {}, {}% charged",
+        battery.manufacturer,
+        battery.model,
+        battery.percent()
+    );
+
+    let can_blend = max.get(LedColor::WHITE) != 0 && max.get(LedColor::AMBER) != 0;
+    if !can_blend && shared.state.lock().unwrap().config.warm_white.is_some() {
+        log::warn!("ignoring `warm-white`, the side LEDs cannot show both white and amber");
+    }
+
+    let mut mode = Mode::Auto;
+    let mut lit = false;
+    loop {
+        let battery = shared.ec.submit(Priority::Normal, None, Battery::read)?;
+        let (config, sunrise, suspended) = {
+            let state = shared.state.lock().unwrap();
+            (state.config.clone(), state.sunrise, state.suspended)
+        };
+        // The EC shows the sleep state while the machine is suspended or hibernated.
+        let mut new_mode = if suspended {
+            Mode::Auto
+        } else {
+            select_mode(&config, &battery, sunrise)
+        };
+        if let (Mode::Solid(SideLedColor::White), Some(warm), true) =
+            (new_mode, &config.warm_white, can_blend)
+        {
+            new_mode = Mode::WarmWhite(warmth(warm));
+        }
+        // The sunrise and the color temperature change the mode every now and then, so only log
+        // when they start and end.
+        let gradual = matches!(
+            (mode, new_mode),
+            (Mode::Sunrise(_), Mode::Sunrise(_)) | (Mode::WarmWhite(_), Mode::WarmWhite(_))
+        );
+        if new_mode != mode && !gradual {
+            log::info!(
+                "battery at {}%, side LEDs: {mode:?} -> {new_mode:?}",
+                battery.percent()
+            );
+        }
+
+        match new_mode {
+            Mode::LowBattery => {
+                lit = !lit;
+                let brightness = if lit {
+                    color(max, SideLedColor::Red)
+                } else {
+                    LedBrightnesses::default()
+                };
+                set_side_leds(shared, move |led| LedControl::manual(led, brightness))?;
+            }
+            Mode::Sunrise(progress) if new_mode != mode => {
+                let brightness = sunrise_color(max, progress);
+                set_side_leds(shared, move |led| LedControl::manual(led, brightness))?;
+            }
+            Mode::WarmWhite(warmth) if new_mode != mode => {
+                let white = LedBrightnesses::single(LedColor::WHITE, max.get(LedColor::WHITE));
+                let amber = LedBrightnesses::single(LedColor::AMBER, max.get(LedColor::AMBER));
+                let brightness = white.mix(amber, warmth);
+                set_side_leds(shared, move |led| LedControl::manual(led, brightness))?;
+            }
+            Mode::Solid(c) if new_mode != mode => {
+                let brightness = color(max, c);
+                set_side_leds(shared, move |led| LedControl::manual(led, brightness))?;
+            }
+            Mode::Auto if new_mode != mode => {
+                set_side_leds(shared, LedControl::auto)?;
+            }
+            Mode::Sunrise(_) | Mode::Solid(_) | Mode::WarmWhite(_) | Mode::Auto => {}
+        }
+        mode = new_mode;
+
+        thread::sleep(INTERVAL);
+    }
+}
+
+fn select_mode(config: &Config, battery: &Battery, sunrise: Option<u8>) -> Mode {
+    if let Some(threshold) = config.low_battery_led {
+        if battery.is_discharging() && battery.percent() < threshold {
+            return Mode::LowBattery;
+        }
+    }
+    if let Some(progress) = sunrise {
+        if config
+            .sunrise
+            .as_ref()
+            .is_some_and(|sunrise| sunrise.side_leds)
+        {
+            return Mode::Sunrise(progress);
+        }
+    }
+    if let Some(leds) = &config.charge_leds {
+        if leds.hours.as_ref().is_none_or(|hours| hours.contains_now()) {
+            return Mode::Solid(if battery.is_charging() {
+                leds.charging
+            } else if battery.is_ac_present() {
+                leds.full
+            } else {
+                leds.discharging
+            });
+        }
+    }
+    Mode::Auto
+}
+
+/// Returns the share of amber in white right now, in percent.
+///
+/// The shift happens during the `transition` minutes before the start and end of the warm hours.
+fn warmth(warm: &WarmWhite) -> u8 {
+    let transition = Duration::from_secs(u64::from(warm.transition) * 60);
+    let remaining = warm.hours.until_change();
+    // How far along the transition towards the next change is.
+    let along = if remaining >= transition {
+        0
+    } else {
+        (100 - remaining.as_secs() * 100 / transition.as_secs()) as u8
+    };
+    if warm.hours.contains_now() {
+        100 - along
+    } else {
+        along
+    }
+}
+
+/// Returns the brightnesses that make the side LEDs show `color` at full brightness.
+fn color(max: LedBrightnesses, color: SideLedColor) -> LedBrightnesses {
+    let color = match color {
+        SideLedColor::Off => return LedBrightnesses::default(),
+        SideLedColor::Red => LedColor::RED,
+        SideLedColor::Green => LedColor::GREEN,
+        SideLedColor::Blue => LedColor::BLUE,
+        SideLedColor::Yellow => LedColor::YELLOW,
+        SideLedColor::White => LedColor::WHITE,
+        SideLedColor::Amber => LedColor::AMBER,
+    };
+    if max.get(color) == 0 {
+        log::warn!("the side LEDs cannot show {}", color.name());
+    }
+    LedBrightnesses::single(color, max.get(color))
+}
+
+/// Returns the brightnesses for the sunrise at `progress` percent: amber, or white if the LEDs
+/// can't show amber.
+fn sunrise_color(max: LedBrightnesses, progress: u8) -> LedBrightnesses {
+    let color = if max.get(LedColor::AMBER) != 0 {
+        LedColor::AMBER
+    } else {
+        LedColor::WHITE
+    };
+    let level = u16::from(max.get(color)) * u16::from(progress) / 100;
+    LedBrightnesses::single(color, level as u8)
+}
+
+/// Sends the command returned by `control` to both side LEDs.
+fn set_side_leds(
+    shared: &Shared,
+    control: impl Fn(LedId) -> LedControl + Send + Sync + 'static,
+) -> io::Result<()> {
+    shared
+        .ec
+        .submit(Priority::Normal, Some("side-leds"), move |ec| {
+            for led in [LedId::LEFT, LedId::RIGHT] {
+                ec.command(control(led))?;
+            }
+            Ok(())
+        })
+}
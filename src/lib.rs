@@ -0,0 +1,12 @@
+//! The parts of keylightd that talk to the embedded controller, and the fade logic.
+//!
+//! These are split into a library so that the response parsing can be fuzzed (see `fuzz/`), and
+//! the fades can be tested (see `tests/`). The library is not meant to be used by anything else and
+//! has no stable API.
+
+pub mod battery;
+pub mod command;
+pub mod ec;
+pub mod fade;
+pub mod thermal;
+pub mod wire;
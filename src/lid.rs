@@ -0,0 +1,70 @@
+//! Following the lid switch.
+//!
+//! Nobody can see the keyboard with the lid closed, so the backlight is turned off right away
+//! instead of after the timeout, and opening the lid counts as activity. The switch is an evdev
+//! device of its own (usually called "Lid Switch"), so it belongs to the evdev backend.
+
+use std::{io, sync::Arc, thread};
+
+use evdev::{Device, InputEventKind, SwitchType};
+
+use crate::{
+    cli::Backend,
+    daemon::{ActivityCause, ActivityKind, Event, Shared},
+    input::{self, Alive},
+};
+
+/// Returns whether `device` is a lid switch.
+pub fn is_lid_switch(device: &Device) -> bool {
+    device
+        .supported_switches()
+        .is_some_and(|switches| switches.contains(SwitchType::SW_LID))
+}
+
+/// Spawns a thread that follows the lid switch, if there is one.
+pub fn spawn(shared: Arc<Shared>) {
+    let Some((path, mut device)) = evdev::enumerate().find(|(_, device)| is_lid_switch(device))
+    else {
+        log::debug!("no lid switch found");
+        return;
+    };
+    let name = device.name().unwrap_or("lid switch").to_string();
+    log::info!("following the lid switch {}: {name}", path.display());
+    thread::spawn(move || {
+        let id = input::register(&shared, &name, Some(&path), Backend::Evdev);
+        if let Err(e) = watch(&shared, &mut device, id) {
+            log::error!("cannot read the lid switch: {e}");
+            input::record_error(&shared, id);
+        }
+        shared.update(|state| state.lid_closed = None);
+    });
+}
+
+fn watch(shared: &Shared, device: &mut Device, id: usize) -> io::Result<()> {
+    let _alive = Alive::new(&shared.listeners);
+    // Only changes are reported, so start out with the current state.
+    let closed = device.get_switch_state()?.contains(SwitchType::SW_LID);
+    shared.publish(if closed {
+        Event::LidClosed
+    } else {
+        Event::LidOpened
+    });
+    loop {
+        for event in device.fetch_events()? {
+            let InputEventKind::Switch(SwitchType::SW_LID) = event.kind() else {
+                continue;
+            };
+            input::record_events(shared, id, 1, 0);
+            if event.value() != 0 {
+                shared.publish(Event::LidClosed);
+            } else {
+                shared.publish(Event::LidOpened);
+                let cause = ActivityCause {
+                    source: id,
+                    event: Some(event.kind()),
+                };
+                shared.activity(ActivityKind::Keyboard, cause);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Lightbar support for Chromebooks.
+//!
+//! Some Chromebooks (like the Chromebook Pixel) have a lightbar on the lid that the EC animates on
+//! its own. With `[lightbar]`, keylightd switches it between two patterns as the keyboard backlight
+//! turns on and off. The EC is asked whether it has a lightbar first, so the same configuration
+//! also works on machines without one.
+
+use std::{io, sync::Arc, thread, time::Duration};
+
+use crate::{
+    cli::LightbarPattern,
+    command::{Lightbar as LightbarCmd, LightbarSeq, LightbarVersion},
+    config::Lightbar,
+    daemon::Shared,
+    ec::EmbeddedController,
+    worker::Priority,
+};
+
+/// How often the backlight state is checked.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// Checks that the EC has a lightbar and spawns a thread that drives it.
+pub fn spawn(shared: Arc<Shared>, settings: Lightbar) -> io::Result<()> {
+    let Some(version) = shared
+        .ec
+        .submit(Priority::Normal, None, LightbarVersion::probe)?
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the EC has no lightbar",
+        ));
+    };
+    log::info!("lightbar interface version {}", version.num);
+    thread::spawn(move || {
+        if let Err(e) = run(&shared, &settings) {
+            log::error!("lightbar control disabled: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn run(shared: &Shared, settings: &Lightbar) -> io::Result<()> {
+    if let Some(brightness) = settings.brightness {
+        shared.ec.command(LightbarCmd::SetBrightness(brightness))?;
+    }
+
+    let mut shown = None;
+    loop {
+        let (lit, suspended) = {
+            let state = shared.state.lock().unwrap();
+            (state.target_brightness() != 0, state.suspended)
+        };
+        // The EC shows the sleep state while the machine is suspended or hibernated.
+        let pattern = if suspended {
+            LightbarPattern::Auto
+        } else if lit {
+            settings.active
+        } else {
+            settings.idle
+        };
+        if shown != Some(pattern) {
+            log::debug!("lightbar: {shown:?} -> {pattern:?}");
+            shared
+                .ec
+                .submit(Priority::Normal, Some("lightbar"), move |ec| {
+                    show(ec, pattern)
+                })?;
+            shown = Some(pattern);
+        }
+        thread::sleep(INTERVAL);
+    }
+}
+
+/// Switches the lightbar to `pattern`.
+pub fn show(ec: &EmbeddedController, pattern: LightbarPattern) -> io::Result<()> {
+    let seq = match pattern {
+        LightbarPattern::Off => {
+            ec.command(LightbarCmd::Off)?;
+            return Ok(());
+        }
+        LightbarPattern::S0 => LightbarSeq::S0,
+        LightbarPattern::S3 => LightbarSeq::S3,
+        LightbarPattern::S5 => LightbarSeq::S5,
+        LightbarPattern::Tap => LightbarSeq::TAP,
+        LightbarPattern::Konami => LightbarSeq::KONAMI,
+        LightbarPattern::Auto => LightbarSeq::RUN,
+    };
+    ec.command(LightbarCmd::On)?;
+    ec.command(LightbarCmd::Seq(seq))?;
+    Ok(())
+}
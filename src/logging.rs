@@ -0,0 +1,47 @@
+//! Logging setup and runtime log level changes.
+//!
+//! The log level of the daemon can be changed while it is running with `keylightd log-level`, or
+//! toggled between the default and a verbose level by sending it `SIGUSR1`. That way, debug output
+//! of a misbehaving state transition can be captured without restarting the daemon (and losing
+//! the state that triggered the problem).
+
+use log::LevelFilter;
+
+/// Returns the log level used at startup.
+pub fn default_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/// Initializes the logger with the [`default_level`].
+pub fn init() {
+    // Let everything from keylightd through the logger's own filter, and filter by the global
+    // maximum level instead, which can be changed later.
+    env_logger::builder()
+        .filter_module(env!("CARGO_PKG_NAME"), LevelFilter::Trace)
+        .init();
+    log::set_max_level(default_level());
+}
+
+/// Changes the log level.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+    log::info!("log level set to {}", level.as_str().to_lowercase());
+}
+
+/// Switches between the [`default_level`] and a more verbose level.
+pub fn toggle() {
+    let default = default_level();
+    if log::max_level() == default {
+        set_level(if default < LevelFilter::Debug {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Trace
+        });
+    } else {
+        set_level(default);
+    }
+}
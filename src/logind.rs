@@ -0,0 +1,136 @@
+//! Activity from the idle hint that the desktop maintains in logind.
+//!
+//! Desktops like GNOME and KDE tell logind when the session becomes idle (`loginctl` shows it as
+//! `IdleHint`), and tools like `xss-lock` or `swayidle` can do the same on other desktops. The
+//! hint takes all input the desktop sees into account, including external keyboards and mice, but
+//! it changes after the desktop's own idle delay instead of keylightd's `timeout`.
+//!
+//! logind doesn't reliably signal changes of the combined hint of all sessions, so it is polled.
+//!
+//! Independently of the backend, logind also announces when the machine suspends and resumes,
+//! and when it shuts down. The daemon holds a delay inhibitor lock, so that it can hand the LEDs
+//! back to the EC before that happens.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use zbus::{
+    blocking::{Connection, Proxy},
+    zvariant::{OwnedFd, OwnedValue},
+};
+
+use crate::{
+    cli::Backend,
+    command::FpLedLevel,
+    daemon::{self, Event, Shared},
+    input::{self, Alive},
+};
+
+/// How often the idle hint is read.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spawns a thread that follows logind's idle hint.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        let id = input::register(&shared, "logind", None, Backend::Logind);
+        if let Err(e) = watch(&shared, id) {
+            log::error!("cannot read the idle hint from logind: {e}");
+            input::record_error(&shared, id);
+        }
+        shared.update(|state| state.idle_hints.remove(&Backend::Logind));
+    });
+}
+
+/// Spawns a thread that turns the backlight off when the machine suspends, and restores the LEDs
+/// and the backlight when it shuts down.
+///
+/// `power_level` is the original brightness level of the power LED.
+pub fn spawn_sleep_watcher(shared: Arc<Shared>, power_level: FpLedLevel) {
+    thread::spawn(move || {
+        if let Err(e) = watch_sleep(&shared, power_level) {
+            log::warn!("cannot follow suspend and resume through logind: {e}");
+        }
+    });
+}
+
+fn watch_sleep(shared: &Arc<Shared>, power_level: FpLedLevel) -> zbus::Result<()> {
+    let conn = Connection::system()?;
+    let proxy = Proxy::new(
+        &conn,
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+    )?;
+    let signals = proxy.receive_all_signals()?;
+    let mut inhibitor = inhibit(&proxy);
+    for msg in signals {
+        let header = msg.header();
+        let Some(member) = header.member() else {
+            continue;
+        };
+        match member.as_str() {
+            // `true` right before suspending, `false` after resuming.
+            "PrepareForSleep" => {
+                if msg.body().deserialize()? {
+                    if let Err(e) = daemon::prepare_for_sleep(shared, power_level) {
+                        log::error!("failed to prepare the LEDs for sleep: {e}");
+                    }
+                    // Let the machine sleep.
+                    drop(inhibitor.take());
+                } else {
+                    shared.publish(Event::Resume);
+                    inhibitor = inhibit(&proxy);
+                }
+            }
+            // `true` right before shutting down, `false` if that was cancelled.
+            "PrepareForShutdown" => {
+                if msg.body().deserialize()? {
+                    // Exiting releases the inhibitor.
+                    daemon::shut_down(shared, power_level, "shutting down");
+                }
+                if inhibitor.is_none() {
+                    inhibitor = inhibit(&proxy);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Takes a delay inhibitor lock on sleep and shutdown, which delays them until it is released
+/// (or for logind's `InhibitDelayMaxSec` at most).
+fn inhibit(proxy: &Proxy<'_>) -> Option<OwnedFd> {
+    let result = proxy.call(
+        "Inhibit",
+        &(
+            "sleep:shutdown",
+            "keylightd",
+            "Handing the LEDs and keyboard backlight back to the EC",
+            "delay",
+        ),
+    );
+    match result {
+        Ok(fd) => Some(fd),
+        Err(e) => {
+            log::warn!("failed to take a logind inhibitor, the LEDs might not be restored: {e}");
+            None
+        }
+    }
+}
+
+fn watch(shared: &Shared, id: usize) -> zbus::Result<()> {
+    let conn = Connection::system()?;
+    let proxy = Proxy::new(
+        &conn,
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.DBus.Properties",
+    )?;
+    let _alive = Alive::new(&shared.listeners);
+    loop {
+        let value: OwnedValue =
+            proxy.call("Get", &("org.freedesktop.login1.Manager", "IdleHint"))?;
+        shared.idle_hint(Backend::Logind, id, bool::try_from(value)?);
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -1,158 +1,593 @@
 use std::{
-    io,
-    sync::{Arc, Condvar, Mutex},
-    thread,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    process::ExitCode,
 };
 
-use argh::FromArgs;
-use command::{GetKeyboardBacklight, SetKeyboardBacklight};
+use anyhow::{anyhow, bail, Context};
+use cli::{
+    Args, Backend, BackendCommand, Command, EcCommand, Led, LedCommand, LedMode, LightbarCommand,
+};
+use command::{ConsoleRead, GetVersion, LedColor, LedControl, LedId, Lightbar, LightbarVersion};
+use config::Config;
+use control::{OffLatch, Request, Response};
 use ec::EmbeddedController;
+use error::KeylightdError;
+use keylightd::{battery, command, ec, fade, thermal, wire};
 
-use crate::command::{LedBrightnesses, LedControl, LedFlags, LedId};
-
-mod command;
-mod ec;
+mod activation;
+#[cfg(feature = "als")]
+mod ambient;
+mod background;
+mod cli;
+mod clock;
+mod completions;
+mod config;
+mod control;
+mod daemon;
+mod diagnose;
+mod dirs;
+mod dock;
+mod dpms;
+mod drm;
+mod emulator;
+mod error;
+mod history;
+mod hooks;
+#[cfg(feature = "socket")]
+mod http;
+mod iio;
+mod input;
+mod install;
+#[cfg(feature = "dbus")]
+mod kbd_backlight;
+mod learning;
+mod leds;
+mod lid;
+mod lightbar;
+mod logging;
+#[cfg(feature = "dbus")]
+mod logind;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod persist;
+mod pidfile;
+mod power;
+mod proximity;
+mod quirks;
+mod recovery;
+mod remote;
+mod rules;
+mod schedule;
+mod setup;
+mod statefile;
+mod sun;
+mod sunrise;
+mod systemd_backlight;
+#[cfg(feature = "dbus")]
+mod theme;
+#[cfg(feature = "dbus")]
+mod upower;
+#[cfg(feature = "socket")]
+mod varlink;
+mod wakelock;
+mod wayland;
+mod worker;
 
-/// keylightd - automatic keyboard backlight daemon for Framework laptops
-#[derive(Debug, FromArgs)]
-struct Args {
-    /// brightness level when active (0-100) [default=30]
-    #[argh(option, default = "30", from_str_fn(parse_brightness))]
-    brightness: u8,
+/// Stand-in for the UPower integration when keylightd is built without D-Bus support.
+#[cfg(not(feature = "dbus"))]
+mod upower {
+    use std::io;
 
-    /// activity timeout in seconds [default=10]
-    #[argh(option, default = "10")]
-    timeout: u32,
+    /// Can never be constructed, since there is no UPower to talk to.
+    #[derive(Clone)]
+    pub enum KbdBacklight {}
 
-    /// also control the power LED in the fingerprint module
-    #[argh(switch)]
-    power: bool,
-}
+    impl KbdBacklight {
+        pub fn get(&self) -> io::Result<u8> {
+            match *self {}
+        }
 
-fn parse_brightness(s: &str) -> Result<u8, String> {
-    let brightness = s.parse::<u8>().map_err(|e| e.to_string())?;
-    if brightness > 100 {
-        return Err("invalid brightness value {brightness} (valid range: 0-100)".into());
+        pub fn set(&self, _percent: u8) -> io::Result<()> {
+            match *self {}
+        }
     }
-    Ok(brightness)
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::builder()
-        .filter_module(
-            env!("CARGO_PKG_NAME"),
-            if cfg!(debug_assertions) {
-                log::LevelFilter::Debug
-            } else {
-                log::LevelFilter::Info
-            },
-        )
-        .init();
+fn main() -> ExitCode {
+    logging::init();
 
     let args: Args = argh::from_env();
     log::debug!("args={:?}", args);
+    let result = run(args);
+    pidfile::remove();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => KeylightdError::classify(e).report(),
+    }
+}
 
-    let ec = EmbeddedController::open()?;
-    let fade_to = |target: u8| -> io::Result<()> {
-        let resp = ec.command(GetKeyboardBacklight)?;
-        let mut cur = if resp.enabled != 0 { resp.percent } else { 0 };
-        while cur != target {
-            if cur > target {
-                cur -= 1;
-            } else {
-                cur += 1;
+fn run(args: Args) -> anyhow::Result<()> {
+    let ec_device = args.ec_device.as_deref().map(Path::new);
+    match args.command {
+        Command::Run(args) => {
+            activation::init();
+            // Forking is only safe while there is a single thread, so this comes before anything
+            // that starts one.
+            if args.background {
+                background::detach()?;
             }
-
-            if args.power {
-                // The power LED cannot be faded from software (although the beta BIOS apparently
-                // has a switch for dimming it, so maybe it'll work with the next BIOS update).
-                // So instead, we treat 0 as off and set it back to auto for any non-zero value.
-                if cur == 0 {
-                    ec.command(LedControl {
-                        led_id: LedId::POWER,
-                        flags: LedFlags::NONE,
-                        brightness: LedBrightnesses::default(),
-                    })?;
-                } else if cur == 1 {
-                    ec.command(LedControl {
-                        led_id: LedId::POWER,
-                        flags: LedFlags::AUTO,
-                        brightness: LedBrightnesses::default(),
-                    })?;
+            let pid_path = args
+                .pidfile
+                .as_ref()
+                .map_or_else(pidfile::path, PathBuf::from);
+            pidfile::create(&pid_path)?;
+            if let Some(clock) = clock::from_env().map_err(KeylightdError::config)? {
+                log::warn!(
+                    "simulating time: starting at {} seconds since the epoch, {}x as fast",
+                    clock.start_unix(),
+                    clock.speed()
+                );
+                clock::set(Box::new(clock));
+            }
+            if args.fail_fast && args.best_effort {
+                let e = anyhow!("`--fail-fast` and `--best-effort` can't be used together");
+                return Err(KeylightdError::config(e).into());
+            }
+            let mut config =
+                Config::load(args.config.as_deref()).map_err(KeylightdError::config)?;
+            config.apply_args(&args);
+            daemon::run(
+                open_ec(ec_device)?,
+                config,
+                Config::save_path(args.config.as_deref()),
+            )
+        }
+        Command::Status(args) => {
+            let Response::Status(status) = control::request(&Request::Status)? else {
+                bail!("unexpected response from daemon");
+            };
+            if args.json {
+                println!("{}", serde_json::to_string(&status)?);
+                return Ok(());
+            }
+            let state = match (status.off, status.active) {
+                (Some(OffLatch::UntilActivity), _) => "off until activity",
+                (Some(OffLatch::UntilEnabled), _) => "off until `keylightd on`",
+                (None, _) if status.night_off => "off for the night",
+                (None, Some(true)) => "active",
+                (None, Some(false)) => "idle",
+                (None, None) => "starting",
+            };
+            println!("state:      {state}");
+            println!("brightness: {}%", status.brightness);
+            if let Some(backlight) = status.backlight {
+                println!("backlight:  {backlight}%");
+            }
+            println!("timeout:    {}s", status.timeout);
+            if let Some(timeout) = status.suggested_timeout {
+                println!("learned:    {timeout}s");
+            }
+            println!("power LED:  {}", if status.power { "on" } else { "off" });
+            if let Some(present) = status.present {
+                let presence = if present { "detected" } else { "none" };
+                println!("presence:   {presence}");
+            }
+            if let Some(process) = &status.wake_lock {
+                println!("kept on by: {process}");
+            }
+            if let Some(progress) = status.sunrise {
+                println!("sunrise:    {progress}%");
+            }
+            if let Some(sessions) = status.remote_sessions {
+                println!("remote:     {sessions} sessions");
+            }
+            if let Some(docked) = status.docked {
+                println!("docked:     {}", if docked { "yes" } else { "no" });
+            }
+            if let Some(on) = status.display_on {
+                println!("display:    {}", if on { "on" } else { "off" });
+            }
+            if let Some(closed) = status.lid_closed {
+                println!("lid:        {}", if closed { "closed" } else { "open" });
+            }
+            if let Some(rule) = &status.rule {
+                println!("rule:       {rule}");
+            }
+            let backends = status
+                .backends
+                .iter()
+                .map(|b| match (b.enabled, b.idle) {
+                    (false, _) => format!("{} (disabled)", b.backend.name()),
+                    (true, Some(true)) => format!("{} (idle)", b.backend.name()),
+                    (true, _) => b.backend.name().to_string(),
+                })
+                .collect::<Vec<_>>();
+            if !backends.is_empty() {
+                println!("backends:   {}", backends.join(", "));
+            }
+            if let Some(wake) = &status.wake {
+                let event = wake
+                    .event
+                    .as_deref()
+                    .map_or(String::new(), |e| format!(" ({e})"));
+                println!("woken by:   {}{event}, {}s ago", wake.source, wake.secs_ago);
+            }
+            println!("version:    {}", status.version);
+            if let Some(version) = &status.ec_version {
+                println!("EC:         {version}");
+            }
+            if let Some(problem) = &status.firmware_bug {
+                println!("            known to {problem}, please update the BIOS");
+            }
+            for (i, fan) in status.fans.iter().enumerate() {
+                if fan.stalled {
+                    println!("fan {i}:      stalled");
+                } else {
+                    println!("fan {i}:      {} RPM", fan.rpm);
                 }
             }
-
-            ec.command(SetKeyboardBacklight { percent: cur })?;
-
-            thread::sleep(Duration::from_millis(3));
+            if !status.temperatures.is_empty() {
+                println!("temperatures:");
+            }
+            for temp in &status.temperatures {
+                match temp.celsius {
+                    Some(celsius) => println!("  {}: {celsius}°C", temp.name),
+                    None => println!("  {}: unavailable", temp.name),
+                }
+            }
+            if !status.sources.is_empty() {
+                println!("sources:");
+            }
+            for source in &status.sources {
+                let last = match source.last_event_secs {
+                    Some(secs) => format!("last {secs}s ago"),
+                    None => "never".to_string(),
+                };
+                let name = match &source.path {
+                    Some(path) => format!("{} ({})", source.name, path.display()),
+                    None => source.name.clone(),
+                };
+                let ignored = match source.ignored {
+                    0 => String::new(),
+                    ignored => format!(" ({ignored} ignored)"),
+                };
+                println!(
+                    "  {name}: {} events{ignored}, {last}, {} errors",
+                    source.events, source.errors
+                );
+            }
+            Ok(())
         }
-        Ok(())
-    };
-
-    let act = Arc::new(ActivityState {
-        last_activity: Mutex::new(Instant::now()),
-        condvar: Condvar::new(),
-    });
-
-    for (path, mut device) in evdev::enumerate() {
-        // Filter devices so that only the Framework's builtin touchpad and keyboard are listened
-        // to. Since we don't support hotplug, listening on USB devices wouldn't work reliably.
-        match device.name() {
-            Some("PIXA3854:00 093A:0274 Touchpad" | "AT Translated Set 2 keyboard") => {
-                let act = act.clone();
-                thread::spawn(move || -> io::Result<()> {
-                    let name = device.name();
-                    let name = name.as_deref().unwrap_or("<unknown>").to_string();
-                    log::info!("starting listener on {}: {name}", path.display());
-                    loop {
-                        if let Err(e) = device.fetch_events() {
-                            log::warn!(
-                                "error while fetching events for device '{name}': {e}; closing"
-                            );
-                            return Err(e);
+        Command::Health(args) => {
+            let Response::Health(health) = control::request(&Request::Health)? else {
+                bail!("unexpected response from daemon");
+            };
+            if args.json {
+                println!("{}", serde_json::to_string(&health)?);
+            } else {
+                match &health.ec_error {
+                    None => println!("EC:        ok"),
+                    Some(e) => println!("EC:        {e}"),
+                }
+                println!("listeners: {}", health.listeners);
+            }
+            if !health.healthy {
+                bail!("the daemon is unhealthy");
+            }
+            Ok(())
+        }
+        Command::Preview(args) => {
+            control::request(&Request::Preview {
+                brightness: args.brightness,
+                fade_ms: args.fade_ms,
+            })?;
+            Ok(())
+        }
+        Command::Off(args) => {
+            control::request(&Request::Off {
+                until_activity: args.until_activity,
+            })?;
+            Ok(())
+        }
+        Command::On(_) => {
+            control::request(&Request::On)?;
+            Ok(())
+        }
+        Command::Backend(args) => {
+            let (backend, enabled) = match args.command {
+                BackendCommand::Enable(args) => (args.backend, true),
+                BackendCommand::Disable(args) => (args.backend, false),
+            };
+            control::request(&Request::Backend { backend, enabled })?;
+            Ok(())
+        }
+        Command::DumpHistory(_) => {
+            let Response::History { entries } = control::request(&Request::History)? else {
+                bail!("unexpected response from daemon");
+            };
+            for entry in entries {
+                println!("{}  {}", history::format_time(entry.time), entry.event);
+            }
+            Ok(())
+        }
+        Command::LogLevel(args) => {
+            let Response::LogLevel { level } =
+                control::request(&Request::LogLevel { level: args.level })?
+            else {
+                bail!("unexpected response from daemon");
+            };
+            println!("log level: {level}");
+            Ok(())
+        }
+        Command::Set(args) => {
+            if args.brightness.is_none()
+                && args.timeout.is_none()
+                && args.hardware_profile.is_none()
+            {
+                bail!("nothing to set (use `--brightness`, `--timeout` or `--hardware-profile`)");
+            }
+            let Response::Status(status) = control::request(&Request::Set {
+                brightness: args.brightness,
+                timeout: args.timeout,
+                hardware_profile: args.hardware_profile.clone(),
+                save: args.save,
+            })?
+            else {
+                bail!("unexpected response from daemon");
+            };
+            if args.brightness.is_some() {
+                println!("brightness: {}%", status.brightness);
+            }
+            if let Some(id) = args.hardware_profile {
+                println!("hardware profile `{id}` saved, restart the daemon to use it");
+            }
+            Ok(())
+        }
+        Command::Ec(args) => match args.command {
+            EcCommand::Version(_) => {
+                let ec = open_ec(ec_device)?;
+                let version = ec.command(GetVersion).map_err(KeylightdError::ec)?;
+                println!("RO version:    {}", version.version_string_ro);
+                println!("RW version:    {}", version.version_string_rw);
+                println!("current image: {}", version.current_image_name());
+                let protocol = ec.protocol();
+                println!(
+                    "protocol:      v{} (max. request {} bytes, max. response {} bytes)",
+                    protocol.version, protocol.max_request, protocol.max_response
+                );
+                Ok(())
+            }
+            EcCommand::Console(_) => {
+                let ec = open_ec(ec_device)?;
+                let console = ConsoleRead::snapshot(&ec).map_err(KeylightdError::ec)?;
+                print!("{console}");
+                if !console.is_empty() && !console.ends_with('\n') {
+                    println!();
+                }
+                Ok(())
+            }
+            EcCommand::Raw(args) => {
+                let ec = open_ec(ec_device)?;
+                let mut resp = vec![0; args.response_size];
+                let payload = args.payload.map(|p| p.0).unwrap_or_default();
+                let (result, len) = ec
+                    .raw_command(args.command, args.version, &payload, &mut resp)
+                    .map_err(KeylightdError::ec)?;
+                println!(
+                    "result: {result} ({})",
+                    command::result_name(result).unwrap_or("unknown")
+                );
+                println!("response: {len} bytes");
+                for chunk in resp[..len].chunks(16) {
+                    let hex = chunk.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+                    println!("  {}", hex.join(" "));
+                }
+                Ok(())
+            }
+            EcCommand::Lightbar(args) => {
+                let ec = open_ec(ec_device)?;
+                let Some(version) = LightbarVersion::probe(&ec).map_err(KeylightdError::ec)? else {
+                    return Err(KeylightdError::device(anyhow!("this EC has no lightbar")).into());
+                };
+                match args.command {
+                    LightbarCommand::Version(_) => {
+                        println!("version: {}", version.num);
+                        println!("flags:   {:#x}", version.flags);
+                    }
+                    LightbarCommand::Show(args) => {
+                        lightbar::show(&ec, args.pattern).map_err(KeylightdError::ec)?
+                    }
+                    LightbarCommand::Brightness(args) => {
+                        ec.command(Lightbar::SetBrightness(args.brightness))
+                            .map_err(KeylightdError::ec)?;
+                    }
+                }
+                Ok(())
+            }
+        },
+        Command::Led(args) => match args.command {
+            LedCommand::Set(args) => {
+                let control = match args.mode {
+                    LedMode::Auto => LedControl::auto,
+                    LedMode::Off => LedControl::off,
+                };
+                let ec = open_ec(ec_device)?;
+                ec.command(control(led_id(args.led)))
+                    .map_err(KeylightdError::ec)?;
+                Ok(())
+            }
+            LedCommand::Query(args) => {
+                let ec = open_ec(ec_device)?;
+                let leds = match args.led {
+                    Some(led) => vec![led_id(led)],
+                    None => LedId::all().collect(),
+                };
+                for led in leds {
+                    let capabilities = led.capabilities(&ec).map_err(KeylightdError::ec)?;
+                    println!("{}:", led.name());
+                    let mut any = false;
+                    for color in LedColor::ALL {
+                        let max = capabilities.get(color);
+                        if max != 0 {
+                            println!("  {:<8} 0-{max}", color.name());
+                            any = true;
                         }
-                        *act.last_activity.lock().unwrap() = Instant::now();
-                        act.condvar.notify_one();
-
-                        // Delay a bit, to avoid busy looping.
-                        thread::sleep(Duration::from_millis(500));
                     }
-                });
+                    if !any {
+                        println!("  (no controllable colors)");
+                    }
+                }
+                Ok(())
+            }
+        },
+        Command::CheckConfig(args) => {
+            let path = args.path.as_deref();
+            let sources = Config::sources(path).map_err(KeylightdError::config)?;
+            if sources.is_empty() {
+                println!("# no configuration files found, using the defaults");
             }
-            _ => {}
+            for source in sources {
+                println!("# loaded from {}", source.display());
+            }
+            let config = Config::load(path).map_err(KeylightdError::config)?;
+            print!("{}", toml::to_string(&config)?);
+            Ok(())
         }
-    }
-
-    log::info!("idle timeout: {} seconds", args.timeout);
-    log::info!("brightness level: {}%", args.brightness);
+        Command::Doctor(_) => {
+            // A broken configuration file shouldn't keep the other checks from running.
+            let quirks = Config::load(None)
+                .and_then(|config| config.quirks())
+                .unwrap_or_else(|_| quirks::detect());
+            let checks = diagnose::checks(ec_device, quirks);
+            for check in &checks {
+                match &check.problem {
+                    None => println!("[ OK ] {}", check.name),
+                    Some(problem) => println!("[FAIL] {}: {problem}", check.name),
+                }
+                for line in &check.details {
+                    println!("       {line}");
+                }
+            }
+            let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+            println!();
+            println!(
+                "{} of {} checks passed",
+                checks.len() - failed,
+                checks.len()
+            );
+            if failed != 0 {
+                bail!("{failed} check(s) failed");
+            }
+            Ok(())
+        }
+        Command::ListDevices(args) => {
+            let config = Config::load(args.config.as_deref()).map_err(KeylightdError::config)?;
+            let quirks = config.quirks().map_err(KeylightdError::config)?;
+            println!("hardware profile: {} ({})", quirks.id, quirks.name);
+            let uses_evdev = config.activity_backends.contains(&Backend::Evdev);
+            if !uses_evdev {
+                println!("the `evdev` activity backend is disabled, so no device is used");
+            }
+            println!();
 
-    let mut state = None;
-    loop {
-        let guard = act.last_activity.lock().unwrap();
-        let last = *guard;
-        let (_, result) = act
-            .condvar
-            .wait_timeout_while(guard, Duration::from_secs(args.timeout.into()), |instant| {
-                *instant == last
-            })
-            .unwrap();
-        let new_state = !result.timed_out();
-        if state != Some(new_state) {
-            log::info!("activity state changed: {state:?} -> {new_state}");
-            if new_state {
-                // Fade in
-                fade_to(args.brightness)?;
-            } else {
-                // Fade out
-                fade_to(0)?;
+            // Selected in the same order as by the daemon, which matters for the lid switch.
+            let devices = evdev::enumerate().collect::<Vec<_>>();
+            let uses = input::select(&config, quirks, &devices);
+            let mut devices = devices.into_iter().zip(uses).collect::<Vec<_>>();
+            devices.sort_by(|((a, _), _), ((b, _), _)| a.cmp(b));
+            println!("{:<20} {:<12} {:<9} NAME", "DEVICE", "CLASS", "USED");
+            for ((path, device), used) in devices {
+                let used = used.filter(|_| uses_evdev).map_or("-", input::Use::name);
+                println!(
+                    "{:<20} {:<12} {used:<9} {}",
+                    path.display(),
+                    input::DeviceClass::of(&device).name(),
+                    device.name().unwrap_or("(unnamed)"),
+                );
+                if let Some(phys) = device.physical_path().filter(|phys| !phys.is_empty()) {
+                    println!("{:<43} {phys}", "");
+                }
             }
-            state = Some(new_state);
+            if let Some(hint) = diagnose::input_hint() {
+                println!();
+                println!("some devices can't be opened: {hint}");
+            }
+            Ok(())
+        }
+        Command::Setup(args) => setup::run(&open_ec(ec_device)?, args.config.as_deref()),
+        Command::Install(args) => {
+            let target = install::Target::from_args(args.system, args.user, args.openrc)
+                .map_err(KeylightdError::config)?;
+            install::install(target)
+        }
+        Command::Uninstall(args) => {
+            let target = install::Target::from_args(args.system, args.user, args.openrc)
+                .map_err(KeylightdError::config)?;
+            install::uninstall(target)
+        }
+        Command::Restore(args) => {
+            // Prefer the state recorded by a daemon that didn't exit cleanly.
+            let path = recovery::path();
+            let saved = recovery::load(&path)?;
+            let config = match Config::load(args.config.as_deref()) {
+                Ok(config) => config,
+                // The recorded state can be restored without the configuration.
+                Err(e) if saved.is_some() => {
+                    log::warn!("{e:#}");
+                    Config::default()
+                }
+                Err(e) => return Err(KeylightdError::config(e).into()),
+            };
+            let (brightness, power_level) = match saved {
+                Some(saved) => (saved.backlight, saved.power_level),
+                None => (config.brightness, None),
+            };
+            let upower = daemon::connect_upower(&config)?;
+            daemon::restore(
+                &open_ec(ec_device)?,
+                upower.as_ref(),
+                brightness,
+                power_level,
+            )
+            .map_err(KeylightdError::ec)?;
+            recovery::clear(&path)?;
+            Ok(())
+        }
+        Command::EmulateEc(args) => {
+            emulator::run(Path::new(&args.socket))?;
+            Ok(())
+        }
+        Command::Completions(args) => {
+            print!("{}", completions::generate(args.shell));
+            Ok(())
         }
     }
 }
 
-struct ActivityState {
-    last_activity: Mutex<Instant>,
-    condvar: Condvar,
+/// Opens the EC at `path`, or detects it if `None`, explaining permission problems in a more
+/// helpful way.
+///
+/// Failures count as [`KeylightdError::Device`] errors, unless access was denied.
+fn open_ec(path: Option<&Path>) -> Result<EmbeddedController, KeylightdError> {
+    let Some(path) = path else {
+        return EmbeddedController::open().map_err(|e| match diagnose::ec_hint() {
+            Some(hint) => KeylightdError::open_device(
+                anyhow::Error::new(e).context(format!("failed to open the EC: {hint}")),
+            ),
+            None => KeylightdError::open_device(e),
+        });
+    };
+    EmbeddedController::open_path(path)
+        .with_context(|| format!("failed to open the EC at {}", path.display()))
+        .map_err(KeylightdError::open_device)
+}
+
+fn led_id(led: Led) -> LedId {
+    match led {
+        Led::Battery => LedId::BATTERY,
+        Led::Power => LedId::POWER,
+        Led::Adapter => LedId::ADAPTER,
+        Led::Left => LedId::LEFT,
+        Led::Right => LedId::RIGHT,
+    }
 }
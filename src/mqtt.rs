@@ -0,0 +1,452 @@
+//! Publishes the daemon's state via MQTT and accepts commands, for home automation.
+//!
+//! This implements just enough of MQTT 3.1.1 to publish and subscribe with QoS 0, which keeps the
+//! dependency tree small. The state is published as a retained JSON message to `<topic>/state`
+//! whenever it changes:
+//!
+//! ```json
+//! {"active":true,"brightness":30,"inhibit":false}
+//! ```
+//!
+//! Commands are accepted as JSON on `<topic>/set`. `brightness` sets the active brightness like
+//! `keylightd set --brightness`, and `inhibit` keeps the backlight on regardless of activity:
+//!
+//! ```json
+//! {"brightness":50,"inhibit":true}
+//! ```
+//!
+//! `<topic>/availability` is `online` while keylightd is connected, and is set to `offline` by the
+//! broker when the connection is lost. With a `discovery-prefix`, the backlight is also announced
+//! to Home Assistant as a dimmable light using the JSON schema, which is why the state contains a
+//! `state` field, and `{"state":"ON"}` and `{"state":"OFF"}` are accepted as commands (they set
+//! and clear `inhibit`).
+
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::BrightnessChange,
+    config::MqttConfig,
+    control::{self, Request, Response},
+    daemon::Shared,
+    history,
+};
+
+/// Keep-alive interval announced to the broker.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// How often the state is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the first reconnection attempt.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum delay between reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Control packet types.
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const PINGREQ: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum LightState {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+struct PublishedState {
+    active: bool,
+    brightness: u8,
+    inhibit: bool,
+    /// Whether the backlight is lit, for Home Assistant.
+    state: LightState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MqttCommand {
+    brightness: Option<u8>,
+    inhibit: Option<bool>,
+    state: Option<LightState>,
+}
+
+/// Spawns a thread that keeps a connection to the broker, reconnecting when it is lost.
+pub fn spawn(shared: Arc<Shared>, config: MqttConfig) {
+    thread::spawn(move || {
+        let mut delay = MIN_BACKOFF;
+        loop {
+            let start = Instant::now();
+            if let Err(e) = run(&shared, &config) {
+                log::warn!(
+                    "MQTT connection to {}:{} failed: {e}",
+                    config.host,
+                    config.port
+                );
+            }
+            // Only back off if connecting keeps failing quickly.
+            if start.elapsed() > MAX_BACKOFF {
+                delay = MIN_BACKOFF;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+fn run(shared: &Arc<Shared>, config: &MqttConfig) -> io::Result<()> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = Arc::new(Mutex::new(stream));
+
+    write_packet(&writer, CONNECT << 4, &connect_packet(config))?;
+    let (kind, body) = read_packet(&mut reader)?;
+    if kind >> 4 != CONNACK || body.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected CONNACK from broker",
+        ));
+    }
+    if body[1] != 0 {
+        return Err(io::Error::other(format!(
+            "broker refused the connection (return code {})",
+            body[1]
+        )));
+    }
+    log::info!("connected to MQTT broker {}:{}", config.host, config.port);
+
+    let command_topic = format!("{}/set", config.topic);
+    let mut subscribe = Vec::new();
+    // Packet identifier.
+    subscribe.extend_from_slice(&1u16.to_be_bytes());
+    put_str(&mut subscribe, &command_topic);
+    // Maximum QoS.
+    subscribe.push(0);
+    write_packet(&writer, SUBSCRIBE << 4 | 0b0010, &subscribe)?;
+
+    publish(&writer, &availability_topic(config), b"online", true)?;
+    if let Some(prefix) = &config.discovery_prefix {
+        let topic = format!("{prefix}/light/{}/config", config.client_id);
+        let payload = serde_json::to_string(&discovery_payload(config))?;
+        publish(&writer, &topic, payload.as_bytes(), true)?;
+    }
+
+    // Incoming packets are handled on a separate thread, so that this one can publish changes.
+    let receiver = {
+        let shared = shared.clone();
+        thread::spawn(move || -> io::Result<()> {
+            loop {
+                let (kind, body) = read_packet(&mut reader)?;
+                if kind >> 4 == PUBLISH {
+                    let (topic, payload) = parse_publish(kind, &body)?;
+                    if topic == command_topic {
+                        handle_command(&shared, payload);
+                    }
+                }
+            }
+        })
+    };
+
+    let result = publish_changes(shared, config, &writer, receiver);
+    // Make sure the receiver thread exits too.
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    result
+}
+
+/// Publishes the state whenever it changes, until the connection fails.
+fn publish_changes(
+    shared: &Shared,
+    config: &MqttConfig,
+    writer: &Mutex<TcpStream>,
+    receiver: JoinHandle<io::Result<()>>,
+) -> io::Result<()> {
+    let state_topic = format!("{}/state", config.topic);
+    let mut published = None;
+    let mut last_sent = Instant::now();
+    loop {
+        if receiver.is_finished() {
+            return match receiver.join() {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::other("MQTT receiver panicked")),
+            };
+        }
+
+        let state = {
+            let state = shared.state.lock().unwrap();
+            let active = state.active.unwrap_or(true);
+            let brightness = state.active_brightness();
+            let lit = (active || state.inhibit) && state.off.is_none() && brightness != 0;
+            PublishedState {
+                active,
+                brightness,
+                inhibit: state.inhibit,
+                state: if lit { LightState::On } else { LightState::Off },
+            }
+        };
+        if published != Some(state) {
+            let payload = serde_json::to_string(&state)?;
+            publish(writer, &state_topic, payload.as_bytes(), true)?;
+            published = Some(state);
+            last_sent = Instant::now();
+        } else if last_sent.elapsed() >= KEEP_ALIVE / 2 {
+            write_packet(writer, PINGREQ << 4, &[])?;
+            last_sent = Instant::now();
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn handle_command(shared: &Shared, payload: &[u8]) {
+    let command = match serde_json::from_slice::<MqttCommand>(payload) {
+        Ok(command) => command,
+        Err(e) => {
+            log::warn!("invalid MQTT command: {e}");
+            return;
+        }
+    };
+    log::debug!("MQTT command: {command:?}");
+    let inhibit = command
+        .inhibit
+        .or(command.state.map(|state| state == LightState::On));
+    if let Some(inhibit) = inhibit {
+        history::record(format!("MQTT inhibit: {inhibit}"));
+        shared.update(|state| state.inhibit = inhibit);
+    }
+    if let Some(brightness) = command.brightness {
+        let req = Request::Set {
+            brightness: Some(BrightnessChange::Set(brightness)),
+            timeout: None,
+            hardware_profile: None,
+            save: false,
+        };
+        if let Response::Error { message } = control::handle(shared, req) {
+            log::warn!("MQTT command failed: {message}");
+        }
+    }
+}
+
+fn availability_topic(config: &MqttConfig) -> String {
+    format!("{}/availability", config.topic)
+}
+
+/// Returns the Home Assistant discovery message describing the backlight as a light entity.
+fn discovery_payload(config: &MqttConfig) -> serde_json::Value {
+    serde_json::json!({
+        "name": "Keyboard backlight",
+        "unique_id": format!("{}_keyboard_backlight", config.client_id),
+        "schema": "json",
+        "state_topic": format!("{}/state", config.topic),
+        "command_topic": format!("{}/set", config.topic),
+        "availability_topic": availability_topic(config),
+        "brightness": true,
+        "brightness_scale": 100,
+        "device": {
+            "identifiers": [config.client_id],
+            "name": "keylightd",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    // Clean session, and a retained will message that marks keylightd as unavailable.
+    let mut flags = 0b0010_0110;
+    if config.username.is_some() {
+        flags |= 0b1000_0000;
+    }
+    if config.password.is_some() {
+        flags |= 0b0100_0000;
+    }
+
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    // Protocol level 4 (MQTT 3.1.1).
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_str(&mut body, &config.client_id);
+    put_str(&mut body, &availability_topic(config));
+    put_str(&mut body, "offline");
+    if let Some(username) = &config.username {
+        put_str(&mut body, username);
+    }
+    if let Some(password) = &config.password {
+        put_str(&mut body, password);
+    }
+    body
+}
+
+fn publish(writer: &Mutex<TcpStream>, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+    let packet = publish_packet(topic, payload, retain);
+    writer.lock().unwrap().write_all(&packet)
+}
+
+/// Returns a QoS 0 PUBLISH packet.
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic);
+    body.extend_from_slice(payload);
+    encode_packet(PUBLISH << 4 | u8::from(retain), &body)
+}
+
+/// Returns the topic and payload of a received PUBLISH packet.
+fn parse_publish(kind: u8, body: &[u8]) -> io::Result<(String, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed PUBLISH packet");
+    let len = usize::from(u16::from_be_bytes(
+        body.get(..2).ok_or_else(invalid)?.try_into().unwrap(),
+    ));
+    let topic = body.get(2..2 + len).ok_or_else(invalid)?;
+    let topic = String::from_utf8(topic.to_vec()).map_err(|_| invalid())?;
+    let mut rest = &body[2 + len..];
+    // QoS 1 and 2 messages carry a packet identifier.
+    if kind & 0b0110 != 0 {
+        rest = rest.get(2..).ok_or_else(invalid)?;
+    }
+    Ok((topic, rest))
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_packet(writer: &Mutex<TcpStream>, header: u8, body: &[u8]) -> io::Result<()> {
+    writer
+        .lock()
+        .unwrap()
+        .write_all(&encode_packet(header, body))
+}
+
+/// Returns a packet with the first header byte `header` and `body`.
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    // The remaining length is encoded in 7-bit groups.
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Reads a packet and returns its first header byte and body.
+fn read_packet(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    let kind = byte[0];
+
+    let mut len = 0;
+    for shift in (0..28).step_by(7) {
+        reader.read_exact(&mut byte)?;
+        len |= usize::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            return Ok((kind, body));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid packet length",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length() {
+        for (len, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xff, 0xff, 0x7f]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let body = vec![0xaa; len];
+            let packet = encode_packet(0x30, &body);
+            assert_eq!(packet[0], 0x30);
+            assert_eq!(&packet[1..1 + encoded.len()], encoded, "length {len}");
+            assert_eq!(packet.len(), 1 + encoded.len() + len);
+
+            let (kind, read) = read_packet(&mut &packet[..]).unwrap();
+            assert_eq!(kind, 0x30);
+            assert_eq!(read, body);
+        }
+    }
+
+    #[test]
+    fn remaining_length_too_long() {
+        let packet = [0x30, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let e = read_packet(&mut &packet[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn connect() {
+        let mut config = MqttConfig {
+            client_id: "kl".into(),
+            topic: "t".into(),
+            ..MqttConfig::default()
+        };
+        let packet = encode_packet(CONNECT << 4, &connect_packet(&config));
+        #[rustfmt::skip]
+        assert_eq!(packet, [
+            0x10, 39,
+            0, 4, b'M', b'Q', b'T', b'T',
+            4,
+            // Will retain, will QoS 0, will flag, clean session.
+            0b0010_0110,
+            0, 60,
+            0, 2, b'k', b'l',
+            0, 14, b't', b'/', b'a', b'v', b'a', b'i', b'l', b'a', b'b', b'i', b'l', b'i', b't', b'y',
+            0, 7, b'o', b'f', b'f', b'l', b'i', b'n', b'e',
+        ]);
+
+        config.username = Some("u".into());
+        config.password = Some("pw".into());
+        let body = connect_packet(&config);
+        // The user name and password flags are set.
+        assert_eq!(body[7], 0b1110_0110);
+        assert!(body.ends_with(&[0, 1, b'u', 0, 2, b'p', b'w']));
+    }
+
+    #[test]
+    fn publish() {
+        let packet = publish_packet("kl/state", b"ON", true);
+        #[rustfmt::skip]
+        assert_eq!(packet, [
+            0x31, 12,
+            0, 8, b'k', b'l', b'/', b's', b't', b'a', b't', b'e',
+            b'O', b'N',
+        ]);
+
+        let (kind, body) = read_packet(&mut &packet[..]).unwrap();
+        let (topic, payload) = parse_publish(kind, &body).unwrap();
+        assert_eq!(topic, "kl/state");
+        assert_eq!(payload, b"ON");
+    }
+}
@@ -0,0 +1,76 @@
+//! Persistence of runtime adjustments across restarts.
+//!
+//! Changes made with `keylightd set` (without `--save`), over MQTT or through a desktop brightness
+//! slider only live in memory, as do `keylightd off` and MQTT's `inhibit`. To keep a daemon
+//! upgrade or restart from undoing them, the daemon writes them to `/var/lib/keylightd/state.json`
+//! (or `$XDG_STATE_HOME/keylightd/state.json` when running as a regular user) and applies them
+//! again on startup.
+//!
+//! Along with the adjusted settings, the file records the configured settings they were made on
+//! top of. If the configuration has been edited since, the adjustments are discarded, so that
+//! editing the configuration file and restarting the daemon works as expected.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, dirs, statefile};
+
+/// Path of the state file when the daemon runs as root.
+pub const SYSTEM_STATE_PATH: &str = "/var/lib/keylightd/state.json";
+
+/// The settings that can be adjusted at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    pub brightness: u8,
+    pub dark_brightness: Option<u8>,
+    pub timeout: u32,
+}
+
+impl Settings {
+    pub fn of(config: &Config) -> Self {
+        Self {
+            brightness: config.brightness,
+            dark_brightness: config.dark_brightness,
+            timeout: config.timeout,
+        }
+    }
+
+    pub fn apply(self, config: &mut Config) {
+        config.brightness = self.brightness;
+        config.dark_brightness = self.dark_brightness;
+        config.timeout = self.timeout;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PersistedState {
+    /// The settings from the configuration file (and command line) when the daemon started.
+    pub base: Settings,
+    /// The settings in effect, including runtime adjustments.
+    pub settings: Settings,
+    /// Whether the backlight is kept on regardless of activity.
+    pub inhibit: bool,
+    /// Whether the backlight is kept off until `keylightd on`.
+    pub off: bool,
+}
+
+/// Returns the path of the state file for the current user.
+pub fn path() -> PathBuf {
+    match dirs::state_dir() {
+        Some(dir) => dir.join("state.json"),
+        None => SYSTEM_STATE_PATH.into(),
+    }
+}
+
+/// Loads the state saved by a previous instance, if there is one.
+pub fn load(path: &Path) -> anyhow::Result<Option<PersistedState>> {
+    statefile::load(path)
+}
+
+/// Saves `state` so that the next instance can restore it.
+pub fn save(path: &Path, state: &PersistedState) -> anyhow::Result<()> {
+    statefile::save(path, state)
+}
@@ -0,0 +1,98 @@
+//! The PID file of the daemon, which also keeps a second instance from starting.
+//!
+//! Two daemons would fight over the EC, each undoing the other's changes to the backlight, so the
+//! daemon holds an exclusive `flock(2)` on its PID file for as long as it runs. A second instance
+//! fails to take the lock and exits, naming the PID of the first one. Since the kernel releases
+//! the lock when the process dies, a PID file left behind by a crashed daemon doesn't get in the
+//! way.
+//!
+//! The file is in `/run` (or `$XDG_RUNTIME_DIR` when running as a regular user), unless another
+//! path is passed with `keylightd run --pidfile`, which init systems without cgroups, like
+//! OpenRC, use to find the daemon to stop. It is removed again when the daemon exits, including
+//! when it's stopped by a signal.
+
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+
+use crate::dirs;
+
+/// Path of the PID file when the daemon runs as root.
+pub const SYSTEM_PID_PATH: &str = "/run/keylightd.pid";
+
+/// The PID file that was written, and the open file holding the lock.
+static PIDFILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Returns the default path of the PID file for the current user.
+pub fn path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("keylightd.pid"),
+        None => SYSTEM_PID_PATH.into(),
+    }
+}
+
+/// Locks the PID file at `path` and writes the PID of this process to it.
+///
+/// Fails if another instance holds the lock.
+pub fn create(path: &Path) -> anyhow::Result<()> {
+    let context = || format!("failed to write the PID file {}", path.display());
+    let mut file = loop {
+        // Don't truncate the file before holding the lock, it might belong to a running daemon.
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(context)?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                let owner = match pid.trim() {
+                    "" => String::new(),
+                    pid => format!(" as PID {pid}"),
+                };
+                return Err(anyhow!(
+                    "keylightd is already running{owner} (it holds the lock on {})",
+                    path.display()
+                ));
+            }
+            Err(e) => return Err(e).with_context(context),
+        }
+        // An exiting daemon removes the file, possibly after it was opened here. The lock on the
+        // removed file would not keep the next instance from starting, so start over.
+        let current = fs::metadata(path).ok().map(|meta| (meta.dev(), meta.ino()));
+        let meta = file.metadata().with_context(context)?;
+        if current == Some((meta.dev(), meta.ino())) {
+            break file;
+        }
+    };
+    file.set_len(0).with_context(context)?;
+    file.rewind().with_context(context)?;
+    writeln!(file, "{}", process::id()).with_context(context)?;
+    *PIDFILE.lock().unwrap() = Some((path.to_path_buf(), file));
+    Ok(())
+}
+
+/// Removes the PID file, if one was written.
+pub fn remove() {
+    // The lock is released when the file is closed, after it was removed.
+    if let Some((path, _file)) = PIDFILE.lock().unwrap().take() {
+        if let Err(e) = fs::remove_file(&path) {
+            log::warn!("failed to remove the PID file {}: {e}", path.display());
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Tracking whether the machine runs on battery, for rules with `on_battery`.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{battery::Battery, daemon::Shared, history, worker::Priority};
+
+/// How often the EC is asked about the power source.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spawns a thread that keeps track of whether the machine runs on battery.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || loop {
+        match shared.ec.submit(Priority::Normal, None, Battery::read) {
+            Ok(battery) => {
+                let on_battery = !battery.is_ac_present();
+                if shared.state.lock().unwrap().on_battery != Some(on_battery) {
+                    shared.update(|state| state.on_battery = Some(on_battery));
+                    if on_battery {
+                        log::info!("running on battery");
+                        history::record("on battery");
+                    } else {
+                        log::info!("running on AC power");
+                        history::record("on AC power");
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("cannot read the power source: {e}");
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
@@ -0,0 +1,102 @@
+//! Keeping the backlight on while someone is in front of the machine, using a proximity sensor.
+//!
+//! While the sensor reports someone nearby, the user doesn't become idle, so the backlight stays on
+//! while reading a long document without touching the keyboard. Presence alone never turns the
+//! backlight back on once it went off, since a person sitting in front of the machine isn't
+//! necessarily using it. When they leave, the regular idle timeout starts.
+
+use std::{io, path::PathBuf, sync::Arc, thread, time::Duration};
+
+use crate::{config::Proximity, daemon::Shared, history, iio};
+
+/// How often the sensor is read.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A proximity sensor exposed through iio.
+struct Sensor {
+    /// Attribute containing the raw proximity reading, which increases as objects get closer.
+    path: PathBuf,
+    /// Readings at or above this value mean that someone is present.
+    near_level: f64,
+}
+
+impl Sensor {
+    /// Finds the first iio device that measures proximity.
+    ///
+    /// Unless `settings` specify a threshold, the sensor's own `nearlevel` is used.
+    fn find(settings: &Proximity) -> io::Result<Option<Self>> {
+        for dir in iio::devices()? {
+            for channel in ["in_proximity", "in_proximity0"] {
+                let path = dir.join(format!("{channel}_raw"));
+                if !path.exists() {
+                    continue;
+                }
+                let near_level = match settings.near_level {
+                    Some(level) => f64::from(level),
+                    None => iio::read_number(&dir.join(format!("{channel}_nearlevel"))).map_err(
+                        |e| {
+                            io::Error::new(
+                                e.kind(),
+                                format!(
+                                    "the sensor at {} has no usable `nearlevel` ({e}), set \
+                                     `proximity.near-level`",
+                                    dir.display()
+                                ),
+                            )
+                        },
+                    )?,
+                };
+                return Ok(Some(Self { path, near_level }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn present(&self) -> io::Result<bool> {
+        Ok(iio::read_number(&self.path)? >= self.near_level)
+    }
+}
+
+/// Finds the proximity sensor and spawns a thread that follows it.
+pub fn spawn(shared: Arc<Shared>, settings: Proximity) -> io::Result<()> {
+    let Some(sensor) = Sensor::find(&settings)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no proximity sensor found",
+        ));
+    };
+    log::info!(
+        "reading proximity from {} (near level {})",
+        sensor.path.display(),
+        sensor.near_level
+    );
+    thread::spawn(move || {
+        if let Err(e) = run(&shared, &sensor) {
+            log::error!("cannot use the proximity sensor: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn run(shared: &Shared, sensor: &Sensor) -> io::Result<()> {
+    loop {
+        let present = sensor.present()?;
+        if shared.state.lock().unwrap().present != Some(present) {
+            shared.update(|state| state.set_present(present));
+            log::info!(
+                "{}",
+                if present {
+                    "someone is in front of the machine"
+                } else {
+                    "nobody is in front of the machine"
+                }
+            );
+            history::record(if present {
+                "presence detected"
+            } else {
+                "presence lost"
+            });
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,184 @@
+//! Hardware profiles, describing how the machines keylightd runs on differ.
+//!
+//! keylightd started out on Framework laptops, but all it really needs is a ChromeOS EC that
+//! controls the keyboard backlight, which most Chromebooks have as well. Everything that differs
+//! between machines, like the names of the builtin input devices, which LEDs exist, or how fast the
+//! EC can take brightness changes, lives in the table below.
+//!
+//! The profile is selected from the DMI vendor and product name (or the device tree on ARM
+//! machines), using the first entry that matches. The `hardware` setting or `--hardware-profile`
+//! override the selection.
+
+use std::{fs, time::Duration};
+
+/// DMI system vendor, which identifies x86 machines.
+const SYS_VENDOR_PATH: &str = "/sys/class/dmi/id/sys_vendor";
+
+/// DMI product name, which identifies the model.
+const PRODUCT_NAME_PATH: &str = "/sys/class/dmi/id/product_name";
+
+/// Device tree compatible strings, which identify ARM machines.
+const DT_COMPATIBLE_PATH: &str = "/proc/device-tree/compatible";
+
+/// How a model or family of machines differs from the others.
+#[derive(Debug)]
+pub struct Quirks {
+    /// Name of the profile, as used by the `hardware` setting.
+    pub id: &'static str,
+    /// Human-readable name of the hardware.
+    pub name: &'static str,
+    /// DMI system vendor of the machines this profile applies to.
+    vendor: Option<&'static str>,
+    /// DMI product names this profile applies to (any product if empty). A trailing `*` matches
+    /// any suffix.
+    products: &'static [&'static str],
+    /// Prefix of a device tree compatible string this profile applies to.
+    compatible: Option<&'static str>,
+    /// Names of the builtin keyboards and touchpads, which are listened to for activity.
+    pub input_devices: &'static [&'static str],
+    /// Whether every touchpad counts as builtin, because the models differ too much to list them.
+    pub any_touchpad: bool,
+    /// Whether the EC's power LED is the one in the power button, which `power` switches off.
+    pub power_led: bool,
+    /// Whether the EC supports Framework's command for the brightness level of the power LED.
+    pub power_led_level: bool,
+    /// Whether there are LEDs on both sides that show the charging state.
+    pub side_leds: bool,
+    /// Delay between the 1% steps of a fade.
+    pub fade_step: Duration,
+}
+
+impl Quirks {
+    /// Returns whether the input device called `name` is one of the builtin devices.
+    pub fn is_input_device(&self, name: &str) -> bool {
+        self.input_devices.contains(&name) || (self.any_touchpad && name.ends_with("Touchpad"))
+    }
+
+    fn matches(&self, vendor: &str, product: &str, compatible: &[u8]) -> bool {
+        let dmi = self.vendor.is_some_and(|v| v == vendor)
+            && (self.products.is_empty()
+                || self.products.iter().any(|&p| match p.strip_suffix('*') {
+                    Some(prefix) => product.starts_with(prefix),
+                    None => product == p,
+                }));
+        // The compatible strings are NUL-separated, most specific first.
+        let dt = self.compatible.is_some_and(|prefix| {
+            compatible
+                .split(|&b| b == 0)
+                .any(|c| c.starts_with(prefix.as_bytes()))
+        });
+        dmi || dt
+    }
+}
+
+/// Selects the profile of the machine from its firmware.
+pub fn detect() -> &'static Quirks {
+    let read = |path| fs::read_to_string(path).unwrap_or_default();
+    let (vendor, product) = (read(SYS_VENDOR_PATH), read(PRODUCT_NAME_PATH));
+    let compatible = fs::read(DT_COMPATIBLE_PATH).unwrap_or_default();
+    PROFILES
+        .iter()
+        .find(|profile| profile.matches(vendor.trim(), product.trim(), &compatible))
+        // The last profile matches everything.
+        .unwrap_or(&PROFILES[PROFILES.len() - 1])
+}
+
+/// Returns the profile called `id`.
+pub fn by_id(id: &str) -> Option<&'static Quirks> {
+    PROFILES.iter().find(|profile| profile.id == id)
+}
+
+/// Returns the names of all profiles.
+pub fn ids() -> impl Iterator<Item = &'static str> {
+    PROFILES.iter().map(|profile| profile.id)
+}
+
+/// An EC firmware version with a known problem that affects keylightd.
+struct FirmwareBug {
+    /// Prefix of the affected version strings, as shown by `keylightd ec version`.
+    version: &'static str,
+    /// What goes wrong, to complete "the EC firmware is known to ...".
+    problem: &'static str,
+}
+
+/// EC firmware versions with known backlight-related problems.
+///
+/// Entries should come from bug reports that include the exact version string, so that working
+/// firmware with a similar version isn't flagged.
+static FIRMWARE_BUGS: &[FirmwareBug] = &[];
+
+/// Returns the known problem of the EC firmware `version`, if there is one.
+pub fn firmware_bug(version: &str) -> Option<&'static str> {
+    FIRMWARE_BUGS
+        .iter()
+        .find(|bug| version.starts_with(bug.version))
+        .map(|bug| bug.problem)
+}
+
+/// The builtin keyboard and touchpad of the Framework Laptop 13.
+const FRAMEWORK_13_INPUT: &[&str] = &[
+    "PIXA3854:00 093A:0274 Touchpad",
+    "AT Translated Set 2 keyboard",
+];
+
+/// x86 Chromebooks have a PS/2 keyboard, ARM ones a keyboard attached to the EC.
+const CHROMEBOOK_INPUT: &[&str] = &["AT Translated Set 2 keyboard", "cros_ec"];
+
+/// All profiles, most specific first.
+static PROFILES: &[Quirks] = &[
+    Quirks {
+        id: "framework-13",
+        name: "Framework Laptop 13",
+        vendor: Some("Framework"),
+        // The first generations are just called "Laptop", with the CPU in parentheses.
+        products: &["Laptop", "Laptop (*", "Laptop 13*"],
+        compatible: None,
+        input_devices: FRAMEWORK_13_INPUT,
+        any_touchpad: false,
+        power_led: true,
+        power_led_level: true,
+        side_leds: true,
+        fade_step: Duration::from_millis(3),
+    },
+    Quirks {
+        id: "framework",
+        name: "Framework Laptop",
+        vendor: Some("Framework"),
+        products: &[],
+        compatible: None,
+        input_devices: FRAMEWORK_13_INPUT,
+        any_touchpad: true,
+        power_led: true,
+        power_led_level: true,
+        side_leds: true,
+        fade_step: Duration::from_millis(3),
+    },
+    Quirks {
+        id: "chromebook",
+        name: "Chromebook",
+        // Chromebooks running coreboot report Google as the vendor, whoever built them.
+        vendor: Some("Google"),
+        products: &[],
+        compatible: Some("google,"),
+        input_devices: CHROMEBOOK_INPUT,
+        any_touchpad: true,
+        power_led: false,
+        power_led_level: false,
+        side_leds: false,
+        // Many Chromebook ECs sit behind a slower bus than the Framework's LPC interface.
+        fade_step: Duration::from_millis(5),
+    },
+    Quirks {
+        id: "generic",
+        name: "unknown machine",
+        vendor: None,
+        products: &[],
+        compatible: None,
+        input_devices: CHROMEBOOK_INPUT,
+        any_touchpad: true,
+        power_led: false,
+        power_led_level: false,
+        side_leds: false,
+        fade_step: Duration::from_millis(3),
+    },
+];
@@ -0,0 +1,54 @@
+//! Crash-safe restoration of the LED and backlight state.
+//!
+//! At startup, the daemon records the state it found the hardware in to a small file in `/run`
+//! (or `$XDG_RUNTIME_DIR` when running as a regular user), and removes it again after restoring
+//! that state on a clean exit. If the file still exists on the next start, the previous instance
+//! crashed or was killed, possibly leaving the power LED disabled or dimmed, so the recorded state
+//! is restored first.
+//!
+//! Since `/run` is a tmpfs, the file does not survive a reboot.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{command::FpLedLevel, dirs, statefile};
+
+/// Path of the state file when the daemon runs as root.
+pub const SYSTEM_STATE_PATH: &str = "/run/keylightd.state";
+
+/// The state of the hardware before the daemon took control of it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SavedState {
+    /// Keyboard backlight brightness in percent.
+    pub backlight: u8,
+    /// Brightness level of the power LED, if the daemon changes it.
+    pub power_level: Option<FpLedLevel>,
+}
+
+/// Returns the path of the state file for the current user.
+pub fn path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("keylightd.state"),
+        None => SYSTEM_STATE_PATH.into(),
+    }
+}
+
+/// Loads the state left behind by a previous instance that did not exit cleanly.
+pub fn load(path: &Path) -> anyhow::Result<Option<SavedState>> {
+    statefile::load(path)
+}
+
+/// Records `state` so that it can be restored if the daemon does not exit cleanly.
+pub fn save(path: &Path, state: &SavedState) -> anyhow::Result<()> {
+    statefile::save(path, state)
+}
+
+/// Removes the state file after the state has been restored.
+pub fn clear(path: &Path) -> io::Result<()> {
+    statefile::remove(path)
+}
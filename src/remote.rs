@@ -0,0 +1,102 @@
+//! Detection of remote sessions (SSH, VNC, RDP).
+//!
+//! Remote sessions are detected by looking for established TCP connections to the configured local
+//! ports in `/proc/net/tcp` and `/proc/net/tcp6`, which works the same for every protocol and
+//! doesn't need any privileges. Connections from the machine itself (eg. an SSH tunnel) don't
+//! count.
+//!
+//! With `keep-off`, the backlight stays off while remote sessions are open and nobody has used the
+//! builtin keyboard or touchpad, so that activity reported by the activity command on behalf of a
+//! remote user doesn't light up the keyboard of an unattended laptop.
+
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{config::RemoteSessions, daemon::Shared, history};
+
+/// How often the connection tables are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `TCP_ESTABLISHED` in the `st` column.
+const ESTABLISHED: &str = "01";
+
+/// Counts the established connections from other machines to any of `ports`.
+fn count_sessions(ports: &[u16]) -> io::Result<usize> {
+    let mut count = 0;
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let text = match fs::read_to_string(table) {
+            Ok(text) => text,
+            // Kernels without IPv6 support have no `tcp6` table.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        count += text
+            .lines()
+            .skip(1)
+            .filter_map(parse_connection)
+            .filter(|(port, remote)| ports.contains(port) && !remote.is_loopback())
+            .count();
+    }
+    Ok(count)
+}
+
+/// Parses a line of `/proc/net/tcp{,6}`, and returns the local port and remote address of an
+/// established connection.
+fn parse_connection(line: &str) -> Option<(u16, IpAddr)> {
+    let mut fields = line.split_whitespace().skip(1);
+    let local = fields.next()?;
+    let remote = fields.next()?;
+    if fields.next()? != ESTABLISHED {
+        return None;
+    }
+    let (_, port) = local.split_once(':')?;
+    let (addr, _) = remote.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    Some((port, parse_addr(addr)?))
+}
+
+/// Parses an address from the connection tables, which are printed as 32-bit words in host byte
+/// order.
+fn parse_addr(hex: &str) -> Option<IpAddr> {
+    let mut bytes = [0; 16];
+    let len = hex.len() / 2;
+    if !matches!(len, 4 | 16) {
+        return None;
+    }
+    for (i, chunk) in hex.as_bytes().chunks(8).enumerate() {
+        let word = u32::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+    Some(if len == 4 {
+        IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+    } else {
+        // Treat IPv4-mapped addresses like IPv4 addresses, so that `is_loopback` works for them.
+        Ipv6Addr::from(bytes).to_canonical()
+    })
+}
+
+/// Spawns a thread that keeps track of the remote sessions.
+pub fn spawn(shared: Arc<Shared>, settings: RemoteSessions) {
+    thread::spawn(move || loop {
+        match count_sessions(&settings.ports) {
+            Ok(count) => {
+                let previous = shared.state.lock().unwrap().remote_sessions;
+                if previous != Some(count) {
+                    shared.update(|state| state.remote_sessions = Some(count));
+                    log::info!("remote sessions: {count}");
+                    history::record(format!("remote sessions: {count}"));
+                }
+            }
+            Err(e) => {
+                log::error!("cannot detect remote sessions: {e}");
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
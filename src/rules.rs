@@ -0,0 +1,233 @@
+//! Conditions for the brightness rules in the configuration.
+//!
+//! A rule like `when = "on_battery && after_sunset"` combines facts about the machine with `!`,
+//! `&&`, `||` and parentheses, where `!` binds tightest and `||` loosest. Conditions are parsed
+//! when the configuration is loaded, so that typos are reported right away, and evaluated by the
+//! main loop whenever the state changes.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Something about the machine that a rule can depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fact {
+    /// The machine runs on battery.
+    OnBattery,
+    /// The sun has set at the configured `location`, and hasn't risen yet.
+    AfterSunset,
+    /// An external display is connected.
+    Docked,
+    /// The desktop prefers a dark color scheme.
+    DarkTheme,
+    /// Remote sessions are open.
+    Remote,
+    /// Someone is known to be in front of the machine.
+    Present,
+}
+
+impl Fact {
+    const ALL: [Fact; 6] = [
+        Fact::OnBattery,
+        Fact::AfterSunset,
+        Fact::Docked,
+        Fact::DarkTheme,
+        Fact::Remote,
+        Fact::Present,
+    ];
+
+    /// Returns the name the fact has in conditions.
+    pub fn name(self) -> &'static str {
+        match self {
+            Fact::OnBattery => "on_battery",
+            Fact::AfterSunset => "after_sunset",
+            Fact::Docked => "docked",
+            Fact::DarkTheme => "dark_theme",
+            Fact::Remote => "remote",
+            Fact::Present => "present",
+        }
+    }
+}
+
+/// A parsed rule condition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Condition {
+    Fact(Fact),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    /// Returns whether the condition holds, given whether each fact does.
+    pub fn eval(&self, fact: &impl Fn(Fact) -> bool) -> bool {
+        match self {
+            Condition::Fact(f) => fact(*f),
+            Condition::Not(c) => !c.eval(fact),
+            Condition::And(a, b) => a.eval(fact) && b.eval(fact),
+            Condition::Or(a, b) => a.eval(fact) || b.eval(fact),
+        }
+    }
+
+    /// Returns whether the condition depends on `fact`, so that it has to be tracked.
+    pub fn uses(&self, fact: Fact) -> bool {
+        match self {
+            Condition::Fact(f) => *f == fact,
+            Condition::Not(c) => c.uses(fact),
+            Condition::And(a, b) | Condition::Or(a, b) => a.uses(fact) || b.uses(fact),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = match c {
+            '!' => (Token::Not, 1),
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            '&' if rest.starts_with("&&") => (Token::And, 2),
+            '|' if rest.starts_with("||") => (Token::Or, 2),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (Token::Ident(&rest[..len]), len)
+            }
+            _ => return Err(format!("unexpected `{c}` in condition '{s}'")),
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens of a condition.
+struct Parser<'a> {
+    tokens: &'a [Token<'a>],
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token<'_>> {
+        let (first, rest) = self.tokens.split_first()?;
+        self.tokens = rest;
+        Some(*first)
+    }
+
+    fn eat(&mut self, token: Token<'_>) -> bool {
+        let matches = self.tokens.first() == Some(&token);
+        if matches {
+            self.tokens = &self.tokens[1..];
+        }
+        matches
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut cond = self.and()?;
+        while self.eat(Token::Or) {
+            cond = Condition::Or(Box::new(cond), Box::new(self.and()?));
+        }
+        Ok(cond)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut cond = self.unary()?;
+        while self.eat(Token::And) {
+            cond = Condition::And(Box::new(cond), Box::new(self.unary()?));
+        }
+        Ok(cond)
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        let source = self.source;
+        match self.next() {
+            Some(Token::Not) => Ok(Condition::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let cond = self.or()?;
+                if !self.eat(Token::Close) {
+                    return Err(format!("missing `)` in condition '{source}'"));
+                }
+                Ok(cond)
+            }
+            Some(Token::Ident(name)) => Fact::ALL
+                .into_iter()
+                .find(|fact| fact.name() == name)
+                .map(Condition::Fact)
+                .ok_or_else(|| {
+                    let names = Fact::ALL.map(Fact::name).join(", ");
+                    format!("unknown fact `{name}` in condition '{source}' (known facts: {names})")
+                }),
+            _ => Err(format!("incomplete condition '{source}'")),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            source: s,
+        };
+        let cond = parser.or()?;
+        if !parser.tokens.is_empty() {
+            return Err(format!("unexpected input at the end of condition '{s}'"));
+        }
+        Ok(cond)
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Condition> for String {
+    fn from(cond: Condition) -> Self {
+        cond.to_string()
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Nested operators are parenthesized, so that the result parses back the same way.
+        let operand = |f: &mut fmt::Formatter<'_>, c: &Condition| match c {
+            Condition::And(..) | Condition::Or(..) => write!(f, "({c})"),
+            _ => write!(f, "{c}"),
+        };
+        match self {
+            Condition::Fact(fact) => f.write_str(fact.name()),
+            Condition::Not(c) => {
+                f.write_str("!")?;
+                operand(f, c)
+            }
+            Condition::And(a, b) | Condition::Or(a, b) => {
+                operand(f, a)?;
+                f.write_str(if matches!(self, Condition::And(..)) {
+                    " && "
+                } else {
+                    " || "
+                })?;
+                operand(f, b)
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Time-of-day handling for settings that only apply during certain hours.
+//!
+//! A [`Schedule`] is either a single [`TimeRange`] that applies every day, or a table with
+//! different ranges per weekday, for the weekend, and for holidays. A range that wraps around
+//! midnight belongs to the day it starts on, so a Friday range of `23:00-02:00` lasts until 2am on
+//! Saturday, no matter what Saturday's range is.
+
+use std::{array, fmt, mem::MaybeUninit, str::FromStr, time::Duration};
+
+use nix::libc;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::clock;
+
+/// Minutes per day.
+const DAY_MINUTES: u16 = 24 * 60;
+
+/// A range of local times of day, like `22:00-07:00`.
+///
+/// The start is inclusive and the end exclusive. If the end is before the start, the range wraps
+/// around midnight. An empty range like `00:00-00:00` never applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    /// Minutes since midnight.
+    start: u16,
+    end: u16,
+}
+
+impl TimeRange {
+    /// Creates a range from two times in minutes since midnight.
+    pub fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    /// Returns whether `minute` (minutes since midnight) is within the range.
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Returns whether `minute` is within the part of the range that lies on the day it starts.
+    fn contains_same_day(&self, minute: u16) -> bool {
+        self.start <= minute && (minute < self.end || self.end < self.start)
+    }
+
+    /// Returns whether `minute` is within the part of the range that spills over into the next
+    /// day.
+    fn contains_next_day(&self, minute: u16) -> bool {
+        self.end < self.start && minute < self.end
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time range '{s}' (expected eg. `22:00-07:00`)"))?;
+        Ok(Self {
+            start: parse_time(start.trim())?,
+            end: parse_time(end.trim())?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// A local time of day, like `07:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// Minutes since midnight.
+    minute: u16,
+}
+
+impl TimeOfDay {
+    /// Returns how long it takes until the local time is at this time of day next. If it is right
+    /// now, that's tomorrow.
+    pub fn until_next(&self) -> Duration {
+        let (minute, second) = local_minute_of_day();
+        let day = u64::from(DAY_MINUTES);
+        let minutes = (u64::from(self.minute) + day - u64::from(minute) - 1) % day + 1;
+        Duration::from_secs(minutes * 60 - u64::from(second))
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            minute: parse_time(s.trim())?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minute / 60, self.minute % 60)
+    }
+}
+
+fn parse_time(s: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid time '{s}' (expected HH:MM)");
+    let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+    let hour = hour.parse::<u16>().map_err(|_| invalid())?;
+    let minute = minute.parse::<u16>().map_err(|_| invalid())?;
+    if hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+    Ok(hour * 60 + minute)
+}
+
+/// The hours during which a setting applies, either every day alike or depending on the day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Schedule {
+    Daily(TimeRange),
+    Weekly(WeeklySchedule),
+}
+
+// Implemented by hand instead of with `#[serde(untagged)]`, so that errors in the time ranges
+// aren't replaced by a generic "no variant matched" message.
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Schedule;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a time range like \"22:00-07:00\", or a table of time ranges per day")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Schedule, E> {
+                s.parse().map(Schedule::Daily).map_err(E::custom)
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Schedule, A::Error> {
+                WeeklySchedule::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(Schedule::Weekly)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Time ranges for specific days. The most specific entry for a day applies: a holiday uses
+/// `holiday` (or `weekend`), other days use the entry for that weekday, then `weekdays` or
+/// `weekend`, then `default`. Days without any entry are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeeklySchedule {
+    pub default: Option<TimeRange>,
+    /// Monday to Friday.
+    pub weekdays: Option<TimeRange>,
+    /// Saturday and Sunday.
+    pub weekend: Option<TimeRange>,
+    pub mon: Option<TimeRange>,
+    pub tue: Option<TimeRange>,
+    pub wed: Option<TimeRange>,
+    pub thu: Option<TimeRange>,
+    pub fri: Option<TimeRange>,
+    pub sat: Option<TimeRange>,
+    pub sun: Option<TimeRange>,
+    /// Range used on the `holidays`.
+    pub holiday: Option<TimeRange>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub holidays: Vec<Date>,
+}
+
+impl WeeklySchedule {
+    /// Returns the range that applies on `day`.
+    fn range(&self, day: &Day) -> Option<TimeRange> {
+        let weekend = matches!(day.weekday, 0 | 6);
+        if self.holidays.iter().any(|date| date.matches(day)) {
+            if let Some(range) = self.holiday.or(self.weekend) {
+                return Some(range);
+            }
+        }
+        let specific = match day.weekday {
+            0 => self.sun,
+            1 => self.mon,
+            2 => self.tue,
+            3 => self.wed,
+            4 => self.thu,
+            5 => self.fri,
+            _ => self.sat,
+        };
+        let group = if weekend { self.weekend } else { self.weekdays };
+        specific.or(group).or(self.default)
+    }
+}
+
+impl Schedule {
+    /// Returns whether `minute` on `today` is within the schedule.
+    fn contains(&self, yesterday: &Day, today: &Day, minute: u16) -> bool {
+        match self {
+            Self::Daily(range) => range.contains(minute),
+            Self::Weekly(schedule) => {
+                schedule
+                    .range(today)
+                    .is_some_and(|range| range.contains_same_day(minute))
+                    || schedule
+                        .range(yesterday)
+                        .is_some_and(|range| range.contains_next_day(minute))
+            }
+        }
+    }
+
+    /// Returns whether the current local time is within the schedule.
+    pub fn contains_now(&self) -> bool {
+        let (minute, _) = local_minute_of_day();
+        self.contains(&Day::local(-1), &Day::local(0), minute)
+    }
+
+    /// Returns how long it takes until the current local time enters or leaves the schedule.
+    pub fn until_change(&self) -> Duration {
+        let (minute, second) = local_minute_of_day();
+        // Every range lasts at most a day, so unless the schedule is empty, there is a change
+        // within the next 8 days.
+        let days: [Day; 10] = array::from_fn(|i| Day::local(i as i64 - 1));
+        let inside = self.contains(&days[0], &days[1], minute);
+        for (i, pair) in days.windows(2).enumerate() {
+            let (yesterday, today) = (&pair[0], &pair[1]);
+            // The schedule can only change at midnight, and where a range starts or ends.
+            let mut boundaries = [0; 5];
+            boundaries[1..].copy_from_slice(&self.boundaries(yesterday, today));
+            boundaries.sort_unstable();
+            for m in boundaries {
+                if i == 0 && m <= minute {
+                    continue;
+                }
+                if self.contains(yesterday, today, m) != inside {
+                    let minutes =
+                        i as u64 * u64::from(DAY_MINUTES) + u64::from(m) - u64::from(minute);
+                    return Duration::from_secs(minutes * 60 - u64::from(second));
+                }
+            }
+        }
+        Duration::from_secs(8 * 24 * 60 * 60)
+    }
+
+    /// Returns the start and end of the ranges of `yesterday` and `today`.
+    fn boundaries(&self, yesterday: &Day, today: &Day) -> [u16; 4] {
+        let ranges = match self {
+            Self::Daily(range) => [Some(*range); 2],
+            Self::Weekly(schedule) => [schedule.range(yesterday), schedule.range(today)],
+        };
+        let [a, b] = ranges.map(|range| range.map_or([0, 0], |range| [range.start, range.end]));
+        [a[0], a[1], b[0], b[1]]
+    }
+}
+
+/// A date for [`WeeklySchedule::holidays`], like `2026-12-24`, or `12-24` for every year.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Date {
+    year: Option<i32>,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    fn matches(&self, day: &Day) -> bool {
+        self.year.is_none_or(|year| year == day.year)
+            && self.month == day.month
+            && self.day == day.day
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid date '{s}' (expected YYYY-MM-DD or MM-DD)");
+        let mut parts = s.split('-').rev();
+        let day = parts
+            .next()
+            .and_then(|day| day.parse().ok())
+            .ok_or_else(invalid)?;
+        let month = parts
+            .next()
+            .and_then(|month| month.parse().ok())
+            .ok_or_else(invalid)?;
+        let year = match parts.next() {
+            Some(year) => Some(year.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        if parts.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month, day })
+    }
+}
+
+impl TryFrom<String> for Date {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Date> for String {
+    fn from(date: Date) -> Self {
+        match date.year {
+            Some(year) => format!("{year:04}-{:02}-{:02}", date.month, date.day),
+            None => format!("{:02}-{:02}", date.month, date.day),
+        }
+    }
+}
+
+/// A local calendar day.
+struct Day {
+    year: i32,
+    month: u8,
+    day: u8,
+    /// Day of the week, with 0 being Sunday.
+    weekday: u8,
+}
+
+impl Day {
+    /// Returns the local day `offset` days from today.
+    fn local(offset: i64) -> Self {
+        let tm = local_time(offset * 24 * 60 * 60);
+        Self {
+            year: tm.tm_year + 1900,
+            month: (tm.tm_mon + 1) as u8,
+            day: tm.tm_mday as u8,
+            weekday: tm.tm_wday as u8,
+        }
+    }
+}
+
+extern "C" {
+    // Not exposed by the `libc` crate.
+    fn tzset();
+}
+
+/// Returns the current local time as minutes since midnight, along with the seconds into that
+/// minute.
+pub fn local_minute_of_day() -> (u16, u8) {
+    let tm = local_time(0);
+    // Leap seconds are counted as the last second of the minute.
+    (
+        (tm.tm_hour * 60 + tm.tm_min) as u16,
+        tm.tm_sec.min(59) as u8,
+    )
+}
+
+/// Returns the current local day of the year (starting at 0), and the offset of the local time zone
+/// from UTC in minutes.
+pub fn local_day_of_year() -> (u16, i32) {
+    let tm = local_time(0);
+    (tm.tm_yday as u16, (tm.tm_gmtoff / 60) as i32)
+}
+
+/// Returns the local time `offset` seconds from now, broken down.
+fn local_time(offset: i64) -> libc::tm {
+    unsafe {
+        // The time zone might have changed since the last call.
+        tzset();
+        let now = (clock::unix_time() + offset) as libc::time_t;
+        let mut tm = MaybeUninit::<libc::tm>::zeroed();
+        // On failure, `tm` stays zeroed, which is midnight.
+        libc::localtime_r(&now, tm.as_mut_ptr());
+        tm.assume_init()
+    }
+}
@@ -0,0 +1,281 @@
+//! The interactive setup wizard (`keylightd setup`).
+//!
+//! The wizard walks through the decisions a new user would otherwise have to look up in the
+//! README: it confirms the detected hardware profile, checks that the input devices of that profile
+//! are there (and offers the keyboard fallback if they aren't), asks for the brightness and the
+//! timeout, shows a test fade, writes the configuration file, and offers to install the systemd
+//! or OpenRC service. Every question has a default, so pressing enter all the way through gives a working
+//! setup on supported hardware.
+
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    str::FromStr,
+    thread,
+};
+
+use anyhow::bail;
+use nix::unistd::Uid;
+
+use crate::{
+    command::{GetKeyboardBacklight, SetKeyboardBacklight},
+    config::Config,
+    control::{self, Request},
+    diagnose,
+    ec::EmbeddedController,
+    fade::{self, Backlight},
+    input,
+    install::{self, Target},
+    quirks::{self, Quirks},
+};
+
+/// How long the test fade through a running daemon takes in each direction.
+const PREVIEW_FADE_MS: u32 = 500;
+
+pub fn run(ec: &EmbeddedController, config_path: Option<&str>) -> anyhow::Result<()> {
+    let path = Config::save_path(config_path);
+    println!(
+        "This sets up keylightd, writing the configuration to {}.",
+        path.display()
+    );
+    println!("Press enter to accept the suggestion in brackets.");
+    println!();
+
+    let mut config = Config::default();
+    let detected = quirks::detect();
+    println!(
+        "Detected hardware: {} (profile `{}`)",
+        detected.name, detected.id
+    );
+    let quirks = if confirm("Is this correct?", true)? {
+        detected
+    } else {
+        let ids = quirks::ids().collect::<Vec<_>>().join(", ");
+        let quirks = loop {
+            let id = ask(&format!("Hardware profile ({ids})"), detected.id)?;
+            match quirks::by_id(&id) {
+                Some(quirks) => break quirks,
+                None => println!("Unknown profile `{id}`."),
+            }
+        };
+        config.hardware = Some(quirks.id.to_string());
+        quirks
+    };
+    println!();
+
+    config.keyboard_fallback = check_input_devices(&config, quirks)?;
+    println!();
+
+    config.brightness = ask_parsed(
+        "Keyboard brightness while typing, in percent (0-100)",
+        config.brightness,
+        |b| *b <= 100,
+    )?;
+    config.timeout = ask_parsed(
+        "Seconds without activity before the backlight turns off",
+        config.timeout,
+        |t| *t > 0,
+    )?;
+    println!();
+
+    test_fade(ec, quirks, config.brightness)?;
+    println!();
+
+    write_config(&path, &config)?;
+    println!();
+
+    offer_service()?;
+    Ok(())
+}
+
+/// Lists the input devices the profile uses, and asks whether to fall back to all keyboards if
+/// there are none.
+fn check_input_devices(config: &Config, quirks: &Quirks) -> anyhow::Result<bool> {
+    if let Some(hint) = diagnose::input_hint() {
+        println!("Some input devices can't be opened: {hint}");
+    }
+    let devices = evdev::enumerate().collect::<Vec<_>>();
+    let uses = input::select(config, quirks, &devices);
+    let mut builtin = devices
+        .iter()
+        .zip(&uses)
+        .filter(|(_, used)| **used == Some(input::Use::Builtin))
+        .map(|((path, device), _)| {
+            let name = device.name().unwrap_or("(unnamed)");
+            format!("{}: {name}", path.display())
+        })
+        .collect::<Vec<_>>();
+    if !builtin.is_empty() {
+        builtin.sort();
+        println!("Activity will be detected on these input devices:");
+        for device in builtin {
+            println!("  {device}");
+        }
+        return Ok(false);
+    }
+
+    println!("None of the builtin keyboards and touchpads of this hardware were found.");
+    let mut available = input::available_devices();
+    if available.is_empty() {
+        available.push("(none)".into());
+    }
+    println!("Available input devices:");
+    for device in available {
+        println!("  {device}");
+    }
+    confirm("Listen on all keyboards instead?", true)
+}
+
+/// The keyboard backlight, controlled through the EC directly.
+struct EcBacklight<'a>(&'a EmbeddedController);
+
+impl Backlight for EcBacklight<'_> {
+    fn get(&mut self) -> io::Result<u8> {
+        let resp = self.0.command(GetKeyboardBacklight)?;
+        Ok(if resp.enabled != 0 { resp.percent } else { 0 })
+    }
+
+    fn set(&mut self, _from: u8, to: u8) -> io::Result<()> {
+        self.0.command(SetKeyboardBacklight { percent: to })?;
+        Ok(())
+    }
+}
+
+/// Fades the keyboard backlight off, to `brightness`, and back to where it was.
+fn test_fade(ec: &EmbeddedController, quirks: &Quirks, brightness: u8) -> anyhow::Result<()> {
+    if !confirm("Try a fade of the keyboard backlight now?", true)? {
+        return Ok(());
+    }
+    // A running daemon would immediately undo the changes, so let it do the fade.
+    if control::request(&Request::Status).is_ok() {
+        control::request(&Request::Preview {
+            brightness,
+            fade_ms: PREVIEW_FADE_MS,
+        })?;
+    } else {
+        if !GetKeyboardBacklight::probe(ec)? {
+            println!(
+                "The EC doesn't control the keyboard backlight on this machine. If the kernel \
+                 provides one, set `upower = true` in the configuration to change it through \
+                 UPower instead."
+            );
+            return Ok(());
+        }
+        let mut backlight = EcBacklight(ec);
+        let original = backlight.get()?;
+        for target in [0, brightness, original] {
+            fade::fade_to(
+                &mut backlight,
+                false,
+                || target,
+                || thread::sleep(quirks.fade_step),
+            )?;
+        }
+    }
+    if !confirm("Did the keyboard backlight fade?", true)? {
+        println!("`keylightd doctor` checks for common problems.");
+    }
+    Ok(())
+}
+
+/// Writes the chosen settings to the configuration file at `path`.
+fn write_config(path: &Path, config: &Config) -> anyhow::Result<()> {
+    if path.exists() {
+        println!(
+            "{} already exists, the settings will be changed in it.",
+            path.display()
+        );
+    }
+    if !confirm(
+        &format!("Write the configuration to {}?", path.display()),
+        true,
+    )? {
+        return Ok(());
+    }
+    config.validate()?;
+    Config::edit(path, |doc| {
+        doc["brightness"] = toml_edit::value(i64::from(config.brightness));
+        doc["timeout"] = toml_edit::value(i64::from(config.timeout));
+        match &config.hardware {
+            Some(id) => doc["hardware"] = toml_edit::value(id.as_str()),
+            None => {
+                doc.remove("hardware");
+            }
+        }
+        if config.keyboard_fallback {
+            doc["keyboard-fallback"] = toml_edit::value(true);
+        } else {
+            doc.remove("keyboard-fallback");
+        }
+    })
+}
+
+/// Offers to install, enable and start the systemd or OpenRC service.
+fn offer_service() -> anyhow::Result<()> {
+    let (target, kind) = if Path::new("/run/systemd/system").exists() {
+        if Uid::effective().is_root() {
+            (Target::System, "systemd system service")
+        } else {
+            (Target::User, "systemd user service")
+        }
+    } else if Path::new("/run/openrc").exists() && Uid::effective().is_root() {
+        (Target::OpenRc, "OpenRC service")
+    } else {
+        println!(
+            "Start `keylightd run` however your system runs services (see the README for runit)."
+        );
+        return Ok(());
+    };
+    if !confirm(&format!("Install and start keylightd as a {kind}?"), false)? {
+        return Ok(());
+    }
+    install::install(target)?;
+    println!("keylightd is running. `keylightd status` shows what it's doing.");
+    Ok(())
+}
+
+/// Asks a question and returns the answer, or `default` if the answer is empty.
+fn ask(question: &str, default: &str) -> anyhow::Result<String> {
+    print!("{question} [{default}]: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        bail!("no answer (end of input)");
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+/// Asks a question until the answer parses and passes `valid`.
+fn ask_parsed<T: FromStr + ToString>(
+    question: &str,
+    default: T,
+    valid: impl Fn(&T) -> bool,
+) -> anyhow::Result<T> {
+    let default = default.to_string();
+    loop {
+        match ask(question, &default)?.parse() {
+            Ok(value) if valid(&value) => return Ok(value),
+            _ => println!("That's not a valid answer."),
+        }
+    }
+}
+
+/// Asks a yes/no question.
+fn confirm(question: &str, default: bool) -> anyhow::Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+    loop {
+        print!("{question} [{choices}]: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            bail!("no answer (end of input)");
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no."),
+        }
+    }
+}
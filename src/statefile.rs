@@ -0,0 +1,43 @@
+//! Small JSON files that the daemon keeps its state in.
+//!
+//! Used for the [recovery state](crate::recovery) and the [persisted adjustments](crate::persist).
+//! A missing file just means there is no state, and files are replaced atomically.
+
+use std::{ffi::OsString, fs, io, path::Path};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Loads the state in the file at `path`, or returns `None` if there is no such file.
+pub fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let state = serde_json::from_str(&json)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    Ok(Some(state))
+}
+
+/// Writes `state` to the file at `path`, creating its directory if needed.
+pub fn save<T: Serialize>(path: &Path, state: &T) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    // Write to a temporary file first, so that a crash can't leave a truncated file behind.
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_string(state)?)
+        .and_then(|()| fs::rename(&tmp, path))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Removes the file at `path`, if it exists.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,94 @@
+//! Sunrise and sunset times, for rules that depend on whether it's dark outside.
+//!
+//! The times are calculated with NOAA's approximation of the solar equations, which is accurate to
+//! a few minutes away from the poles. That's plenty for a keyboard backlight.
+
+use std::{f64::consts::PI, time::Duration};
+
+use crate::{
+    config::Location,
+    schedule::{self, TimeRange},
+};
+
+/// Minutes per day.
+const DAY_MINUTES: u16 = 24 * 60;
+
+/// Zenith angle of the sun's center at sunrise and sunset, including atmospheric refraction.
+const ZENITH: f64 = 90.833;
+
+/// When the sun is up on a given day.
+enum Daylight {
+    /// The sun rises and sets at these local times, in minutes since midnight.
+    Day { sunrise: u16, sunset: u16 },
+    /// The sun doesn't set all day.
+    PolarDay,
+    /// The sun doesn't rise all day.
+    PolarNight,
+}
+
+/// Calculates sunrise and sunset on the `day`th day of the year (starting at 0), in a time zone
+/// `utc_offset` minutes ahead of UTC.
+fn daylight(location: &Location, day: u16, utc_offset: i32) -> Daylight {
+    // The fractional year in radians, at noon.
+    let gamma = 2.0 * PI / 365.0 * f64::from(day);
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = ZENITH.to_radians().cos() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if cos_hour_angle > 1.0 {
+        return Daylight::PolarNight;
+    }
+    if cos_hour_angle < -1.0 {
+        return Daylight::PolarDay;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let local = |utc_minutes: f64| {
+        let minutes = (utc_minutes + f64::from(utc_offset)).round() as i32;
+        minutes.rem_euclid(i32::from(DAY_MINUTES)) as u16
+    };
+    Daylight::Day {
+        sunrise: local(720.0 - 4.0 * (location.longitude + hour_angle) - equation_of_time),
+        sunset: local(720.0 - 4.0 * (location.longitude - hour_angle) - equation_of_time),
+    }
+}
+
+fn today(location: &Location) -> Daylight {
+    let (day, utc_offset) = schedule::local_day_of_year();
+    daylight(location, day, utc_offset)
+}
+
+/// Returns whether the sun is down at `location` right now.
+pub fn is_dark(location: &Location) -> bool {
+    match today(location) {
+        Daylight::Day { sunrise, sunset } => {
+            let (minute, _) = schedule::local_minute_of_day();
+            !TimeRange::new(sunrise, sunset).contains(minute)
+        }
+        Daylight::PolarDay => false,
+        Daylight::PolarNight => true,
+    }
+}
+
+/// Returns how long it takes until the sun rises or sets next, or until the next day, when the
+/// times have to be calculated anew.
+pub fn until_change(location: &Location) -> Duration {
+    let (minute, second) = schedule::local_minute_of_day();
+    let mut minutes = DAY_MINUTES - minute;
+    if let Daylight::Day { sunrise, sunset } = today(location) {
+        for change in [sunrise, sunset] {
+            if change > minute {
+                minutes = minutes.min(change - minute);
+            }
+        }
+    }
+    Duration::from_secs(u64::from(minutes) * 60 - u64::from(second))
+}
@@ -0,0 +1,88 @@
+//! A gentle wake-up alarm that slowly fades in the keyboard backlight.
+//!
+//! At the configured time, the keyboard backlight (and optionally the side LEDs) fades in over
+//! `duration` minutes, and then stays on for `hold` minutes. Any activity ends the sunrise right
+//! away and hands control back to the regular activity handling, so once you're up, the keyboard
+//! behaves as usual. Since it is explicitly requested, the sunrise also lights the keyboard during
+//! the `night-off` hours.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{clock, config::Sunrise, daemon::Shared, history};
+
+/// The longest single sleep while waiting for the alarm time.
+///
+/// Sleeping doesn't advance while the laptop is suspended, so the remaining time is recomputed
+/// from the wall clock regularly, to notice when the alarm time has passed during a suspend.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Number of steps of the fade-in.
+const STEPS: u8 = 100;
+
+/// Spawns a thread that runs the sunrise every day.
+pub fn spawn(shared: Arc<Shared>, settings: Sunrise) {
+    let duration = Duration::from_secs(u64::from(settings.duration) * 60);
+    thread::spawn(move || loop {
+        let remaining = settings.time.until_next();
+        log::debug!("next sunrise at {} in {remaining:?}", settings.time);
+        let at = clock::unix_time() + remaining.as_secs() as i64;
+        loop {
+            let remaining = at - clock::unix_time();
+            if remaining <= 0 {
+                break;
+            }
+            clock::sleep(Duration::from_secs(remaining as u64).min(MAX_SLEEP));
+        }
+        // If the laptop woke up from suspend after the fade would have been over, skip today's.
+        let late = Duration::from_secs((clock::unix_time() - at) as u64);
+        if late < duration {
+            run(&shared, &settings);
+        } else {
+            log::info!("sunrise: skipped, the laptop was asleep");
+        }
+    });
+}
+
+fn run(shared: &Shared, settings: &Sunrise) {
+    log::info!("sunrise: fading in over {} minutes", settings.duration);
+    history::record("sunrise started");
+    let step = Duration::from_secs(u64::from(settings.duration) * 60) / u32::from(STEPS);
+    shared.update(|state| state.sunrise = Some(0));
+    for progress in 1..=STEPS {
+        clock::sleep(step);
+        if !advance(shared, progress) {
+            return;
+        }
+    }
+    let hold_until = clock::now() + Duration::from_secs(u64::from(settings.hold) * 60);
+    while clock::now() < hold_until {
+        if shared.state.lock().unwrap().sunrise.is_none() {
+            cancelled();
+            return;
+        }
+        clock::sleep(Duration::from_secs(1));
+    }
+    shared.update(|state| state.sunrise = None);
+    log::info!("sunrise: done");
+    history::record("sunrise finished");
+}
+
+/// Moves the sunrise to `progress` percent, unless activity has ended it.
+fn advance(shared: &Shared, progress: u8) -> bool {
+    let running = shared.update(|state| {
+        let running = state.sunrise.is_some();
+        if running {
+            state.sunrise = Some(progress);
+        }
+        running
+    });
+    if !running {
+        cancelled();
+    }
+    running
+}
+
+fn cancelled() {
+    log::info!("sunrise: ended by activity");
+    history::record("sunrise ended by activity");
+}
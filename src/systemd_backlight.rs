@@ -0,0 +1,56 @@
+//! Interoperation with `systemd-backlight`, which saves the keyboard backlight brightness at
+//! shutdown and restores it at boot.
+//!
+//! Since keylightd turns the backlight off when idle, `systemd-backlight` often saves a brightness
+//! of 0, and the keyboard comes up dark until keylightd takes over. With `systemd-backlight = true`
+//! keylightd writes its brightness level into `systemd-backlight`'s saved state instead, so that
+//! boots start out with the right brightness.
+
+use std::{fs, io, path::Path};
+
+/// Directory where `systemd-backlight` keeps its saved brightness values.
+const STATE_DIR: &str = "/var/lib/systemd/backlight";
+
+/// Suffix of the state file names belonging to the keyboard backlight.
+///
+/// The names are `<ID_PATH>:<subsystem>:<sysname>`, where the udev `ID_PATH` is hard to compute
+/// without udev, so the files are found by the rest of their name.
+const STATE_SUFFIX: &str = ":leds:chromeos::kbd_backlight";
+
+/// sysfs file with the maximum raw brightness of the keyboard backlight.
+const MAX_BRIGHTNESS: &str = "/sys/class/leds/chromeos::kbd_backlight/max_brightness";
+
+/// Writes `percent` into `systemd-backlight`'s saved state for the keyboard backlight.
+///
+/// Returns the number of state files that were updated. The state file only exists once
+/// `systemd-backlight` saved the brightness for the first time.
+pub fn save(percent: u8) -> io::Result<usize> {
+    let max = match fs::read_to_string(MAX_BRIGHTNESS) {
+        Ok(max) => max.trim().parse().unwrap_or(100),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 100,
+        Err(e) => return Err(e),
+    };
+    let value = (u32::from(percent) * max + 50) / 100;
+
+    let entries = match fs::read_dir(STATE_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut count = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if is_state_file(&path) {
+            fs::write(&path, format!("{value}\n"))?;
+            log::debug!("wrote brightness {value} to {}", path.display());
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn is_state_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(STATE_SUFFIX))
+}
@@ -0,0 +1,77 @@
+//! Following the desktop's dark/light color scheme preference.
+//!
+//! The preference is read from the `org.freedesktop.appearance` `color-scheme` setting exposed by
+//! the XDG desktop portal on the session bus.
+
+use std::{sync::Arc, thread};
+
+use zbus::{
+    blocking::{Connection, Proxy},
+    zvariant::{OwnedValue, Value},
+};
+
+use crate::{daemon::Shared, history};
+
+const NAMESPACE: &str = "org.freedesktop.appearance";
+const KEY: &str = "color-scheme";
+
+/// Value of `color-scheme` that indicates a preference for dark themes (1 = dark, 2 = light,
+/// 0 = no preference).
+const PREFER_DARK: u32 = 1;
+
+/// Spawns a thread that keeps the daemon's `dark_theme` state in sync with the desktop.
+pub fn spawn_watcher(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        if let Err(e) = watch(&shared) {
+            log::warn!(
+                "cannot follow the desktop color scheme: {e} (this requires running keylightd \
+                 inside a user session)"
+            );
+        }
+    });
+}
+
+fn watch(shared: &Shared) -> zbus::Result<()> {
+    let conn = Connection::session()?;
+    let proxy = Proxy::new(
+        &conn,
+        "org.freedesktop.portal.Desktop",
+        "/org/freedesktop/portal/desktop",
+        "org.freedesktop.portal.Settings",
+    )?;
+
+    // Subscribe before reading the initial value, so that no change gets lost in between.
+    let signals = proxy.receive_signal("SettingChanged")?;
+    let value: OwnedValue = proxy.call("Read", &(NAMESPACE, KEY))?;
+    apply(shared, &value);
+
+    for msg in signals {
+        let (namespace, key, value): (String, String, OwnedValue) = msg.body().deserialize()?;
+        if namespace == NAMESPACE && key == KEY {
+            apply(shared, &value);
+        }
+    }
+    Ok(())
+}
+
+fn apply(shared: &Shared, value: &Value<'_>) {
+    let dark = color_scheme(value) == Some(PREFER_DARK);
+    let changed = shared.update(|state| {
+        let changed = state.dark_theme != dark;
+        state.dark_theme = dark;
+        changed
+    });
+    if changed {
+        log::info!("desktop color scheme changed (dark: {dark})");
+        history::record(format!("dark theme: {dark}"));
+    }
+}
+
+fn color_scheme(value: &Value<'_>) -> Option<u32> {
+    match value {
+        // The deprecated `Read` method wraps the value in an additional variant.
+        Value::Value(inner) => color_scheme(inner),
+        Value::U32(scheme) => Some(*scheme),
+        _ => None,
+    }
+}
@@ -0,0 +1,148 @@
+//! Temperature sensors and fans, read from the EC memory map.
+//!
+//! Reference: `EC_MEMMAP_TEMP_SENSOR*`, `EC_TEMP_SENSOR_*`, `EC_MEMMAP_FAN` and `EC_FAN_SPEED_*`
+//! in `include/ec_commands.h`.
+
+use std::io;
+
+use crate::{
+    command::TempSensorGetInfo,
+    ec::EmbeddedController,
+    wire::{Decode, Reader},
+};
+
+/// Offset of the first 16 temperature sensors in the memory map (`EC_MEMMAP_TEMP_SENSOR`).
+const MEMMAP_OFFSET: u8 = 0x00;
+
+/// Offset of the second 16 temperature sensors (`EC_MEMMAP_TEMP_SENSOR_B`), which only exist if
+/// the thermal version is at least 2.
+const MEMMAP_OFFSET_B: u8 = 0x18;
+
+/// Offset of the thermal data version (`EC_MEMMAP_THERMAL_VERSION`).
+const MEMMAP_THERMAL_VERSION: u8 = 0x20;
+
+/// Offset of the fan speeds in the memory map (`EC_MEMMAP_FAN`).
+const MEMMAP_FAN: u8 = 0x10;
+
+/// Number of fan speed entries in the memory map (`EC_FAN_SPEED_ENTRIES`).
+const FAN_ENTRIES: usize = 4;
+
+/// Number of sensors in each memory map section.
+const SENSORS_PER_SECTION: usize = 16;
+
+/// Readings are stored in Kelvin, minus this offset (`EC_TEMP_SENSOR_OFFSET`).
+const TEMP_OFFSET: u16 = 200;
+
+/// The reading of one temperature sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reading {
+    /// `EC_TEMP_SENSOR_NOT_PRESENT`
+    NotPresent,
+    /// `EC_TEMP_SENSOR_ERROR`
+    Error,
+    /// `EC_TEMP_SENSOR_NOT_POWERED`
+    NotPowered,
+    /// `EC_TEMP_SENSOR_NOT_CALIBRATED`
+    NotCalibrated,
+    Kelvin(u16),
+}
+
+impl Reading {
+    /// Returns the temperature in degrees Celsius, if the sensor could be read.
+    pub fn celsius(self) -> Option<i32> {
+        match self {
+            Self::Kelvin(k) => Some(i32::from(k) - 273),
+            _ => None,
+        }
+    }
+}
+
+impl Decode for Reading {
+    const SIZE: usize = 1;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        match reader.read::<u8>() {
+            0xff => Self::NotPresent,
+            0xfe => Self::Error,
+            0xfd => Self::NotPowered,
+            0xfc => Self::NotCalibrated,
+            value => Self::Kelvin(u16::from(value) + TEMP_OFFSET),
+        }
+    }
+}
+
+/// A temperature sensor and its current reading.
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    /// Index of the sensor, as used by [`TempSensorGetInfo`].
+    pub id: u8,
+    pub reading: Reading,
+}
+
+impl Sensor {
+    /// Reads all present temperature sensors from the EC.
+    pub fn read_all(ec: &EmbeddedController) -> io::Result<Vec<Self>> {
+        let mut version = [0];
+        ec.read_memmap(MEMMAP_THERMAL_VERSION, &mut version)?;
+
+        let mut sections = vec![MEMMAP_OFFSET];
+        if version[0] >= 2 {
+            sections.push(MEMMAP_OFFSET_B);
+        }
+        let mut sensors = Vec::new();
+        for (section, offset) in sections.into_iter().enumerate() {
+            let mut buf = [0; SENSORS_PER_SECTION];
+            ec.read_memmap(offset, &mut buf)?;
+            let mut reader = Reader::new(&buf);
+            for i in 0..SENSORS_PER_SECTION {
+                let reading = reader.read();
+                if reading != Reading::NotPresent {
+                    sensors.push(Self {
+                        id: (section * SENSORS_PER_SECTION + i) as u8,
+                        reading,
+                    });
+                }
+            }
+        }
+        Ok(sensors)
+    }
+
+    /// Queries the name of the sensor.
+    pub fn name(&self, ec: &EmbeddedController) -> io::Result<String> {
+        let info = ec.command(TempSensorGetInfo { id: self.id })?;
+        Ok(info.sensor_name.as_str().to_string())
+    }
+}
+
+/// The speed of a fan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanSpeed {
+    /// `EC_FAN_SPEED_NOT_PRESENT`
+    NotPresent,
+    /// `EC_FAN_SPEED_STALLED`
+    Stalled,
+    Rpm(u16),
+}
+
+impl Decode for FanSpeed {
+    const SIZE: usize = 2;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        match reader.read::<u16>() {
+            0xffff => Self::NotPresent,
+            0xfffe => Self::Stalled,
+            rpm => Self::Rpm(rpm),
+        }
+    }
+}
+
+/// Reads the speeds of all present fans from the EC.
+pub fn read_fans(ec: &EmbeddedController) -> io::Result<Vec<FanSpeed>> {
+    let mut buf = [0; FAN_ENTRIES * 2];
+    ec.read_memmap(MEMMAP_FAN, &mut buf)?;
+    let mut reader = Reader::new(&buf);
+    Ok((0..FAN_ENTRIES)
+        .map(|_| reader.read())
+        .filter(|speed| *speed != FanSpeed::NotPresent)
+        .collect())
+}
@@ -0,0 +1,107 @@
+//! Cooperation with UPower, which can also manage the keyboard backlight.
+//!
+//! Desktops like GNOME restore and change the keyboard backlight brightness through UPower's
+//! `KbdBacklight` interface. When keylightd writes to the EC directly, the two fight over the
+//! brightness. With `upower = true`, keylightd instead makes all its changes through UPower, and
+//! adopts brightness changes made with the keyboard's brightness key as its new brightness level.
+
+use std::{io, sync::Arc, thread};
+
+use zbus::blocking::{Connection, Proxy};
+
+use crate::{daemon::Shared, history};
+
+const DESTINATION: &str = "org.freedesktop.UPower";
+const PATH: &str = "/org/freedesktop/UPower/KbdBacklight";
+const INTERFACE: &str = "org.freedesktop.UPower.KbdBacklight";
+
+/// UPower's keyboard backlight.
+#[derive(Clone)]
+pub struct KbdBacklight {
+    proxy: Proxy<'static>,
+    /// The maximum brightness, in UPower's units.
+    max: i32,
+}
+
+impl KbdBacklight {
+    /// Connects to UPower on the system bus.
+    ///
+    /// Fails if UPower isn't running or doesn't manage a keyboard backlight.
+    pub fn connect() -> zbus::Result<Self> {
+        let conn = Connection::system()?;
+        let proxy = Proxy::new(&conn, DESTINATION, PATH, INTERFACE)?;
+        let max: i32 = proxy.call("GetMaxBrightness", &())?;
+        if max <= 0 {
+            return Err(zbus::Error::Failure(format!(
+                "UPower reports an invalid maximum brightness of {max}"
+            )));
+        }
+        Ok(Self { proxy, max })
+    }
+
+    /// Returns the current brightness as a percentage.
+    pub fn get(&self) -> io::Result<u8> {
+        let value: i32 = self
+            .proxy
+            .call("GetBrightness", &())
+            .map_err(io::Error::other)?;
+        Ok(self.to_percent(value))
+    }
+
+    /// Sets the brightness to `percent`.
+    pub fn set(&self, percent: u8) -> io::Result<()> {
+        let value = (i32::from(percent) * self.max + 50) / 100;
+        self.proxy
+            .call("SetBrightness", &value)
+            .map_err(io::Error::other)
+    }
+
+    fn to_percent(&self, value: i32) -> u8 {
+        ((value.clamp(0, self.max) * 100 + self.max / 2) / self.max) as u8
+    }
+}
+
+/// Returns whether UPower manages a keyboard backlight, for warning about conflicts.
+pub fn detect() -> bool {
+    KbdBacklight::connect().is_ok()
+}
+
+/// Spawns a thread that adopts brightness changes made with the brightness key.
+pub fn spawn_watcher(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        if let Err(e) = watch(&shared) {
+            log::warn!("cannot follow UPower's keyboard backlight changes: {e}");
+        }
+    });
+}
+
+fn watch(shared: &Shared) -> zbus::Result<()> {
+    let Some(upower) = &shared.upower else {
+        return Ok(());
+    };
+    for msg in upower.proxy.receive_signal("BrightnessChangedWithSource")? {
+        let (value, source): (i32, String) = msg.body().deserialize()?;
+        // "internal" changes were made by the hardware (ie. the brightness key), everything else
+        // by a program, including keylightd itself.
+        if source != "internal" {
+            continue;
+        }
+        let percent = upower.to_percent(value);
+        if shared.state.lock().unwrap().active_brightness() == percent {
+            continue;
+        }
+        log::info!("brightness changed to {percent}% with the brightness key");
+        history::record(format!("brightness key: {percent}%"));
+        let result = shared.reconfigure(|state, config| {
+            match &mut config.dark_brightness {
+                Some(dark) if state.dark_theme => *dark = percent,
+                _ => config.brightness = percent,
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            log::warn!("ignoring the brightness set with the brightness key: {e}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! A [varlink](https://varlink.org/) interface to the running daemon, as a typed alternative to
+//! the JSON control socket.
+//!
+//! The daemon listens on `/run/io.keylightd` (or `$XDG_RUNTIME_DIR/io.keylightd` when running as
+//! a regular user) and implements the `io.keylightd` interface described in [`INTERFACE`], plus
+//! the `org.varlink.service` introspection methods, so it can be used with `varlinkctl`:
+//!
+//! ```console
+//! $ varlinkctl call /run/io.keylightd io.keylightd.Set '{"brightness": 40}'
+//! ```
+//!
+//! Like on the control socket, only root and members of the socket's group may change settings.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use nix::unistd::Gid;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    cli::BrightnessChange,
+    control::{self, Request, Response},
+    daemon::Shared,
+    dirs,
+};
+
+/// Path of the varlink socket when the daemon runs as root.
+const SYSTEM_SOCKET_PATH: &str = "/run/io.keylightd";
+
+/// Description of the `io.keylightd` interface.
+const INTERFACE: &str = "\
+# Control interface of the keylightd keyboard backlight daemon.
+interface io.keylightd
+
+type Status (
+  # Whether there was recent input activity (null before the first state change).
+  active: ?bool,
+  # Brightness level used when active (0-100).
+  brightness: int,
+  # Activity timeout in seconds.
+  timeout: int,
+  # Whether the power LED is controlled.
+  power: bool,
+  # The current keyboard backlight brightness reported by the EC, if it could be queried.
+  backlight: ?int,
+  # The timeout learned from the user's pauses, if learning is enabled and has enough data.
+  suggested_timeout: ?int,
+  # Whether the backlight is kept off by `keylightd off`.
+  off: ?(until_activity, until_enabled),
+  # Whether the backlight is kept off because it's within the `night-off` hours.
+  night_off: bool,
+  # Statistics about the input devices and other activity sources.
+  sources: []Source,
+  # Readings of the EC's temperature sensors.
+  temperatures: []Temperature,
+  # Speeds of the fans controlled by the EC.
+  fans: []Fan,
+  # What last woke up the backlight.
+  wake: ?Wake,
+  # Whether someone is in front of the machine, according to the proximity sensor or the
+  # activity command's presence reports (null if neither is used).
+  present: ?bool,
+  # Number of open remote sessions (null if they aren't tracked).
+  remote_sessions: ?int,
+  # Whether an external display is connected (null if it isn't tracked).
+  docked: ?bool,
+  # Whether the primary display is on (null if it isn't tracked).
+  display_on: ?bool,
+  # Whether the lid is closed (null without a lid switch).
+  lid_closed: ?bool,
+  # The condition of the rule that sets the brightness, if any applies.
+  rule: ?string,
+  # The activity backends that are running.
+  backends: []Backend,
+  # The application whose wake lock keeps the backlight on, if any.
+  wake_lock: ?string,
+  # Progress of the running wake-up fade in percent, if any.
+  sunrise: ?int,
+  # Version of the daemon.
+  version: string,
+  # Version of the EC firmware, if it could be queried.
+  ec_version: ?string,
+  # Known problem of the EC firmware that affects keylightd, if any.
+  firmware_bug: ?string
+)
+
+type Backend (
+  backend: (evdev, command, logind, wayland),
+  # Whether activity reported by the backend counts.
+  enabled: bool,
+  # Whether the backend considers the user idle, if it tracks that on its own and knows.
+  idle: ?bool
+)
+
+type Source (
+  name: string,
+  # Device node of an input device.
+  path: ?string,
+  # Number of input events received.
+  events: int,
+  # Number of events that were ignored by the touchpad filter.
+  ignored: int,
+  # Number of errors while reading from the source.
+  errors: int,
+  # Seconds since the last event, if there was one.
+  last_event_secs: ?int
+)
+
+type Temperature (
+  name: string,
+  # Temperature in degrees Celsius, or null if the sensor could not be read.
+  celsius: ?int
+)
+
+type Wake (
+  # Name of the activity source.
+  source: string,
+  # The input event, if the source is an input device (keyboard keys are not named).
+  event: ?string,
+  # Seconds since the backlight was woken up.
+  secs_ago: int
+)
+
+type Fan (
+  # Fan speed in RPM (0 if the fan is stopped or stalled).
+  rpm: int,
+  # Whether the fan is stalled, meaning it should spin but doesn't.
+  stalled: bool
+)
+
+type Health (
+  # Whether the EC answers and at least one input listener works.
+  healthy: bool,
+  # Why the EC did not answer, if it didn't.
+  ec_error: ?string,
+  # Number of input listeners that are currently receiving events.
+  listeners: int
+)
+
+method GetStatus() -> (status: Status)
+
+method GetHealth() -> (health: Health)
+
+# Changes the settings of the running daemon, and optionally saves them to its configuration file.
+method Set(brightness: ?int, timeout: ?int, save: ?bool) -> (status: Status)
+
+# Fades to a brightness and back once, without changing any settings.
+method Preview(brightness: int, fade_ms: int) -> (status: Status)
+
+# The request was valid, but the daemon could not carry it out.
+error Failed (message: string)
+";
+
+/// Description of the `org.varlink.service` interface every varlink service implements.
+const SERVICE_INTERFACE: &str = "\
+# The Varlink Service Interface is provided by every varlink service.
+interface org.varlink.service
+
+method GetInfo() -> (
+  vendor: string,
+  product: string,
+  version: string,
+  url: string,
+  interfaces: []string
+)
+
+method GetInterfaceDescription(interface: string) -> (description: string)
+
+error InterfaceNotFound (interface: string)
+error MethodNotFound (method: string)
+error MethodNotImplemented (method: string)
+error InvalidParameter (parameter: string)
+";
+
+#[derive(Deserialize)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    parameters: Map<String, Value>,
+    #[serde(default)]
+    oneway: bool,
+}
+
+/// A varlink error reply.
+struct Error {
+    name: &'static str,
+    parameters: Value,
+}
+
+impl Error {
+    fn invalid_parameter(name: &str) -> Self {
+        Self {
+            name: "org.varlink.service.InvalidParameter",
+            parameters: json!({ "parameter": name }),
+        }
+    }
+}
+
+/// Returns the path of the varlink socket for the current user.
+fn socket_path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("io.keylightd"),
+        None => SYSTEM_SOCKET_PATH.into(),
+    }
+}
+
+/// Binds the varlink socket and spawns a thread accepting connections on it.
+pub fn spawn_server(shared: Arc<Shared>) -> io::Result<()> {
+    let path = socket_path();
+    // A previous instance might have left its socket behind.
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(&path)?;
+    if path == Path::new(SYSTEM_SOCKET_PATH) {
+        // Same permissions as the control socket.
+        control::set_group(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+    }
+    let group = control::socket_group(&listener)?;
+    log::info!("varlink interface listening on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("failed to accept varlink connection: {e}");
+                    continue;
+                }
+            };
+            // Varlink clients may keep their connection open for several calls.
+            let shared = shared.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(&shared, group, stream) {
+                    log::warn!("error while serving varlink connection: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve(shared: &Shared, group: Gid, stream: UnixStream) -> io::Result<()> {
+    let may_change = control::may_change(&control::peer(&stream)?, group);
+    let mut reader = BufReader::new(&stream);
+    let mut message = Vec::new();
+    loop {
+        // Messages are terminated by a NUL byte.
+        message.clear();
+        if reader.read_until(0, &mut message)? == 0 {
+            return Ok(());
+        }
+        if message.pop() != Some(0) {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let call = match serde_json::from_slice::<Call>(&message) {
+            Ok(call) => call,
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid varlink message: {e}"),
+                ))
+            }
+        };
+        log::debug!("varlink call: {} {:?}", call.method, call.parameters);
+        let reply = match handle(shared, may_change, &call.method, &call.parameters) {
+            Ok(parameters) => json!({ "parameters": parameters }),
+            Err(error) => json!({ "error": error.name, "parameters": error.parameters }),
+        };
+        if !call.oneway {
+            let mut message = serde_json::to_vec(&reply)?;
+            message.push(0);
+            (&stream).write_all(&message)?;
+        }
+    }
+}
+
+fn handle(
+    shared: &Shared,
+    may_change: bool,
+    method: &str,
+    params: &Map<String, Value>,
+) -> Result<Value, Error> {
+    match method {
+        "org.varlink.service.GetInfo" => Ok(json!({
+            "vendor": "keylightd",
+            "product": "keylightd",
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": env!("CARGO_PKG_REPOSITORY"),
+            "interfaces": ["org.varlink.service", "io.keylightd"],
+        })),
+        "org.varlink.service.GetInterfaceDescription" => {
+            let interface: String =
+                param(params, "interface")?.ok_or_else(|| Error::invalid_parameter("interface"))?;
+            let description = match interface.as_str() {
+                "org.varlink.service" => SERVICE_INTERFACE,
+                "io.keylightd" => INTERFACE,
+                _ => {
+                    return Err(Error {
+                        name: "org.varlink.service.InterfaceNotFound",
+                        parameters: json!({ "interface": interface }),
+                    })
+                }
+            };
+            Ok(json!({ "description": description }))
+        }
+        "io.keylightd.GetStatus" => reply(control::handle(shared, Request::Status)),
+        "io.keylightd.GetHealth" => reply(control::handle(shared, Request::Health)),
+        "io.keylightd.Set" => {
+            let req = Request::Set {
+                brightness: param(params, "brightness")?.map(BrightnessChange::Set),
+                timeout: param(params, "timeout")?,
+                hardware_profile: None,
+                save: param(params, "save")?.unwrap_or(false),
+            };
+            change(shared, may_change, req)
+        }
+        "io.keylightd.Preview" => {
+            let req = Request::Preview {
+                brightness: param(params, "brightness")?
+                    .ok_or_else(|| Error::invalid_parameter("brightness"))?,
+                fade_ms: param(params, "fade_ms")?
+                    .ok_or_else(|| Error::invalid_parameter("fade_ms"))?,
+            };
+            change(shared, may_change, req)
+        }
+        _ => Err(Error {
+            name: "org.varlink.service.MethodNotFound",
+            parameters: json!({ "method": method }),
+        }),
+    }
+}
+
+/// Returns the parameter `name`, or `None` if it is missing or null.
+fn param<T: DeserializeOwned>(params: &Map<String, Value>, name: &str) -> Result<Option<T>, Error> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => T::deserialize(value)
+            .map(Some)
+            .map_err(|_| Error::invalid_parameter(name)),
+    }
+}
+
+/// Handles a request that changes the daemon's state, if the client may do that.
+fn change(shared: &Shared, may_change: bool, req: Request) -> Result<Value, Error> {
+    if !may_change {
+        return Err(Error {
+            name: "org.varlink.service.PermissionDenied",
+            parameters: json!({}),
+        });
+    }
+    reply(control::handle(shared, req))
+}
+
+/// Converts a control socket response into varlink reply parameters.
+fn reply(resp: Response) -> Result<Value, Error> {
+    match resp {
+        Response::Status(status) => Ok(json!({ "status": status })),
+        // The control protocol uses kebab-case, which isn't valid in varlink field names.
+        Response::Health(health) => Ok(json!({
+            "health": {
+                "healthy": health.healthy,
+                "ec_error": health.ec_error,
+                "listeners": health.listeners,
+            },
+        })),
+        Response::LogLevel { level } => Ok(json!({ "level": level })),
+        Response::History { entries } => Ok(json!({ "entries": entries })),
+        Response::Error { message } => Err(Error {
+            name: "io.keylightd.Failed",
+            parameters: json!({ "message": message }),
+        }),
+    }
+}
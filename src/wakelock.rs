@@ -0,0 +1,101 @@
+//! Keeping the backlight on while certain applications are running.
+//!
+//! Every `[[wake-locks]]` rule names a process. While a process with that name is running, the user
+//! is kept from becoming idle, just like with MQTT's `inhibit`, optionally at a brightness of its
+//! own. That way, the keyboard stays lit at a camera-friendly level during a video call, for
+//! example.
+//!
+//! Processes are found by their name in `/proc/<pid>/comm`, which is what `ps` and `pgrep` show.
+
+use std::{fs, io, sync::Arc, thread, time::Duration};
+
+use crate::{config::WakeLockRule, daemon::Shared, history};
+
+/// How often the running processes are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The kernel truncates process names to this many bytes.
+const COMM_LEN: usize = 15;
+
+/// A wake lock held by a running application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WakeLock {
+    /// Name of the process holding the lock.
+    pub process: String,
+    /// Brightness to use while the lock is held, instead of the usual one.
+    pub brightness: Option<u8>,
+}
+
+/// Returns whether the process name `comm` matches the name configured in a rule.
+fn matches(comm: &str, name: &str) -> bool {
+    comm == name || (comm.len() == COMM_LEN && name.len() > COMM_LEN && name.starts_with(comm))
+}
+
+/// Finds the wake lock that should be held, given the processes that are currently running.
+///
+/// If several rules match, the one with the highest brightness wins, and a rule without a
+/// brightness (which uses the regular one) wins over all others.
+fn find(rules: &[WakeLockRule]) -> io::Result<Option<WakeLock>> {
+    let mut found: Option<WakeLock> = None;
+    for entry in fs::read_dir("/proc")? {
+        let path = entry?.path();
+        let is_pid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+        // The process might have exited in the meantime.
+        let Ok(comm) = fs::read_to_string(path.join("comm")) else {
+            continue;
+        };
+        let comm = comm.trim_end_matches('\n');
+        for rule in rules.iter().filter(|rule| matches(comm, &rule.process)) {
+            let better = match &found {
+                None => true,
+                Some(lock) => match (lock.brightness, rule.brightness) {
+                    (Some(current), Some(new)) => new > current,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                },
+            };
+            if better {
+                found = Some(WakeLock {
+                    process: rule.process.clone(),
+                    brightness: rule.brightness,
+                });
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Spawns a thread that takes and releases wake locks as the configured applications start and
+/// exit.
+pub fn spawn(shared: Arc<Shared>, rules: Vec<WakeLockRule>) {
+    thread::spawn(move || loop {
+        match find(&rules) {
+            Ok(lock) => {
+                if shared.state.lock().unwrap().wake_lock != lock {
+                    match &lock {
+                        Some(lock) => {
+                            log::info!("{} is running, keeping the backlight on", lock.process);
+                            history::record(format!("wake lock held by {}", lock.process));
+                        }
+                        None => {
+                            log::info!("no application holds a wake lock anymore");
+                            history::record("wake lock released");
+                        }
+                    }
+                    shared.update(|state| state.set_wake_lock(lock));
+                }
+            }
+            Err(e) => {
+                log::error!("cannot look for applications holding wake locks: {e}");
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
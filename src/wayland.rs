@@ -0,0 +1,345 @@
+//! Activity from the idle notifications of the Wayland compositor.
+//!
+//! With the `ext-idle-notify-v1` protocol, the compositor tells its clients when the user has been
+//! idle for a given time and when they are back, based on all input it handles (including external
+//! keyboards and mice that keylightd doesn't listen to), and taking idle inhibitors like video
+//! players into account. keylightd asks to be notified after its `timeout`, so that this backend
+//! agrees with the evdev backend about when the user becomes idle.
+//!
+//! Only a handful of messages are needed, so they are encoded by hand instead of pulling in a
+//! Wayland client library. When running as a system service, there is no `WAYLAND_DISPLAY` to go
+//! by, so the compositor of the first logged in user that has one is used.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    cli::Backend,
+    daemon::Shared,
+    input::{self, Alive},
+};
+
+/// How long to wait before connecting again after the compositor went away.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the configured timeout is checked for changes.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interface name of the idle notifier global.
+const IDLE_NOTIFIER: &str = "ext_idle_notifier_v1";
+/// Interface name of the seat global, whose input the notifications are about.
+const SEAT: &str = "wl_seat";
+
+// Object IDs. The client picks them, and we simply number the objects in the order they are
+// created. New notifications get new IDs, so that IDs never have to be reused.
+const DISPLAY: u32 = 1;
+const REGISTRY: u32 = 2;
+const SYNC_CALLBACK: u32 = 3;
+const NOTIFIER: u32 = 4;
+const SEAT_OBJECT: u32 = 5;
+const FIRST_NOTIFICATION: u32 = 6;
+
+// Request opcodes.
+const DISPLAY_SYNC: u16 = 0;
+const DISPLAY_GET_REGISTRY: u16 = 1;
+const REGISTRY_BIND: u16 = 0;
+const NOTIFIER_GET_IDLE_NOTIFICATION: u16 = 1;
+const NOTIFICATION_DESTROY: u16 = 0;
+
+// Event opcodes.
+const DISPLAY_ERROR: u16 = 0;
+const REGISTRY_GLOBAL: u16 = 0;
+const CALLBACK_DONE: u16 = 0;
+const NOTIFICATION_IDLED: u16 = 0;
+const NOTIFICATION_RESUMED: u16 = 1;
+
+/// An argument of a request. Object IDs (new or existing) are sent as plain integers.
+enum Arg<'a> {
+    Uint(u32),
+    Str(&'a str),
+}
+
+/// An event sent by the compositor.
+struct Event {
+    object: u32,
+    opcode: u16,
+    args: Vec<u8>,
+}
+
+impl Event {
+    fn args(&self) -> ArgReader<'_> {
+        ArgReader(&self.args)
+    }
+}
+
+/// Decodes the arguments of an event, in order.
+struct ArgReader<'a>(&'a [u8]);
+
+impl ArgReader<'_> {
+    fn uint(&mut self) -> io::Result<u32> {
+        let (value, rest) = self.0.split_first_chunk::<4>().ok_or_else(truncated)?;
+        self.0 = rest;
+        Ok(u32::from_ne_bytes(*value))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.uint()? as usize;
+        // Strings include a NUL terminator, and are padded to a multiple of 32 bits.
+        let padded = len.next_multiple_of(4);
+        if len == 0 || self.0.len() < padded {
+            return Err(truncated());
+        }
+        let string = String::from_utf8_lossy(&self.0[..len - 1]).into_owned();
+        self.0 = &self.0[padded..];
+        Ok(string)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated Wayland message")
+}
+
+struct Connection {
+    stream: UnixStream,
+    /// Received data that doesn't form a complete message yet.
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn send(&mut self, object: u32, opcode: u16, args: &[Arg<'_>]) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(64);
+        msg.extend_from_slice(&object.to_ne_bytes());
+        // The message size and opcode, filled in below.
+        msg.extend_from_slice(&[0; 4]);
+        for arg in args {
+            match arg {
+                Arg::Uint(value) => msg.extend_from_slice(&value.to_ne_bytes()),
+                Arg::Str(s) => {
+                    msg.extend_from_slice(&(s.len() as u32 + 1).to_ne_bytes());
+                    msg.extend_from_slice(s.as_bytes());
+                    msg.resize((msg.len() + 1).next_multiple_of(4), 0);
+                }
+            }
+        }
+        let header = (msg.len() as u32) << 16 | u32::from(opcode);
+        msg[4..8].copy_from_slice(&header.to_ne_bytes());
+        self.stream.write_all(&msg)
+    }
+
+    /// Returns the next event, or `None` if the read timeout expired before it arrived.
+    ///
+    /// Protocol errors reported by the compositor are returned as errors.
+    fn read_event(&mut self) -> io::Result<Option<Event>> {
+        loop {
+            if let Some(event) = self.parse_event()? {
+                if event.object == DISPLAY && event.opcode == DISPLAY_ERROR {
+                    let mut args = event.args();
+                    let (object, code) = (args.uint()?, args.uint()?);
+                    let message = args.string()?;
+                    return Err(io::Error::other(format!(
+                        "protocol error {code} on object {object}: {message}"
+                    )));
+                }
+                return Ok(Some(event));
+            }
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the compositor closed the connection",
+                    ))
+                }
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes the first message out of the receive buffer, if it is complete.
+    fn parse_event(&mut self) -> io::Result<Option<Event>> {
+        let Some((object, rest)) = self.buf.split_first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let Some((header, _)) = rest.split_first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let (object, header) = (u32::from_ne_bytes(*object), u32::from_ne_bytes(*header));
+        let size = (header >> 16) as usize;
+        if size < 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid Wayland message size {size}"),
+            ));
+        }
+        if self.buf.len() < size {
+            return Ok(None);
+        }
+        let args = self.buf[8..size].to_vec();
+        self.buf.drain(..size);
+        Ok(Some(Event {
+            object,
+            opcode: header as u16,
+            args,
+        }))
+    }
+}
+
+/// Returns the path of the compositor's socket.
+fn socket_path() -> Option<PathBuf> {
+    let display = PathBuf::from(env::var_os("WAYLAND_DISPLAY").unwrap_or("wayland-0".into()));
+    if display.is_absolute() {
+        return Some(display);
+    }
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Some(PathBuf::from(dir).join(display));
+    }
+    let mut dirs = fs::read_dir("/run/user")
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.into_iter()
+        .map(|dir| dir.join(&display))
+        .find(|path| path.exists())
+}
+
+/// Spawns a thread that follows the idle notifications of the compositor, reconnecting whenever
+/// it goes away.
+pub fn spawn(shared: Arc<Shared>) {
+    thread::spawn(move || {
+        let id = input::register(&shared, "wayland", None, Backend::Wayland);
+        let mut last_error = None;
+        loop {
+            let e = match socket_path() {
+                // Only returns on error.
+                Some(path) => run(&shared, id, &path).unwrap_err(),
+                None => io::Error::new(io::ErrorKind::NotFound, "no Wayland compositor found"),
+            };
+            shared.update(|state| state.idle_hints.remove(&Backend::Wayland));
+            // Without a compositor (eg. on the console), this fails every time, so only log news.
+            let message = e.to_string();
+            if last_error.as_ref() != Some(&message) {
+                log::warn!("cannot receive idle notifications from the Wayland compositor: {e}");
+                input::record_error(&shared, id);
+                last_error = Some(message);
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    });
+}
+
+fn run(shared: &Shared, id: usize, path: &Path) -> io::Result<()> {
+    let mut conn = Connection {
+        stream: UnixStream::connect(path)?,
+        buf: Vec::new(),
+    };
+
+    // Find the globals, and wait for the sync callback, which arrives after all of them.
+    conn.send(DISPLAY, DISPLAY_GET_REGISTRY, &[Arg::Uint(REGISTRY)])?;
+    conn.send(DISPLAY, DISPLAY_SYNC, &[Arg::Uint(SYNC_CALLBACK)])?;
+    let (mut notifier, mut seat) = (None, None);
+    loop {
+        let Some(event) = conn.read_event()? else {
+            continue;
+        };
+        match (event.object, event.opcode) {
+            (REGISTRY, REGISTRY_GLOBAL) => {
+                let mut args = event.args();
+                let name = args.uint()?;
+                match args.string()?.as_str() {
+                    IDLE_NOTIFIER => notifier = Some(name),
+                    SEAT if seat.is_none() => seat = Some(name),
+                    _ => {}
+                }
+            }
+            (SYNC_CALLBACK, CALLBACK_DONE) => break,
+            _ => {}
+        }
+    }
+    let Some(notifier) = notifier else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the compositor doesn't support {IDLE_NOTIFIER}"),
+        ));
+    };
+    let Some(seat) = seat else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the compositor has no seat",
+        ));
+    };
+    for (name, interface, object) in [
+        (notifier, IDLE_NOTIFIER, NOTIFIER),
+        (seat, SEAT, SEAT_OBJECT),
+    ] {
+        conn.send(
+            REGISTRY,
+            REGISTRY_BIND,
+            &[
+                Arg::Uint(name),
+                Arg::Str(interface),
+                Arg::Uint(1),
+                Arg::Uint(object),
+            ],
+        )?;
+    }
+
+    let mut notification = FIRST_NOTIFICATION;
+    let mut timeout = shared.state.lock().unwrap().config.timeout;
+    let get_notification = |conn: &mut Connection, notification, timeout: u32| {
+        let timeout_ms = timeout.saturating_mul(1000);
+        conn.send(
+            NOTIFIER,
+            NOTIFIER_GET_IDLE_NOTIFICATION,
+            &[
+                Arg::Uint(notification),
+                Arg::Uint(timeout_ms),
+                Arg::Uint(SEAT_OBJECT),
+            ],
+        )
+    };
+    get_notification(&mut conn, notification, timeout)?;
+    log::info!("receiving idle notifications from {}", path.display());
+    let _alive = Alive::new(&shared.listeners);
+    // The compositor only reports changes, starting with the user being active.
+    shared.idle_hint(Backend::Wayland, id, false);
+
+    conn.stream.set_read_timeout(Some(TIMEOUT_CHECK_INTERVAL))?;
+    loop {
+        match conn.read_event()? {
+            Some(event) if event.object == notification => match event.opcode {
+                NOTIFICATION_IDLED => shared.idle_hint(Backend::Wayland, id, true),
+                NOTIFICATION_RESUMED => shared.idle_hint(Backend::Wayland, id, false),
+                _ => {}
+            },
+            Some(_) => {}
+            None => {
+                let configured = shared.state.lock().unwrap().config.timeout;
+                if configured != timeout {
+                    conn.send(notification, NOTIFICATION_DESTROY, &[])?;
+                    notification += 1;
+                    timeout = configured;
+                    get_notification(&mut conn, notification, timeout)?;
+                    // Like the first one, the new notification starts out with an active user.
+                    shared.idle_hint(Backend::Wayland, id, false);
+                }
+            }
+        }
+    }
+}
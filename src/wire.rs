@@ -0,0 +1,125 @@
+//! Explicit serialization of EC command payloads.
+//!
+//! The EC expects little-endian, packed structures. Instead of transmuting Rust structs (which
+//! relies on the host being little-endian and on `#[repr(C)]` not inserting any padding), every
+//! command and response spells out its wire format field by field.
+
+use std::{fmt, str};
+
+/// Types that can be written to a request payload.
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// Types that can be read from a response payload.
+pub trait Decode: Sized {
+    /// Size of the encoded value in bytes.
+    const SIZE: usize;
+
+    fn decode(reader: &mut Reader<'_>) -> Self;
+}
+
+/// Reads values from a response payload.
+///
+/// If the EC sends a shorter response than expected, the missing bytes read as zero.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn read<T: Decode>(&mut self) -> T {
+        T::decode(self)
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        let len = N.min(self.buf.len());
+        bytes[..len].copy_from_slice(&self.buf[..len]);
+        self.buf = &self.buf[len..];
+        bytes
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    const SIZE: usize = 0;
+
+    fn decode(_: &mut Reader<'_>) -> Self {}
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn decode(reader: &mut Reader<'_>) -> Self {
+                    Self::from_le_bytes(reader.take())
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32);
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    const SIZE: usize = N;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        reader.take()
+    }
+}
+
+/// A fixed-size, NUL-padded string field, as used by the EC for version strings and the like.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EcString<const N: usize>([u8; N]);
+
+impl<const N: usize> EcString<N> {
+    /// Returns the string up to the first NUL byte.
+    ///
+    /// Invalid UTF-8 is replaced by a placeholder, since it can only come from a broken EC.
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(N);
+        str::from_utf8(&self.0[..len]).unwrap_or("<invalid UTF-8>")
+    }
+}
+
+impl<const N: usize> fmt::Debug for EcString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> fmt::Display for EcString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> Decode for EcString<N> {
+    const SIZE: usize = N;
+
+    fn decode(reader: &mut Reader<'_>) -> Self {
+        Self(reader.read())
+    }
+}
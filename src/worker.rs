@@ -0,0 +1,302 @@
+//! Serializes EC access through a single worker thread.
+//!
+//! All EC commands issued by the daemon go through a prioritized queue that is processed by one
+//! worker thread. A job can consist of several commands (eg. a whole fade), which then run without
+//! anything else interleaving, and a job can carry a key so that a newer job with the same key
+//! replaces one that hasn't started yet, coalescing bursts of requests.
+//!
+//! This also protects the daemon from a hung EC: the EC has been observed to stop responding
+//! entirely (eg. during firmware updates), which makes the ioctl block indefinitely. Since a
+//! blocked ioctl can't be cancelled, a worker that doesn't finish a job in time is abandoned and
+//! replaced by a new one that reopens the device. The abandoned worker still holds the lock on
+//! [`LOCK_PATH`](crate::ec::LOCK_PATH), so that is released on its behalf.
+
+use std::{
+    cmp::Reverse,
+    io,
+    path::PathBuf,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    command::Command,
+    ec::{EmbeddedController, LockHandle},
+};
+
+/// How long to wait for a job to finish, from when the worker starts running it.
+///
+/// This has to be long enough for a complete fade. Jobs that sleep for longer on purpose use
+/// [`EcWorker::submit_with_timeout`].
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a job is retried after the EC failed to answer.
+const RETRIES: u32 = 2;
+
+/// Delay before reopening the EC after a hang, to give it a chance to recover.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between attempts to reopen the EC. The delay doubles after every failure.
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(30);
+
+/// Priority of a queued job. Jobs with higher priority run first, jobs with the same priority
+/// run in the order they were submitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background work, like fades.
+    Normal,
+    /// Requests that a user is waiting for, like status queries.
+    High,
+}
+
+/// Runs a job, or reports why it can't run (eg. because it was superseded).
+type Run = Box<dyn FnOnce(io::Result<&EmbeddedController>) + Send>;
+
+/// What the worker reports to the submitter of a job.
+enum Message<R> {
+    /// The worker took the job off the queue and started running it.
+    Started(Instant),
+    Done(io::Result<R>),
+}
+
+struct Job {
+    seq: u64,
+    priority: Priority,
+    key: Option<&'static str>,
+    run: Run,
+}
+
+struct Queue {
+    jobs: Vec<Job>,
+    next_seq: u64,
+    /// ID of the current worker thread. Abandoned workers exit when they notice the change.
+    worker: u64,
+    /// The lock of the EC opened by the current worker, to release it if the worker hangs.
+    lock: Option<LockHandle>,
+}
+
+impl Queue {
+    /// Fails all queued jobs with `error`.
+    fn fail_all(&mut self, error: &io::Error) {
+        for job in self.jobs.drain(..) {
+            (job.run)(Err(io::Error::new(error.kind(), error.to_string())));
+        }
+    }
+}
+
+struct Inner {
+    /// Device node of the EC, for reopening it.
+    path: PathBuf,
+    queue: Mutex<Queue>,
+    condvar: Condvar,
+}
+
+pub struct EcWorker {
+    inner: Arc<Inner>,
+}
+
+impl EcWorker {
+    /// Spawns a worker thread that takes ownership of `ec`.
+    pub fn new(ec: EmbeddedController) -> Self {
+        let inner = Arc::new(Inner {
+            path: ec.path().to_owned(),
+            queue: Mutex::new(Queue {
+                jobs: Vec::new(),
+                next_seq: 0,
+                worker: 0,
+                lock: None,
+            }),
+            condvar: Condvar::new(),
+        });
+        spawn(inner.clone(), Some(ec));
+        Self { inner }
+    }
+
+    /// Sends `cmd` to the EC and waits for the response.
+    pub fn command<C>(&self, cmd: C) -> io::Result<C::Response>
+    where
+        C: Command + Clone + Send + Sync + 'static,
+        C::Response: Send,
+    {
+        self.submit(Priority::Normal, None, move |ec| ec.command(cmd.clone()))
+    }
+
+    /// Queues `f` to be run on the worker thread and waits for its result.
+    ///
+    /// If `key` is given, a job with the same key that is still waiting in the queue is replaced,
+    /// and fails with [`io::ErrorKind::Interrupted`].
+    ///
+    /// If the job does not finish within [`TIMEOUT`] of being started (time spent waiting behind
+    /// other jobs doesn't count), the device is reopened and the job is retried up to [`RETRIES`]
+    /// times before giving up with an [`io::ErrorKind::TimedOut`] error. If the device can't be
+    /// reopened, queued jobs fail with the error.
+    pub fn submit<R, F>(&self, priority: Priority, key: Option<&'static str>, f: F) -> io::Result<R>
+    where
+        R: Send + 'static,
+        F: Fn(&EmbeddedController) -> io::Result<R> + Send + Sync + 'static,
+    {
+        self.submit_with_timeout(priority, key, TIMEOUT, f)
+    }
+
+    /// Like [`EcWorker::submit`], but considers the EC hung only if the job doesn't finish within
+    /// `timeout`.
+    pub fn submit_with_timeout<R, F>(
+        &self,
+        priority: Priority,
+        key: Option<&'static str>,
+        timeout: Duration,
+        f: F,
+    ) -> io::Result<R>
+    where
+        R: Send + 'static,
+        F: Fn(&EmbeddedController) -> io::Result<R> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        for _ in 0..=RETRIES {
+            let (tx, rx) = mpsc::channel();
+            let f = f.clone();
+            self.push(
+                priority,
+                key,
+                Box::new(move |ec| {
+                    // The caller might have given up already, so ignore errors.
+                    let result = match ec {
+                        Ok(ec) => {
+                            let _ = tx.send(Message::Started(Instant::now()));
+                            f(ec)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    let _ = tx.send(Message::Done(result));
+                }),
+            );
+
+            let started = match rx.recv() {
+                Ok(Message::Started(started)) => started,
+                Ok(Message::Done(result)) => return result,
+                // The worker only drops a job without running it if it panicked.
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "the EC worker stopped",
+                    ))
+                }
+            };
+            let remaining = timeout.saturating_sub(started.elapsed());
+            match rx.recv_timeout(remaining) {
+                Ok(Message::Done(result)) => return result,
+                Ok(Message::Started(_)) => unreachable!("a job is only started once"),
+                Err(_) => log::error!("EC did not respond within {timeout:?}; reopening it"),
+            }
+            self.restart();
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the embedded controller is not responding",
+        ))
+    }
+
+    fn push(&self, priority: Priority, key: Option<&'static str>, run: Run) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if key.is_some() {
+            let (superseded, jobs) = queue.jobs.drain(..).partition(|job| job.key == key);
+            queue.jobs = jobs;
+            for job in superseded {
+                (job.run)(Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "superseded by a newer request",
+                )));
+            }
+        }
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.jobs.push(Job {
+            seq,
+            priority,
+            key,
+            run,
+        });
+        self.inner.condvar.notify_all();
+    }
+
+    /// Abandons the current worker thread and starts a new one that reopens the EC.
+    ///
+    /// If the old worker is stuck in an ioctl, it exits once that returns.
+    fn restart(&self) {
+        spawn(self.inner.clone(), None);
+    }
+}
+
+/// Starts a worker thread that runs jobs on `ec`, or on a newly opened EC if `ec` is `None`.
+fn spawn(inner: Arc<Inner>, ec: Option<EmbeddedController>) {
+    let id = {
+        let mut queue = inner.queue.lock().unwrap();
+        queue.worker += 1;
+        // The previous worker is stuck while holding the lock, which would keep the new one (and
+        // `ectool`) from sending commands.
+        if let Some(lock) = queue.lock.take() {
+            lock.release();
+        }
+        inner.condvar.notify_all();
+        queue.worker
+    };
+    thread::spawn(move || {
+        let ec = match ec {
+            Some(ec) => ec,
+            None => match reopen(&inner, id) {
+                Some(ec) => ec,
+                None => return,
+            },
+        };
+        {
+            let mut queue = inner.queue.lock().unwrap();
+            if queue.worker != id {
+                return;
+            }
+            queue.lock = ec.lock_handle();
+        }
+        loop {
+            let job = {
+                let queue = inner.queue.lock().unwrap();
+                let mut queue = inner
+                    .condvar
+                    .wait_while(queue, |queue| queue.worker == id && queue.jobs.is_empty())
+                    .unwrap();
+                if queue.worker != id {
+                    return;
+                }
+                let next = (0..queue.jobs.len())
+                    .max_by_key(|&i| (queue.jobs[i].priority, Reverse(queue.jobs[i].seq)))
+                    .unwrap();
+                queue.jobs.swap_remove(next)
+            };
+            (job.run)(Ok(&ec));
+        }
+    });
+}
+
+/// Reopens the EC, retrying with increasing delays until that works.
+///
+/// Jobs that are queued while the EC can't be opened fail with the error. Returns `None` if the
+/// worker `id` was replaced in the meantime.
+fn reopen(inner: &Inner, id: u64) -> Option<EmbeddedController> {
+    let mut delay = REOPEN_DELAY;
+    loop {
+        thread::sleep(delay);
+        let result = EmbeddedController::open_path(&inner.path);
+        let mut queue = inner.queue.lock().unwrap();
+        if queue.worker != id {
+            return None;
+        }
+        match result {
+            Ok(ec) => return Some(ec),
+            Err(e) => {
+                delay = (delay * 2).min(MAX_REOPEN_DELAY);
+                log::error!("failed to reopen the EC: {e}; retrying in {delay:?}");
+                queue.fail_all(&e);
+            }
+        }
+    }
+}
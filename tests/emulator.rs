@@ -0,0 +1,189 @@
+//! Runs the CLI and the daemon against the EC emulator.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+const KEYLIGHTD: &str = env!("CARGO_BIN_EXE_keylightd");
+
+/// An emulator process that is killed when dropped.
+struct Emulator {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Emulator {
+    fn start() -> Self {
+        Self::start_at(env::temp_dir().join(format!("keylightd-test-{}.sock", std::process::id())))
+    }
+
+    fn start_at(socket: PathBuf) -> Self {
+        let child = Command::new(KEYLIGHTD)
+            .arg("emulate-ec")
+            .arg(&socket)
+            .spawn()
+            .unwrap();
+        wait_for(&socket);
+        Self { child, socket }
+    }
+
+    fn run(&self, args: &[&str]) -> String {
+        run(
+            Command::new(KEYLIGHTD)
+                .arg("--ec-device")
+                .arg(Path::new(&self.socket)),
+            args,
+        )
+    }
+
+    /// Returns the keyboard backlight brightness the emulated EC currently has.
+    fn backlight(&self) -> String {
+        // The response of `EC_CMD_PWM_GET_KEYBOARD_BACKLIGHT` is the percentage and an "enabled"
+        // flag.
+        let raw = self.run(&["ec", "raw", "0x22"]);
+        raw.lines().last().unwrap().trim().to_string()
+    }
+}
+
+fn run(command: &mut Command, args: &[&str]) -> String {
+    let output = command.args(args).output().unwrap();
+    assert!(
+        output.status.success(),
+        "keylightd {args:?} failed: {output:?}"
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn wait_for(path: &Path) {
+    for _ in 0..500 {
+        if path.exists() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} was not created", path.display());
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+/// A daemon with its own runtime and state directory, killed when dropped.
+struct Daemon {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Daemon {
+    fn start(dir: &Path, emulator: &Emulator, config: &str) -> Self {
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, config).unwrap();
+        let child = Self::command(dir)
+            .arg("--ec-device")
+            .arg(&emulator.socket)
+            .arg("run")
+            .arg("--config")
+            .arg(&config_path)
+            .spawn()
+            .unwrap();
+        wait_for(&dir.join("keylightd.sock"));
+        Self {
+            child,
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn command(dir: &Path) -> Command {
+        let mut command = Command::new(KEYLIGHTD);
+        command
+            .env("KEYLIGHTD_RUNTIME_DIR", dir)
+            .env("KEYLIGHTD_STATE_DIR", dir);
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> String {
+        run(&mut Self::command(&self.dir), args)
+    }
+
+    fn stop(mut self) {
+        kill(Pid::from_raw(self.child.id() as i32), Signal::SIGTERM).unwrap();
+        let status = self.child.wait().unwrap();
+        assert!(status.success(), "daemon exited with {status}");
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn cli_against_emulator() {
+    let emulator = Emulator::start();
+
+    let version = emulator.run(&["ec", "version"]);
+    assert!(
+        version.contains("RO version:    keylightd-emulator"),
+        "{version}"
+    );
+    assert!(version.contains("protocol:      v3"), "{version}");
+
+    let leds = emulator.run(&["led", "query", "power"]);
+    assert_eq!(leds, "power:\n  white    0-100\n");
+
+    emulator.run(&["led", "set", "left", "off"]);
+    emulator.run(&["led", "set", "left", "auto"]);
+}
+
+#[test]
+fn daemon_against_emulator() {
+    let dir = env::temp_dir().join(format!("keylightd-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let emulator = Emulator::start_at(dir.join("ec.sock"));
+    let daemon = Daemon::start(&dir, &emulator, "brightness = 40\ntimeout = 2\n");
+
+    let status = daemon.run(&["status"]);
+    assert!(status.contains("brightness: 40%"), "{status}");
+    assert!(status.contains("timeout:    2s"), "{status}");
+
+    daemon.run(&["set", "--brightness", "60"]);
+    let status = daemon.run(&["status"]);
+    assert!(status.contains("brightness: 60%"), "{status}");
+    assert_eq!(emulator.backlight(), "3c 01");
+
+    // Nothing is typed during the test, so the backlight fades out after the timeout.
+    let start = Instant::now();
+    while emulator.backlight() != "00 01" {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the backlight didn't fade out: {}",
+            emulator.backlight(),
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Stopping the daemon turns the backlight back on, and removes the PID and recovery files.
+    daemon.stop();
+    assert_eq!(emulator.backlight(), "3c 01");
+    assert!(!dir.join("keylightd.pid").exists());
+    assert!(!dir.join("keylightd.state").exists());
+
+    drop(emulator);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,137 @@
+//! Property tests of the fade logic against a mock backlight.
+
+use std::{cell::Cell, io};
+
+use keylightd::fade::{fade_to, Backlight};
+use proptest::{collection::vec, prelude::*};
+
+/// A backlight that records every change, and checks that each one starts where the last ended.
+struct MockBacklight {
+    level: u8,
+    changes: Vec<u8>,
+}
+
+impl MockBacklight {
+    fn new(level: u8) -> Self {
+        Self {
+            level,
+            changes: Vec::new(),
+        }
+    }
+}
+
+impl Backlight for MockBacklight {
+    fn get(&mut self) -> io::Result<u8> {
+        Ok(self.level)
+    }
+
+    fn set(&mut self, from: u8, to: u8) -> io::Result<()> {
+        assert_eq!(
+            from, self.level,
+            "change doesn't start at the current level"
+        );
+        assert!(to <= 100, "brightness {to} out of range");
+        // Bounds the fade, so that a fade that doesn't terminate fails instead of hanging.
+        assert!(self.changes.len() < 10_000, "fade doesn't terminate");
+        self.level = to;
+        self.changes.push(to);
+        Ok(())
+    }
+}
+
+proptest! {
+    // Targets above 100 are clamped, so the target covers the whole `u8` range.
+    #[test]
+    fn fade_to_fixed_target(start in 0..=100u8, target: u8, instant: bool) {
+        let expected = target.min(100);
+
+        let mut backlight = MockBacklight::new(start);
+        let pauses = Cell::new(0);
+        let reached = fade_to(
+            &mut backlight,
+            instant,
+            || target,
+            || pauses.set(pauses.get() + 1),
+        )
+        .unwrap();
+
+        prop_assert_eq!(reached, expected);
+        prop_assert_eq!(backlight.level, expected);
+        let commands = if instant {
+            usize::from(start != expected)
+        } else {
+            usize::from(start.abs_diff(expected))
+        };
+        prop_assert_eq!(backlight.changes.len(), commands);
+        prop_assert_eq!(pauses.get(), commands);
+
+        // Every step moves towards the target, without overshooting it.
+        let (low, high) = (start.min(expected), start.max(expected));
+        let mut prev = start;
+        for &level in &backlight.changes {
+            prop_assert!((low..=high).contains(&level), "{} out of bounds", level);
+            prop_assert!(
+                level.abs_diff(expected) < prev.abs_diff(expected),
+                "{} -> {} moves away from the target",
+                prev,
+                level
+            );
+            if !instant {
+                prop_assert_eq!(level.abs_diff(prev), 1, "{} -> {}", prev, level);
+            }
+            prev = level;
+        }
+    }
+
+    // The target changes a few times during the fade, each after holding for a number of steps.
+    #[test]
+    fn fade_to_changing_target(
+        start in 0..=100u8,
+        targets in vec((0..=100u8, 0..30usize), 1..=6),
+    ) {
+        let (last, _) = *targets.last().unwrap();
+
+        let mut backlight = MockBacklight::new(start);
+        let calls = Cell::new(0);
+        let current = Cell::new(None);
+        let target = || {
+            let call = calls.get();
+            calls.set(call + 1);
+            let mut steps = 0;
+            let target = targets
+                .iter()
+                .find(|&&(_, hold)| {
+                    steps += hold;
+                    call < steps
+                })
+                .map_or(last, |&(target, _)| target);
+            current.set(Some(target));
+            target
+        };
+        let reached = fade_to(&mut backlight, false, target, || {}).unwrap();
+
+        // The fade ends as soon as it reaches the target of the moment.
+        prop_assert_eq!(Some(reached), current.get());
+        let mut prev = start;
+        for &level in &backlight.changes {
+            prop_assert_eq!(level.abs_diff(prev), 1, "{} -> {}", prev, level);
+            prev = level;
+        }
+        // Once the target stops changing, the fade goes straight there.
+        let settled = targets.iter().map(|&(_, hold)| hold).sum();
+        let tail = backlight.changes.get(settled..).unwrap_or_default();
+        let mut prev = settled
+            .checked_sub(1)
+            .and_then(|i| backlight.changes.get(i).copied())
+            .unwrap_or(start);
+        for &level in tail {
+            prop_assert!(
+                level.abs_diff(last) < prev.abs_diff(last),
+                "{} -> {} moves away from the final target",
+                prev,
+                level
+            );
+            prev = level;
+        }
+    }
+}